@@ -0,0 +1,4 @@
+fn main() {
+    // Compiling grammars at runtime (see `src/compile.rs`) requires knowing the target.
+    println!("cargo:rustc-env=TSC_TARGET={}", std::env::var("TARGET").unwrap());
+}
@@ -0,0 +1,268 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    mem,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use emacs::{defun, Env, GlobalRef, IntoLisp, Result, Value};
+use once_cell::sync::Lazy;
+
+use crate::{
+    types,
+    lang::{self, Language},
+    query::{self, Query, QueryCursor},
+    pattern::Patterns,
+    parser::RParser,
+    instrument::Counter,
+    error,
+};
+
+// -------------------------------------------------------------------------------------------------
+// Shared queries
+
+/// A compiled query, shared by all callers that compile the same source with the same
+/// tag assigner, e.g. all buffers of the same major mode.
+struct SharedQuery {
+    tag_assigner: GlobalRef,
+    query: GlobalRef,
+}
+
+/// Shared queries, by language id and source.
+static QUERIES: Lazy<Mutex<HashMap<(usize, String), Vec<SharedQuery>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub(crate) static QUERY_HITS: Counter = Counter::new();
+pub(crate) static QUERY_MISSES: Counter = Counter::new();
+pub(crate) static LANGUAGE_HITS: Counter = Counter::new();
+
+/// Lock the shared query cache. The lock is never held across calls into Lisp.
+fn queries() -> MutexGuard<'static, HashMap<(usize, String), Vec<SharedQuery>>> {
+    QUERIES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Return the query compiled from SOURCE for LANGUAGE, with TAG-ASSIGNER, compiling it only if
+/// it's not in the shared cache. See `tsc--make-query'.
+///
+/// The returned query is shared, so it must not be modified (e.g. with
+/// `tsc--disable-capture') or released. A released query is compiled again.
+#[defun]
+fn _make_shared_query<'e>(
+    language: Language,
+    source: Value<'e>,
+    tag_assigner: Value<'e>,
+) -> Result<Value<'e>> {
+    let env = tag_assigner.env;
+    // Patterns given as Lisp data are cached by their serialization.
+    let patterns = Patterns::new(source)?;
+    let key = (language.id(), patterns.source.clone());
+    let cached = queries().get(&key).and_then(|entries| {
+        entries.iter()
+            .find(|e| e.tag_assigner.bind(env).eq(tag_assigner))
+            .map(|e| e.query.bind(env))
+    });
+    if let Some(query) = cached {
+        let released = query.into_rust::<&RefCell<Query>>()?.try_borrow()?.is_released();
+        if !released {
+            QUERY_HITS.incr();
+            return Ok(query);
+        }
+    }
+    QUERY_MISSES.incr();
+    let (language_id, source) = key;
+    let query = RefCell::new(query::make_query(language, &patterns, tag_assigner)?)
+        .into_lisp(env)?;
+    let mut cache = queries();
+    let entries = cache.entry((language_id, source)).or_default();
+    // Replace the released query, if any.
+    match entries.iter_mut().find(|e| e.tag_assigner.bind(env).eq(tag_assigner)) {
+        Some(entry) => mem::replace(&mut entry.query, query.make_global_ref()).free(env)?,
+        None => entries.push(SharedQuery {
+            tag_assigner: tag_assigner.make_global_ref(),
+            query: query.make_global_ref(),
+        }),
+    }
+    Ok(query)
+}
+
+// -------------------------------------------------------------------------------------------------
+// Parser pool
+
+/// Maximum number of idle parsers kept in the pool, per language.
+const POOL_CAPACITY: usize = 4;
+
+/// Idle parsers checked in with `tsc-checkin-parser`, by language id.
+static PARSERS: Lazy<Mutex<HashMap<usize, Vec<GlobalRef>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Lock the parser pool. Like the shared query cache, it is never held across calls into Lisp.
+fn parsers() -> MutexGuard<'static, HashMap<usize, Vec<GlobalRef>>> {
+    PARSERS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Return an idle parser of LANGUAGE from the parser pool, or a new one if there is
+/// none. It should be returned with `tsc-checkin-parser' when it's not needed anymore,
+/// e.g. when its buffer is killed, so that buffers of the same major mode can share a
+/// small number of parsers.
+///
+/// The returned parser is like a new parser of LANGUAGE: it has no included ranges,
+/// timeout, cancellation flag, or logger, and its next parse starts from scratch.
+#[defun]
+fn checkout_parser(env: &Env, language: Language) -> Result<Value<'_>> {
+    let idle = parsers().get_mut(&language.id()).and_then(Vec::pop);
+    if let Some(idle) = idle {
+        let parser = idle.bind(env);
+        idle.free(env)?;
+        parser.into_rust::<&RefCell<RParser>>()?.try_borrow_mut()?.check_out();
+        return Ok(parser);
+    }
+    let mut parser = RParser::new();
+    parser.set_language(language.into()).or_else(|err| error::signal_lang_abi_error(env, err))?;
+    RefCell::new(parser).into_lisp(env)
+}
+
+/// Return PARSER, obtained with `tsc-checkout-parser', to the parser pool.
+///
+/// PARSER is reset, and its included ranges, timeout, cancellation flag, and logger
+/// are discarded. Parsing with it signals `tsc-error' until it's checked out again.
+/// If the pool already has enough idle parsers of PARSER's language, it's left to
+/// the garbage collector instead.
+#[defun]
+fn checkin_parser(env: &Env, parser: Value) -> Result<()> {
+    let language_id = {
+        let mut rparser = parser.into_rust::<&RefCell<RParser>>()?.try_borrow_mut()?;
+        let language_id = match rparser.language() {
+            Some(language) => Language::from(language).id(),
+            None => return env.signal(error::tsc_error, (
+                "Parser has no language",
+                error::_kind, error::kind(env, "no-language")?,
+            )),
+        };
+        rparser.check_in(env)?;
+        language_id
+    };
+    let mut pool = parsers();
+    let idle = pool.entry(language_id).or_default();
+    if idle.len() < POOL_CAPACITY {
+        idle.push(parser.make_global_ref());
+    }
+    Ok(())
+}
+
+// -------------------------------------------------------------------------------------------------
+// Query cursor pool
+
+/// Maximum number of idle query cursors kept in the pool.
+const CURSOR_POOL_CAPACITY: usize = 8;
+
+/// Idle query cursors, used by the query functions that are not given a cursor.
+///
+/// Only the cursors are pooled. The matches and captures are still copied eagerly into a vector
+/// allocated by each call, since its elements are Lisp values, which cannot outlive the call.
+static CURSORS: Lazy<Mutex<Vec<GlobalRef>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Lock the query cursor pool. Like the parser pool, it is never held across calls into Lisp.
+fn cursors() -> MutexGuard<'static, Vec<GlobalRef>> {
+    CURSORS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Return an idle query cursor from the pool, or a new one if there is none. It
+/// should be returned with `tsc--checkin-query-cursor' right after use, so that
+/// repeated queries, e.g. while scrolling, don't allocate cursors each time. See
+/// `tsc--with-query-cursor'.
+#[defun]
+fn _checkout_query_cursor(env: &Env) -> Result<Value<'_>> {
+    let idle = cursors().pop();
+    if let Some(idle) = idle {
+        let cursor = idle.bind(env);
+        idle.free(env)?;
+        return Ok(cursor);
+    }
+    RefCell::new(QueryCursor::new()).into_lisp(env)
+}
+
+/// Reset CURSOR, obtained with `tsc--checkout-query-cursor', and return it to the
+/// pool. If the pool is full, CURSOR is left to the garbage collector instead.
+#[defun]
+fn _checkin_query_cursor(env: &Env, cursor: Value) -> Result<()> {
+    {
+        let mut rcursor = cursor.into_rust::<&RefCell<QueryCursor>>()?.try_borrow_mut()?;
+        if rcursor.is_released() {
+            return Ok(());
+        }
+        rcursor.reset(env)?;
+    }
+    let mut pool = cursors();
+    if pool.len() < CURSOR_POOL_CAPACITY {
+        pool.push(cursor.make_global_ref());
+    }
+    Ok(())
+}
+
+// -------------------------------------------------------------------------------------------------
+
+emacs::use_symbols! {
+    _languages      => ":languages"
+    _language_hits  => ":language-hits"
+    _queries        => ":queries"
+    _query_hits     => ":query-hits"
+    _query_misses   => ":query-misses"
+    _pooled_parsers => ":pooled-parsers"
+    _pooled_cursors => ":pooled-query-cursors"
+}
+
+/// Return statistics about the module's shared caches, as a plist:
+///
+/// :languages      Number of loaded languages.
+/// :language-hits  Number of language loads that returned an already loaded language.
+/// :queries        Number of shared queries. See `tsc-make-shared-query'.
+/// :query-hits     Number of shared query requests that did not compile anything.
+/// :query-misses   Number of shared query requests that compiled a query.
+/// :pooled-parsers Number of idle parsers in the parser pool. See `tsc-checkout-parser'.
+/// :pooled-query-cursors
+///                 Number of idle query cursors, reused by the query functions that
+///                 are not given a cursor, e.g. `tsc-query-captures'.
+#[defun]
+fn cache_stats(env: &Env) -> Result<Value> {
+    let queries: usize = queries().values().map(Vec::len).sum();
+    let pooled_parsers: usize = parsers().values().map(Vec::len).sum();
+    let pooled_cursors = cursors().len();
+    types::list(env, vec![
+        _languages.bind(env), lang::loaded_count().into_lisp(env)?,
+        _language_hits.bind(env), LANGUAGE_HITS.get().into_lisp(env)?,
+        _queries.bind(env), queries.into_lisp(env)?,
+        _query_hits.bind(env), QUERY_HITS.get().into_lisp(env)?,
+        _query_misses.bind(env), QUERY_MISSES.get().into_lisp(env)?,
+        _pooled_parsers.bind(env), pooled_parsers.into_lisp(env)?,
+        _pooled_cursors.bind(env), pooled_cursors.into_lisp(env)?,
+    ])
+}
+
+/// Clear the module's shared query cache, parser pool, and query cursor pool, and
+/// reset the counters of `tsc-cache-stats'.
+///
+/// Queries that are still in use are not affected, but the next request for them
+/// compiles them again. Loaded languages are kept, since trees may still use them.
+#[defun]
+fn clear_caches(env: &Env) -> Result<()> {
+    let entries = mem::take(&mut *queries());
+    for (_, entries) in entries {
+        for entry in entries {
+            entry.tag_assigner.free(env)?;
+            entry.query.free(env)?;
+        }
+    }
+    let idle = mem::take(&mut *parsers());
+    for (_, parsers) in idle {
+        for parser in parsers {
+            parser.free(env)?;
+        }
+    }
+    for cursor in mem::take(&mut *cursors()) {
+        cursor.free(env)?;
+    }
+    for counter in &[&QUERY_HITS, &QUERY_MISSES, &LANGUAGE_HITS] {
+        counter.reset();
+    }
+    Ok(())
+}
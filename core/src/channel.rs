@@ -0,0 +1,229 @@
+use std::{
+    mem,
+    sync::{Mutex, MutexGuard, PoisonError},
+    thread,
+};
+
+#[cfg(unix)]
+use std::{
+    io::Write,
+    os::unix::net::{UnixListener, UnixStream},
+};
+
+use emacs::{defun, Env, IntoLisp, Result, Value, Vector};
+use once_cell::sync::Lazy;
+use tree_sitter::{Parser, Tree};
+
+use crate::{
+    lang::Language,
+    parser::{self, RParser},
+    tree::{self, Borrowed},
+    instrument::{self, Timer, PARSE_MICROS},
+    error,
+};
+
+// -------------------------------------------------------------------------------------------------
+// Completion channel
+
+/// The result of a background operation, which can be converted into a Lisp value on the main
+/// thread.
+trait Deliver: Send {
+    fn deliver<'e>(self: Box<Self>, env: &'e Env) -> Result<Value<'e>>;
+}
+
+struct Completion<T, D> {
+    result: T,
+    convert: D,
+}
+
+impl<T, D> Deliver for Completion<T, D>
+where
+    T: Send,
+    D: for<'e> FnOnce(&'e Env, T) -> Result<Value<'e>> + Send,
+{
+    fn deliver<'e>(self: Box<Self>, env: &'e Env) -> Result<Value<'e>> {
+        (self.convert)(env, self.result)
+    }
+}
+
+/// The results of background operations, waiting to be delivered to their Lisp callbacks.
+///
+/// Lisp values cannot be created outside of the main thread, so workers push their results
+/// here, and wake the main thread up by writing to the stream, which is connected to a Lisp
+/// network process. That process's filter then takes the completions. On platforms without
+/// Unix domain sockets, there is no stream, and the Lisp side polls instead.
+struct Channel {
+    next_id: u64,
+    completions: Vec<(u64, Box<dyn Deliver>)>,
+    #[cfg(unix)]
+    listener: Option<UnixListener>,
+    #[cfg(unix)]
+    stream: Option<UnixStream>,
+}
+
+static CHANNEL: Lazy<Mutex<Channel>> = Lazy::new(|| Mutex::new(Channel {
+    next_id: 1,
+    completions: vec![],
+    #[cfg(unix)]
+    listener: None,
+    #[cfg(unix)]
+    stream: None,
+}));
+
+/// Lock the channel. The lock is never held across calls into Lisp, or while doing any work
+/// other than queuing, so workers do not block the main thread.
+fn channel() -> MutexGuard<'static, Channel> {
+    CHANNEL.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Channel {
+    /// Wake the main thread up, if it's listening.
+    fn notify(&self) {
+        #[cfg(unix)]
+        if let Some(mut stream) = self.stream.as_ref() {
+            // A lost wakeup only delays the delivery until the next one.
+            stream.write_all(b"\n").ok();
+        }
+    }
+}
+
+/// Run WORK on a worker thread, and return the id of the operation. Its result is converted
+/// by CONVERT on the main thread, then passed to the callback registered with
+/// `tsc-async-then'.
+pub(crate) fn spawn<T, W, D>(work: W, convert: D) -> u64
+where
+    T: Send + 'static,
+    W: FnOnce() -> T + Send + 'static,
+    D: for<'e> FnOnce(&'e Env, T) -> Result<Value<'e>> + Send + 'static,
+{
+    let id = {
+        let mut channel = channel();
+        let id = channel.next_id;
+        channel.next_id += 1;
+        id
+    };
+    thread::spawn(move || {
+        let completion = Box::new(Completion { result: work(), convert });
+        let mut channel = channel();
+        channel.completions.push((id, completion));
+        channel.notify();
+    });
+    id
+}
+
+/// Start listening on a Unix domain socket at PATH, for the connection of the channel's
+/// Lisp process. Return nil if the platform does not support Unix domain sockets.
+#[cfg(unix)]
+#[defun]
+fn _channel_listen(path: String) -> Result<bool> {
+    channel().listener = Some(UnixListener::bind(path)?);
+    Ok(true)
+}
+
+/// Start listening on a Unix domain socket at PATH, for the connection of the channel's
+/// Lisp process. Return nil if the platform does not support Unix domain sockets.
+#[cfg(not(unix))]
+#[defun]
+fn _channel_listen(_path: String) -> Result<bool> {
+    Ok(false)
+}
+
+/// Accept the connection of the channel's Lisp process, which must already be connected to
+/// the socket created by `tsc--channel-listen'.
+#[defun]
+fn _channel_accept() -> Result<()> {
+    #[cfg(unix)]
+    {
+        let listener = channel().listener.take();
+        if let Some(listener) = listener {
+            let (stream, _) = listener.accept()?;
+            let mut channel = channel();
+            channel.stream = Some(stream);
+            // Deliver the completions that arrived before the connection.
+            if !channel.completions.is_empty() {
+                channel.notify();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Return the completed operations' results, as a vector of [ID VALUE ERROR]. ERROR is
+/// nil, or the error that prevented the result's conversion, as (ERROR-SYMBOL . DATA),
+/// in which case VALUE is nil.
+#[defun]
+fn _channel_take(env: &Env) -> Result<Vector> {
+    let completions = mem::take(&mut channel().completions);
+    let vector = env.make_vector(completions.len(), ())?;
+    for (i, (id, completion)) in completions.into_iter().enumerate() {
+        let item = match completion.deliver(env) {
+            Ok(value) => env.vector((id, value, ()))?,
+            Err(err) => env.vector((id, (), error::error_to_lisp(env, err)?))?,
+        };
+        vector.set(i, item)?;
+    }
+    Ok(vector)
+}
+
+// -------------------------------------------------------------------------------------------------
+// Background operations
+
+/// Start parsing the INPUT string with LANGUAGE on a worker thread. Return the
+/// operation's id, which should be passed to `tsc-async-then'. The result is a tree.
+#[defun]
+fn _parse_string_async(language: Language, input: String) -> Result<u64> {
+    Ok(spawn(move || {
+        let mut parser = Parser::new();
+        // If this fails, the parser has no language, which is reported as the failure's reason.
+        parser.set_language(language.into()).ok();
+        let bytes = input.len();
+        let tree = {
+            let _timer = Timer::new(&PARSE_MICROS);
+            parser.parse(input, None)
+        };
+        instrument::record_parse(bytes, false);
+        tree.ok_or_else(|| parser::failure_reason(&parser))
+    }, |env, tree| match tree {
+        Ok(tree) => parser::shared_tree(tree).into_lisp(env),
+        Err(reason) => error::signal_parse_failed(env, reason),
+    }))
+}
+
+/// Start parsing TEXT with PARSER on a worker thread, reusing OLD-TREE if non-nil,
+/// like `tsc-parse-string'. Return the operation's id, which should be passed to
+/// `tsc-async-then'. The result is a tree.
+///
+/// The parse uses a copy of PARSER's language, included ranges, timeout, and
+/// cancellation flag. Until it completes, PARSER is busy: parsing with it, or
+/// changing its language or included ranges, signals `tsc-error' with the `:kind'
+/// `parser-busy'. A parse that times out, or is cancelled, cannot be resumed.
+#[defun]
+fn _parse_async<'e>(
+    env: &'e Env,
+    parser: &RParser,
+    text: String,
+    old_tree: Option<Borrowed<'e, Tree>>,
+) -> Result<u64> {
+    let old_tree = match old_tree {
+        Some(tree) => Some(tree.try_borrow()?.clone()),
+        None => None,
+    };
+    let mut detached = parser.detach(env)?;
+    let included_ranges = parser.included_ranges().to_vec();
+    Ok(spawn(move || {
+        let bytes = text.len();
+        let tree = {
+            let _timer = Timer::new(&PARSE_MICROS);
+            detached.parser.parse(text, old_tree.as_ref())
+        };
+        instrument::record_parse(bytes, old_tree.is_some());
+        tree.ok_or_else(|| parser::failure_reason(&detached.parser))
+    }, move |env, tree| match tree {
+        Ok(tree) => {
+            let tree = parser::shared_tree(tree);
+            tree::record_included_ranges(&tree, &included_ranges);
+            tree.into_lisp(env)
+        }
+        Err(reason) => error::signal_parse_failed(env, reason),
+    }))
+}
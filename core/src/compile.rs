@@ -0,0 +1,76 @@
+use std::path::{Path, PathBuf};
+
+use emacs::{defun, Env, Result};
+
+use crate::error;
+
+/// Return the directory containing the `parser.c` of the grammar in SRC_DIR: either SRC_DIR
+/// itself, or its `src` subdirectory, which is where the tree-sitter CLI generates it.
+fn parser_dir(src_dir: &Path) -> Option<PathBuf> {
+    [src_dir.to_path_buf(), src_dir.join("src")]
+        .iter()
+        .find(|dir| dir.join("parser.c").is_file())
+        .cloned()
+}
+
+/// Compile the grammar whose parser was generated in SRC-DIR into OUT-FILE, a shared
+/// lib that can be loaded with `tsc--load-language'. See `tsc-compile-grammar'.
+#[defun]
+fn _compile_grammar(env: &Env, src_dir: String, out_file: String) -> Result<()> {
+    let failed = |message: String, kind| {
+        error::signal_grammar_compile_failed(env, message, kind, &src_dir)
+    };
+    let dir = match parser_dir(Path::new(&src_dir)) {
+        Some(dir) => dir,
+        None => return failed(format!("Cannot find parser.c in {}", src_dir), "no-parser"),
+    };
+    let parser = dir.join("parser.c");
+    let scanner = ["scanner.c", "scanner.cc"].iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file());
+    let cpp = scanner.as_ref().map_or(false, |s| s.extension() == Some("cc".as_ref()));
+    let target = env!("TSC_TARGET");
+    let compiler = cc::Build::new()
+        .cargo_metadata(false)
+        .target(target)
+        .host(target)
+        .opt_level(2)
+        .cpp(cpp)
+        .try_get_compiler();
+    let compiler = match compiler {
+        Ok(compiler) => compiler,
+        Err(err) => return failed(err.to_string(), "no-compiler"),
+    };
+    let mut command = compiler.to_command();
+    if compiler.is_like_msvc() {
+        command.args(&["/nologo", "/LD", "/utf-8", "/I"]).arg(&dir).arg(&parser);
+        if let Some(scanner) = &scanner {
+            command.arg(scanner);
+        }
+        command.arg("/link").arg(format!("/out:{}", out_file));
+    } else {
+        command.args(&["-shared", "-fPIC", "-g0", "-I"]).arg(&dir);
+        // A C++ compiler must be told that parser.c is C.
+        if cpp {
+            command.args(&["-x", "c"]);
+        }
+        command.arg(&parser);
+        if let Some(scanner) = &scanner {
+            if cpp {
+                command.args(&["-x", "c++"]);
+            }
+            command.arg(scanner);
+        }
+        command.arg("-o").arg(&out_file);
+    }
+    let output = match command.output() {
+        Ok(output) => output,
+        Err(err) => return failed(format!("Cannot run the compiler: {}", err), "no-compiler"),
+    };
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let message = format!("Compiler failed ({}):\n{}", output.status, stderr.trim());
+        return failed(message, "compiler");
+    }
+    Ok(())
+}
@@ -0,0 +1,255 @@
+use std::{collections::HashSet, iter};
+
+use emacs::{defun, Result, Value, Vector};
+use tree_sitter::{InputEdit, Node, Parser, Tree};
+
+use crate::{
+    types::BytePos,
+    tree,
+    session::{self, Session},
+};
+
+/// Maximum duration of a trial parse. A trial that takes longer rejects its token.
+const TRIAL_TIMEOUT_MICROS: u64 = 20_000;
+
+/// Return whether NODE is the first node on its line.
+fn starts_line(node: Node) -> bool {
+    let row = node.start_position().row;
+    let mut n = node;
+    loop {
+        if let Some(previous) = n.prev_sibling() {
+            return previous.end_position().row < row;
+        }
+        match n.parent() {
+            Some(parent) => n = parent,
+            None => return true,
+        }
+    }
+}
+
+/// Return the region of ROOT that a token inserted at POS can affect, for trial parses: the
+/// innermost ERROR node around POS, or else the innermost statement-like node, i.e. a named node
+/// that starts its line, and that does not start at the same position as its parent. Return
+/// None if the region is the whole document.
+fn trial_region(root: Node, pos: usize) -> Option<Node> {
+    let node = root.descendant_for_byte_range(pos, pos)?;
+    let mut ancestors = iter::successors(Some(node), |n| n.parent())
+        .take_while(|n| n.parent().is_some());
+    let region = match ancestors.clone().find(|n| n.is_error()) {
+        Some(error) => error,
+        None => ancestors.find(|n| {
+            n.is_named() && n.parent().map_or(false, |p| p.start_byte() < n.start_byte())
+                && starts_line(*n)
+        })?,
+    };
+    Some(region)
+}
+
+/// Return the text generated by INPUT_FUNCTION, from byte offset START, at POINT, to byte offset
+/// END, or to the end of the text if END is None.
+fn read_text(
+    input_function: Value,
+    start: usize,
+    point: tree_sitter::Point,
+    end: Option<usize>,
+) -> Result<Vec<u8>> {
+    let mut text = vec![];
+    session::for_each_chunk_from(input_function, start, point, |chunk| {
+        text.extend_from_slice(chunk.as_bytes());
+        end.map_or(true, |end| start + text.len() < end)
+    })?;
+    if let Some(end) = end {
+        text.truncate(end.saturating_sub(start));
+    }
+    Ok(text)
+}
+
+/// Return the literal tokens of SESSION's language (keywords, punctuation) that can
+/// appear at BYTEPOS, as a vector of strings. INPUT-FUNCTION must generate the text
+/// of SESSION's latest tree, which must be in sync with it.
+///
+/// Since this version of tree-sitter does not expose parse states, each candidate
+/// token is checked by an incremental trial parse, with the token inserted at
+/// BYTEPOS. A token is valid if the parser accepts it there, outside of any ERROR
+/// node. Code after BYTEPOS is not required to stay valid.
+///
+/// The trial parses only cover the ERROR node, or else the statement, around
+/// BYTEPOS, if it can be parsed on its own. Only that part of the text is
+/// generated. Each trial parse is bounded by a timeout. The results are cached
+/// until SESSION's next parse.
+#[defun]
+fn _session_valid_tokens_at<'e>(
+    session: &mut Session,
+    bytepos: BytePos,
+    input_function: Value<'e>,
+) -> Result<Vector<'e>> {
+    let env = input_function.env;
+    let tree = match &session.tree {
+        Some(tree) => tree,
+        None => return env.make_vector(0, ()),
+    };
+    let tree_id = tree::tree_id(tree);
+    let tree = tree.try_borrow()?.clone();
+    let language = match session.parser.language() {
+        Some(language) => language,
+        None => return env.make_vector(0, ()),
+    };
+    let pos: usize = bytepos.into();
+    let cacheable = !tree.root_node().has_changes();
+    let cached = session.valid_tokens.as_ref()
+        .filter(|(id, _)| cacheable && *id == tree_id)
+        .and_then(|(_, cache)| cache.get(&pos).cloned());
+    let valid = match cached {
+        Some(valid) => valid,
+        None => {
+            let timeout = session.parser.timeout_micros();
+            session.parser.set_timeout_micros(TRIAL_TIMEOUT_MICROS);
+            let valid = trial_tokens(&mut session.parser, language, &tree, pos, input_function);
+            // The parser's internal state must not leak into the next parse.
+            session.parser.set_included_ranges(&[]).ok();
+            session.parser.set_timeout_micros(timeout);
+            session.parser.reset();
+            let valid = valid?;
+            if cacheable {
+                match &mut session.valid_tokens {
+                    Some((id, cache)) if *id == tree_id => {
+                        cache.insert(pos, valid.clone());
+                    }
+                    cache => *cache = Some((tree_id, iter::once((pos, valid.clone())).collect())),
+                }
+            }
+            valid
+        }
+    };
+    let vector = env.make_vector(valid.len(), ())?;
+    for (i, token) in valid.into_iter().enumerate() {
+        vector.set(i, token)?;
+    }
+    Ok(vector)
+}
+
+/// Return the literal tokens of LANGUAGE that PARSER accepts at POS, by trial parses of the text
+/// generated by INPUT_FUNCTION, which TREE was parsed from. See `_session_valid_tokens_at`.
+fn trial_tokens(
+    parser: &mut Parser,
+    language: tree_sitter::Language,
+    tree: &Tree,
+    pos: usize,
+    input_function: Value,
+) -> Result<Vec<&'static str>> {
+    // Parse the region on its own, to check that it can be, and to get a tree to reuse. An ERROR
+    // node is not expected to parse without errors.
+    let region = trial_region(tree.root_node(), pos).and_then(|region| {
+        let range = tree_sitter::Range {
+            start_byte: region.start_byte(),
+            end_byte: region.end_byte(),
+            start_point: region.start_position(),
+            end_point: region.end_position(),
+        };
+        let text = match read_text(input_function, range.start_byte, range.start_point,
+                                   Some(range.end_byte)) {
+            Ok(text) => text,
+            Err(e) => return Some(Err(e)),
+        };
+        parser.set_included_ranges(&[range]).ok()?;
+        let start = range.start_byte;
+        let baseline = parser.parse_with(&mut |byte: usize, _: tree_sitter::Point| -> &[u8] {
+            byte.checked_sub(start).and_then(|offset| text.get(offset..)).unwrap_or(&[])
+        }, None)?;
+        if baseline.root_node().has_error() && !region.is_error() {
+            return None;
+        }
+        Some(Ok((range, text, baseline)))
+    }).transpose()?;
+    let (range, text, old_tree) = match region {
+        Some((range, text, baseline)) => (Some(range), text, baseline),
+        None => {
+            parser.set_included_ranges(&[]).ok();
+            let start = tree_sitter::Point { row: 0, column: 0 };
+            (None, read_text(input_function, 0, start, None)?, tree.clone())
+        }
+    };
+    let start = range.map_or(0, |range| range.start_byte);
+    let pos = pos.max(start).min(start + text.len());
+    let before = &text[..pos - start];
+    let (row, column) = match before.iter().rposition(|&b| b == b'\n') {
+        Some(i) => (before.iter().filter(|&&b| b == b'\n').count(), before.len() - i - 1),
+        None => (0, before.len()),
+    };
+    let start_point = range.map_or(tree_sitter::Point { row: 0, column: 0 }, |r| r.start_point);
+    let start_position = tree_sitter::Point {
+        row: start_point.row + row,
+        column: if row == 0 { start_point.column + column } else { column },
+    };
+
+    let mut seen = HashSet::new();
+    let mut valid = vec![];
+    for id in 0..language.node_kind_count() as u16 {
+        if language.node_kind_is_named(id) || !language.node_kind_is_visible(id) {
+            continue;
+        }
+        let token = match language.node_kind_for_id(id) {
+            Some(token) if !token.is_empty() && !token.contains(char::is_whitespace) => token,
+            _ => continue,
+        };
+        if !seen.insert(token) {
+            continue;
+        }
+        // Surround the token with spaces, so that it's not lexed together with its neighbors.
+        let inserted = format!(" {} ", token);
+        let inserted = inserted.as_bytes();
+        let edit = InputEdit {
+            start_byte: pos,
+            old_end_byte: pos,
+            new_end_byte: pos + inserted.len(),
+            start_position,
+            old_end_position: start_position,
+            new_end_position: tree_sitter::Point {
+                row: start_position.row,
+                column: start_position.column + inserted.len(),
+            },
+        };
+        if let Some(range) = range {
+            let mut range = range;
+            range.end_byte += inserted.len();
+            if range.end_point.row == start_position.row {
+                range.end_point.column += inserted.len();
+            }
+            parser.set_included_ranges(&[range]).ok();
+        }
+        let mut trial = old_tree.clone();
+        trial.edit(&edit);
+        let input = &mut |byte: usize, _: tree_sitter::Point| -> &[u8] {
+            let offset = match byte.checked_sub(start) {
+                Some(offset) => offset,
+                None => return &[],
+            };
+            let at = pos - start;
+            if offset < at {
+                &text[offset..at]
+            } else if offset < at + inserted.len() {
+                &inserted[offset - at..]
+            } else {
+                text.get(offset - inserted.len()..).unwrap_or(&[])
+            }
+        };
+        let trial = match parser.parse_with(input, Some(&trial)) {
+            Some(trial) => trial,
+            None => {
+                // A timed-out parse must not be resumed by the next one.
+                parser.reset();
+                continue;
+            }
+        };
+        let (beg, end) = (pos + 1, pos + 1 + token.len());
+        let accepted = trial.root_node().descendant_for_byte_range(beg, end).map_or(false, |node| {
+            let mut ancestors = iter::successors(Some(node), |n| n.parent());
+            node.start_byte() == beg && node.end_byte() == end && node.kind() == token
+                && !node.is_missing() && !ancestors.any(|n| n.is_error())
+        });
+        if accepted {
+            valid.push(token);
+        }
+    }
+    Ok(valid)
+}
@@ -8,9 +8,12 @@ use emacs::{defun, Result, Value, Env, GlobalRef, Vector, IntoLisp, FromLisp};
 use tree_sitter::{Tree, TreeCursor, Node};
 
 use crate::{
-    types::{self, Shared, BytePos},
-    node::{RNode, LispUtils},
+    types::{self, Shared, BytePos, Point},
+    node::{self, RNode, NodeRef, LispUtils},
     lang::Language,
+    tree,
+    live::{Live, CURSORS},
+    error::Internal,
 };
 
 emacs::use_symbols! {
@@ -29,6 +32,7 @@ emacs::use_symbols! {
     _end_point   => ":end-point"
     _range       => ":range"
     _byte_range  => ":byte-range"
+    _node_ref    => ":node-ref"
 
     _field       => ":field"
     _depth       => ":depth"
@@ -42,6 +46,7 @@ emacs::use_symbols! {
 pub struct RCursor {
     tree: Shared<Tree>,
     inner: TreeCursor<'static>,
+    _live: Live,
 }
 
 impl_pred!(cursor_p, &RefCell<RCursor>);
@@ -85,9 +90,10 @@ impl<'e> DerefMut for RCursorBorrowMut<'e> {
 
 impl RCursor {
     pub fn new<'e, F: FnOnce(&'e Tree) -> TreeCursor<'e>>(tree: Shared<Tree>, f: F) -> Self {
+        // Trees are never mutably borrowed across calls into Lisp, so this cannot fail.
         let rtree = unsafe { types::erase_lifetime(&*tree.borrow()) };
         let inner = unsafe { mem::transmute(f(rtree)) };
-        Self { tree, inner }
+        Self { tree, inner, _live: Live::new(&CURSORS) }
     }
 
     pub fn clone_tree(&self) -> Shared<Tree> {
@@ -95,18 +101,25 @@ impl RCursor {
     }
 
     #[inline]
-    pub fn borrow(&self) -> RCursorBorrow {
-        let reft = self.tree.borrow();
+    pub fn tree_id(&self) -> usize {
+        tree::tree_id(&self.tree)
+    }
+
+    /// Borrow the cursor, together with its tree. This fails if the tree is being edited.
+    #[inline]
+    pub fn borrow(&self) -> Result<RCursorBorrow> {
+        let reft = self.tree.try_borrow()?;
         let cursor = &self.inner;
-        RCursorBorrow { reft, cursor }
+        Ok(RCursorBorrow { reft, cursor })
     }
 
+    /// Borrow the cursor mutably, and its tree immutably. This fails if the tree is being edited.
     #[inline]
-    pub fn borrow_mut<'e>(&'e mut self) -> RCursorBorrowMut {
-        let reft: Ref<'e, Tree> = self.tree.borrow();
+    pub fn borrow_mut<'e>(&'e mut self) -> Result<RCursorBorrowMut> {
+        let reft: Ref<'e, Tree> = self.tree.try_borrow()?;
         // XXX: Explain the safety here.
         let cursor: &'e mut _ = unsafe { mem::transmute(&mut self.inner) };
-        RCursorBorrowMut { reft, cursor }
+        Ok(RCursorBorrowMut { reft, cursor })
     }
 }
 
@@ -128,14 +141,15 @@ impl<'e> FromLisp<'e> for TreeOrNode<'e> {
 }
 
 impl<'e> TreeOrNode<'e> {
-    fn walk(&self) -> RCursor {
-        match *self {
+    fn walk(&self) -> Result<RCursor> {
+        Ok(match *self {
             Self::Tree(tree) => RCursor::new(tree.clone(), |tree| tree.walk()),
             Self::Node(node) => {
-                let node = node.borrow();
-                RCursor::new(node.clone_tree(), |_| node.borrow().walk())
+                let node = node.try_borrow()?;
+                let inner = node.borrow()?;
+                RCursor::new(node.clone_tree(), |_| inner.walk())
             }
-        }
+        })
     }
 }
 
@@ -150,23 +164,23 @@ impl<'e> TreeOrNode<'e> {
 /// If a tree is given, the returned cursor starts on its root node.
 #[defun(user_ptr)]
 fn make_cursor(tree_or_node: TreeOrNode) -> Result<RCursor> {
-    Ok(tree_or_node.walk())
+    tree_or_node.walk()
 }
 
 /// Return the field id of CURSOR's current node.
 /// Return nil if the current node doesn't have a field.
 #[defun]
 fn current_field_id(cursor: &RCursor) -> Result<Option<u16>> {
-    Ok(cursor.borrow().field_id())
+    Ok(cursor.borrow()?.field_id())
 }
 
 /// Return the field associated with CURSOR's current node, as a keyword.
 /// Return nil if the current node is not associated with a field.
 #[defun]
 fn current_field(cursor: &RCursor) -> Result<Option<&'static GlobalRef>> {
-    let cursor = cursor.borrow();
+    let cursor = cursor.borrow()?;
     let language: Language = cursor.reft.language().into();
-    Ok(cursor.field_id().and_then(|id| language.info().field_name(id)))
+    Ok(cursor.field_id().and_then(|id| language.info().ok()?.field_name(id)))
 }
 
 macro_rules! defun_cursor_walks {
@@ -175,7 +189,7 @@ macro_rules! defun_cursor_walks {
             $(#[$meta])*
             #[defun$((name = $lisp_name))?]
             fn $name(cursor: &mut RCursor, $( $( $param: $itype ),* )? ) -> Result<$type> {
-                Ok(cursor.borrow_mut().$name( $( $( $param $(.$into())? ),* )? ))
+                Ok(cursor.borrow_mut()?.$name( $( $( $param $(.$into())? ),* )? ))
             }
         )*
     };
@@ -197,12 +211,105 @@ defun_cursor_walks! {
     /// Move CURSOR to the first child that extends beyond the given BYTEPOS.
     /// Return the index of the child node if one was found, nil otherwise.
     "goto-first-child-for-byte" fn goto_first_child_for_byte(bytepos into: BytePos) -> Option<usize>
+
+    /// Move CURSOR to the first child that extends beyond the given POINT, of the form
+    /// (LINE-NUMBER . BYTE-COLUMN).
+    /// Return the index of the child node if one was found, nil otherwise.
+    "goto-first-child-for-point" fn goto_first_child_for_point(point into: Point) -> Option<usize>
 }
 
 /// Re-initialize CURSOR to start at a different NODE.
 #[defun]
 fn reset_cursor(cursor: &mut RCursor, node: &RNode) -> Result<()> {
-    Ok(cursor.borrow_mut().reset(*node.borrow()))
+    Ok(cursor.borrow_mut()?.reset(*node.borrow()?))
+}
+
+/// Move CURSOR to the same node as OTHER, which may be on a different tree. Unlike
+/// with `tsc-reset-cursor', CURSOR can then move back to the parents of that node, up
+/// to the node OTHER started from.
+#[defun]
+fn reset_cursor_to(cursor: &mut RCursor, other: &RCursor) -> Result<()> {
+    cursor.inner = other.inner.clone();
+    cursor.tree = other.clone_tree();
+    Ok(())
+}
+
+// The cursor's depth and descendant index are not tracked by the bundled version of tree-sitter,
+// so they are computed by walking the tree.
+
+/// Move CURSOR to the node after its current node in pre-order, without leaving the node it
+/// started from. Return false if there is no such node.
+fn goto_next_in_preorder(cursor: &mut TreeCursor) -> bool {
+    if cursor.goto_first_child() {
+        return true;
+    }
+    loop {
+        if cursor.goto_next_sibling() {
+            return true;
+        }
+        if !cursor.goto_parent() {
+            return false;
+        }
+    }
+}
+
+/// Return the depth of CURSOR's current node, relative to the node CURSOR started from.
+fn depth(cursor: &TreeCursor) -> usize {
+    let mut cursor = cursor.clone();
+    let mut depth = 0;
+    while cursor.goto_parent() {
+        depth += 1;
+    }
+    depth
+}
+
+/// Return the depth of CURSOR's current node, relative to the node CURSOR started
+/// from, which has depth 0.
+///
+/// This takes time proportional to the depth.
+#[defun]
+fn cursor_depth(cursor: &RCursor) -> Result<usize> {
+    Ok(depth(&cursor.borrow()?))
+}
+
+/// Return the index of CURSOR's current node among all of the descendants of the node
+/// CURSOR started from, in pre-order. The starting node has index 0.
+///
+/// This takes time proportional to the index. See `tsc-goto-descendant'.
+#[defun]
+fn cursor_descendant_index(cursor: &RCursor) -> Result<usize> {
+    let current = cursor.borrow()?;
+    let target = current.node();
+    let mut cursor = current.clone();
+    while cursor.goto_parent() {}
+    let mut index = 0;
+    while cursor.node() != target {
+        if !goto_next_in_preorder(&mut cursor) {
+            return Err(Internal("Failed to find the cursor's current node").into());
+        }
+        index += 1;
+    }
+    Ok(index)
+}
+
+/// Move CURSOR to the descendant at the given INDEX, in pre-order, of the node CURSOR
+/// started from, as returned by `tsc-cursor-descendant-index'.
+/// Return t if CURSOR successfully moved, nil if there was no such descendant, in
+/// which case CURSOR stays where it was.
+///
+/// This takes time proportional to INDEX.
+#[defun]
+fn goto_descendant(cursor: &mut RCursor, index: usize) -> Result<bool> {
+    let mut borrowed = cursor.borrow_mut()?;
+    let mut walker = borrowed.clone();
+    while walker.goto_parent() {}
+    for _ in 0..index {
+        if !goto_next_in_preorder(&mut walker) {
+            return Ok(false);
+        }
+    }
+    *borrowed = walker;
+    Ok(true)
 }
 
 // -------------------------------------------------------------------------------------------------
@@ -220,17 +327,37 @@ struct DepthFirstIterator {
     cursor: RCursor,
     state: TraversalState,
     depth: usize,
+    /// Positions of the current node and its ancestors within their parents, for `:node-ref`.
+    child_indexes: Vec<usize>,
 }
 
 // TODO: Provide a function to move backward.
 impl DepthFirstIterator {
-    fn new(tree_or_node: TreeOrNode) -> Self {
-        Self { cursor: tree_or_node.walk(), state: Start, depth: 0 }
+    fn new(tree_or_node: TreeOrNode) -> Result<Self> {
+        let cursor = tree_or_node.walk()?;
+        let child_indexes = vec![node::child_index(cursor.borrow()?.node())];
+        Ok(Self { cursor, state: Start, depth: 0, child_indexes })
+    }
+
+    #[inline]
+    fn child_index(&self) -> Option<usize> {
+        self.child_indexes.last().copied()
     }
 
     #[inline]
     fn item(&self) -> Option<(RNode, usize)> {
-        Some((RNode::new(self.cursor.clone_tree(), |_| self.cursor.borrow().node()), self.depth))
+        let node = self.cursor.borrow().ok()?.node();
+        Some((RNode::new(self.cursor.clone_tree(), |_| node), self.depth))
+    }
+
+    /// Move the cursor with F. If the tree cannot be borrowed (because it is being edited), the
+    /// cursor does not move, which ends the traversal.
+    #[inline]
+    fn goto<F: FnOnce(&mut TreeCursor) -> bool>(&mut self, f: F) -> bool {
+        match self.cursor.borrow_mut() {
+            Ok(mut cursor) => f(&mut cursor),
+            Err(_) => false,
+        }
     }
 
     fn close(&mut self) {
@@ -248,8 +375,9 @@ impl Iterator for DepthFirstIterator {
                 self.item()
             }
             Down => {
-                if self.cursor.borrow_mut().goto_first_child() {
+                if self.goto(|c| c.goto_first_child()) {
                     self.depth += 1;
+                    self.child_indexes.push(0);
                     self.item()
                 } else {
                     self.state = Right;
@@ -257,11 +385,15 @@ impl Iterator for DepthFirstIterator {
                 }
             }
             Right => {
-                if self.cursor.borrow_mut().goto_next_sibling() {
+                if self.goto(|c| c.goto_next_sibling()) {
                     self.state = Down;
+                    if let Some(i) = self.child_indexes.last_mut() {
+                        *i += 1;
+                    }
                     self.item()
-                } else if self.cursor.borrow_mut().goto_parent() {
+                } else if self.goto(|c| c.goto_parent()) {
                     self.depth -= 1;
+                    self.child_indexes.pop();
                     self.next()
                 } else {
                     self.state = Done;
@@ -277,7 +409,7 @@ impl Iterator for DepthFirstIterator {
 /// The traversal is pre-order.
 #[defun(user_ptr)]
 fn _iter(tree_or_node: TreeOrNode) -> Result<DepthFirstIterator> {
-    Ok(DepthFirstIterator::new(tree_or_node))
+    DepthFirstIterator::new(tree_or_node)
 }
 
 /// Move ITERATOR to the next node.
@@ -335,16 +467,17 @@ fn _iter_current_node<'e>(
             iterator.depth.into_lisp(env)
         }
         _ => {
-            let result = _current_node(cursor, props, output, env)?;
+            let result = current_node(cursor, iterator.child_index(), props, output, env)?;
             if let Some(VectorOrKeyword::Vector(props)) = props {
-                if let Some(output) = output {
-                    for (i, prop) in props.into_iter().enumerate() {
-                        if prop.eq(_depth.bind(env)) {
-                            output.set(i, iterator.depth)?;
-                        }
+                // Without OUTPUT, the result is a new vector.
+                let output: Vector = match output {
+                    Some(output) => output,
+                    None => result.into_rust()?,
+                };
+                for (i, prop) in props.into_iter().enumerate() {
+                    if prop.eq(_depth.bind(env)) {
+                        output.set(i, iterator.depth)?;
                     }
-                } else {
-                    todo!()
                 }
             }
             Ok(result)
@@ -369,7 +502,12 @@ fn _iter_next_node<'e>(
     }
 }
 
-fn get<'e>(prop: Value<'e>, node: Node, cursor: &RCursor) -> Result<Value<'e>> {
+fn get<'e>(
+    prop: Value<'e>,
+    node: Node,
+    cursor: &RCursor,
+    child_index: Option<usize>,
+) -> Result<Value<'e>> {
     macro_rules! sugar {
         ($prop:ident, $env:ident) => {
             macro_rules! eq {
@@ -382,7 +520,7 @@ fn get<'e>(prop: Value<'e>, node: Node, cursor: &RCursor) -> Result<Value<'e>> {
     let env = prop.env;
     sugar!(prop, env);
     if eq!(_type) {
-        node.lisp_type().into_lisp(env)
+        node.lisp_type()?.into_lisp(env)
     } else if eq!(_byte_range) {
         node.lisp_byte_range(env)
     } else if eq!(_start_byte) {
@@ -391,6 +529,8 @@ fn get<'e>(prop: Value<'e>, node: Node, cursor: &RCursor) -> Result<Value<'e>> {
         node.lisp_end_byte().into_lisp(env)
     } else if eq!(_field) {
         current_field(cursor)?.into_lisp(env)
+    } else if eq!(_depth) {
+        depth(&cursor.borrow()?).into_lisp(env)
     } else if eq!(_named_p) {
         node.is_named().into_lisp(env)
     } else if eq!(_extra_p) {
@@ -407,6 +547,9 @@ fn get<'e>(prop: Value<'e>, node: Node, cursor: &RCursor) -> Result<Value<'e>> {
         node.lisp_end_point().into_lisp(env)
     } else if eq!(_range) {
         node.lisp_range().into_lisp(env)
+    } else if eq!(_node_ref) {
+        let child_index = child_index.unwrap_or_else(|| node::child_index(node));
+        NodeRef::new(cursor.tree_id(), node, child_index).into_lisp(env)
     } else {
         // FIX: Signal an error instead.
         ().into_lisp(env)
@@ -431,7 +574,19 @@ fn _current_node<'e>(
     output: Option<Vector<'e>>,
     env: &'e Env,
 ) -> Result<Value<'e>> {
-    let node = cursor.borrow().node();
+    current_node(cursor, None, props, output, env)
+}
+
+/// Actual logic of `tsc--current-node'. CHILD-INDEX is the position of the current node within
+/// its parent, if it is already known by the caller.
+fn current_node<'e>(
+    cursor: &RCursor,
+    child_index: Option<usize>,
+    props: Option<VectorOrKeyword<'e>>,
+    output: Option<Vector<'e>>,
+    env: &'e Env,
+) -> Result<Value<'e>> {
+    let node = cursor.borrow()?.node();
     match props {
         None => RNode::new(cursor.clone_tree(), |_| node).into_lisp(env),
         Some(VectorOrKeyword::Vector(props)) => {
@@ -440,11 +595,11 @@ fn _current_node<'e>(
                 Some(output) => output,
             };
             for (i, prop) in props.into_iter().enumerate() {
-                result.set(i, get(prop, node, cursor)?)?;
+                result.set(i, get(prop, node, cursor, child_index)?)?;
             }
             result.into_lisp(env)
         }
-        Some(VectorOrKeyword::Keyword(prop)) => get(prop, node, cursor),
+        Some(VectorOrKeyword::Keyword(prop)) => get(prop, node, cursor, child_index),
     }
 }
 
@@ -456,7 +611,7 @@ fn _traverse_mapc(
     tree_or_node: TreeOrNode,
     props: Option<VectorOrKeyword>,
 ) -> Result<()> {
-    let mut iterator = DepthFirstIterator::new(tree_or_node);
+    let mut iterator = DepthFirstIterator::new(tree_or_node)?;
     let env = func.env;
     let mut output = None;
     let mut depth_indexes = Vec::with_capacity(1);
@@ -481,7 +636,8 @@ fn _traverse_mapc(
         let result = if depth {
             iterator.depth.into_lisp(env)?
         } else {
-            let result = _current_node(&iterator.cursor, props, output, env)?;
+            let child_index = iterator.child_index();
+            let result = current_node(&iterator.cursor, child_index, props, output, env)?;
             if let Some(output) = output {
                 for i in &depth_indexes {
                     output.set(*i, iterator.depth)?;
@@ -525,3 +681,130 @@ fn _traverse_mapc(
     // }
     Ok(())
 }
+
+/// Filters of `tsc-traverse-filter'.
+struct Filter {
+    /// Node types, as (NAME, NAMED).
+    types: Option<Vec<(String, bool)>>,
+    named: bool,
+    min_depth: usize,
+    max_depth: usize,
+    beg: usize,
+    end: usize,
+}
+
+impl Filter {
+    #[inline]
+    fn matches(&self, node: Node, depth: usize) -> bool {
+        depth >= self.min_depth
+            && (!self.named || node.is_named())
+            && self.types.as_ref().map_or(true, |types| {
+                types.iter().any(|(name, named)| *named == node.is_named() && name == node.kind())
+            })
+    }
+}
+
+/// Actual logic of `tsc-traverse-filter'.
+#[allow(clippy::too_many_arguments)]
+#[defun]
+fn _traverse_filter<'e>(
+    env: &'e Env,
+    func: Option<Value<'e>>,
+    tree_or_node: TreeOrNode,
+    props: Option<VectorOrKeyword<'e>>,
+    types: Option<Vector<'e>>,
+    named: Option<Value<'e>>,
+    min_depth: Option<usize>,
+    max_depth: Option<usize>,
+    beg: Option<BytePos>,
+    end: Option<BytePos>,
+) -> Result<Option<Value<'e>>> {
+    let types = match types {
+        None => None,
+        Some(types) => {
+            let mut parsed = Vec::with_capacity(types.len());
+            for t in types {
+                parsed.push(match t.into_rust::<String>() {
+                    Ok(name) => (name, false),
+                    Err(_) => (env.call("symbol-name", (t,))?.into_rust()?, true),
+                });
+            }
+            Some(parsed)
+        }
+    };
+    let filter = Filter {
+        types,
+        named: named.is_some(),
+        min_depth: min_depth.unwrap_or(0),
+        max_depth: max_depth.unwrap_or(usize::MAX),
+        beg: beg.map_or(0, |beg| beg.into()),
+        end: end.map_or(usize::MAX, |end| end.into()),
+    };
+    let mut depth_indexes = vec![];
+    if let Some(VectorOrKeyword::Vector(props)) = props {
+        for (i, prop) in props.into_iter().enumerate() {
+            if prop.eq(_depth.bind(env)) {
+                depth_indexes.push(i)
+            }
+        }
+    }
+    // Without FUNC, the results are collected, so they must not share the same vector.
+    let output = match (func, props) {
+        (Some(_), Some(VectorOrKeyword::Vector(props))) => Some(env.make_vector(props.len(), ())?),
+        _ => None,
+    };
+    let mut results = vec![];
+    let mut cursor = tree_or_node.walk()?;
+    let mut depth = 0;
+    // Positions of the current node and its ancestors within their parents, for `:node-ref`.
+    let mut child_indexes = vec![node::child_index(cursor.borrow()?.node())];
+    'walk: loop {
+        let node = cursor.borrow()?.node();
+        let in_range = node::intersects(node, filter.beg, filter.end);
+        if in_range && filter.matches(node, depth) {
+            let child_index = child_indexes.last().copied();
+            let result = current_node(&cursor, child_index, props, output, env)?;
+            if !depth_indexes.is_empty() {
+                let vector: Vector = result.into_rust()?;
+                for i in &depth_indexes {
+                    vector.set(*i, depth)?;
+                }
+            }
+            match func {
+                Some(func) => {
+                    func.call((result,))?;
+                }
+                None => results.push(result),
+            }
+        }
+        if in_range && depth < filter.max_depth && cursor.borrow_mut()?.goto_first_child() {
+            depth += 1;
+            child_indexes.push(0);
+            continue;
+        }
+        loop {
+            let mut cursor = cursor.borrow_mut()?;
+            if cursor.goto_next_sibling() {
+                if let Some(i) = child_indexes.last_mut() {
+                    *i += 1;
+                }
+                break;
+            }
+            if !cursor.goto_parent() {
+                break 'walk;
+            }
+            depth -= 1;
+            child_indexes.pop();
+        }
+    }
+    match func {
+        Some(_) => Ok(None),
+        None => {
+            let vector = env.make_vector(results.len(), ())?;
+            for (i, result) in results.into_iter().enumerate() {
+                vector.set(i, result)?;
+            }
+            Ok(Some(vector.into_lisp(env)?))
+        }
+    }
+}
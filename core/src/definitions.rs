@@ -0,0 +1,78 @@
+use std::{cell::RefCell, cmp::Reverse};
+
+use emacs::{defun, Result, Value, Vector};
+
+use crate::{
+    types::BytePos,
+    node::LispUtils,
+    query::{self, Query},
+    session::Session,
+};
+
+/// Return the names of the definitions enclosing BYTEPOS in SESSION's latest tree, outermost
+/// first, as a vector of strings.
+///
+/// NAME-QUERY's patterns should capture a definition's name as `@name', and the
+/// definition itself with another capture, for example:
+///
+///   (function_item name: (identifier) @name) @function
+///
+/// If a pattern captures only the name, the name's parent is used as the
+/// definition. TEXT-FUNCTION is called with 2 byte positions, and should return the
+/// source code between them. It is also used by text-based predicates.
+#[defun]
+fn _session_which_function<'e>(
+    session: &Session,
+    bytepos: BytePos,
+    name_query: &Query,
+    text_function: Value<'e>,
+) -> Result<Vector<'e>> {
+    let env = text_function.env;
+    let shared = match &session.tree {
+        Some(tree) => tree,
+        None => return env.make_vector(0, ()),
+    };
+    let tree = shared.try_borrow()?;
+    let byte: usize = bytepos.into();
+    let name_index = name_query.raw.capture_names().iter().position(|n| n == "name");
+    let mut definitions = vec![];
+    let error = RefCell::new(None);
+    let mut cursor = tree_sitter::QueryCursor::new();
+    cursor.set_byte_range(byte..byte + 1);
+    let matches = cursor.matches(
+        &name_query.raw,
+        tree.root_node(),
+        query::text_callback(text_function, &error),
+    );
+    for m in matches {
+        if !query::predicates_hold(&name_query.raw, &m, text_function, shared)? {
+            continue;
+        }
+        let name = match m.captures.iter().find(|c| Some(c.index as usize) == name_index) {
+            Some(c) => c.node,
+            None => continue,
+        };
+        let definition = m.captures.iter()
+            .find(|c| Some(c.index as usize) != name_index)
+            .map(|c| c.node)
+            .or_else(|| name.parent());
+        if let Some(definition) = definition {
+            if definition.start_byte() <= byte && byte < definition.end_byte() {
+                definitions.push((definition, name));
+            }
+        }
+    }
+    if let Some(error) = error.borrow_mut().take() {
+        return Err(error);
+    }
+    // Outer definitions start earlier, or at the same byte but end later.
+    definitions.sort_by_key(|(d, _)| (d.start_byte(), Reverse(d.end_byte())));
+    definitions.dedup_by_key(|(d, _)| d.id());
+    let vector = env.make_vector(definitions.len(), ())?;
+    for (i, (_, name)) in definitions.into_iter().enumerate() {
+        let name: String = text_function.call((name.lisp_start_byte(), name.lisp_end_byte()))?
+            .into_rust()?;
+        vector.set(i, name)?;
+    }
+    Ok(vector)
+}
@@ -0,0 +1,172 @@
+use std::path::Path;
+
+use emacs::{defun, Result, Value, Vector};
+use tree_sitter::{Parser, Tree};
+
+use crate::lang::Language;
+
+/// An entry of the registry passed to `tsc-detect-language'.
+struct Candidate<'e> {
+    name: Value<'e>,
+    extensions: Vec<String>,
+    interpreters: Vec<String>,
+    modes: Vec<String>,
+    language: Option<Language>,
+}
+
+/// Convert STRINGS, a vector of strings, into a `Vec`.
+fn strings(strings: Vector) -> Result<Vec<String>> {
+    (0..strings.len()).map(|i| strings.get(i)).collect()
+}
+
+impl<'e> Candidate<'e> {
+    /// Convert ENTRY, a vector [NAME EXTENSIONS INTERPRETERS MODES LANGUAGE], into a candidate.
+    fn from_entry(entry: Vector<'e>) -> Result<Self> {
+        Ok(Self {
+            name: entry.get(0)?,
+            extensions: strings(entry.get(1)?)?,
+            interpreters: strings(entry.get(2)?)?,
+            modes: strings(entry.get(3)?)?,
+            language: entry.get(4)?,
+        })
+    }
+}
+
+/// Return the interpreter named by the shebang line of HEAD, without its directory, e.g.
+/// "python3" for "#!/usr/bin/env python3".
+fn shebang_interpreter(head: &str) -> Option<&str> {
+    let line = head.lines().next()?.strip_prefix("#!")?;
+    let mut words = line.split_whitespace();
+    let mut program = words.next()?;
+    if program.rsplit('/').next() == Some("env") {
+        program = words.find(|w| !w.starts_with('-') && !w.contains('='))?;
+    }
+    program.rsplit('/').next()
+}
+
+/// Return the mode named by the `-*- ... -*-' line of HEAD, which must be one of its first 2
+/// lines, in lowercase, and without the "-mode" suffix.
+fn modeline_mode(head: &str) -> Option<String> {
+    let line = head.lines().take(2).find(|line| line.contains("-*-"))?;
+    let (_, rest) = line.split_once("-*-")?;
+    let (vars, _) = rest.split_once("-*-")?;
+    let mode = if vars.contains(':') {
+        vars.split(';').find_map(|var| {
+            let (name, value) = var.split_once(':')?;
+            if name.trim().eq_ignore_ascii_case("mode") { Some(value) } else { None }
+        })?
+    } else {
+        vars
+    };
+    let mode = mode.trim().to_lowercase();
+    Some(mode.strip_suffix("-mode").map(str::to_owned).unwrap_or(mode))
+}
+
+/// Return whether INTERPRETER matches NAME, ignoring version suffixes, e.g. "python3.11" and
+/// "python".
+fn interpreter_matches(interpreter: &str, name: &str) -> bool {
+    let unversioned = interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    interpreter == name || unversioned == name
+}
+
+/// Return the fraction of TREE's source code, which is LEN bytes long, covered by errors,
+/// counting each MISSING node as 1 byte.
+fn error_ratio(tree: &Tree, len: usize) -> f64 {
+    let mut errors = 0;
+    let mut cursor = tree.walk();
+    'walk: loop {
+        let node = cursor.node();
+        if node.is_error() {
+            errors += node.end_byte() - node.start_byte();
+        } else if node.is_missing() {
+            errors += 1;
+        } else if node.has_error() && cursor.goto_first_child() {
+            continue;
+        }
+        loop {
+            if cursor.goto_next_sibling() {
+                break;
+            }
+            if !cursor.goto_parent() {
+                break 'walk;
+            }
+        }
+    }
+    errors as f64 / len.max(1) as f64
+}
+
+/// Return the candidate among CANDIDATES whose language parses TEXT with the fewest errors, or
+/// None if none of them has a language. Ties are broken by registry order.
+fn best_trial<'c, 'e>(candidates: &[&'c Candidate<'e>], text: &str) -> Option<&'c Candidate<'e>> {
+    let mut parser = Parser::new();
+    let mut best: Option<(f64, &Candidate)> = None;
+    for candidate in candidates {
+        let language = match candidate.language {
+            Some(language) => language,
+            None => continue,
+        };
+        if parser.set_language(language.into()).is_err() {
+            continue;
+        }
+        let ratio = match parser.parse(text, None) {
+            Some(tree) => error_ratio(&tree, text.len()),
+            None => continue,
+        };
+        if best.map_or(true, |(best, _)| ratio < best) {
+            best = Some((ratio, *candidate));
+        }
+    }
+    best.map(|(_, candidate)| candidate)
+}
+
+/// Return the name of the language of the source code whose FILE-NAME, and first
+/// lines HEAD are given, or nil if it cannot be determined.
+///
+/// REGISTRY is a vector of [NAME EXTENSIONS INTERPRETERS MODES LANGUAGE]. The
+/// criteria are checked in this order, like `set-auto-mode': the mode named by the
+/// `-*-' line, the interpreter named by the shebang line, and FILE-NAME's
+/// extension. If several entries match the same criterion, and TRIAL-TEXT is
+/// non-nil, it is parsed with each of their LANGUAGEs, to pick the one with the
+/// fewest errors. Otherwise, the first one is picked. If no entry matches, and
+/// TRIAL-TEXT is non-nil, all entries with a LANGUAGE are tried.
+#[defun]
+fn _detect_language<'e>(
+    file_name: Option<String>,
+    head: String,
+    registry: Vector<'e>,
+    trial_text: Option<String>,
+) -> Result<Option<Value<'e>>> {
+    let candidates = (0..registry.len())
+        .map(|i| Candidate::from_entry(registry.get(i)?))
+        .collect::<Result<Vec<_>>>()?;
+    let mode = modeline_mode(&head);
+    let interpreter = shebang_interpreter(&head);
+    let extension = file_name.as_deref()
+        .and_then(|f| Path::new(f).extension())
+        .and_then(|e| e.to_str());
+    let criteria: [&dyn Fn(&Candidate) -> bool; 3] = [
+        &|c| mode.as_ref().map_or(false, |m| c.modes.contains(m)),
+        &|c| match interpreter {
+            Some(i) => c.interpreters.iter().any(|name| interpreter_matches(i, name)),
+            None => false,
+        },
+        &|c| match extension {
+            Some(e) => c.extensions.iter().any(|x| x.trim_start_matches('.') == e),
+            None => false,
+        },
+    ];
+    for matches in criteria.iter() {
+        let matched: Vec<&Candidate> = candidates.iter().filter(|c| matches(c)).collect();
+        let picked = match (matched.as_slice(), &trial_text) {
+            ([], _) => continue,
+            ([only], _) => Some(*only),
+            (several, Some(text)) => best_trial(several, text).or_else(|| several.first().copied()),
+            (several, None) => several.first().copied(),
+        };
+        return Ok(picked.map(|c| c.name));
+    }
+    Ok(match &trial_text {
+        Some(text) => best_trial(&candidates.iter().collect::<Vec<_>>(), text).map(|c| c.name),
+        None => None,
+    })
+}
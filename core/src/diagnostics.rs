@@ -0,0 +1,260 @@
+use emacs::{defun, Env, IntoLisp, Result, Value, Vector};
+use tree_sitter::{Node, Tree};
+
+use crate::{
+    types::{self, BytePos},
+    node::{self, RNode, LispUtils},
+    tree::Borrowed,
+};
+
+emacs::use_symbols! {
+    _error_count    => ":error-count"
+    _error_bytes    => ":error-bytes"
+    _largest_error  => ":largest-error"
+    _skipped_tokens => ":skipped-tokens"
+    _missing_count  => ":missing-count"
+    _total_bytes    => ":total-bytes"
+}
+
+/// Call F with each ERROR and MISSING node under NODE (inclusive), in document order. Subtrees
+/// without errors are skipped. So are the descendants of ERROR nodes.
+pub(crate) fn for_each_error_node<'t, F: FnMut(Node<'t>)>(node: Node<'t>, f: F) {
+    for_each_error_node_in(node, 0, usize::MAX, f)
+}
+
+/// Like `for_each_error_node`, but only for the nodes intersecting the range of byte offsets
+/// from BEG to END. Subtrees outside of the range are skipped.
+fn for_each_error_node_in<'t, F>(node: Node<'t>, beg: usize, end: usize, mut f: F)
+where
+    F: FnMut(Node<'t>),
+{
+    let mut cursor = node.walk();
+    loop {
+        let node = cursor.node();
+        let descend = if !node::intersects(node, beg, end) {
+            false
+        } else if node.is_error() || node.is_missing() {
+            f(node);
+            false
+        } else {
+            node.has_error()
+        };
+        if descend && cursor.goto_first_child() {
+            continue;
+        }
+        // The cursor cannot move past the starting node.
+        loop {
+            if cursor.goto_next_sibling() {
+                break;
+            }
+            if !cursor.goto_parent() {
+                return;
+            }
+        }
+    }
+}
+
+/// If NODE is a MISSING node, return the name of the zero-width token that the parser inserted
+/// to recover from a syntax error. Return nil otherwise.
+///
+/// For anonymous tokens, the name is the token's literal text, e.g. ";" or ")".
+/// For named tokens, it is the token's type, e.g. "identifier".
+#[defun]
+fn missing_token(node: &RNode) -> Result<Option<&'static str>> {
+    let node = node.borrow()?;
+    Ok(if node.is_missing() { Some(node.kind()) } else { None })
+}
+
+/// Return a vector of TREE's ERROR and MISSING nodes, in document order.
+/// Descendants of ERROR nodes are not included.
+#[defun]
+fn _error_nodes<'e>(env: &'e Env, tree: Borrowed<'e, Tree>) -> Result<Vector<'e>> {
+    let mut nodes = vec![];
+    for_each_error_node(tree.try_borrow()?.root_node(), |node| {
+        nodes.push(RNode::new(tree.clone(), |_| node));
+    });
+    let vector = env.make_vector(nodes.len(), ())?;
+    for (i, node) in nodes.into_iter().enumerate() {
+        vector.set(i, node)?;
+    }
+    Ok(vector)
+}
+
+/// Return a vector of the ERROR and MISSING nodes under NODE (inclusive), in document
+/// order, each in the form of [START-BYTEPOS END-BYTEPOS TYPE MISSING-P].
+///
+/// TYPE is ERROR for an ERROR node, and the type of the inserted token for a MISSING
+/// node, like `tsc-node-type'. Descendants of ERROR nodes are not included.
+///
+/// If BEG and END are non-nil, only the nodes intersecting that range of byte
+/// positions are returned, and the subtrees outside of it are not walked. A
+/// MISSING node, being zero-width, intersects the range if it's at its boundaries.
+///
+/// Unlike `tsc-diagnostics', this doesn't create node objects, or look at the
+/// source code, so it's cheap enough to be called after every change.
+#[defun]
+fn node_errors<'e>(
+    env: &'e Env,
+    node: &RNode,
+    beg: Option<BytePos>,
+    end: Option<BytePos>,
+) -> Result<Vector<'e>> {
+    let inner = node.borrow()?;
+    let beg = beg.map_or(0, |beg| beg.into());
+    let end = end.map_or(usize::MAX, |end| end.into());
+    let mut errors = vec![];
+    for_each_error_node_in(*inner, beg, end, |node| errors.push(node));
+    let vector = env.make_vector(errors.len(), ())?;
+    for (i, node) in errors.into_iter().enumerate() {
+        vector.set(i, env.vector((
+            node.lisp_start_byte(),
+            node.lisp_end_byte(),
+            node.lisp_type()?.bind(env),
+            node.is_missing(),
+        ))?)?;
+    }
+    Ok(vector)
+}
+
+/// Call F with each leaf node (token) under NODE (inclusive), in document order.
+pub(crate) fn for_each_leaf<'t, F: FnMut(Node<'t>)>(node: Node<'t>, mut f: F) {
+    let mut cursor = node.walk();
+    loop {
+        if cursor.goto_first_child() {
+            continue;
+        }
+        f(cursor.node());
+        loop {
+            if cursor.goto_next_sibling() {
+                break;
+            }
+            if !cursor.goto_parent() {
+                return;
+            }
+        }
+    }
+}
+
+/// Return the number of leaf nodes (tokens) under NODE (inclusive).
+fn count_leaves(node: Node) -> usize {
+    let mut count = 0;
+    for_each_leaf(node, |_| count += 1);
+    count
+}
+
+/// Return metrics of the quality of TREE's error recovery, as a plist:
+///
+/// :error-count     Number of ERROR nodes.
+/// :error-bytes     Total number of bytes inside ERROR nodes.
+/// :largest-error   Number of bytes spanned by the largest ERROR node.
+/// :skipped-tokens  Number of tokens inside ERROR nodes, i.e. skipped by the parser.
+/// :missing-count   Number of MISSING nodes, i.e. tokens inserted by the parser.
+/// :total-bytes     Number of bytes spanned by the whole tree.
+///
+/// These are useful to decide when tree-based features should be disabled, because
+/// the parse is too broken to be trusted.
+#[defun]
+fn error_metrics<'e>(env: &'e Env, tree: Borrowed<'e, Tree>) -> Result<Value<'e>> {
+    let tree = tree.try_borrow()?;
+    let root = tree.root_node();
+    let (mut error_count, mut error_bytes, mut largest_error) = (0, 0, 0);
+    let (mut skipped_tokens, mut missing_count) = (0, 0);
+    for_each_error_node(root, |node| {
+        if node.is_missing() {
+            missing_count += 1;
+        } else {
+            let bytes = node.end_byte() - node.start_byte();
+            error_count += 1;
+            error_bytes += bytes;
+            largest_error = largest_error.max(bytes);
+            skipped_tokens += count_leaves(node);
+        }
+    });
+    types::list(env, vec![
+        _error_count.bind(env), error_count.into_lisp(env)?,
+        _error_bytes.bind(env), error_bytes.into_lisp(env)?,
+        _largest_error.bind(env), largest_error.into_lisp(env)?,
+        _skipped_tokens.bind(env), skipped_tokens.into_lisp(env)?,
+        _missing_count.bind(env), missing_count.into_lisp(env)?,
+        _total_bytes.bind(env), (root.end_byte() - root.start_byte()).into_lisp(env)?,
+    ])
+}
+
+/// Return up to COUNT of TREE's literal tokens, sampled evenly in document order, as a
+/// vector of (BEG END . TEXT), where BEG and END are byte positions.
+///
+/// A literal token is an anonymous, non-MISSING leaf node, such as "fn" or ";". Its
+/// text is known from its type, so it can be compared against the source code.
+#[defun]
+fn _sample_tokens<'e>(env: &'e Env, tree: Borrowed<'e, Tree>, count: usize) -> Result<Vector<'e>> {
+    let tree = tree.try_borrow()?;
+    let mut tokens = vec![];
+    for_each_leaf(tree.root_node(), |node| {
+        if !node.is_named() && !node.is_missing() && node.start_byte() < node.end_byte() {
+            tokens.push(node);
+        }
+    });
+    let count = count.min(tokens.len());
+    let vector = env.make_vector(count, ())?;
+    for i in 0..count {
+        let node = tokens[i * tokens.len() / count];
+        let beg: BytePos = node.start_byte().into();
+        let end: BytePos = node.end_byte().into();
+        vector.set(i, env.cons(beg, env.cons(end, node.kind())?)?)?;
+    }
+    Ok(vector)
+}
+
+/// Maximum number of source lines of an error span to show in `explain_error`.
+const EXPLAIN_MAX_LINES: usize = 8;
+
+/// Return an annotated excerpt of SOURCE around the error NODE, for bug reports.
+///
+/// SOURCE must be the whole text that NODE's tree was parsed from. The excerpt
+/// shows the source lines spanned by NODE (with one line of context on each side),
+/// with NODE's bytes underlined, followed by the sexp of NODE's parent.
+///
+/// NODE is typically an ERROR or MISSING node, but can be any node.
+#[defun]
+fn explain_error(node: &RNode, source: String) -> Result<String> {
+    use std::fmt::Write;
+
+    let node = node.borrow()?;
+    let (start, end) = (node.start_position(), node.end_position());
+    let lines: Vec<&str> = source.split('\n').collect();
+    let first = start.row.saturating_sub(1);
+    let last = (end.row + 1).min(lines.len().saturating_sub(1));
+    let shown_last = last.min(start.row + EXPLAIN_MAX_LINES);
+    let width = (shown_last + 1).to_string().len();
+
+    let mut out = String::new();
+    let what = if node.is_missing() {
+        format!("missing `{}'", node.kind())
+    } else if node.is_error() {
+        "syntax error".to_owned()
+    } else {
+        format!("node `{}'", node.kind())
+    };
+    writeln!(out, "{} at {}:{}", what, start.row + 1, start.column).ok();
+    for row in first..=shown_last {
+        let line = lines.get(row).copied().unwrap_or("");
+        writeln!(out, "{:>width$} | {}", row + 1, line, width = width).ok();
+        if row < start.row || row > end.row {
+            continue;
+        }
+        let from = if row == start.row { start.column.min(line.len()) } else { 0 };
+        let to = if row == end.row { end.column.min(line.len()) } else { line.len() };
+        // Keep tabs, so that the underline is aligned with the line above.
+        let indent: String = line.get(..from).unwrap_or("").chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let carets = line.get(from..to.max(from)).map_or(0, |s| s.chars().count()).max(1);
+        writeln!(out, "{:>width$} | {}{}", "", indent, "^".repeat(carets), width = width).ok();
+    }
+    if shown_last < last {
+        writeln!(out, "{:>width$} | ...", "", width = width).ok();
+    }
+    let context = node.parent().unwrap_or(*node);
+    write!(out, "in: {}", context.to_sexp()).ok();
+    Ok(out)
+}
@@ -0,0 +1,311 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+};
+
+use emacs::{defun, Env, Result, Value, Vector};
+use tree_sitter::{Node, Tree};
+
+use crate::{
+    node::RNode,
+    tree::Borrowed,
+};
+
+emacs::use_symbols! {
+    insert
+    delete
+    update
+    move_ => "move"
+}
+
+/// Subtrees shorter than this are too common, e.g. punctuation, to be matched across the whole
+/// tree. They are only matched among the children of matched nodes.
+const MIN_MOVE_HEIGHT: usize = 2;
+
+/// A node of a flattened tree, in pre-order.
+struct Entry<'t> {
+    node: Node<'t>,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    field_id: Option<u16>,
+    /// Hash of the subtree's kinds, and leaves' texts. Identical subtrees have the same hash.
+    hash: u64,
+    height: usize,
+}
+
+fn flatten<'t>(tree: &'t Tree, text: &[u8]) -> Vec<Entry<'t>> {
+    let mut entries: Vec<Entry> = vec![];
+    let mut parents: Vec<usize> = vec![];
+    let mut cursor = tree.walk();
+    'walk: loop {
+        let index = entries.len();
+        let parent = parents.last().copied();
+        entries.push(Entry {
+            node: cursor.node(),
+            parent,
+            children: vec![],
+            field_id: cursor.field_id(),
+            hash: 0,
+            height: 0,
+        });
+        if let Some(parent) = parent {
+            entries[parent].children.push(index);
+        }
+        if cursor.goto_first_child() {
+            parents.push(index);
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                break 'walk;
+            }
+            parents.pop();
+        }
+    }
+    // Children come after their parents.
+    for i in (0..entries.len()).rev() {
+        let mut hasher = DefaultHasher::new();
+        let entry = &entries[i];
+        entry.node.kind_id().hash(&mut hasher);
+        if entry.children.is_empty() {
+            text.get(entry.node.start_byte()..entry.node.end_byte()).hash(&mut hasher);
+        }
+        let mut height = 0;
+        for &child in &entry.children {
+            entries[child].hash.hash(&mut hasher);
+            height = height.max(entries[child].height + 1);
+        }
+        entries[i].hash = hasher.finish();
+        entries[i].height = height;
+    }
+    entries
+}
+
+/// A mapping between the nodes of two flattened trees.
+struct Matching<'a, 't> {
+    old: &'a [Entry<'t>],
+    new: &'a [Entry<'t>],
+    old_to_new: Vec<Option<usize>>,
+    new_to_old: Vec<Option<usize>>,
+}
+
+impl<'a, 't> Matching<'a, 't> {
+    fn new(old: &'a [Entry<'t>], new: &'a [Entry<'t>]) -> Self {
+        Self { old, new, old_to_new: vec![None; old.len()], new_to_old: vec![None; new.len()] }
+    }
+
+    fn pair(&mut self, o: usize, n: usize) {
+        self.old_to_new[o] = Some(n);
+        self.new_to_old[n] = Some(o);
+    }
+
+    /// Match the identical subtrees at O and N, node by node.
+    fn pair_subtrees(&mut self, o: usize, n: usize) {
+        self.pair(o, n);
+        let (old, new) = (self.old, self.new);
+        for (&oc, &nc) in old[o].children.iter().zip(&new[n].children) {
+            self.pair_subtrees(oc, nc);
+        }
+    }
+
+    /// Match the unmatched children of the matched nodes O and N: first the identical ones, then
+    /// those with the same kind and field, in order. The latter are matched recursively.
+    fn pair_children(&mut self, o: usize, n: usize) {
+        let (old, new) = (self.old, self.new);
+        for identical in &[true, false] {
+            for &nc in &new[n].children {
+                if self.new_to_old[nc].is_some() {
+                    continue;
+                }
+                let found = old[o].children.iter().copied().find(|&oc| {
+                    self.old_to_new[oc].is_none() && if *identical {
+                        old[oc].hash == new[nc].hash
+                    } else {
+                        old[oc].node.kind_id() == new[nc].node.kind_id()
+                            && old[oc].field_id == new[nc].field_id
+                    }
+                });
+                match found {
+                    Some(oc) if *identical => self.pair_subtrees(oc, nc),
+                    Some(oc) => {
+                        self.pair(oc, nc);
+                        self.pair_children(oc, nc);
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+
+    /// Match the whole trees: top-down from the roots, then the identical subtrees that were
+    /// moved elsewhere, then bottom-up, pairing the parents of matched nodes.
+    fn compute(&mut self) {
+        if self.old[0].node.kind_id() == self.new[0].node.kind_id() {
+            self.pair(0, 0);
+            self.pair_children(0, 0);
+        }
+        let mut by_hash: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (o, entry) in self.old.iter().enumerate().rev() {
+            if self.old_to_new[o].is_none() && entry.height >= MIN_MOVE_HEIGHT {
+                by_hash.entry(entry.hash).or_default().push(o);
+            }
+        }
+        for n in 0..self.new.len() {
+            if self.new_to_old[n].is_some() || self.new[n].height < MIN_MOVE_HEIGHT {
+                continue;
+            }
+            if let Some(candidates) = by_hash.get_mut(&self.new[n].hash) {
+                // Candidates are popped in pre-order. Some may have been matched with an ancestor.
+                while let Some(o) = candidates.pop() {
+                    if self.old_to_new[o].is_none() {
+                        self.pair_subtrees(o, n);
+                        break;
+                    }
+                }
+            }
+        }
+        for n in (0..self.new.len()).rev() {
+            if self.new_to_old[n].is_some() || self.new[n].children.is_empty() {
+                continue;
+            }
+            let mut votes: HashMap<usize, usize> = HashMap::new();
+            for &nc in &self.new[n].children {
+                if let Some(parent) = self.new_to_old[nc].and_then(|oc| self.old[oc].parent) {
+                    *votes.entry(parent).or_default() += 1;
+                }
+            }
+            let kind_id = self.new[n].node.kind_id();
+            let best = votes.into_iter()
+                .filter(|&(o, _)| {
+                    self.old_to_new[o].is_none() && self.old[o].node.kind_id() == kind_id
+                })
+                .max_by_key(|&(o, count)| (count, std::cmp::Reverse(o)));
+            if let Some((o, _)) = best {
+                self.pair(o, n);
+                self.pair_children(o, n);
+            }
+        }
+    }
+
+    /// Return which nodes of the new tree were moved: those whose parents are not matched with
+    /// their counterparts' parents, and those not in the longest sequence of matched siblings that
+    /// kept their order.
+    fn moved(&self) -> Vec<bool> {
+        let mut moved = vec![false; self.new.len()];
+        for (n, entry) in self.new.iter().enumerate() {
+            let o = match self.new_to_old[n] {
+                Some(o) => o,
+                None => continue,
+            };
+            match (entry.parent, self.old[o].parent) {
+                (Some(np), Some(op)) if self.new_to_old[np] == Some(op) => {}
+                (None, None) => {}
+                _ => moved[n] = true,
+            }
+            // Siblings under matched parents.
+            let siblings: Vec<(usize, usize)> = entry.children.iter().filter_map(|&nc| {
+                self.new_to_old[nc]
+                    .filter(|&oc| self.old[oc].parent == Some(o))
+                    .map(|oc| (nc, oc))
+            }).collect();
+            let kept = longest_increasing(&siblings);
+            for (i, &(nc, _)) in siblings.iter().enumerate() {
+                if !kept[i] {
+                    moved[nc] = true;
+                }
+            }
+        }
+        moved
+    }
+}
+
+/// Return which of PAIRS are in the longest subsequence whose second elements are increasing.
+fn longest_increasing(pairs: &[(usize, usize)]) -> Vec<bool> {
+    // Indices of the smallest tails of the increasing subsequences of each length.
+    let mut tails: Vec<usize> = vec![];
+    let mut previous: Vec<Option<usize>> = vec![None; pairs.len()];
+    for (i, &(_, value)) in pairs.iter().enumerate() {
+        let length = tails.partition_point(|&t| pairs[t].1 < value);
+        previous[i] = length.checked_sub(1).map(|l| tails[l]);
+        if length == tails.len() {
+            tails.push(i);
+        } else {
+            tails[length] = i;
+        }
+    }
+    let mut kept = vec![false; pairs.len()];
+    let mut i = tails.last().copied();
+    while let Some(j) = i {
+        kept[j] = true;
+        i = previous[j];
+    }
+    kept
+}
+
+/// Return the structural differences from OLD-TREE to NEW-TREE, which were parsed
+/// from OLD-TEXT and NEW-TEXT, as a vector of [ACTION OLD-NODE NEW-NODE].
+///
+/// The nodes of the trees are matched by their types, fields, and positions, and
+/// identical subtrees are matched even if they were moved. ACTION is one of:
+///
+/// `insert'  NEW-NODE has no counterpart in OLD-TREE. OLD-NODE is nil.
+/// `delete'  OLD-NODE has no counterpart in NEW-TREE. NEW-NODE is nil.
+/// `move'    OLD-NODE was moved to a different parent, or among its siblings.
+/// `update'  OLD-NODE is a leaf whose text was changed.
+///
+/// Only the outermost inserted or deleted nodes are returned, since their
+/// descendants are inserted or deleted along with them, unless they are moved.
+/// Deletions come first, ordered by position in OLD-TREE. The other differences are
+/// ordered by position in NEW-TREE.
+///
+/// Unlike `tsc-changed-ranges', the trees can be unrelated, e.g. parsed from two
+/// revisions of a file.
+#[defun]
+fn tree_diff<'e>(
+    env: &'e Env,
+    old_tree: Borrowed<'e, Tree>,
+    old_text: String,
+    new_tree: Borrowed<'e, Tree>,
+    new_text: String,
+) -> Result<Vector<'e>> {
+    let (old_ref, new_ref) = (old_tree.try_borrow()?, new_tree.try_borrow()?);
+    let old = flatten(&old_ref, old_text.as_bytes());
+    let new = flatten(&new_ref, new_text.as_bytes());
+    let mut matching = Matching::new(&old, &new);
+    matching.compute();
+    let moved = matching.moved();
+    let old_node = |o: usize| RNode::new(old_tree.clone(), |_| old[o].node);
+    let new_node = |n: usize| RNode::new(new_tree.clone(), |_| new[n].node);
+    let is_outermost = |parent: Option<usize>, to: &[Option<usize>]| {
+        parent.map_or(true, |p| to[p].is_some())
+    };
+    let mut diffs: Vec<Value> = vec![];
+    for (o, entry) in old.iter().enumerate() {
+        if matching.old_to_new[o].is_none() && is_outermost(entry.parent, &matching.old_to_new) {
+            diffs.push(env.vector((delete.bind(env), old_node(o), ()))?);
+        }
+    }
+    for (n, entry) in new.iter().enumerate() {
+        let o = match matching.new_to_old[n] {
+            Some(o) => o,
+            None => {
+                if is_outermost(entry.parent, &matching.new_to_old) {
+                    diffs.push(env.vector((insert.bind(env), (), new_node(n)))?);
+                }
+                continue;
+            }
+        };
+        if moved[n] {
+            diffs.push(env.vector((move_.bind(env), old_node(o), new_node(n)))?);
+        }
+        let is_leaf = entry.children.is_empty() && old[o].children.is_empty();
+        if is_leaf && old[o].hash != entry.hash {
+            diffs.push(env.vector((update.bind(env), old_node(o), new_node(n)))?);
+        }
+    }
+    let vector = env.make_vector(diffs.len(), ())?;
+    for (i, diff) in diffs.into_iter().enumerate() {
+        vector.set(i, diff)?;
+    }
+    Ok(vector)
+}
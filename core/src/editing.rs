@@ -0,0 +1,205 @@
+use emacs::{defun, Env, IntoLisp, Result, Value};
+use tree_sitter::{Node, Tree};
+
+use crate::{
+    types::BytePos,
+    node::{LispUtils, RNode},
+    tree::Borrowed,
+};
+
+/// A change to the source code: replacing the bytes from `beg` to `end` with `text`.
+struct Edit {
+    beg: usize,
+    end: usize,
+    text: String,
+}
+
+/// Return the text of NODE, as generated by TEXT-FUNCTION.
+fn text_of(text_function: Value, node: Node) -> Result<String> {
+    text_function.call((node.lisp_start_byte(), node.lisp_end_byte()))?.into_rust()
+}
+
+/// Return the token that mirrors the punctuation token OPEN, e.g. ")" for "(", "]#" for "#[", or
+/// "\"" for "\"". Return None if OPEN is not punctuation.
+pub(crate) fn mirror_token(open: &str) -> Option<String> {
+    if open.is_empty() || open.contains(char::is_alphanumeric) {
+        return None;
+    }
+    Some(open.chars().rev().map(|c| match c {
+        '(' => ')',
+        '[' => ']',
+        '{' => '}',
+        '<' => '>',
+        c => c,
+    }).collect())
+}
+
+/// Return whether the token CLOSE mirrors the token OPEN, e.g. ")" and "(", or "\"" and "\"".
+fn is_pair(open: &str, close: &str) -> bool {
+    mirror_token(open).map_or(false, |mirror| mirror == close)
+}
+
+/// Return the opening and closing delimiters of NODE, i.e. its first and last children, if they
+/// are anonymous tokens that mirror each other, like the parentheses of an argument list.
+fn delimiters(node: Node) -> Option<(Node, Node)> {
+    let count = node.child_count();
+    if count < 2 {
+        return None;
+    }
+    let (open, close) = (node.child(0)?, node.child(count - 1)?);
+    if open.is_named() || close.is_named() || open.is_missing() || close.is_missing() {
+        return None;
+    }
+    if !is_pair(open.kind(), close.kind()) {
+        return None;
+    }
+    Some((open, close))
+}
+
+/// Return the byte range of NODE's content, following the conventions of text object queries'
+/// `@*.inner` captures: the part between NODE's delimiters, or between the delimiters of its last
+/// named child (e.g. a function's body block), without the surrounding whitespace. If there are
+/// no such delimiters, return NODE's whole range.
+pub(crate) fn inner_range(node: Node) -> (usize, usize) {
+    let delimited = delimiters(node).map(|d| (node, d)).or_else(|| {
+        let body = node.named_child(node.named_child_count().checked_sub(1)?)?;
+        delimiters(body).map(|d| (body, d))
+    });
+    let (node, (open, close)) = match delimited {
+        Some(delimited) => delimited,
+        None => return (node.start_byte(), node.end_byte()),
+    };
+    let count = node.child_count();
+    match (node.child(1), node.child(count - 2)) {
+        (Some(first), Some(last)) if count > 2 => (first.start_byte(), last.end_byte()),
+        _ => (open.end_byte(), close.start_byte()),
+    }
+}
+
+/// Return EDITS as a vector of [BEG END TEXT], sorted by position, or nil if there are none.
+fn into_lisp_edits<'e>(env: &'e Env, mut edits: Vec<Edit>) -> Result<Value<'e>> {
+    if edits.is_empty() {
+        return ().into_lisp(env);
+    }
+    edits.sort_by_key(|e| (e.beg, e.end));
+    let vector = env.make_vector(edits.len(), ())?;
+    for (i, edit) in edits.into_iter().enumerate() {
+        let beg: BytePos = edit.beg.into();
+        let end: BytePos = edit.end.into();
+        vector.set(i, env.vector((beg, end, edit.text))?)?;
+    }
+    Ok(vector.value())
+}
+
+// All the functions below return nil if the operation does not apply to the given node. Otherwise,
+// they return a vector of edits, in the same format as `tsc--format-edits'. TEXT-FUNCTION is
+// called with 2 byte positions, and should return the source code between them.
+
+/// Return the edits that replace NODE's closest named ancestor with NODE.
+#[defun]
+fn _raise_node<'e>(node: &RNode, text_function: Value<'e>) -> Result<Value<'e>> {
+    let env = text_function.env;
+    let node = node.borrow()?;
+    let parent = match std::iter::successors(node.parent(), |n| n.parent()).find(|n| n.is_named()) {
+        Some(parent) => parent,
+        None => return ().into_lisp(env),
+    };
+    let text = text_of(text_function, *node)?;
+    into_lisp_edits(env, vec![Edit { beg: parent.start_byte(), end: parent.end_byte(), text }])
+}
+
+/// Return the edits that remove NODE's delimiters, keeping its content.
+#[defun]
+fn _splice_node<'e>(node: &RNode, text_function: Value<'e>) -> Result<Value<'e>> {
+    let env = text_function.env;
+    let node = node.borrow()?;
+    let edits = match delimiters(*node) {
+        Some((open, close)) => vec![
+            Edit { beg: open.start_byte(), end: open.end_byte(), text: String::new() },
+            Edit { beg: close.start_byte(), end: close.end_byte(), text: String::new() },
+        ],
+        None => vec![],
+    };
+    into_lisp_edits(env, edits)
+}
+
+/// Return the named node following NODE. If NODE is the last child of its parent, and they end
+/// at the same byte, the parent's next named sibling is used, and so on.
+fn next_named_node(node: Node) -> Option<Node> {
+    let end = node.end_byte();
+    std::iter::successors(Some(node), |n| n.parent())
+        .take_while(|n| n.end_byte() == end)
+        .find_map(|n| n.next_named_sibling())
+}
+
+/// Return the edits that move NODE's closing delimiter forward, past the next named node, so
+/// that the node becomes NODE's last child. See `next_named_node`.
+#[defun]
+fn _slurp_forward<'e>(node: &RNode, text_function: Value<'e>) -> Result<Value<'e>> {
+    let env = text_function.env;
+    let node = node.borrow()?;
+    let (close, next) = match (delimiters(*node), next_named_node(*node)) {
+        (Some((_, close)), Some(next)) => (close, next),
+        _ => return ().into_lisp(env),
+    };
+    let text = text_of(text_function, close)?;
+    into_lisp_edits(env, vec![
+        Edit { beg: close.start_byte(), end: close.end_byte(), text: String::new() },
+        Edit { beg: next.end_byte(), end: next.end_byte(), text },
+    ])
+}
+
+/// Return the edits that move NODE's closing delimiter backward, before NODE's last named
+/// child, so that the child becomes NODE's next sibling.
+///
+/// The delimiter is placed right after the named child that precedes the child (or after the
+/// opening delimiter), so separators like commas end up outside.
+#[defun]
+fn _barf_forward<'e>(node: &RNode, text_function: Value<'e>) -> Result<Value<'e>> {
+    let env = text_function.env;
+    let node = node.borrow()?;
+    let (open, close) = match delimiters(*node) {
+        Some(delimiters) => delimiters,
+        None => return ().into_lisp(env),
+    };
+    let last = match close.prev_named_sibling() {
+        Some(last) => last,
+        None => return ().into_lisp(env),
+    };
+    let at = last.prev_named_sibling().unwrap_or(open).end_byte();
+    let text = text_of(text_function, close)?;
+    into_lisp_edits(env, vec![
+        Edit { beg: at, end: at, text },
+        Edit { beg: close.start_byte(), end: close.end_byte(), text: String::new() },
+    ])
+}
+
+/// Return the delimiters of the innermost node of TREE that encloses BYTEPOS and has delimiters,
+/// as a vector [OPEN-BEG OPEN-END CLOSE-BEG CLOSE-END] of byte positions. Return nil if there is
+/// no such node.
+///
+/// The delimiters are anonymous tokens that mirror each other, such as "(" and ")", or the
+/// quotes of a string, so brackets inside strings and comments are not matched. If BYTEPOS is
+/// on a delimiter, its own pair is returned.
+#[defun]
+fn _matching_delimiter<'e>(
+    env: &'e Env,
+    tree: Borrowed<'e, Tree>,
+    bytepos: BytePos,
+) -> Result<Value<'e>> {
+    let tree = tree.try_borrow()?;
+    let byte: usize = bytepos.into();
+    let node = match tree.root_node().descendant_for_byte_range(byte, byte) {
+        Some(node) => node,
+        None => return ().into_lisp(env),
+    };
+    match std::iter::successors(Some(node), |n| n.parent()).find_map(delimiters) {
+        Some((open, close)) => Ok(env.vector((
+            open.lisp_start_byte(),
+            open.lisp_end_byte(),
+            close.lisp_start_byte(),
+            close.lisp_end_byte(),
+        ))?),
+        None => ().into_lisp(env),
+    }
+}
@@ -1,3 +1,10 @@
+use std::fmt;
+
+use emacs::{Env, Error, ErrorKind, IntoLisp, Result, Value};
+use tree_sitter::LanguageError;
+
+use crate::{lang, types};
+
 emacs::define_errors! {
     tsc_error "Tree-sitter core error"
 
@@ -8,6 +15,12 @@ emacs::define_errors! {
 
     tsc_invalid_ranges "Invalid parsing ranges" (tsc_error)
 
+    tsc_parse_failed "Parsing failed" (tsc_error)
+
+    tsc_node_text_too_long "Node's text is longer than the maximum length" (tsc_error)
+
+    tsc_grammar_compile_failed "Grammar compilation failed" (tsc_error)
+
     tsc_query_invalid "Invalid query" (tsc_error)
     tsc_query_invalid_syntax "Query syntax error" (tsc_query_invalid)
     tsc_query_invalid_node_type "Query contains invalid node type" (tsc_query_invalid)
@@ -16,3 +29,189 @@ emacs::define_errors! {
     tsc_query_invalid_predicate "Query contains invalid predicate usage" (tsc_query_invalid)
     tsc_query_invalid_structure "Query contains invalid pattern structure" (tsc_query_invalid)
 }
+
+
+// -------------------------------------------------------------------------------------------------
+// Structured error data.
+//
+// Each error's data has the form (MESSAGE . PLIST), where MESSAGE is a human-readable string, and
+// PLIST always contains `:kind`, a symbol identifying the specific failure. The other properties
+// depend on the error. From Lisp, use `tsc-error-property' to access them.
+
+emacs::use_symbols! {
+    _kind       => ":kind"
+    _actual     => ":actual"
+    _expected   => ":expected"
+    _file       => ":file"
+    _symbol     => ":symbol"
+    _index      => ":index"
+    _range      => ":range"
+    _point      => ":point"
+    _bytepos    => ":bytepos"
+    _form       => ":form"
+    _references => ":references"
+    _length     => ":length"
+    _max_length => ":max-length"
+}
+
+/// Return the symbol identifying an error's specific KIND.
+#[inline]
+pub(crate) fn kind<'e>(env: &'e Env, kind: &str) -> Result<Value<'e>> {
+    env.intern(kind)
+}
+
+/// Signal `tsc-lang-abi-error`, because a parser cannot use a language.
+pub(crate) fn signal_lang_abi_error<T>(env: &Env, error: LanguageError) -> Result<T> {
+    env.signal(tsc_lang_abi_error, (
+        error.to_string(),
+        _kind, kind(env, "abi-version")?,
+        _actual, error.version,
+        _expected, lang::supported_abi_range(env)?,
+    ))
+}
+
+/// Signal `tsc-lang-load-failed`, because the language SYMBOL could not be loaded from FILE.
+pub(crate) fn signal_lang_load_failed<T>(
+    env: &Env,
+    message: String,
+    load_kind: &str,
+    file: &str,
+    symbol: &str,
+) -> Result<T> {
+    env.signal(tsc_lang_load_failed, (
+        message,
+        _kind, kind(env, load_kind)?,
+        _file, file,
+        _symbol, symbol,
+    ))
+}
+
+/// Signal `tsc-error`, because a language's node types could not be loaded from FILE. LOAD_KIND
+/// is `read` or `parse`.
+pub(crate) fn signal_node_types_failed<T>(
+    env: &Env,
+    message: String,
+    load_kind: &str,
+    file: &str,
+) -> Result<T> {
+    env.signal(tsc_error, (
+        message,
+        _kind, kind(env, &format!("node-types-{}", load_kind))?,
+        _file, file,
+    ))
+}
+
+/// Signal `tsc-grammar-compile-failed`, because the grammar in SRC_DIR could not be compiled.
+pub(crate) fn signal_grammar_compile_failed<T>(
+    env: &Env,
+    message: String,
+    compile_kind: &str,
+    src_dir: &str,
+) -> Result<T> {
+    env.signal(tsc_grammar_compile_failed, (
+        message,
+        _kind, kind(env, compile_kind)?,
+        _file, src_dir,
+    ))
+}
+
+/// Signal `tsc-invalid-ranges`, because the RANGE at INDEX does not come after the preceding one.
+pub(crate) fn signal_invalid_ranges<'e, T>(
+    env: &'e Env,
+    index: usize,
+    range: Value<'e>,
+) -> Result<T> {
+    env.signal(tsc_invalid_ranges, (
+        format!("Range {} overlaps with, or comes before, the preceding range", index),
+        _kind, kind(env, "unordered")?,
+        _index, index,
+        _range, range,
+    ))
+}
+
+/// Signal `tsc-parse-failed`, because a parser did not return a tree. REASON is `no-language`,
+/// `timeout`, `cancelled`, or `parse-failed`, as returned by `parser::failure_reason`.
+pub(crate) fn signal_parse_failed<T>(env: &Env, reason: &str) -> Result<T> {
+    let message = match reason {
+        "no-language" => "Parser has no language",
+        "timeout" => "Parsing timed out",
+        "cancelled" => "Parsing was cancelled",
+        _ => "Parsing failed",
+    };
+    env.signal(tsc_parse_failed, (
+        message,
+        _kind, kind(env, reason)?,
+    ))
+}
+
+/// Signal `tsc-node-text-too-long`, because a node's text is LENGTH bytes long, more than
+/// MAX_LENGTH.
+pub(crate) fn signal_node_text_too_long<T>(
+    env: &Env,
+    length: usize,
+    max_length: usize,
+) -> Result<T> {
+    env.signal(tsc_node_text_too_long, (
+        format!("Node's text is {} bytes long, more than {}", length, max_length),
+        _kind, kind(env, "too-long")?,
+        _length, length,
+        _max_length, max_length,
+    ))
+}
+
+/// Signal `tsc-error`, because a parser is busy with a background parse.
+pub(crate) fn signal_parser_busy<T>(env: &Env) -> Result<T> {
+    env.signal(tsc_error, (
+        "Parser is busy with a background parse",
+        _kind, kind(env, "parser-busy")?,
+    ))
+}
+
+/// Signal `tsc-error`, because a parser was used after being checked in to the parser pool.
+pub(crate) fn signal_parser_pooled<T>(env: &Env) -> Result<T> {
+    env.signal(tsc_error, (
+        "Parser was checked in to the parser pool",
+        _kind, kind(env, "parser-checked-in")?,
+    ))
+}
+
+/// Signal `tsc-error`, because a query was to be changed while being executed by a streaming query
+/// cursor.
+pub(crate) fn signal_query_streamed<T>(env: &Env) -> Result<T> {
+    env.signal(tsc_error, (
+        "Query is being executed by a streaming query cursor",
+        _kind, kind(env, "query-streamed")?,
+    ))
+}
+
+/// Return ERR in the form (ERROR-SYMBOL . DATA) that `condition-case' binds it to, e.g. so that it
+/// can be passed to a callback, instead of being signaled. An error that is not a Lisp signal is
+/// returned as a `rust-error`, which is how the module would signal it.
+pub(crate) fn error_to_lisp<'e>(env: &'e Env, err: Error) -> Result<Value<'e>> {
+    match err.downcast_ref::<ErrorKind>() {
+        // SAFETY: ERR was raised during the current call into the module, so its symbol and data
+        // are still alive.
+        Some(ErrorKind::Signal { symbol, data }) => unsafe {
+            env.cons(symbol.value(env), data.value(env))
+        },
+        _ => env.cons(env.intern("rust-error")?, types::list(env, vec![
+            err.to_string().into_lisp(env)?,
+        ])?),
+    }
+}
+
+// -------------------------------------------------------------------------------------------------
+// Internal errors.
+
+/// Failure of an internal invariant, which is reported to Lisp as an error, instead of a panic
+/// that would take down the whole Emacs process.
+#[derive(Debug)]
+pub(crate) struct Internal(pub(crate) &'static str);
+
+impl fmt::Display for Internal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for Internal {}
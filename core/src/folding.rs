@@ -0,0 +1,144 @@
+use std::cell::RefCell;
+
+use emacs::{defun, Env, Result, Value, Vector};
+use tree_sitter::{Node, QueryCursor, Tree};
+
+use crate::{
+    types::BytePos,
+    tree::Borrowed,
+    query::{self, Query},
+};
+
+/// Return whether NODE looks like a block: its type contains "block" or "comment", or its first
+/// and last children are matching brackets.
+fn is_block_like(node: Node) -> bool {
+    if !node.is_named() {
+        return false;
+    }
+    let kind = node.kind();
+    if kind.contains("block") || kind.contains("comment") {
+        return true;
+    }
+    let count = node.child_count();
+    match (node.child(0), count.checked_sub(1).and_then(|i| node.child(i))) {
+        (Some(open), Some(close)) if count >= 2 && !open.is_named() && !close.is_named() => {
+            matches!((open.kind(), close.kind()), ("{", "}") | ("[", "]") | ("(", ")"))
+        }
+        _ => false,
+    }
+}
+
+/// Return the block-like nodes of TREE, in pre-order.
+fn block_like_nodes(tree: &Tree) -> Vec<Node> {
+    let mut nodes = vec![];
+    let mut cursor = tree.walk();
+    loop {
+        let node = cursor.node();
+        if is_block_like(node) {
+            nodes.push(node);
+        }
+        if cursor.goto_first_child() {
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                return nodes;
+            }
+        }
+    }
+}
+
+/// Return the byte offset of a position after the end of NODE's first line, and before the next
+/// line's text: the start of its first descendant on a later line, or its end.
+fn first_line_bound(node: Node) -> usize {
+    let row = node.start_position().row;
+    let mut parent = node;
+    'descend: loop {
+        let mut cursor = parent.walk();
+        if !cursor.goto_first_child() {
+            return parent.end_byte();
+        }
+        loop {
+            let child = cursor.node();
+            if child.start_position().row > row {
+                return child.start_byte();
+            }
+            if child.end_position().row > row {
+                parent = child;
+                continue 'descend;
+            }
+            if !cursor.goto_next_sibling() {
+                return parent.end_byte();
+            }
+        }
+    }
+}
+
+/// Return the foldable regions of TREE, as a vector of (BEG-BYTE END-BYTE . SUMMARY),
+/// sorted by position. See `tsc-fold-ranges'.
+///
+/// The regions are the nodes captured as @fold by QUERY, or, if QUERY is nil, the
+/// block-like named nodes. Regions of fewer than MIN-LINES lines are dropped. Of
+/// the regions that start on the same line, only the longest one is kept. SUMMARY
+/// is the text of a region's first line, without surrounding whitespace.
+///
+/// TEXT-FUNCTION is called for the text of each region's first line, and of the
+/// nodes needed by QUERY's predicates. It is the same as for `tsc-query-captures'.
+#[defun]
+fn _fold_ranges<'e>(
+    env: &'e Env,
+    tree: Borrowed<'e, Tree>,
+    query: Value<'e>,
+    min_lines: usize,
+    text_function: Value<'e>,
+) -> Result<Vector<'e>> {
+    let query = match query.into_rust::<Option<&RefCell<Query>>>()? {
+        Some(query) => Some(query.try_borrow()?),
+        None => None,
+    };
+    let shared = tree;
+    let tree = tree.try_borrow()?;
+    let mut nodes = match &query {
+        None => block_like_nodes(&tree),
+        Some(query) => {
+            let raw: &tree_sitter::Query = &query.raw;
+            let fold = raw.capture_names().iter().position(|name| name == "fold");
+            let error = RefCell::new(None);
+            let mut cursor = QueryCursor::new();
+            let captures = cursor.captures(
+                raw,
+                tree.root_node(),
+                query::text_callback(text_function, &error),
+            );
+            let mut nodes = vec![];
+            for (m, i) in captures {
+                if let Some(error) = error.borrow_mut().take() {
+                    return Err(error);
+                }
+                if Some(m.captures[i].index as usize) != fold {
+                    continue;
+                }
+                if query::predicates_hold(raw, &m, text_function, shared)? {
+                    nodes.push(m.captures[i].node);
+                }
+            }
+            if let Some(error) = error.into_inner() {
+                return Err(error);
+            }
+            nodes
+        }
+    };
+    nodes.retain(|node| node.end_position().row - node.start_position().row + 1 >= min_lines);
+    nodes.sort_by_key(|node| (node.start_position().row, std::cmp::Reverse(node.end_byte())));
+    nodes.dedup_by_key(|node| node.start_position().row);
+    let vector = env.make_vector(nodes.len(), ())?;
+    for (i, node) in nodes.iter().enumerate() {
+        let line_start: BytePos = (node.start_byte() - node.start_position().column).into();
+        let line_bound: BytePos = first_line_bound(*node).into();
+        let line: String = text_function.call((line_start, line_bound))?.into_rust()?;
+        let summary = line.split('\n').next().unwrap_or("");
+        let (beg, end): (BytePos, BytePos) = (node.start_byte().into(), node.end_byte().into());
+        vector.set(i, env.cons(beg, env.cons(end, summary.trim())?)?)?;
+    }
+    Ok(vector)
+}
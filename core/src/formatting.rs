@@ -0,0 +1,147 @@
+use std::cell::RefCell;
+
+use emacs::{defun, Env, Result, Value, Vector};
+use tree_sitter::{Node, Tree};
+
+use crate::{
+    types::BytePos,
+    tree::Borrowed,
+    query::{self, Query},
+};
+
+/// A whitespace-only change to the source code: replacing the bytes from `beg` to `end` with
+/// `text`.
+struct Edit {
+    beg: usize,
+    end: usize,
+    text: &'static str,
+}
+
+/// Return the end byte and end row of the token preceding NODE.
+fn prev_token_end(node: Node) -> Option<(usize, usize)> {
+    let mut node = node;
+    loop {
+        if let Some(prev) = node.prev_sibling() {
+            return Some((prev.end_byte(), prev.end_position().row));
+        }
+        node = node.parent()?;
+    }
+}
+
+/// Return the start byte and start row of the token following NODE.
+fn next_token_start(node: Node) -> Option<(usize, usize)> {
+    let mut node = node;
+    loop {
+        if let Some(next) = node.next_sibling() {
+            return Some((next.start_byte(), next.start_position().row));
+        }
+        node = node.parent()?;
+    }
+}
+
+/// Return the edit that applies the formatting DIRECTIVE to NODE, if any is needed.
+///
+/// Only the whitespace between NODE and its adjacent tokens is changed. Gaps that already span
+/// multiple lines are left alone by space directives, since their indentation is not the
+/// formatter's concern.
+fn edit_for(node: Node, directive: &str) -> Option<Edit> {
+    let (before, text) = match directive {
+        "newline-before" => (true, "\n"),
+        "newline-after" => (false, "\n"),
+        "space-before" => (true, " "),
+        "space-after" => (false, " "),
+        "no-space-before" => (true, ""),
+        "no-space-after" => (false, ""),
+        _ => return None,
+    };
+    let (beg, end, same_line) = if before {
+        let (prev_end, prev_row) = prev_token_end(node)?;
+        (prev_end, node.start_byte(), prev_row == node.start_position().row)
+    } else {
+        let (next_start, next_row) = next_token_start(node)?;
+        (node.end_byte(), next_start, next_row == node.end_position().row)
+    };
+    // The gap is whitespace on a single line, so its length is enough to tell if it's as wanted.
+    if !same_line || (end - beg == text.len() && text != "\n") {
+        return None;
+    }
+    Some(Edit { beg, end, text })
+}
+
+/// Return the whitespace edits that format the code between BEG and END, as
+/// directed by QUERY on TREE. BEG and END are byte positions.
+///
+/// QUERY's patterns attach formatting directives to captured nodes, with the
+/// property `format', for example:
+///
+///   (("{" @open) (#set! @open format "newline-after"))
+///
+/// If the property does not name a capture, it applies to all of the pattern's
+/// captures. The directives are `newline-before', `newline-after', `space-before',
+/// `space-after', `no-space-before', and `no-space-after'. They only affect tokens
+/// on the same line.
+///
+/// Return a vector of edits, sorted by position. Each edit has the form
+/// [BEG END TEXT], meaning the bytes from BEG to END should be replaced with TEXT.
+/// TEXT-FUNCTION is used by text-based predicates. See `tsc-query-captures'.
+#[defun]
+fn _format_edits<'e>(
+    env: &'e Env,
+    query: &Query,
+    tree: Borrowed<'e, Tree>,
+    beg: BytePos,
+    end: BytePos,
+    text_function: Value<'e>,
+) -> Result<Vector<'e>> {
+    let (beg, end): (usize, usize) = (beg.into(), end.into());
+    let (shared, tree) = (tree, tree.try_borrow()?);
+    let mut edits = vec![];
+    let error = RefCell::new(None);
+    let mut cursor = tree_sitter::QueryCursor::new();
+    cursor.set_byte_range(beg..end);
+    let matches = cursor.matches(
+        &query.raw,
+        tree.root_node(),
+        query::text_callback(text_function, &error),
+    );
+    for m in matches {
+        if let Some(error) = error.borrow_mut().take() {
+            return Err(error);
+        }
+        if !query::predicates_hold(&query.raw, &m, text_function, shared)? {
+            continue;
+        }
+        for property in query.raw.property_settings(m.pattern_index) {
+            let directive = match (&*property.key, &property.value) {
+                ("format", Some(value)) => value,
+                _ => continue,
+            };
+            for c in m.captures {
+                if property.capture_id.map_or(false, |id| id != c.index as usize) {
+                    continue;
+                }
+                if c.node.is_missing() {
+                    continue;
+                }
+                if let Some(edit) = edit_for(c.node, directive) {
+                    if beg <= edit.beg && edit.end <= end {
+                        edits.push(edit);
+                    }
+                }
+            }
+        }
+    }
+    if let Some(error) = error.borrow_mut().take() {
+        return Err(error);
+    }
+    // The first directive for a gap wins.
+    edits.sort_by_key(|e| (e.beg, e.end));
+    edits.dedup_by_key(|e| (e.beg, e.end));
+    let vector = env.make_vector(edits.len(), ())?;
+    for (i, edit) in edits.into_iter().enumerate() {
+        let beg: BytePos = edit.beg.into();
+        let end: BytePos = edit.end.into();
+        vector.set(i, env.vector((beg, end, edit.text))?)?;
+    }
+    Ok(vector)
+}
@@ -0,0 +1,222 @@
+use emacs::{defun, Env, IntoLisp, Result, Value};
+use tree_sitter::{InputEdit, Node, Parser, Point, Tree};
+
+use crate::{
+    types::{self, BytePos},
+    node::LispUtils,
+    session::{self, Session},
+    error::{self, Internal},
+};
+
+/// Number of iterations between 2 comparisons of the incrementally parsed tree against a tree
+/// parsed from scratch.
+const CHECK_INTERVAL: u64 = 10;
+
+/// Short snippets inserted by random edits, chosen to open and close constructs of most
+/// languages.
+const SNIPPETS: &[&str] = &[
+    "(", ")", "{", "}", "[", "]", "\"", "'", ";", ",", " ", "\n", "/*", "*/", "//", "<", ">",
+];
+
+/// A xorshift64* generator, so that a seed reproduces the same edits on every platform.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // splitmix64, so that similar seeds give unrelated sequences.
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        Self((z ^ (z >> 31)).max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Return a number in `0..n`, or 0 if N is 0.
+    fn below(&mut self, n: usize) -> usize {
+        if n == 0 { 0 } else { (self.next() % n as u64) as usize }
+    }
+}
+
+/// Return the largest char boundary of TEXT that is not after BYTE.
+fn floor_char_boundary(text: &str, mut byte: usize) -> usize {
+    while !text.is_char_boundary(byte) {
+        byte -= 1;
+    }
+    byte
+}
+
+/// Return the position of BYTE in TEXT.
+fn point_at(text: &str, byte: usize) -> Point {
+    let before = &text.as_bytes()[..byte];
+    let row = before.iter().filter(|&&b| b == b'\n').count();
+    let line_start = before.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+    Point { row, column: byte - line_start }
+}
+
+/// A random change to TEXT: replacing the bytes from `beg` to `end` with `text`.
+struct Edit {
+    beg: usize,
+    end: usize,
+    text: String,
+}
+
+impl Edit {
+    fn random(rng: &mut Rng, text: &str) -> Self {
+        let beg = floor_char_boundary(text, rng.below(text.len() + 1));
+        let end = floor_char_boundary(text, text.len().min(beg + rng.below(16)));
+        let inserted = match rng.below(3) {
+            0 => String::new(),
+            1 => {
+                let from = floor_char_boundary(text, rng.below(text.len() + 1));
+                let to = floor_char_boundary(text, text.len().min(from + rng.below(16)));
+                text[from..to].to_owned()
+            }
+            _ => SNIPPETS[rng.below(SNIPPETS.len())].to_owned(),
+        };
+        Self { beg, end, text: inserted }
+    }
+
+    /// Apply the edit to TEXT, and return the corresponding `InputEdit`.
+    fn apply(&self, text: &mut String) -> InputEdit {
+        let start_position = point_at(text, self.beg);
+        let old_end_position = point_at(text, self.end);
+        text.replace_range(self.beg..self.end, &self.text);
+        let new_end_byte = self.beg + self.text.len();
+        InputEdit {
+            start_byte: self.beg,
+            old_end_byte: self.end,
+            new_end_byte,
+            start_position,
+            old_end_position,
+            new_end_position: point_at(text, new_end_byte),
+        }
+    }
+}
+
+/// Return the first nodes of TREE and EXPECTED, in pre-order, that differ in type, range, or
+/// number of children.
+fn first_difference<'t>(tree: &'t Tree, expected: &'t Tree) -> Option<(Node<'t>, Node<'t>)> {
+    let (mut a, mut b) = (tree.walk(), expected.walk());
+    loop {
+        let (x, y) = (a.node(), b.node());
+        if x.kind_id() != y.kind_id()
+            || x.byte_range() != y.byte_range()
+            || x.child_count() != y.child_count()
+            || x.is_missing() != y.is_missing()
+        {
+            return Some((x, y));
+        }
+        // Since the nodes have the same number of children, the cursors move in lockstep.
+        if a.goto_first_child() {
+            b.goto_first_child();
+            continue;
+        }
+        loop {
+            if a.goto_next_sibling() {
+                b.goto_next_sibling();
+                break;
+            }
+            if !a.goto_parent() {
+                return None;
+            }
+            b.goto_parent();
+        }
+    }
+}
+
+/// Return NODE as a list (TYPE BEG END).
+fn describe<'e>(env: &'e Env, node: Node) -> Result<Value<'e>> {
+    types::list(env, vec![
+        node.kind().into_lisp(env)?,
+        node.lisp_start_byte().into_lisp(env)?,
+        node.lisp_end_byte().into_lisp(env)?,
+    ])
+}
+
+emacs::use_symbols! {
+    _seed      => ":seed"
+    _iteration => ":iteration"
+    _text      => ":text"
+    _edits     => ":edits"
+    _node      => ":node"
+    _expected  => ":expected"
+}
+
+/// Apply ITERATIONS random edits, generated from SEED, to a copy of the text
+/// generated by INPUT-FUNCTION, and to a copy of SESSION's latest tree, reparsing
+/// it incrementally after each edit. Every few edits, compare it against a tree
+/// parsed from scratch. Return nil if they never diverge.
+///
+/// Otherwise, return a plist describing the first divergence:
+///
+/// :seed       SEED.
+/// :iteration  Number of edits applied. 0 means SESSION's latest tree was
+///             already out of sync with the text.
+/// :text       Text after the edits.
+/// :edits      Vector of the edits applied since the previous comparison, as
+///             [BEG END TEXT], in the same format as `tsc--format-edits'.
+/// :node       The first differing node of the incremental tree, as (TYPE BEG END).
+/// :expected   The corresponding node of the tree parsed from scratch.
+///
+/// SESSION is not changed. Running again with the same text and SEED, and with the
+/// returned iteration as ITERATIONS, reproduces the divergence.
+#[defun]
+fn _fuzz_session<'e>(
+    session: &Session,
+    iterations: u64,
+    seed: i64,
+    input_function: Value<'e>,
+) -> Result<Option<Value<'e>>> {
+    let env = input_function.env;
+    let mut text = String::new();
+    session::for_each_chunk(input_function, |chunk| text.push_str(chunk))?;
+    let mut parser = Parser::new();
+    let language = session.language().ok_or(Internal("Session has no language"))?;
+    parser.set_language(language).or_else(|err| error::signal_lang_abi_error(env, err))?;
+    let mut parse = |text: &str, old_tree: Option<&Tree>| {
+        parser.parse(text, old_tree).ok_or(Internal("Parsing was unexpectedly cancelled"))
+    };
+    let mut tree = match session.tree_copy()? {
+        Some(tree) => tree,
+        None => parse(&text, None)?,
+    };
+    let mut rng = Rng::new(seed as u64);
+    let mut edits = vec![];
+    for iteration in 0..=iterations {
+        if iteration > 0 {
+            let edit = Edit::random(&mut rng, &text);
+            tree.edit(&edit.apply(&mut text));
+            tree = parse(&text, Some(&tree))?;
+            edits.push(edit);
+        }
+        if iteration % CHECK_INTERVAL != 0 && iteration != iterations {
+            continue;
+        }
+        let expected = parse(&text, None)?;
+        if let Some((node, expected_node)) = first_difference(&tree, &expected) {
+            let vector = env.make_vector(edits.len(), ())?;
+            for (i, edit) in edits.into_iter().enumerate() {
+                let (beg, end): (BytePos, BytePos) = (edit.beg.into(), edit.end.into());
+                vector.set(i, env.vector((beg, end, edit.text))?)?;
+            }
+            return Ok(Some(types::list(env, vec![
+                _seed.bind(env), seed.into_lisp(env)?,
+                _iteration.bind(env), iteration.into_lisp(env)?,
+                _text.bind(env), text.into_lisp(env)?,
+                _edits.bind(env), vector.value(),
+                _node.bind(env), describe(env, node)?,
+                _expected.bind(env), describe(env, expected_node)?,
+            ])?));
+        }
+        edits.clear();
+    }
+    Ok(None)
+}
@@ -0,0 +1,282 @@
+use std::{cell::RefCell, collections::HashMap, mem};
+
+use emacs::{defun, Env, IntoLisp, Result, Value, Vector};
+use tree_sitter::{QueryCursor, Tree};
+
+use crate::{
+    types::{BytePos, Shared},
+    tree,
+    query::{self, Query},
+    spans::{self, Highlight},
+    instrument::{Timer, QUERY_EXECUTIONS, QUERY_MICROS},
+};
+
+emacs::use_symbols! {
+    car
+    cdr
+    consp
+    add_text_properties
+    put_text_property
+}
+
+/// A scope of a locals query, and the ranges of the definitions in it, by name.
+struct Scope {
+    end: usize,
+    definitions: HashMap<Vec<u8>, (usize, usize)>,
+}
+
+/// Pairs of (REFERENCE, DEFINITION) byte ranges, sorted by the references' positions.
+type References = Vec<((usize, usize), (usize, usize))>;
+
+/// Return the ranges of the definitions referred to by the local references of TREE,
+/// according to LOCALS, in the manner of `tree-sitter-highlight`: `@local.scope`,
+/// `@local.definition`, and `@local.reference`.
+fn resolve_references(
+    locals: &tree_sitter::Query,
+    tree: &Tree,
+    text_function: Value,
+) -> Result<References> {
+    let index = |name| locals.capture_names().iter().position(|n| n == name);
+    let (scope, definition, reference) =
+        (index("local.scope"), index("local.definition"), index("local.reference"));
+    let mut references = vec![];
+    let error = RefCell::new(None);
+    let mut cursor = QueryCursor::new();
+    let mut scopes = vec![Scope { end: usize::MAX, definitions: HashMap::new() }];
+    let captures = cursor.captures(
+        locals,
+        tree.root_node(),
+        query::text_callback(text_function, &error),
+    );
+    for (m, i) in captures {
+        if let Some(error) = error.borrow_mut().take() {
+            return Err(error);
+        }
+        if !query::general_predicates_hold(locals, &m, |node| {
+            query::node_text(text_function, node)
+        })? {
+            continue;
+        }
+        let node = m.captures[i].node;
+        let index = Some(m.captures[i].index as usize);
+        while scopes.len() > 1 && scopes.last().map_or(false, |s| s.end <= node.start_byte()) {
+            scopes.pop();
+        }
+        let range = (node.start_byte(), node.end_byte());
+        if index == scope {
+            scopes.push(Scope { end: node.end_byte(), definitions: HashMap::new() });
+        } else if index == definition {
+            if let Some(scope) = scopes.last_mut() {
+                scope.definitions.insert(query::node_text(text_function, node)?, range);
+            }
+        } else if index == reference {
+            let name = query::node_text(text_function, node)?;
+            let found = scopes.iter().rev().find_map(|s| s.definitions.get(&name)).copied();
+            if let Some(definition) = found.filter(|&d| d != range) {
+                references.push((range, definition));
+            }
+        }
+    }
+    if let Some(error) = error.into_inner() {
+        return Err(error);
+    }
+    references.sort_unstable();
+    references.dedup();
+    Ok(references)
+}
+
+/// References resolved for a whole tree, by a locals query.
+struct ResolvedReferences {
+    /// The versions of the tree and of the locals query. See `tree::tree_version`.
+    versions: ((usize, usize), usize),
+    references: References,
+}
+
+thread_local! {
+    /// The references resolved by the last call to `_highlight_region`, so that highlighting
+    /// another range of the same trees doesn't have to resolve them again.
+    static RESOLVED: RefCell<Vec<ResolvedReferences>> = RefCell::new(vec![]);
+}
+
+/// Return the highlights of TREE from BEG to END, according to HIGHLIGHTS. REFERENCES are the
+/// resolved local references of TREE. The highlights of this layer have index LAYER.
+fn layer_highlights(
+    layer: usize,
+    highlights: &tree_sitter::Query,
+    references: &[((usize, usize), (usize, usize))],
+    tree: &Tree,
+    text_function: Value,
+    beg: usize,
+    end: usize,
+) -> Result<Vec<Highlight>> {
+    let first = references.partition_point(|&((_, ref_end), _)| ref_end <= beg);
+    let last = references.partition_point(|&((ref_beg, _), _)| ref_beg < end);
+    let references = &references[first..last.max(first)];
+    // The highlights of the definitions are found by the same pass, so it starts early enough.
+    let mut definitions: HashMap<(usize, usize), Option<Highlight>> =
+        references.iter().map(|&(_, definition)| (definition, None)).collect();
+    let start = definitions.keys().map(|&(def_beg, _)| def_beg).fold(beg, usize::min);
+    let error = RefCell::new(None);
+    let mut cursor = QueryCursor::new();
+    cursor.set_byte_range(start..end);
+    let captures = cursor.captures(
+        highlights,
+        tree.root_node(),
+        query::text_callback(text_function, &error),
+    );
+    let mut result = vec![];
+    for (m, i) in captures {
+        if let Some(error) = error.borrow_mut().take() {
+            return Err(error);
+        }
+        if !query::general_predicates_hold(highlights, &m, |node| {
+            query::node_text(text_function, node)
+        })? {
+            continue;
+        }
+        let c = m.captures[i];
+        let h = Highlight {
+            beg: c.node.start_byte(),
+            end: c.node.end_byte(),
+            layer,
+            capture: c.index,
+            pattern: m.pattern_index,
+        };
+        // For the same definition, the earliest pattern wins, as in `spans::merge`.
+        if let Some(definition) = definitions.get_mut(&(h.beg, h.end)) {
+            if definition.map_or(true, |d| h.pattern < d.pattern) {
+                *definition = Some(h);
+            }
+        }
+        if h.end > beg {
+            result.push(h);
+        }
+    }
+    if let Some(error) = error.into_inner() {
+        return Err(error);
+    }
+    // A resolved reference is highlighted like its definition, if the definition is.
+    let mut resolved = HashMap::new();
+    for &((ref_beg, ref_end), definition) in references {
+        if let Some(Some(h)) = definitions.get(&definition) {
+            let h = Highlight { beg: ref_beg, end: ref_end, pattern: 0, ..*h };
+            resolved.insert((ref_beg, ref_end), h);
+        }
+    }
+    result.retain(|h| !resolved.contains_key(&(h.beg, h.end)));
+    result.extend(resolved.values().copied());
+    Ok(result)
+}
+
+/// Return the highlighting of the range of byte positions from BEG to END, as a vector of
+/// non-overlapping spans (BEG-BYTE END-BYTE . CAPTURE-TAG), sorted by position.
+///
+/// Each of LAYERS is a vector [HIGHLIGHTS TREE LOCALS], where LOCALS is nil, or a locals
+/// query. Later layers, e.g. injected trees, are painted over earlier ones. Within a layer,
+/// inner captures take precedence over outer ones, and for the same node, the earlier pattern
+/// wins. A reference resolved by LOCALS is highlighted like its definition.
+///
+/// The references are resolved for the whole tree, and kept until the next call, which reuses
+/// them if neither the tree nor LOCALS has changed since, e.g. when highlighting the next chunk
+/// of a buffer.
+///
+/// TEXT-FUNCTION is called with a node's start and end byte positions to get its text, when
+/// needed by a predicate, or by locals. It is the same as for `tsc-query-captures'.
+#[defun]
+fn _highlight_region<'e>(
+    env: &'e Env,
+    layers: Vector<'e>,
+    beg: BytePos,
+    end: BytePos,
+    text_function: Value<'e>,
+) -> Result<Vector<'e>> {
+    let (beg, end): (usize, usize) = (beg.into(), end.into());
+    let mut queries = Vec::with_capacity(layers.len());
+    for i in 0..layers.len() {
+        let layer: Vector = layers.get(i)?;
+        let highlights = layer.get::<&RefCell<Query>>(0)?.try_borrow()?;
+        let shared_tree = layer.get::<&Shared<Tree>>(1)?;
+        let version = tree::tree_version(shared_tree);
+        let tree = shared_tree.try_borrow()?;
+        let locals = match layer.get::<Option<&RefCell<Query>>>(2)? {
+            Some(locals) => Some(locals.try_borrow()?),
+            None => None,
+        };
+        queries.push((highlights, tree, version, locals));
+    }
+    QUERY_EXECUTIONS.add(queries.len() as u64);
+    let _timer = Timer::new(&QUERY_MICROS);
+    let mut cached = RESOLVED.with(|resolved| mem::take(&mut *resolved.borrow_mut()));
+    let mut resolved = Vec::with_capacity(queries.len());
+    let mut layer_spans = Vec::with_capacity(queries.len());
+    for (layer, (highlights, tree, version, locals)) in queries.iter().enumerate() {
+        let references = match locals {
+            Some(locals) => {
+                let versions = (*version, locals.version);
+                let references = match cached.iter().position(|r| r.versions == versions) {
+                    Some(i) => cached.swap_remove(i).references,
+                    None => resolve_references(&locals.raw, tree, text_function)?,
+                };
+                resolved.push(ResolvedReferences { versions, references });
+                &resolved[resolved.len() - 1].references[..]
+            }
+            None => &[],
+        };
+        let highlights = &highlights.raw;
+        layer_spans.push(
+            layer_highlights(layer, highlights, references, tree, text_function, beg, end)?
+        );
+    }
+    RESOLVED.with(|r| *r.borrow_mut() = resolved);
+    let spans = spans::merge(layer_spans, beg, end);
+    let vector = env.make_vector(spans.len(), ())?;
+    for (i, h) in spans.iter().enumerate() {
+        let (span_beg, span_end): (BytePos, BytePos) = (h.beg.into(), h.end.into());
+        let tag: Value = queries[h.layer].0.capture_tags[h.capture as usize].bind(env);
+        vector.set(i, env.cons(span_beg, env.cons(span_end, tag)?)?)?;
+    }
+    Ok(vector)
+}
+
+/// Apply SPANS, a vector of (BEG-BYTE END-BYTE . VALUE), as text properties of the current
+/// buffer. VALUE is either a plist of properties, or the value of PROPERTY. Spans whose VALUE
+/// is nil are skipped. The caller should disable narrowing. See `tsc-apply-spans'.
+#[defun]
+fn _apply_spans<'e>(env: &'e Env, spans: Vector<'e>, property: Value<'e>) -> Result<()> {
+    let point_max = env.call("buffer-size", ((),))?.into_rust::<usize>()? + 1;
+    let end_byte: usize = env.call("position-bytes", (point_max,))?.into_rust()?;
+    // Without multibyte characters, byte positions are positions.
+    let single_byte = end_byte == point_max;
+    // The end of a span is usually the beginning of the next one.
+    let mut last: Option<(usize, Value)> = None;
+    let mut to_position = |bytepos: usize| -> Result<Value<'e>> {
+        if single_byte {
+            return bytepos.min(point_max).into_lisp(env);
+        }
+        if let Some((b, position)) = last {
+            if b == bytepos {
+                return Ok(position);
+            }
+        }
+        let position: Value = env.call("byte-to-position", (bytepos,))?;
+        let position = if position.is_not_nil() { position } else { point_max.into_lisp(env)? };
+        last = Some((bytepos, position));
+        Ok(position)
+    };
+    for i in 0..spans.len() {
+        let span: Value = spans.get(i)?;
+        let beg: usize = car.bind(env).call((span,))?.into_rust()?;
+        let rest = cdr.bind(env).call((span,))?;
+        let end: usize = car.bind(env).call((rest,))?.into_rust()?;
+        let value = cdr.bind(env).call((rest,))?;
+        if value.is_not_nil() {
+            let (beg, end) = (to_position(beg)?, to_position(end)?);
+            if consp.bind(env).call((value,))?.is_not_nil() {
+                add_text_properties.bind(env).call((beg, end, value))?;
+            } else {
+                put_text_property.bind(env).call((beg, end, property, value))?;
+            }
+        }
+    }
+    Ok(())
+}
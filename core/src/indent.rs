@@ -0,0 +1,204 @@
+use std::{cell::RefCell, collections::HashSet};
+
+use emacs::{defun, Result, Value};
+use tree_sitter::{Node, Tree};
+
+use crate::{
+    types::BytePos,
+    tree::Borrowed,
+    query::{self, Query},
+};
+
+/// Nodes captured by an indentation query, by role, identified by their ids.
+#[derive(Default)]
+struct Roles {
+    indent: HashSet<usize>,
+    dedent: HashSet<usize>,
+    branch: HashSet<usize>,
+    aligned: HashSet<usize>,
+    zero: HashSet<usize>,
+    ignore: HashSet<usize>,
+}
+
+impl Roles {
+    /// Return the nodes with the role of the capture NAME. Besides this module's own names, the
+    /// names used by the `indents.scm` files of other editors are understood.
+    fn get_mut(&mut self, name: &str) -> Option<&mut HashSet<usize>> {
+        Some(match name {
+            "indent" | "indent.begin" => &mut self.indent,
+            "dedent" | "indent.dedent" => &mut self.dedent,
+            "branch" | "outdent" | "indent.branch" | "indent.end" => &mut self.branch,
+            "aligned" | "align" | "indent.align" => &mut self.aligned,
+            "zero" | "indent.zero" => &mut self.zero,
+            "ignore" | "indent.ignore" => &mut self.ignore,
+            _ => return None,
+        })
+    }
+}
+
+/// Measures columns of source lines, reading their text through a Lisp function.
+struct Columns<'e> {
+    text_function: Value<'e>,
+    tab_width: usize,
+}
+
+impl<'e> Columns<'e> {
+    /// Return the display column of BYTE, on the line that starts at LINE_START.
+    fn column(&self, line_start: usize, byte: usize) -> Result<usize> {
+        let text = self.text(line_start, byte)?;
+        Ok(text.chars().fold(0, |column, c| self.advance(column, c)))
+    }
+
+    /// Return the indentation of the line that starts at LINE_START, and contains BYTE.
+    fn indentation(&self, line_start: usize, byte: usize) -> Result<usize> {
+        let text = self.text(line_start, byte)?;
+        Ok(text.chars()
+            .take_while(|&c| c == ' ' || c == '\t')
+            .fold(0, |column, c| self.advance(column, c)))
+    }
+
+    #[inline]
+    fn advance(&self, column: usize, c: char) -> usize {
+        if c == '\t' && self.tab_width > 0 {
+            (column / self.tab_width + 1) * self.tab_width
+        } else {
+            column + 1
+        }
+    }
+
+    fn text(&self, beg: usize, end: usize) -> Result<String> {
+        let beg: BytePos = beg.into();
+        let end: BytePos = end.into();
+        self.text_function.call((beg, end))?.into_rust()
+    }
+}
+
+/// Return the byte position of the start of NODE's first line.
+#[inline]
+fn line_start(node: Node) -> usize {
+    node.start_byte() - node.start_position().column
+}
+
+/// Return the column that the contents of the aligned NODE should be aligned to: that of the
+/// first token after its opening delimiter, if that token is on the same line, or else right after
+/// the delimiter. If IS_CLOSING, return the column of the opening delimiter itself.
+fn aligned_column(node: Node, is_closing: bool, columns: &Columns) -> Result<Option<usize>> {
+    let open = match node.child(0) {
+        Some(open) => open,
+        None => return Ok(None),
+    };
+    let byte = match node.child(1) {
+        _ if is_closing => open.start_byte(),
+        Some(next) if next.start_position().row == open.end_position().row => next.start_byte(),
+        _ => open.end_byte(),
+    };
+    columns.column(line_start(open), byte).map(Some)
+}
+
+/// Return the indentation column of the line that starts at LINE-BEG, and whose
+/// first non-whitespace character is at BYTEPOS, according to TREE and the
+/// indentation QUERY. Both are byte positions.
+///
+/// QUERY's captures assign roles to nodes, based on their names:
+///
+/// @indent   The lines inside the node are indented one level deeper than the
+///           node's first line.
+/// @dedent   The lines inside the node are indented one level shallower than the
+///           node's first line.
+/// @branch   A line that starts with the node is not indented by its enclosing
+///           @indent node, e.g. a closing brace, or an `else'.
+/// @aligned  The lines inside the node are aligned with the first token after its
+///           opening delimiter, e.g. arguments inside parentheses.
+/// @zero     A line that starts with the node is not indented at all, e.g. a C
+///           preprocessor directive.
+/// @ignore   The lines inside the node keep their indentation, e.g. those of a
+///           multi-line string.
+///
+/// The names used by other editors' indents.scm files are understood too: @outdent
+/// and @align, and @indent.begin, @indent.end, @indent.branch, @indent.dedent,
+/// @indent.align, @indent.zero, and @indent.ignore. Other captures are ignored.
+/// The innermost node, which started on an earlier line and has a role, determines
+/// the indentation. If there is none, return 0.
+///
+/// INDENT-WIDTH is the number of columns per level. TAB-WIDTH is the display width
+/// of tabs. TEXT-FUNCTION is called to get the text of lines, and for text-based
+/// predicates. See `tsc-query-captures'.
+#[defun]
+fn _indent_column<'e>(
+    query: &Query,
+    tree: Borrowed<'e, Tree>,
+    line_beg: BytePos,
+    bytepos: BytePos,
+    indent_width: usize,
+    tab_width: usize,
+    text_function: Value<'e>,
+) -> Result<usize> {
+    let line_beg: usize = line_beg.into();
+    let pos: usize = bytepos.into();
+    let (shared, tree) = (tree, tree.try_borrow()?);
+    let root = tree.root_node();
+
+    // Only the nodes that contain, or start at, the position matter.
+    let mut roles = Roles::default();
+    {
+        let error = RefCell::new(None);
+        let mut cursor = tree_sitter::QueryCursor::new();
+        cursor.set_byte_range(pos..pos + 1);
+        let names = query.raw.capture_names();
+        let captures = cursor.captures(
+            &query.raw,
+            root,
+            query::text_callback(text_function, &error),
+        );
+        for (m, i) in captures {
+            if let Some(error) = error.borrow_mut().take() {
+                return Err(error);
+            }
+            if !query::predicates_hold(&query.raw, &m, text_function, shared)? {
+                continue;
+            }
+            let c = m.captures[i];
+            if let Some(set) = roles.get_mut(&names[c.index as usize]) {
+                set.insert(c.node.id());
+            }
+        }
+        if let Some(error) = error.borrow_mut().take() {
+            return Err(error);
+        }
+    }
+
+    let columns = Columns { text_function, tab_width };
+    let mut is_branch = false;
+    let mut node = root.descendant_for_byte_range(pos, pos);
+    while let Some(current) = node {
+        let id = current.id();
+        if current.start_byte() == pos {
+            if roles.zero.contains(&id) {
+                return Ok(0);
+            }
+            if roles.branch.contains(&id) {
+                is_branch = true;
+            }
+        }
+        if current.start_byte() < line_beg {
+            if roles.ignore.contains(&id) {
+                return columns.indentation(line_beg, pos);
+            }
+            if roles.aligned.contains(&id) {
+                if let Some(column) = aligned_column(current, is_branch, &columns)? {
+                    return Ok(column);
+                }
+            }
+            if roles.indent.contains(&id) {
+                let base = columns.indentation(line_start(current), current.start_byte())?;
+                return Ok(if is_branch { base } else { base + indent_width });
+            }
+            if roles.dedent.contains(&id) {
+                let base = columns.indentation(line_start(current), current.start_byte())?;
+                return Ok(base.saturating_sub(indent_width));
+            }
+        }
+        node = current.parent();
+    }
+    Ok(0)
+}
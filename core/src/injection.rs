@@ -0,0 +1,331 @@
+use std::cell::RefCell;
+
+use emacs::{defun, Env, GlobalRef, IntoLisp, Result, Value};
+use tree_sitter::{InputEdit, Node, Parser, Tree};
+
+use crate::{
+    types::{self, BytePos, Point, Shared},
+    lang::Language,
+    parser,
+    query::{self, Query},
+    tree::{self, Borrowed},
+    live::{Live, INJECTORS},
+    error::{self, Internal},
+};
+
+/// The trees of an injected language: a parser whose included ranges are all the regions of
+/// the host tree in that language, and the latest tree it produced.
+struct Layer {
+    name: String,
+    parser: Parser,
+    ranges: Vec<tree_sitter::Range>,
+    tree: Option<Shared<Tree>>,
+    /// Whether the tree was edited since it was parsed.
+    edited: bool,
+}
+
+/// The injected languages of a host tree, discovered with an injections query, in the manner of
+/// `tree-sitter-highlight`.
+pub(crate) struct Injector {
+    /// The Lisp injections query, kept alive by this reference, and the function returning the
+    /// language of an injected language's name. They are freed when the injector is released.
+    refs: Option<(GlobalRef, GlobalRef)>,
+    layers: Vec<Layer>,
+    _live: Live,
+}
+
+impl Injector {
+    /// Free the injector's trees, and its references to Lisp objects.
+    pub(crate) fn release(&mut self, env: &Env) -> Result<()> {
+        self.layers.clear();
+        if let Some((query, language_function)) = self.refs.take() {
+            query.free(env)?;
+            language_function.free(env)?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn is_released(&self) -> bool {
+        self.refs.is_none()
+    }
+}
+
+impl Drop for Injector {
+    fn drop(&mut self) {
+        // Without an `Env`, the references are freed later.
+        if let Some((query, language_function)) = self.refs.take() {
+            types::free_later(query);
+            types::free_later(language_function);
+        }
+    }
+}
+
+impl_pred!(injector_p, &RefCell<Injector>);
+
+/// Create an injector, which maintains the trees of the languages injected into a
+/// host tree, according to QUERY, typically loaded from an injections.scm file.
+///
+/// QUERY's patterns should capture the nodes containing injected code as
+/// @injection.content. The injected language's name is either set with
+/// (#set! injection.language \"NAME\"), or is the text of an @injection.language
+/// capture. The children of an @injection.content node are excluded from its
+/// region, unless the pattern has (#set! injection.include-children).
+///
+/// LANGUAGE-FUNCTION is called with a language's name, and should return the
+/// language, or nil if it's not available, e.g. `tree-sitter-require'.
+///
+/// See `tsc-injector-update' and `tsc-injector-trees'.
+#[defun(user_ptr)]
+fn make_injector(query: Value, language_function: Value) -> Result<Injector> {
+    // Check the type.
+    query.into_rust::<&RefCell<Query>>()?;
+    types::free_dropped_refs(query.env)?;
+    Ok(Injector {
+        refs: Some((query.make_global_ref(), language_function.make_global_ref())),
+        layers: vec![],
+        _live: Live::new(&INJECTORS),
+    })
+}
+
+/// Return the regions of NODE, an @injection.content node, excluding its children's unless
+/// INCLUDE_CHILDREN is true.
+fn content_ranges(node: Node, include_children: bool) -> Vec<tree_sitter::Range> {
+    if include_children || node.child_count() == 0 {
+        return vec![node.range()];
+    }
+    let mut ranges = vec![];
+    let (mut start_byte, mut start_point) = (node.start_byte(), node.start_position());
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        if child.start_byte() > start_byte {
+            ranges.push(tree_sitter::Range {
+                start_byte,
+                end_byte: child.start_byte(),
+                start_point,
+                end_point: child.start_position(),
+            });
+        }
+        start_byte = child.end_byte();
+        start_point = child.end_position();
+    }
+    if node.end_byte() > start_byte {
+        ranges.push(tree_sitter::Range {
+            start_byte,
+            end_byte: node.end_byte(),
+            start_point,
+            end_point: node.end_position(),
+        });
+    }
+    ranges
+}
+
+/// Return the injected regions of HOST_TREE, by language name, in order of first appearance.
+fn discover(
+    query: &Query,
+    host_tree: &Shared<Tree>,
+    text_function: Value,
+) -> Result<Vec<(String, Vec<tree_sitter::Range>)>> {
+    let host = host_tree.try_borrow()?;
+    let raw = &query.raw;
+    let index = |name| raw.capture_names().iter().position(|n| n == name);
+    let (content_index, language_index) = (index("injection.content"), index("injection.language"));
+    let mut regions: Vec<(String, Vec<tree_sitter::Range>)> = vec![];
+    let error = RefCell::new(None);
+    let mut cursor = tree_sitter::QueryCursor::new();
+    let matches = cursor.matches(
+        raw,
+        host.root_node(),
+        query::text_callback(text_function, &error),
+    );
+    for m in matches {
+        if let Some(error) = error.borrow_mut().take() {
+            return Err(error);
+        }
+        if !query::predicates_hold(raw, &m, text_function, host_tree)? {
+            continue;
+        }
+        let captured = |index: Option<usize>| {
+            m.captures.iter().find(|c| Some(c.index as usize) == index).map(|c| c.node)
+        };
+        let mut name = None;
+        let mut include_children = false;
+        for property in raw.property_settings(m.pattern_index) {
+            match (&*property.key, &property.value) {
+                ("injection.language", Some(value)) => name = Some(value.to_string()),
+                ("injection.include-children", _) => include_children = true,
+                _ => {}
+            }
+        }
+        if name.is_none() {
+            if let Some(node) = captured(language_index) {
+                let (beg, end): (BytePos, BytePos) =
+                    (node.start_byte().into(), node.end_byte().into());
+                name = Some(text_function.call((beg, end))?.into_rust()?);
+            }
+        }
+        let (name, content) = match (name, captured(content_index)) {
+            (Some(name), Some(content)) if !name.is_empty() => (name, content),
+            _ => continue,
+        };
+        let ranges = content_ranges(content, include_children);
+        match regions.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, existing)) => existing.extend(ranges),
+            None => regions.push((name, ranges)),
+        }
+    }
+    if let Some(error) = error.borrow_mut().take() {
+        return Err(error);
+    }
+    for (_, ranges) in &mut regions {
+        ranges.sort_by_key(|r| (r.start_byte, r.end_byte));
+        // Included ranges must not overlap.
+        let mut end = 0;
+        ranges.retain(|r| {
+            let keep = r.start_byte >= end;
+            if keep {
+                end = r.end_byte;
+            }
+            keep
+        });
+    }
+    Ok(regions)
+}
+
+/// Update INJECTOR's injected trees, after HOST-TREE was re-parsed.
+///
+/// The injected regions are discovered by running INJECTOR's query on HOST-TREE.
+/// TEXT-FUNCTION is used for text-based predicates, and for @injection.language
+/// captures, like in `tsc-query-captures'. Each injected language whose regions
+/// changed, or whose tree was edited with `tsc-injector-edit', is re-parsed
+/// incrementally, with the source code chunks generated by INPUT-FUNCTION, like in
+/// `tsc-parse-chunks'. Languages that are not injected anymore are dropped. If an
+/// error is signaled, the languages that were not updated yet are kept as they were.
+///
+/// Return the names of the re-parsed languages. A released INJECTOR is not updated.
+#[defun]
+fn _injector_update<'e>(
+    env: &'e Env,
+    injector: &mut Injector,
+    host_tree: Borrowed<'e, Tree>,
+    input_function: Value<'e>,
+    text_function: Value<'e>,
+) -> Result<Value<'e>> {
+    let (query, language_function) = match &injector.refs {
+        Some((query, language_function)) => (query.bind(env), language_function.bind(env)),
+        None => return ().into_lisp(env),
+    };
+    let regions = {
+        let query = query.into_rust::<&RefCell<Query>>()?.try_borrow()?;
+        discover(&query, host_tree, text_function)?
+    };
+    let mut old_layers = std::mem::take(&mut injector.layers);
+    let reparsed = update_layers(
+        env,
+        language_function,
+        input_function,
+        regions,
+        &mut old_layers,
+        &mut injector.layers,
+    );
+    if reparsed.is_err() {
+        injector.layers.append(&mut old_layers);
+    }
+    types::list(env, reparsed?)
+}
+
+/// Move the layers of REGIONS from OLD_LAYERS to LAYERS, creating and re-parsing them as needed,
+/// and return the names of the re-parsed languages. A layer is only moved once it is up to date.
+fn update_layers<'e>(
+    env: &'e Env,
+    language_function: Value<'e>,
+    input_function: Value<'e>,
+    regions: Vec<(String, Vec<tree_sitter::Range>)>,
+    old_layers: &mut Vec<Layer>,
+    layers: &mut Vec<Layer>,
+) -> Result<Vec<Value<'e>>> {
+    let mut reparsed = vec![];
+    for (name, ranges) in regions {
+        let i = match old_layers.iter().position(|l| l.name == name) {
+            Some(i) => i,
+            None => {
+                let language = language_function.call((&*name,))?;
+                let language = match language.into_rust::<Option<Language>>()? {
+                    Some(language) => language,
+                    None => continue,
+                };
+                let mut parser = Parser::new();
+                parser.set_language(language.into())
+                    .or_else(|err| error::signal_lang_abi_error(env, err))?;
+                old_layers.push(Layer { name, parser, ranges: vec![], tree: None, edited: false });
+                old_layers.len() - 1
+            }
+        };
+        let layer = &mut old_layers[i];
+        if layer.tree.is_none() || layer.edited || layer.ranges != ranges {
+            layer.parser.set_included_ranges(&ranges)
+                .or(Err(Internal("Injected regions are not ordered")))?;
+            let tree = {
+                let old_tree = match &layer.tree {
+                    Some(tree) => Some(tree.try_borrow()?),
+                    None => None,
+                };
+                parser::parse_input(&mut layer.parser, input_function, old_tree.as_deref(), None)?
+            };
+            let tree = parser::shared_tree(tree);
+            tree::record_included_ranges(&tree, &ranges);
+            layer.tree = Some(tree);
+            layer.ranges = ranges;
+            layer.edited = false;
+            reparsed.push(layer.name.clone().into_lisp(env)?);
+        }
+        layers.push(old_layers.swap_remove(i));
+    }
+    Ok(reparsed)
+}
+
+/// Apply an edit to INJECTOR's injected trees, in sync with the same edit applied to
+/// the host tree with `tsc-edit-tree'. See `tsc-edit-tree' for the meaning of the
+/// arguments. The edited trees are re-parsed by the next `tsc-injector-update'.
+#[defun]
+fn injector_edit(
+    injector: &mut Injector,
+    start_bytepos: BytePos,
+    old_end_bytepos: BytePos,
+    new_end_bytepos: BytePos,
+    start_point: Point,
+    old_end_point: Point,
+    new_end_point: Point,
+) -> Result<()> {
+    let edit = InputEdit {
+        start_byte: start_bytepos.into(),
+        old_end_byte: old_end_bytepos.into(),
+        new_end_byte: new_end_bytepos.into(),
+        start_position: start_point.into(),
+        old_end_position: old_end_point.into(),
+        new_end_position: new_end_point.into(),
+    };
+    for layer in &mut injector.layers {
+        if let Some(tree) = &layer.tree {
+            tree.try_borrow_mut()?.edit(&edit);
+            tree::record_edit(tree, &edit);
+            layer.edited = true;
+        }
+    }
+    Ok(())
+}
+
+/// Return INJECTOR's injected trees, as a list of (NAME . TREE), where NAME is the
+/// name of an injected language, in order of first appearance in the host tree.
+/// Each TREE covers all the regions in its language, which are returned by
+/// `tsc-tree-included-ranges'.
+#[defun]
+fn injector_trees<'e>(env: &'e Env, injector: &Injector) -> Result<Value<'e>> {
+    let mut trees = vec![];
+    for layer in &injector.layers {
+        if let Some(tree) = &layer.tree {
+            trees.push(env.cons(&*layer.name, tree.clone())?);
+        }
+    }
+    types::list(env, trees)
+}
@@ -0,0 +1,119 @@
+use std::{cell::RefCell, iter};
+
+use emacs::{defun, Env, IntoLisp, Result, Value};
+use tree_sitter::Node;
+
+use crate::{
+    types::{self, BytePos},
+    lang::Language,
+    node::{LispUtils, RNode},
+    query::{self, Query},
+    session::Session,
+};
+
+emacs::use_symbols! {
+    _node       => ":node"
+    _type       => ":type"
+    _field      => ":field"
+    _byte_range => ":byte-range"
+    _ancestors  => ":ancestors"
+    _captures   => ":captures"
+}
+
+/// Return the field of NODE within its parent, as a keyword, or nil.
+fn field_in_parent<'e>(env: &'e Env, node: Node) -> Result<Value<'e>> {
+    let mut cursor = match node.parent() {
+        Some(parent) => parent.walk(),
+        None => return ().into_lisp(env),
+    };
+    cursor.goto_first_child();
+    while cursor.node() != node {
+        if !cursor.goto_next_sibling() {
+            return ().into_lisp(env);
+        }
+    }
+    let language: Language = node.language().into();
+    match cursor.field_id().and_then(|id| language.info().ok()?.field_name(id)) {
+        Some(field) => Ok(field.bind(env)),
+        None => ().into_lisp(env),
+    }
+}
+
+/// Return a plist describing the syntax of SESSION's latest tree at BYTEPOS, for
+/// "describe syntax at point" commands. Return nil if SESSION has not parsed
+/// anything yet.
+///
+/// :node        The smallest node at BYTEPOS.
+/// :type        Its type.
+/// :field       Its field within its parent, or nil.
+/// :byte-range  Its byte range, as (BEG . END).
+/// :ancestors   Its ancestors, innermost first, as a list of (TYPE FIELD BEG . END).
+/// :captures    If QUERY is non-nil, the captures of QUERY's matches that contain
+///              BYTEPOS, as a list of (TAG BEG . END), where TAG is as in
+///              `tsc-query-captures'. TEXT-FUNCTION is used by text-based predicates.
+///
+/// All positions are byte positions.
+#[defun]
+fn _session_inspect_at<'e>(
+    env: &'e Env,
+    session: &Session,
+    bytepos: BytePos,
+    query: Option<&Query>,
+    text_function: Value<'e>,
+) -> Result<Option<Value<'e>>> {
+    let shared = match &session.tree {
+        Some(tree) => tree.clone(),
+        None => return Ok(None),
+    };
+    let tree = shared.try_borrow()?;
+    let byte: usize = bytepos.into();
+    let root = tree.root_node();
+    let node = root.descendant_for_byte_range(byte, byte).unwrap_or(root);
+
+    let mut ancestors = vec![];
+    for ancestor in iter::successors(node.parent(), |n| n.parent()) {
+        let range = ancestor.lisp_byte_range(env)?;
+        let summary = env.cons(field_in_parent(env, ancestor)?, range)?;
+        ancestors.push(env.cons(ancestor.lisp_type()?.bind(env), summary)?);
+    }
+
+    let mut captures = vec![];
+    if let Some(query) = query {
+        let error = RefCell::new(None);
+        let mut cursor = tree_sitter::QueryCursor::new();
+        cursor.set_byte_range(byte..byte + 1);
+        let matched = cursor.captures(
+            &query.raw,
+            root,
+            query::text_callback(text_function, &error),
+        );
+        for (m, i) in matched {
+            if !query::predicates_hold(&query.raw, &m, text_function, &shared)? {
+                continue;
+            }
+            let capture = m.captures[i];
+            let (beg, end) = (capture.node.start_byte(), capture.node.end_byte());
+            let tag = match query.capture_tags.get(capture.index as usize) {
+                Some(tag) if beg <= byte && byte < end => tag.bind(env),
+                _ => continue,
+            };
+            captures.push(env.cons(tag, capture.node.lisp_byte_range(env)?)?);
+        }
+        if let Some(error) = error.borrow_mut().take() {
+            return Err(error);
+        }
+    }
+
+    let rnode = RNode::new(shared.clone(), |tree| {
+        let root = tree.root_node();
+        root.descendant_for_byte_range(byte, byte).unwrap_or(root)
+    });
+    Ok(Some(types::list(env, vec![
+        _node.bind(env), rnode.into_lisp(env)?,
+        _type.bind(env), node.lisp_type()?.bind(env),
+        _field.bind(env), field_in_parent(env, node)?,
+        _byte_range.bind(env), node.lisp_byte_range(env)?,
+        _ancestors.bind(env), types::list(env, ancestors)?,
+        _captures.bind(env), types::list(env, captures)?,
+    ])?))
+}
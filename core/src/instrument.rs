@@ -0,0 +1,133 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+use emacs::{defun, Env, IntoLisp, Result, Value};
+
+use crate::types;
+
+// -------------------------------------------------------------------------------------------------
+
+/// A cumulative, process-wide counter. Updates are relaxed atomic additions, which are cheap
+/// enough to be done on every parse and query execution.
+pub(crate) struct Counter(AtomicU64);
+
+impl Counter {
+    pub(crate) const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    #[inline(always)]
+    pub(crate) fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    pub(crate) fn incr(&self) {
+        self.add(1)
+    }
+
+    #[inline(always)]
+    pub(crate) fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn reset(&self) {
+        self.0.store(0, Ordering::Relaxed);
+    }
+}
+
+pub(crate) static PARSES: Counter = Counter::new();
+pub(crate) static REPARSES: Counter = Counter::new();
+pub(crate) static BYTES_PARSED: Counter = Counter::new();
+pub(crate) static PARSE_MICROS: Counter = Counter::new();
+pub(crate) static QUERY_EXECUTIONS: Counter = Counter::new();
+pub(crate) static QUERY_MICROS: Counter = Counter::new();
+pub(crate) static NODES_CREATED: Counter = Counter::new();
+
+/// Guard that adds the time elapsed between its creation and its drop (in microseconds) to a
+/// counter. Using a guard makes sure early returns are accounted for.
+pub(crate) struct Timer {
+    micros: &'static Counter,
+    start: Instant,
+}
+
+impl Timer {
+    #[inline]
+    pub(crate) fn new(micros: &'static Counter) -> Self {
+        Self { micros, start: Instant::now() }
+    }
+}
+
+impl Drop for Timer {
+    #[inline]
+    fn drop(&mut self) {
+        self.micros.add(self.start.elapsed().as_micros() as u64);
+    }
+}
+
+/// Record a parse of BYTES bytes. REUSED indicates whether an old tree was passed in.
+#[inline]
+pub(crate) fn record_parse(bytes: usize, reused: bool) {
+    PARSES.incr();
+    if reused {
+        REPARSES.incr();
+    }
+    BYTES_PARSED.add(bytes as u64);
+}
+
+// -------------------------------------------------------------------------------------------------
+
+emacs::use_symbols! {
+    _parses           => ":parses"
+    _reparses         => ":reparses"
+    _bytes_parsed     => ":bytes-parsed"
+    _parse_time       => ":parse-time"
+    _query_executions => ":query-executions"
+    _query_time       => ":query-time"
+    _nodes_created    => ":nodes-created"
+}
+
+fn seconds(micros: &Counter) -> f64 {
+    micros.get() as f64 / 1_000_000.0
+}
+
+/// Return the module's cumulative instrumentation counters, as a plist.
+///
+/// The counters are shared by all users of the module. They are useful to find
+/// out which package is hammering the module, e.g. by comparing the values before
+/// and after a command:
+///
+/// :parses            Number of parses, including incremental ones.
+/// :reparses          Number of incremental parses, i.e. those given an old tree.
+/// :bytes-parsed      Total number of source bytes fed to the parsers.
+/// :parse-time        Total time spent parsing, in seconds.
+/// :query-executions  Number of query executions.
+/// :query-time        Total time spent executing queries, in seconds.
+/// :nodes-created     Number of node objects returned to Lisp.
+#[defun]
+fn instrumentation(env: &Env) -> Result<Value> {
+    types::list(env, vec![
+        _parses.bind(env), PARSES.get().into_lisp(env)?,
+        _reparses.bind(env), REPARSES.get().into_lisp(env)?,
+        _bytes_parsed.bind(env), BYTES_PARSED.get().into_lisp(env)?,
+        _parse_time.bind(env), seconds(&PARSE_MICROS).into_lisp(env)?,
+        _query_executions.bind(env), QUERY_EXECUTIONS.get().into_lisp(env)?,
+        _query_time.bind(env), seconds(&QUERY_MICROS).into_lisp(env)?,
+        _nodes_created.bind(env), NODES_CREATED.get().into_lisp(env)?,
+    ])
+}
+
+/// Reset all of the module's instrumentation counters to 0.
+/// See `tsc-instrumentation'.
+#[defun]
+fn reset_instrumentation() -> Result<()> {
+    for counter in &[
+        &PARSES, &REPARSES, &BYTES_PARSED, &PARSE_MICROS,
+        &QUERY_EXECUTIONS, &QUERY_MICROS, &NODES_CREATED,
+    ] {
+        counter.reset();
+    }
+    Ok(())
+}
@@ -0,0 +1,134 @@
+use std::cell::RefCell;
+
+use emacs::{defun, Result, Value};
+use serde::Serialize;
+use tree_sitter::{Node, TreeCursor};
+
+use crate::{
+    types::Point,
+    node::{LispUtils, RNode},
+    query::{self, Query},
+    error::Internal,
+};
+
+/// A node, as exported to JSON. Positions are the same as in Lisp: byte positions, and points of
+/// the form [LINE-NUMBER, BYTE-COLUMN].
+#[derive(Serialize)]
+struct JsonNode<'t> {
+    #[serde(rename = "type")]
+    kind: &'t str,
+    named: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<&'t str>,
+    start: usize,
+    end: usize,
+    #[serde(rename = "startPoint")]
+    start_point: [usize; 2],
+    #[serde(rename = "endPoint")]
+    end_point: [usize; 2],
+    #[serde(skip_serializing_if = "is_false")]
+    missing: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    children: Option<Vec<JsonNode<'t>>>,
+}
+
+#[inline]
+fn is_false(b: &bool) -> bool {
+    !b
+}
+
+impl<'t> JsonNode<'t> {
+    fn new(node: Node<'t>, field: Option<&'t str>) -> Self {
+        let json_point = |point: Point| [point.line_number(), point.byte_column()];
+        Self {
+            kind: node.kind(),
+            named: node.is_named(),
+            field,
+            // Byte positions are 1-based.
+            start: node.start_byte() + 1,
+            end: node.end_byte() + 1,
+            start_point: json_point(node.lisp_start_point()),
+            end_point: json_point(node.lisp_end_point()),
+            missing: node.is_missing(),
+            children: None,
+        }
+    }
+
+    /// Export the subtree at CURSOR's current node, skipping anonymous nodes if NAMED_ONLY.
+    fn subtree(cursor: &mut TreeCursor<'t>, named_only: bool) -> Self {
+        let mut json = Self::new(cursor.node(), cursor.field_name());
+        let mut children = vec![];
+        if cursor.goto_first_child() {
+            loop {
+                if !named_only || cursor.node().is_named() {
+                    children.push(Self::subtree(cursor, named_only));
+                }
+                if !cursor.goto_next_sibling() {
+                    break;
+                }
+            }
+            cursor.goto_parent();
+        }
+        json.children = Some(children);
+        json
+    }
+}
+
+fn to_string<T: Serialize>(value: &T) -> Result<String> {
+    Ok(serde_json::to_string(value).map_err(|_| Internal("Failed to serialize JSON"))?)
+}
+
+/// Return the syntax tree rooted at NODE, as a JSON string. See `tsc-tree-to-json'.
+#[defun]
+fn _node_to_json(node: &RNode, named_only: Option<Value>) -> Result<String> {
+    let inner = node.borrow()?;
+    let mut cursor = inner.walk();
+    to_string(&JsonNode::subtree(&mut cursor, named_only.map_or(false, |v| v.is_not_nil())))
+}
+
+/// A query match, as exported to JSON.
+#[derive(Serialize)]
+struct JsonMatch<'t> {
+    pattern: usize,
+    captures: Vec<JsonCapture<'t>>,
+}
+
+#[derive(Serialize)]
+struct JsonCapture<'t> {
+    name: &'t str,
+    #[serde(flatten)]
+    node: JsonNode<'t>,
+}
+
+/// Execute QUERY on NODE, returning the matches as a JSON string. See
+/// `tsc-matches-to-json'.
+#[defun]
+fn _query_matches_to_json(query: &Query, node: &RNode, text_function: Value) -> Result<String> {
+    let raw = &query.raw;
+    let names = raw.capture_names();
+    let error = RefCell::new(None);
+    let inner = node.borrow()?;
+    let tree = node.clone_tree();
+    let mut cursor = tree_sitter::QueryCursor::new();
+    let matches = cursor.matches(raw, *inner, query::text_callback(text_function, &error));
+    let mut json = vec![];
+    for m in matches {
+        if let Some(error) = error.borrow_mut().take() {
+            return Err(error);
+        }
+        if !query::predicates_hold(raw, &m, text_function, &tree)? {
+            continue;
+        }
+        json.push(JsonMatch {
+            pattern: m.pattern_index,
+            captures: m.captures.iter().map(|c| JsonCapture {
+                name: &names[c.index as usize],
+                node: JsonNode::new(c.node, None),
+            }).collect(),
+        });
+    }
+    if let Some(error) = error.borrow_mut().take() {
+        return Err(error);
+    }
+    to_string(&json)
+}
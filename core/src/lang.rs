@@ -1,11 +1,11 @@
-use std::{mem, os, collections::HashMap, sync::Mutex};
+use std::{mem, os, collections::HashMap, sync::{Mutex, MutexGuard, PoisonError}};
 
-use emacs::{defun, Result, ResultExt, GlobalRef, Value, Env, IntoLisp, FromLisp, ErrorKind};
+use emacs::{defun, Result, GlobalRef, Value, Vector, Env, IntoLisp, FromLisp, ErrorKind};
 
 use libloading::{Library, Symbol};
 use once_cell::sync::Lazy;
 
-use crate::{types, error};
+use crate::{types, cache, error::{self, Internal}};
 use tree_sitter::{LANGUAGE_VERSION, MIN_COMPATIBLE_LANGUAGE_VERSION};
 
 #[derive(Copy, Clone)]
@@ -43,12 +43,12 @@ impl Language {
         unsafe { mem::transmute(self) }
     }
 
-    pub fn info(self) -> &'static LangInfo {
+    pub fn info(self) -> Result<&'static LangInfo> {
         // TODO: Explain the safety.
-        LANG_INFOS.try_lock().expect("Failed to access language info registry")
+        lang_infos()
             .get(&self.id())
             .map(|info| unsafe { types::erase_lifetime(info) })
-            .expect("Failed to get language info from the registry")
+            .ok_or_else(|| Internal("Failed to get language info from the registry").into())
     }
 }
 
@@ -57,7 +57,9 @@ unsafe extern "C" fn no_op<T>(_: *mut os::raw::c_void) {}
 // -------------------------------------------------------------------------------------------------
 
 pub struct LangInfo {
+    language: Language,
     load_file: String,
+    symbol_name: String,
     lang_symbol: GlobalRef,
     _lib: Library,
     node_types: Vec<GlobalRef>,
@@ -78,6 +80,12 @@ impl LangInfo {
             self.field_names.get(id as usize - 1)
         }
     }
+
+    /// Return the estimated number of bytes held by this language's interned tables.
+    fn interned_bytes(&self) -> usize {
+        let refs = self.node_types.len() + self.field_names.len() + 1;
+        self.load_file.len() + self.symbol_name.len() + refs * mem::size_of::<GlobalRef>()
+    }
 }
 
 // TODO: Consider optimizing for accessing language's metadata, i.e. making Language a big wrapper
@@ -85,46 +93,102 @@ impl LangInfo {
 // parser/tree/node/query.
 static LANG_INFOS: Lazy<Mutex<HashMap<usize, LangInfo>>> = Lazy::new(|| Mutex::new(HashMap::new()));
 
+/// Lock the language info registry. The lock is never held across calls into Lisp, or while
+/// another entry can be accessed, so this does not block. A poisoned registry is still usable,
+/// since entries are only ever inserted whole.
+fn lang_infos() -> MutexGuard<'static, HashMap<usize, LangInfo>> {
+    LANG_INFOS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Return the estimated number of bytes held by the interned tables of all loaded languages.
+pub(crate) fn interned_bytes() -> usize {
+    lang_infos()
+        .values()
+        .map(LangInfo::interned_bytes)
+        .sum()
+}
+
+/// Return the number of loaded languages.
+pub(crate) fn loaded_count() -> usize {
+    lang_infos().len()
+}
+
 /// Load the shared lib FILE and return the language under SYMBOL-NAME.
 /// The language's name symbol is set to LANG-SYMBOL.
+///
+/// If the language was already loaded from FILE under the same names, it is returned
+/// without loading anything, so that all of its users share the same interned tables.
 #[defun]
 fn _load_language(file: String, symbol_name: String, lang_symbol: Value) -> Result<Language> {
     let env = lang_symbol.env;
-    let lib = unsafe { Library::new(&file) }.or_signal(env, error::tsc_lang_load_failed)?;
+    let loaded = lang_infos().values().find(|info| {
+        info.load_file == file
+            && info.symbol_name == symbol_name
+            && info.lang_symbol.bind(env).eq(lang_symbol)
+    }).map(|info| info.language);
+    if let Some(language) = loaded {
+        cache::LANGUAGE_HITS.incr();
+        return Ok(language);
+    }
+    let load_failed = |kind, err: libloading::Error| {
+        error::signal_lang_load_failed(env, err.to_string(), kind, &file, &symbol_name)
+    };
+    // The bundled version of tree-sitter cannot run grammars compiled to WebAssembly. Loading one
+    // as a shared lib would fail with an obscure message.
+    if file.ends_with(".wasm") {
+        let message = "WebAssembly grammars are not supported by this version of tree-sitter";
+        return error::signal_lang_load_failed(env, message.to_owned(), "wasm", &file, &symbol_name);
+    }
+    let lib = match unsafe { Library::new(&file) } {
+        Ok(lib) => lib,
+        Err(err) => return load_failed("library", err),
+    };
     let tree_sitter_lang: Symbol<'_, unsafe extern "C" fn() -> _> =
-        unsafe { lib.get(symbol_name.as_bytes()) }.or_signal(env, error::tsc_lang_load_failed)?;
+        match unsafe { lib.get(symbol_name.as_bytes()) } {
+            Ok(f) => f,
+            Err(err) => return load_failed("symbol", err),
+        };
     let language: tree_sitter::Language = unsafe { tree_sitter_lang() };
     let version = language.version();
-    if version < MIN_COMPATIBLE_LANGUAGE_VERSION {
-        return env.signal(error::tsc_lang_abi_too_old, (
-            version, supported_abi_range(env)?, file
-        ));
-    }
-    if version > LANGUAGE_VERSION {
-        return env.signal(error::tsc_lang_abi_too_new, (
-            version, supported_abi_range(env)?, file
+    let too_old = version < MIN_COMPATIBLE_LANGUAGE_VERSION;
+    if too_old || version > LANGUAGE_VERSION {
+        let (symbol, kind) = if too_old {
+            (error::tsc_lang_abi_too_old, "abi-too-old")
+        } else {
+            (error::tsc_lang_abi_too_new, "abi-too-new")
+        };
+        return env.signal(symbol, (
+            format!("Incompatible language version {}. Expected minimum {}, maximum {}",
+                    version, MIN_COMPATIBLE_LANGUAGE_VERSION, LANGUAGE_VERSION),
+            error::_kind, error::kind(env, kind)?,
+            error::_actual, version,
+            error::_expected, supported_abi_range(env)?,
+            error::_file, file,
         ));
     }
-    let node_types = (0..language.node_kind_count() as u16).map(|id| {
-        let type_str = language.node_kind_for_id(id).expect("Failed to get node type for id");
+    let node_types = (0..language.node_kind_count() as u16).map(|id| -> Result<GlobalRef> {
+        let type_str = language.node_kind_for_id(id)
+            .ok_or(Internal("Failed to get node type for id"))?;
         let value = if !language.node_kind_is_visible(id) {
-            env.intern(&format!(":{}", type_str)).expect("Failed to intern keyword for node type")
+            env.intern(&format!(":{}", type_str))?
         } else if language.node_kind_is_named(id) {
-            env.intern(type_str).expect("Failed to intern symbol for node type")
+            env.intern(type_str)?
         } else {
-            type_str.into_lisp(env).expect("Failed to make string for node type")
+            type_str.into_lisp(env)?
         };
-        value.make_global_ref()
-    }).collect();
-    let field_names = (1..=language.field_count() as u16).map(|id| {
-        let field_str = language.field_name_for_id(id).expect("Failed to get field name for id");
-        env.intern(&format!(":{}", field_str)).expect("Failed to intern keyword for field name")
-            .make_global_ref()
-    }).collect();
+        Ok(value.make_global_ref())
+    }).collect::<Result<_>>()?;
+    let field_names = (1..=language.field_count() as u16).map(|id| -> Result<GlobalRef> {
+        let field_str = language.field_name_for_id(id)
+            .ok_or(Internal("Failed to get field name for id"))?;
+        Ok(env.intern(&format!(":{}", field_str))?.make_global_ref())
+    }).collect::<Result<_>>()?;
     let language: Language = language.into();
-    LANG_INFOS.try_lock().expect("Failed to access language info registry")
+    lang_infos()
         .insert(language.id(), LangInfo {
+            language,
             load_file: file,
+            symbol_name,
             lang_symbol: lang_symbol.make_global_ref(),
             _lib: lib,
             node_types,
@@ -136,13 +200,13 @@ fn _load_language(file: String, symbol_name: String, lang_symbol: Value) -> Resu
 /// Return LANGUAGE's name, as a symbol.
 #[defun]
 fn _lang_symbol(language: Language) -> Result<&'static GlobalRef> {
-    Ok(&language.info().lang_symbol)
+    Ok(&language.info()?.lang_symbol)
 }
 
 /// Return the shared lib file that LANGUAGE was loaded from.
 #[defun]
 fn _lang_load_file(language: Language) -> Result<&'static String> {
-    Ok(&language.info().load_file)
+    Ok(&language.info()?.load_file)
 }
 
 /// Return the node type associated with the numeric TYPE-ID in LANGUAGE.
@@ -152,13 +216,61 @@ fn _lang_load_file(language: Language) -> Result<&'static String> {
 /// For auxiliary (invisible) nodes, the node type is a keyword. For example: :end, :_expression.
 #[defun]
 fn lang_node_type(language: Language, type_id: u16) -> Result<Option<&'static GlobalRef>> {
-    Ok(language.info().node_type(type_id))
+    Ok(language.info()?.node_type(type_id))
 }
 
 /// Return a field's name keyword, given its numeric FIELD-ID in LANGUAGE.
 #[defun]
 fn lang_field(language: Language, field_id: u16) -> Result<Option<&'static GlobalRef>> {
-    Ok(language.info().field_name(field_id))
+    Ok(language.info()?.field_name(field_id))
+}
+
+// Raw node kinds and field names, as strings, e.g. for building queries.
+
+/// Return the number of distinct node kinds defined in LANGUAGE, including hidden
+/// ones. This is the same as `tsc-lang-count-types'. Node kind ids range from 0 to
+/// this number minus 1.
+#[defun]
+fn lang_node_kind_count(language: Language) -> Result<usize> {
+    Ok(language.0.node_kind_count())
+}
+
+/// Return the name of the node kind with the numeric ID in LANGUAGE, as a string.
+/// Return nil if LANGUAGE doesn't have such a node kind. Unlike `tsc-lang-node-type',
+/// this doesn't distinguish named, anonymous and hidden node kinds.
+#[defun]
+fn lang_node_kind_for_id(language: Language, id: u16) -> Result<Option<&'static str>> {
+    Ok(language.0.node_kind_for_id(id))
+}
+
+/// Return the numeric id of the node kind named KIND in LANGUAGE. If NAMED is
+/// non-nil, look for a named node kind, otherwise for an anonymous one.
+/// Return nil if LANGUAGE doesn't have such a node kind.
+#[defun]
+fn lang_id_for_node_kind(
+    language: Language,
+    kind: String,
+    named: Option<Value>,
+) -> Result<Option<u16>> {
+    // 0 is the id of the built-in "end" node kind, which is also returned when there's no match.
+    Ok(match language.0.id_for_node_kind(&kind, named.is_some()) {
+        0 if kind != "end" => None,
+        id => Some(id),
+    })
+}
+
+/// Return the number of distinct field names defined in LANGUAGE. This is the same
+/// as `tsc-lang-count-fields'. Field ids range from 1 to this number.
+#[defun]
+fn lang_field_count(language: Language) -> Result<usize> {
+    Ok(language.0.field_count())
+}
+
+/// Return the name of the field with the numeric ID in LANGUAGE, as a string.
+/// Return nil if LANGUAGE doesn't have such a field. See also `tsc-lang-field'.
+#[defun]
+fn lang_field_name_for_id(language: Language, id: u16) -> Result<Option<&'static str>> {
+    Ok(language.0.field_name_for_id(id))
 }
 
 /// Return the numeric id of TYPE-NAME in LANGUAGE.
@@ -169,10 +281,87 @@ fn _lang_type_id_for_name(language: Language, type_name: String, named: Option<V
 
 /// Return the range of language ABI's that this module can load.
 #[defun]
-fn supported_abi_range(env: &Env) -> Result<Value> {
+pub(crate) fn supported_abi_range(env: &Env) -> Result<Value> {
     env.cons(MIN_COMPATIBLE_LANGUAGE_VERSION, LANGUAGE_VERSION)
 }
 
+/// Check whether the language under SYMBOL-NAME in the shared lib FILE is usable,
+/// without registering it. Return a list of checks, each of the form
+/// (CHECK OK . DETAILS), where CHECK is a symbol, and OK is t or nil.
+///
+/// (library OK [MESSAGE])                 FILE can be loaded.
+/// (symbol OK [MESSAGE])                  FILE exports SYMBOL-NAME.
+/// (abi OK VERSION (MIN . MAX))           The language's ABI VERSION is supported.
+/// (query OK NAME [MESSAGE BYTEPOS])      The query source named NAME compiles.
+/// (parse OK [ROOT-TYPE HAS-ERROR])       SOURCE can be parsed.
+///
+/// QUERIES should be a vector of (NAME . QUERY-SOURCE). Checks stop at the first
+/// failure of `library', `symbol', or `abi', since the others depend on it.
+#[defun]
+fn _doctor<'e>(
+    env: &'e Env,
+    file: String,
+    symbol_name: String,
+    queries: Vector<'e>,
+    source: String,
+) -> Result<Value<'e>> {
+    let mut checks = vec![];
+    let check = |name: &str, ok: bool, mut details: Vec<Value<'e>>| -> Result<Value<'e>> {
+        let mut check = vec![env.intern(name)?, ok.into_lisp(env)?];
+        check.append(&mut details);
+        types::list(env, check)
+    };
+    let lib = match unsafe { Library::new(&file) } {
+        Ok(lib) => lib,
+        Err(err) => {
+            checks.push(check("library", false, vec![err.to_string().into_lisp(env)?])?);
+            return types::list(env, checks);
+        }
+    };
+    checks.push(check("library", true, vec![])?);
+    let tree_sitter_lang: Symbol<'_, unsafe extern "C" fn() -> tree_sitter::Language> =
+        match unsafe { lib.get(symbol_name.as_bytes()) } {
+            Ok(f) => f,
+            Err(err) => {
+                checks.push(check("symbol", false, vec![err.to_string().into_lisp(env)?])?);
+                return types::list(env, checks);
+            }
+        };
+    checks.push(check("symbol", true, vec![])?);
+    let language = unsafe { tree_sitter_lang() };
+    let version = language.version();
+    let compatible = (MIN_COMPATIBLE_LANGUAGE_VERSION..=LANGUAGE_VERSION).contains(&version);
+    let abi = vec![version.into_lisp(env)?, supported_abi_range(env)?];
+    checks.push(check("abi", compatible, abi)?);
+    if !compatible {
+        return types::list(env, checks);
+    }
+    for i in 0..queries.len() {
+        let query: Value = queries.get(i)?;
+        let name = query.car::<Value>()?;
+        let query_source: String = query.cdr()?;
+        checks.push(match tree_sitter::Query::new(language, &query_source) {
+            Ok(_) => check("query", true, vec![name])?,
+            Err(err) => {
+                let bytepos: types::BytePos = err.offset.into();
+                let details = vec![name, err.message.into_lisp(env)?, bytepos.into_lisp(env)?];
+                check("query", false, details)?
+            }
+        });
+    }
+    let mut parser = tree_sitter::Parser::new();
+    parser.set_language(language).or_else(|err| error::signal_lang_abi_error(env, err))?;
+    checks.push(match parser.parse(&source, None) {
+        Some(tree) => {
+            let root = tree.root_node();
+            let details = vec![root.kind().into_lisp(env)?, root.has_error().into_lisp(env)?];
+            check("parse", true, details)?
+        }
+        None => check("parse", false, vec![])?,
+    });
+    types::list(env, checks)
+}
+
 macro_rules! defun_lang_methods {
     ($($(#[$meta:meta])* $($lisp_name:literal)? fn $name:ident $( ( $( $param:ident : $type:ty ),* ) )? -> $rtype:ty )*) => {
         $(
@@ -202,6 +391,7 @@ defun_lang_methods! {
     /// Return t if the numeric TYPE-ID identifies a named node type in LANGUAGE.
     "lang-node-type-named-p" fn node_kind_is_named(type_id: u16) -> bool
 
-    /// Return the numeric id of FIELD-NAME in LANGUAGE.
-    "-lang-field-id-for-name" fn field_id_for_name(field_name: String) -> Option<u16>
+    /// Return the numeric id of the FIELD-NAME string in LANGUAGE.
+    /// Return nil if LANGUAGE doesn't have such a field. See also `tsc-lang-field-id'.
+    "lang-field-id-for-name" fn field_id_for_name(field_name: String) -> Option<u16>
 }
@@ -10,6 +10,35 @@ mod tree;
 mod node;
 mod cursor;
 mod query;
+mod spans;
+mod pattern;
+mod instrument;
+mod live;
+mod session;
+mod diagnostics;
+mod indent;
+mod formatting;
+mod completion;
+mod editing;
+mod navigation;
+mod text_object;
+mod definitions;
+mod symbols;
+mod cache;
+mod channel;
+mod detect;
+mod fuzz;
+mod inspect;
+mod playground;
+mod position;
+mod highlight;
+mod injection;
+mod node_types;
+mod compile;
+mod diff;
+mod folding;
+mod tags;
+mod json;
 
 emacs::plugin_is_GPL_compatible! {}
 
@@ -0,0 +1,333 @@
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use emacs::{defun, Env, FromLisp, IntoLisp, Result, Value};
+use tree_sitter::Tree;
+
+use crate::{
+    types::{self, Shared},
+    error,
+    lang,
+    parser::{self, RParser},
+    query::{Query, QueryCursor},
+    injection::Injector,
+    tree::{self, Borrowed},
+};
+
+// -------------------------------------------------------------------------------------------------
+
+/// Number of currently alive objects of a kind.
+pub(crate) struct Gauge(AtomicUsize);
+
+impl Gauge {
+    const fn new() -> Self {
+        Self(AtomicUsize::new(0))
+    }
+
+    #[inline(always)]
+    pub(crate) fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    #[inline(always)]
+    pub(crate) fn add(&self, n: usize) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    pub(crate) fn sub(&self, n: usize) {
+        self.0.fetch_sub(n, Ordering::Relaxed);
+    }
+}
+
+pub(crate) static PARSERS: Gauge = Gauge::new();
+pub(crate) static QUERIES: Gauge = Gauge::new();
+pub(crate) static QUERY_CURSORS: Gauge = Gauge::new();
+pub(crate) static NODES: Gauge = Gauge::new();
+pub(crate) static CURSORS: Gauge = Gauge::new();
+pub(crate) static SESSIONS: Gauge = Gauge::new();
+pub(crate) static INJECTORS: Gauge = Gauge::new();
+
+/// Estimated number of bytes held by all alive queries.
+pub(crate) static QUERY_BYTES: Gauge = Gauge::new();
+
+/// Token embedded in module objects, to keep their kind's `Gauge` up-to-date.
+pub(crate) struct Live(&'static Gauge);
+
+impl Live {
+    #[inline]
+    pub(crate) fn new(gauge: &'static Gauge) -> Self {
+        gauge.add(1);
+        Self(gauge)
+    }
+}
+
+impl Clone for Live {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self::new(self.0)
+    }
+}
+
+impl Drop for Live {
+    #[inline]
+    fn drop(&mut self) {
+        self.0.sub(1);
+    }
+}
+
+// -------------------------------------------------------------------------------------------------
+
+emacs::use_symbols! {
+    wrong_type_argument
+    tsc_releasable_p
+
+    _trees         => ":trees"
+    _parsers       => ":parsers"
+    _queries       => ":queries"
+    _query_cursors => ":query-cursors"
+    _cursors       => ":cursors"
+    _nodes         => ":nodes"
+    _sessions      => ":sessions"
+    _injectors     => ":injectors"
+
+    _languages     => ":languages"
+    _total         => ":total"
+
+    _named         => ":named"
+    _errors        => ":errors"
+    _missing       => ":missing"
+    _depth         => ":depth"
+    _bytes         => ":bytes"
+}
+
+enum Releasable<'e> {
+    Tree(&'e Shared<Tree>),
+    Parser(&'e RefCell<RParser>),
+    Query(&'e RefCell<Query>),
+    QueryCursor(&'e RefCell<QueryCursor>),
+    Injector(&'e RefCell<Injector>),
+}
+
+impl<'e> FromLisp<'e> for Releasable<'e> {
+    fn from_lisp(value: Value<'e>) -> Result<Self> {
+        if let Ok(tree) = value.into_rust() {
+            return Ok(Self::Tree(tree));
+        }
+        if let Ok(parser) = value.into_rust() {
+            return Ok(Self::Parser(parser));
+        }
+        if let Ok(query) = value.into_rust() {
+            return Ok(Self::Query(query));
+        }
+        if let Ok(cursor) = value.into_rust() {
+            return Ok(Self::QueryCursor(cursor));
+        }
+        if let Ok(injector) = value.into_rust() {
+            return Ok(Self::Injector(injector));
+        }
+        value.env.signal(wrong_type_argument, (tsc_releasable_p, value))
+    }
+}
+
+/// Release the resources held by OBJECT immediately, without waiting for the
+/// garbage collector. See `tsc-release'.
+///
+/// Return nil if the resources were released. Otherwise, return the reason, in the
+/// form (MESSAGE . PLIST) of the module's error data, with these `:kind's:
+///
+/// `referenced'      OBJECT is a tree that is still referenced by other objects,
+///                   whose number is `:references'. See `tsc-release'.
+/// `no-language'     OBJECT is a tree whose language cannot create an empty tree.
+/// `query-streamed'  OBJECT is a query being executed by a streaming query cursor.
+///                   See `tsc-query-cursor-exec'.
+#[defun]
+fn _release<'e>(env: &'e Env, object: Releasable<'e>) -> Result<Option<Value<'e>>> {
+    match object {
+        Releasable::Tree(tree) => {
+            let references = Rc::strong_count(tree) - 1;
+            if references > 0 {
+                return Ok(Some(types::list(env, vec![
+                    format!("Tree is still referenced by {} other objects", references)
+                        .into_lisp(env)?,
+                    error::_kind.bind(env), error::kind(env, "referenced")?,
+                    error::_references.bind(env), references.into_lisp(env)?,
+                ])?));
+            }
+            let language = tree.try_borrow()?.language();
+            let empty = match parser::empty_tree(language) {
+                Some(empty) => empty,
+                None => return Ok(Some(types::list(env, vec![
+                    "Tree's language cannot create an empty tree".into_lisp(env)?,
+                    error::_kind.bind(env), error::kind(env, "no-language")?,
+                ])?)),
+            };
+            *tree.try_borrow_mut()? = empty;
+            tree::mark_tree_released(tree);
+        }
+        Releasable::Parser(parser) => parser.try_borrow_mut()?.release(env)?,
+        Releasable::Query(query) => {
+            let mut query = query.try_borrow_mut()?;
+            if query.is_streamed() {
+                return Ok(Some(types::list(env, vec![
+                    "Query is being executed by a streaming query cursor".into_lisp(env)?,
+                    error::_kind.bind(env), error::kind(env, "query-streamed")?,
+                ])?));
+            }
+            query.release()?
+        }
+        Releasable::QueryCursor(cursor) => cursor.try_borrow_mut()?.release(env)?,
+        Releasable::Injector(injector) => injector.try_borrow_mut()?.release(env)?,
+    }
+    Ok(None)
+}
+
+/// Return t if OBJECT was released by `tsc-release'.
+#[defun]
+fn released_p(object: Releasable) -> Result<bool> {
+    Ok(match object {
+        Releasable::Tree(tree) => tree::is_tree_released(tree),
+        Releasable::Parser(parser) => parser.try_borrow()?.is_released(),
+        Releasable::Query(query) => query.try_borrow()?.is_released(),
+        Releasable::QueryCursor(cursor) => cursor.try_borrow()?.is_released(),
+        Releasable::Injector(injector) => injector.try_borrow()?.is_released(),
+    })
+}
+
+/// Return the counts of the module's objects that are still alive, by type, as a
+/// plist.
+///
+/// Objects are alive until they are garbage-collected. Released trees are not
+/// counted. See `tsc-release'.
+#[defun]
+fn live_objects(env: &Env) -> Result<Value> {
+    let mut trees = 0;
+    tree::for_each_tree(|_, released| {
+        if !released {
+            trees += 1;
+        }
+    });
+    types::list(env, vec![
+        _trees.bind(env), trees.into_lisp(env)?,
+        _parsers.bind(env), PARSERS.get().into_lisp(env)?,
+        _queries.bind(env), QUERIES.get().into_lisp(env)?,
+        _query_cursors.bind(env), QUERY_CURSORS.get().into_lisp(env)?,
+        _cursors.bind(env), CURSORS.get().into_lisp(env)?,
+        _nodes.bind(env), NODES.get().into_lisp(env)?,
+        _sessions.bind(env), SESSIONS.get().into_lisp(env)?,
+        _injectors.bind(env), INJECTORS.get().into_lisp(env)?,
+    ])
+}
+
+// -------------------------------------------------------------------------------------------------
+// Memory estimation. The numbers approximate tree-sitter's internal layout on 64-bit platforms.
+
+/// Heap size of a non-leaf subtree, excluding its children array.
+const SUBTREE_HEAP_BYTES: usize = 80;
+
+/// Size of a child slot in a subtree's children array.
+const SUBTREE_BYTES: usize = 8;
+
+/// Return the estimated number of bytes held by TREE. Small leaf nodes are inlined by
+/// tree-sitter, so they are not counted. Neither are invisible nodes, which are not reachable
+/// through the public API.
+pub(crate) fn tree_bytes(tree: &Tree) -> usize {
+    let mut bytes = 0;
+    let mut cursor = tree.walk();
+    loop {
+        let child_count = cursor.node().child_count();
+        if child_count > 0 {
+            bytes += SUBTREE_HEAP_BYTES + child_count * SUBTREE_BYTES;
+        }
+        if cursor.goto_first_child() || cursor.goto_next_sibling() {
+            continue;
+        }
+        loop {
+            if !cursor.goto_parent() {
+                return bytes;
+            }
+            if cursor.goto_next_sibling() {
+                break;
+            }
+        }
+    }
+}
+
+/// Return the estimated number of bytes held by TREE.
+#[defun]
+fn _tree_memory(tree: Borrowed<Tree>) -> Result<usize> {
+    Ok(tree_bytes(&*tree.try_borrow()?))
+}
+
+/// Return statistics about TREE, as a plist:
+///
+/// :nodes    Number of nodes, named or anonymous.
+/// :named    Number of named nodes.
+/// :errors   Number of ERROR nodes.
+/// :missing  Number of MISSING nodes, inserted by the parser to recover from errors.
+/// :depth    Maximum depth of the nodes. The root node's depth is 0.
+/// :bytes    Estimated number of bytes held by TREE, as in `tsc-memory-report'.
+#[defun]
+fn tree_stats(env: &Env, tree: Borrowed<Tree>) -> Result<Value> {
+    let tree = tree.try_borrow()?;
+    let (mut nodes, mut named, mut errors, mut missing) = (0usize, 0usize, 0usize, 0usize);
+    let (mut depth, mut max_depth) = (0usize, 0usize);
+    let mut cursor = tree.walk();
+    'walk: loop {
+        let node = cursor.node();
+        nodes += 1;
+        named += node.is_named() as usize;
+        errors += node.is_error() as usize;
+        missing += node.is_missing() as usize;
+        max_depth = max_depth.max(depth);
+        if cursor.goto_first_child() {
+            depth += 1;
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                break 'walk;
+            }
+            depth -= 1;
+        }
+    }
+    types::list(env, vec![
+        _nodes.bind(env), nodes.into_lisp(env)?,
+        _named.bind(env), named.into_lisp(env)?,
+        _errors.bind(env), errors.into_lisp(env)?,
+        _missing.bind(env), missing.into_lisp(env)?,
+        _depth.bind(env), max_depth.into_lisp(env)?,
+        _bytes.bind(env), tree_bytes(&tree).into_lisp(env)?,
+    ])
+}
+
+/// Return the estimated numbers of bytes held by the module's objects, by type, as
+/// a plist:
+///
+/// :trees      All alive syntax trees. Shallow copies are counted once.
+/// :queries    All alive queries.
+/// :languages  Tables of node types and field names, interned for loaded languages.
+/// :total      Sum of the above.
+#[defun]
+fn _memory_report(env: &Env) -> Result<Value> {
+    let mut trees = 0;
+    let mut error = None;
+    tree::for_each_tree(|tree, _| match tree.try_borrow() {
+        Ok(tree) => trees += tree_bytes(&tree),
+        Err(e) => error = Some(e),
+    });
+    if let Some(e) = error {
+        return Err(e.into());
+    }
+    let queries = QUERY_BYTES.get();
+    let languages = lang::interned_bytes();
+    types::list(env, vec![
+        _trees.bind(env), trees.into_lisp(env)?,
+        _queries.bind(env), queries.into_lisp(env)?,
+        _languages.bind(env), languages.into_lisp(env)?,
+        _total.bind(env), (trees + queries + languages).into_lisp(env)?,
+    ])
+}
@@ -0,0 +1,459 @@
+use std::cell::RefCell;
+
+use emacs::{defun, Env, Result, Value, Vector};
+use tree_sitter::{Node, Tree};
+
+use crate::{
+    types::{self, BytePos},
+    node::{LispUtils, RNode},
+    tree::Borrowed,
+    diagnostics::for_each_leaf,
+    editing::{self, mirror_token},
+};
+
+/// Convert KINDS, a vector of node types, into (NAME, NAMED) pairs. Like those returned by
+/// `tsc-node-type', the types of named nodes are symbols, and those of anonymous nodes strings.
+fn kind_names(kinds: Vector) -> Result<Vec<(String, bool)>> {
+    (0..kinds.len()).map(|i| {
+        let kind: Value = kinds.get(i)?;
+        Ok(match kind.into_rust::<String>() {
+            Ok(name) => (name, false),
+            Err(_) => (kind.env.call("symbol-name", (kind,))?.into_rust()?, true),
+        })
+    }).collect()
+}
+
+/// Return whether NODE's type is one of KINDS.
+#[inline]
+fn is_kind_in(node: &Node, kinds: &[(String, bool)]) -> bool {
+    kinds.iter().any(|(name, named)| *named == node.is_named() && name == node.kind())
+}
+
+/// Return NODE and its ancestors, innermost first. They are collected in one descent from ROOT,
+/// since each call to `Node::parent` descends from the root again.
+fn ancestors<'t>(root: Node<'t>, node: Node<'t>) -> Vec<Node<'t>> {
+    let (beg, end) = (node.start_byte(), node.end_byte());
+    // The nodes containing NODE's range, from ROOT to the cursor's parent. Several siblings can
+    // contain it when it is empty, so the search backtracks.
+    let mut path = vec![];
+    let mut cursor = root.walk();
+    'search: loop {
+        let current = cursor.node();
+        if current.start_byte() <= beg && end <= current.end_byte() {
+            path.push(current);
+            if current == node {
+                path.reverse();
+                return path;
+            }
+            if cursor.goto_first_child() {
+                continue;
+            }
+            path.pop();
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                break 'search;
+            }
+            path.pop();
+        }
+    }
+    vec![node]
+}
+
+/// Return the nodes of TREE that strictly enclose BYTE, innermost first.
+fn enclosing_nodes(tree: &Tree, byte: usize) -> Vec<Node> {
+    let root = tree.root_node();
+    let node = match root.descendant_for_byte_range(byte, byte) {
+        Some(node) => node,
+        None => return vec![],
+    };
+    let mut nodes = ancestors(root, node);
+    nodes.retain(|n| n.start_byte() < byte && byte < n.end_byte());
+    nodes
+}
+
+/// Return the names of the kind sets whose nodes enclose BYTEPOS in TREE, innermost first.
+///
+/// KIND-SETS should be a vector of (NAME . KINDS), where KINDS is a vector of node
+/// types, see `kind_names`. If KIND-SETS is nil, 2 sets are used: `string', for node
+/// types containing "string", and `comment', for those containing "comment".
+///
+/// A node encloses BYTEPOS if BYTEPOS is strictly between its start and its end,
+/// so positions right before an opening quote, or right after a closing one, are
+/// not inside the string.
+#[defun]
+fn _syntax_context<'e>(
+    env: &'e Env,
+    tree: Borrowed<'e, Tree>,
+    bytepos: BytePos,
+    kind_sets: Option<Vector<'e>>,
+) -> Result<Value<'e>> {
+    let tree = tree.try_borrow()?;
+    let nodes = enclosing_nodes(&tree, bytepos.into());
+    let mut found: Vec<(usize, Value)> = vec![];
+    match kind_sets {
+        None => {
+            for name in &["string", "comment"] {
+                if let Some(depth) = nodes.iter().position(|n| n.kind().contains(name)) {
+                    found.push((depth, env.intern(name)?));
+                }
+            }
+        }
+        Some(kind_sets) => {
+            for i in 0..kind_sets.len() {
+                let set: Value = kind_sets.get(i)?;
+                let kinds = kind_names(set.cdr()?)?;
+                if let Some(depth) = nodes.iter().position(|n| is_kind_in(n, &kinds)) {
+                    found.push((depth, set.car()?));
+                }
+            }
+        }
+    }
+    found.sort_by_key(|(depth, _)| *depth);
+    types::list(env, found.into_iter().map(|(_, name)| name).collect())
+}
+
+/// Return the text of the token that would close the unclosed construct at BYTEPOS in TREE, or
+/// nil if there is none.
+///
+/// The innermost construct enclosing BYTEPOS whose closing token was inserted by the
+/// parser as a MISSING node is used first. Otherwise, inside an ERROR node, the
+/// innermost bracket-like token before BYTEPOS that is not matched is used.
+#[defun]
+fn _expected_closer(tree: Borrowed<Tree>, bytepos: BytePos) -> Result<Option<String>> {
+    let tree = tree.try_borrow()?;
+    let byte: usize = bytepos.into();
+    let root = tree.root_node();
+    let node = match root.descendant_for_byte_range(byte, byte) {
+        Some(node) => node,
+        None => return Ok(None),
+    };
+    let ancestors = ancestors(root, node);
+    for n in ancestors.iter().filter(|n| n.start_byte() < byte) {
+        let last = n.child_count().checked_sub(1).and_then(|i| n.child(i));
+        if let Some(last) = last.filter(|last| last.is_missing() && !last.is_named()) {
+            return Ok(Some(last.kind().to_owned()));
+        }
+    }
+    for error in ancestors.iter().filter(|n| n.is_error()) {
+        let mut unclosed: Vec<String> = vec![];
+        for_each_leaf(*error, |leaf| {
+            if leaf.is_named() || leaf.start_byte() >= byte {
+                return;
+            }
+            if unclosed.last().map_or(false, |closer| closer == leaf.kind()) {
+                unclosed.pop();
+            } else if let Some(closer) = mirror_token(leaf.kind()) {
+                // Quote-like tokens are their own closers, so they cannot be told apart.
+                if closer != leaf.kind() {
+                    unclosed.push(closer);
+                }
+            }
+        });
+        if let Some(closer) = unclosed.pop() {
+            return Ok(Some(closer));
+        }
+    }
+    Ok(None)
+}
+
+/// Return the successively larger ranges enclosing the range from BEG to END in TREE, as a vector
+/// of (BEG . END) byte positions, for expand-region-like commands.
+///
+/// Each range strictly contains the previous one. The ranges are those of the nodes
+/// enclosing the given range, preceded by their inner ranges, i.e. without their
+/// delimiters and the surrounding whitespace (see `tsc-text-object-range').
+#[defun]
+fn _expansion_steps<'e>(
+    env: &'e Env,
+    tree: Borrowed<'e, Tree>,
+    beg: BytePos,
+    end: BytePos,
+) -> Result<Vector<'e>> {
+    let tree = tree.try_borrow()?;
+    let (beg, end): (usize, usize) = (beg.into(), end.into());
+    let mut steps = vec![];
+    let mut last = (beg, end);
+    let root = tree.root_node();
+    let nodes = root.descendant_for_byte_range(beg, end).map_or(vec![], |n| ancestors(root, n));
+    for node in nodes {
+        for step in [editing::inner_range(node), (node.start_byte(), node.end_byte())].iter() {
+            if step.0 <= last.0 && last.1 <= step.1 && *step != last {
+                steps.push(*step);
+                last = *step;
+            }
+        }
+    }
+    let vector = env.make_vector(steps.len(), ())?;
+    for (i, (beg, end)) in steps.into_iter().enumerate() {
+        let (beg, end): (BytePos, BytePos) = (beg.into(), end.into());
+        vector.set(i, env.cons(beg, end)?)?;
+    }
+    Ok(vector)
+}
+
+/// Return whether NODE should be skipped when moving over constructs: extras (e.g. comments), and
+/// anonymous punctuation tokens (e.g. separators and operators).
+fn is_skipped(node: &Node) -> bool {
+    node.is_extra() || (!node.is_named() && mirror_token(node.kind()).is_some())
+}
+
+/// Return the byte position reached by moving over one construct from BYTE in TREE, forward or
+/// backward. The constructs are the children of the smallest node strictly enclosing BYTE. If
+/// that node is a token, its edge is reached instead.
+fn construct_step(tree: &Tree, byte: usize, forward: bool) -> Option<usize> {
+    let root = tree.root_node();
+    let level = enclosing_nodes(tree, byte).into_iter().next().unwrap_or(root);
+    if level.child_count() == 0 {
+        return Some(if forward { level.end_byte() } else { level.start_byte() });
+    }
+    let mut cursor = level.walk();
+    let children: Vec<Node> = level.children(&mut cursor).filter(|n| !is_skipped(n)).collect();
+    if forward {
+        children.iter().find(|n| n.end_byte() > byte).map(|n| n.end_byte())
+    } else {
+        children.iter().rev().find(|n| n.start_byte() < byte).map(|n| n.start_byte())
+    }
+}
+
+/// Return the byte position reached by moving over COUNT constructs from BYTEPOS in
+/// TREE, like `forward-sexp'. If COUNT is negative, move backward. Return nil if
+/// the end of the current nesting level is reached before that.
+///
+/// The constructs are the child nodes of the smallest node strictly enclosing
+/// BYTEPOS, skipping extras (e.g. comments), and anonymous punctuation tokens
+/// (e.g. commas and operators). Inside a token, its edge is reached first.
+#[defun]
+fn _forward_construct(
+    tree: Borrowed<Tree>,
+    bytepos: BytePos,
+    count: i64,
+) -> Result<Option<BytePos>> {
+    let tree = tree.try_borrow()?;
+    let mut byte: usize = bytepos.into();
+    for _ in 0..count.abs() {
+        byte = match construct_step(&tree, byte, count > 0) {
+            Some(byte) => byte,
+            None => return Ok(None),
+        };
+    }
+    Ok(Some(byte.into()))
+}
+
+/// Return the ranges of TREE's nodes of the given KINDS between BEG and END, merged and clipped,
+/// as a vector of (BEG . END) byte positions. Nested nodes are not visited.
+///
+/// KINDS should be a vector of node types, see `kind_names`. If it is nil, the node types
+/// containing "comment" or "string" are used, which usually covers docstrings as well.
+/// BEG and END default to the start and end of TREE.
+#[defun]
+fn _prose_regions<'e>(
+    env: &'e Env,
+    tree: Borrowed<'e, Tree>,
+    kinds: Option<Vector<'e>>,
+    beg: Option<BytePos>,
+    end: Option<BytePos>,
+) -> Result<Vector<'e>> {
+    let tree = tree.try_borrow()?;
+    let root = tree.root_node();
+    let kinds = match kinds {
+        Some(kinds) => Some(kind_names(kinds)?),
+        None => None,
+    };
+    let is_prose = |node: Node| match &kinds {
+        Some(kinds) => is_kind_in(&node, kinds),
+        None => node.kind().contains("comment") || node.kind().contains("string"),
+    };
+    let beg = beg.map_or(root.start_byte(), |b| b.into());
+    let end = end.map_or(root.end_byte(), |e| e.into());
+    let mut regions: Vec<(usize, usize)> = vec![];
+    let mut cursor = root.walk();
+    'walk: loop {
+        let node = cursor.node();
+        let overlaps = node.start_byte() < end && beg < node.end_byte();
+        let prose = overlaps && is_prose(node);
+        if prose {
+            let region = (node.start_byte().max(beg), node.end_byte().min(end));
+            match regions.last_mut() {
+                Some(last) if last.1 >= region.0 => last.1 = last.1.max(region.1),
+                _ => regions.push(region),
+            }
+        }
+        if overlaps && !prose && cursor.goto_first_child() {
+            continue;
+        }
+        loop {
+            if cursor.goto_next_sibling() {
+                break;
+            }
+            if !cursor.goto_parent() {
+                break 'walk;
+            }
+        }
+    }
+    let vector = env.make_vector(regions.len(), ())?;
+    for (i, (beg, end)) in regions.into_iter().enumerate() {
+        let (beg, end): (BytePos, BytePos) = (beg.into(), end.into());
+        vector.set(i, env.cons(beg, end)?)?;
+    }
+    Ok(vector)
+}
+
+/// Return the leaf nodes (tokens) of TREE between BEG and END, in document order, as a vector of
+/// [TYPE BEG-BYTE END-BYTE]. Subtrees outside of the range are not visited.
+///
+/// If NAMED-ONLY is non-nil, anonymous tokens, e.g. punctuation, are skipped. KINDS, if non-nil,
+/// should be a vector of node types that the tokens are restricted to, see `kind_names`.
+#[defun]
+fn _tokens_in_range<'e>(
+    env: &'e Env,
+    tree: Borrowed<'e, Tree>,
+    beg: BytePos,
+    end: BytePos,
+    named_only: Value<'e>,
+    kinds: Option<Vector<'e>>,
+) -> Result<Vector<'e>> {
+    let tree = tree.try_borrow()?;
+    let (beg, end): (usize, usize) = (beg.into(), end.into());
+    let named_only = named_only.is_not_nil();
+    let kinds = match kinds {
+        Some(kinds) => Some(kind_names(kinds)?),
+        None => None,
+    };
+    // Empty tokens, e.g. MISSING ones, are in the range if they are at its start.
+    let overlaps = |node: Node| if node.start_byte() == node.end_byte() {
+        beg <= node.start_byte() && node.start_byte() < end
+    } else {
+        node.start_byte() < end && beg < node.end_byte()
+    };
+    let mut tokens = vec![];
+    let mut cursor = tree.walk();
+    'walk: loop {
+        let node = cursor.node();
+        if node.start_byte() >= end {
+            break;
+        }
+        if overlaps(node) {
+            if cursor.goto_first_child() {
+                continue;
+            }
+            let wanted = (!named_only || node.is_named())
+                && kinds.as_ref().map_or(true, |kinds| is_kind_in(&node, kinds));
+            if wanted {
+                tokens.push(env.vector((
+                    node.lisp_type()?.bind(env),
+                    node.lisp_start_byte(),
+                    node.lisp_end_byte(),
+                ))?);
+            }
+        }
+        loop {
+            if cursor.goto_next_sibling() {
+                break;
+            }
+            if !cursor.goto_parent() {
+                break 'walk;
+            }
+        }
+    }
+    let vector = env.make_vector(tokens.len(), ())?;
+    for (i, token) in tokens.into_iter().enumerate() {
+        vector.set(i, token)?;
+    }
+    Ok(vector)
+}
+
+/// Return the byte range (BEG . END) of the innermost node of TREE that contains BYTEPOS, and
+/// whose type is one of KINDS, a vector of node types. Return nil if there is no such node.
+#[defun]
+fn _current_construct_range<'e>(
+    env: &'e Env,
+    tree: Borrowed<'e, Tree>,
+    bytepos: BytePos,
+    kinds: Vector<'e>,
+) -> Result<Option<Value<'e>>> {
+    let tree = tree.try_borrow()?;
+    let kinds = kind_names(kinds)?;
+    let byte: usize = bytepos.into();
+    let root = tree.root_node();
+    let nodes = root.descendant_for_byte_range(byte, byte).map_or(vec![], |n| ancestors(root, n));
+    match nodes.into_iter().find(|n| is_kind_in(n, &kinds)) {
+        Some(node) => Ok(Some(env.cons(node.lisp_start_byte(), node.lisp_end_byte())?)),
+        None => Ok(None),
+    }
+}
+
+/// Return the byte range (BEG . END) of the smallest named node that strictly contains the range
+/// from BEG to END, in TREE-OR-NODE, a tree, or a node of the tree. Return nil if there is no
+/// such node.
+#[defun]
+fn _expand_region<'e>(
+    env: &'e Env,
+    tree_or_node: Value<'e>,
+    beg: BytePos,
+    end: BytePos,
+) -> Result<Option<Value<'e>>> {
+    let shared = match tree_or_node.into_rust::<&RefCell<RNode>>() {
+        Ok(node) => node.try_borrow()?.clone_tree(),
+        Err(_) => tree_or_node.into_rust::<Borrowed<Tree>>()?.clone(),
+    };
+    let tree = shared.try_borrow()?;
+    let (beg, end): (usize, usize) = (beg.into(), end.into());
+    let root = tree.root_node();
+    let nodes = root.named_descendant_for_byte_range(beg, end)
+        .map_or(vec![], |n| ancestors(root, n));
+    let is_larger = |n: &Node| {
+        let (start, stop) = (n.start_byte(), n.end_byte());
+        n.is_named() && start <= beg && end <= stop && (start, stop) != (beg, end)
+    };
+    match nodes.into_iter().find(is_larger) {
+        Some(node) => Ok(Some(env.cons(node.lisp_start_byte(), node.lisp_end_byte())?)),
+        None => Ok(None),
+    }
+}
+
+/// Return the nearest sibling of NODE that follows it, or precedes it if BACKWARD is non-nil, and
+/// whose type is one of KINDS, a vector of node types. Return nil if there is no such sibling.
+#[defun]
+fn _sibling_of_type(node: &RNode, kinds: Vector, backward: Value) -> Result<Option<RNode>> {
+    let kinds = kind_names(kinds)?;
+    let backward = backward.is_not_nil();
+    let inner = node.borrow()?;
+    let first = if backward { inner.prev_sibling() } else { inner.next_sibling() };
+    let found = std::iter::successors(first, |n| {
+        if backward { n.prev_sibling() } else { n.next_sibling() }
+    }).find(|n| is_kind_in(n, &kinds));
+    Ok(found.map(|found| node.map(|_| found)))
+}
+
+/// Return the nearest ancestor of NODE whose type is one of KINDS, a vector of node types. Return
+/// nil if there is no such ancestor.
+#[defun]
+fn _parent_until(node: &RNode, kinds: Vector) -> Result<Option<RNode>> {
+    let kinds = kind_names(kinds)?;
+    let inner = node.borrow()?;
+    let tree = node.clone_tree();
+    let tree = tree.try_borrow()?;
+    let found = ancestors(tree.root_node(), *inner).into_iter()
+        .skip(1)
+        .find(|n| is_kind_in(n, &kinds));
+    Ok(found.map(|found| node.map(|_| found)))
+}
+
+/// Return the smallest node of TREE at BYTEPOS, or the smallest named one if NAMED is non-nil.
+#[defun]
+fn _node_at_position(
+    tree: Borrowed<Tree>,
+    bytepos: BytePos,
+    named: Value,
+) -> Result<Option<RNode>> {
+    let byte: usize = bytepos.into();
+    let root = RNode::new(tree.clone(), |tree| tree.root_node());
+    let inner = root.borrow()?;
+    let found = if named.is_not_nil() {
+        inner.named_descendant_for_byte_range(byte, byte)
+    } else {
+        inner.descendant_for_byte_range(byte, byte)
+    };
+    Ok(found.map(|found| root.map(|_| found)))
+}
@@ -2,14 +2,19 @@ use std::{
     cell::{Ref, RefCell, RefMut},
     mem,
     ops::{Deref, DerefMut},
+    rc::Rc,
 };
 
-use emacs::{defun, Env, IntoLisp, Result, Value, GlobalRef};
+use emacs::{defun, Env, FromLisp, IntoLisp, Result, Value, Vector, GlobalRef};
 use tree_sitter::{InputEdit, Node, Tree};
 
 use crate::{
     types::{self, BytePos, Point, Shared, Range},
     lang::Language,
+    tree::{self, Borrowed},
+    instrument::NODES_CREATED,
+    live::{Live, NODES},
+    error::{self, Internal},
 };
 
 // -------------------------------------------------------------------------------------------------
@@ -20,6 +25,7 @@ use crate::{
 pub struct RNode {
     tree: Shared<Tree>,
     inner: Node<'static>,
+    _live: Live,
 }
 
 impl_pred!(node_p, &RefCell<RNode>);
@@ -69,37 +75,47 @@ impl PartialEq for RNode {
 
 impl IntoLisp<'_> for RNode {
     fn into_lisp(self, env: &Env) -> Result<Value> {
+        NODES_CREATED.incr();
         RefCell::new(self).into_lisp(env)
     }
 }
 
 impl RNode {
     pub fn new<'e, F: FnOnce(&'e Tree) -> Node<'e>>(tree: Shared<Tree>, f: F) -> Self {
+        // Trees are never mutably borrowed across calls into Lisp, so this cannot fail.
         let rtree = unsafe { types::erase_lifetime(&*tree.borrow()) };
         let inner = unsafe { mem::transmute(f(rtree)) };
-        Self { tree, inner }
+        Self { tree, inner, _live: Live::new(&NODES) }
     }
 
     pub fn clone_tree(&self) -> Shared<Tree> {
         self.tree.clone()
     }
 
+    #[inline]
+    pub fn tree_id(&self) -> usize {
+        tree::tree_id(&self.tree)
+    }
+
     pub fn map<'e, F: FnOnce(&Node<'e>) -> Node<'e>>(&self, f: F) -> Self {
         Self::new(self.clone_tree(), |_| f(&self.inner))
     }
 
+    /// Borrow the node, together with its tree. This fails if the tree is being edited.
     #[inline]
-    pub fn borrow(&self) -> RNodeBorrow {
-        let reft = self.tree.borrow();
+    pub fn borrow(&self) -> Result<RNodeBorrow> {
+        let reft = self.tree.try_borrow()?;
         let node = &self.inner;
-        RNodeBorrow { reft, node }
+        Ok(RNodeBorrow { reft, node })
     }
 
+    /// Mutably borrow the node, together with its tree. This fails if the tree is being used,
+    /// e.g. by a function that is still iterating over its nodes.
     #[inline]
-    pub fn borrow_mut(&mut self) -> RNodeBorrowMut {
-        let reft = self.tree.borrow_mut();
+    pub fn borrow_mut(&mut self) -> Result<RNodeBorrowMut> {
+        let reft = self.tree.try_borrow_mut()?;
         let node = self.inner;
-        RNodeBorrowMut { reft, node }
+        Ok(RNodeBorrowMut { reft, node })
     }
 }
 
@@ -112,7 +128,7 @@ macro_rules! defun_node_props {
             #[defun$((name = $lisp_name))?]
             $(#[$meta])*
             fn $name(node: &RNode) -> Result<$type> {
-                Ok(node.borrow().$name()$(.$into())?)
+                Ok(node.borrow()?.$name()$(.$into())?)
             }
         )*
     };
@@ -125,7 +141,7 @@ macro_rules! defun_node_navs {
             #[defun$((name = $lisp_name))?]
             $(#[$meta])*
             fn $name(node: &RNode, $( $( $param : $type ),* )? ) -> Result<Option<RNode>> {
-                Ok(node.borrow().$name( $( $( $param $(.$into())? ),* )? ).map(|other| {
+                Ok(node.borrow()?.$name( $( $( $param $(.$into())? ),* )? ).map(|other| {
                     node.map(|_| other)
                 }))
             }
@@ -133,7 +149,11 @@ macro_rules! defun_node_navs {
     };
 }
 
-emacs::use_symbols!(ERROR);
+emacs::use_symbols! {
+    ERROR
+    byte_to_position
+    buffer_substring_no_properties
+}
 
 /// Return NODE's type, as a symbol (named node), or a string (anonymous node).
 ///
@@ -141,11 +161,11 @@ emacs::use_symbols!(ERROR);
 /// If NODE is an anonymous node, its type is a string. For example: "if", "else".
 #[defun]
 fn node_type(node: &RNode) -> Result<&'static GlobalRef> {
-    Ok(node.borrow().lisp_type())
+    node.borrow()?.lisp_type()
 }
 
 pub(crate) trait LispUtils {
-    fn lisp_type(&self) -> &'static GlobalRef;
+    fn lisp_type(&self) -> Result<&'static GlobalRef>;
     fn lisp_byte_range<'e>(&self, env: &'e Env) -> Result<Value<'e>>;
     fn lisp_start_byte(&self) -> BytePos;
     fn lisp_end_byte(&self) -> BytePos;
@@ -156,12 +176,13 @@ pub(crate) trait LispUtils {
 
 impl<'n> LispUtils for Node<'n> {
     #[inline]
-    fn lisp_type(&self) -> &'static GlobalRef {
+    fn lisp_type(&self) -> Result<&'static GlobalRef> {
         let language: Language = self.language().into();
         if self.is_error() {
-            ERROR
+            Ok(ERROR)
         } else {
-            &language.info().node_type(self.kind_id()).expect("Failed to get node type from id")
+            language.info()?.node_type(self.kind_id())
+                .ok_or_else(|| Internal("Failed to get node type from id").into())
         }
     }
 
@@ -258,31 +279,155 @@ defun_node_props! {
 /// Return NODE's (START-BYTEPOS . END-BYTEPOS).
 #[defun]
 fn node_byte_range<'e>(env: &'e Env, node: &RNode) -> Result<Value<'e>> {
-    node.borrow().lisp_byte_range(env)
+    node.borrow()?.lisp_byte_range(env)
+}
+
+/// Return NODE's text, assuming it's from the current buffer's syntax tree.
+/// Prefer `tsc-node-buffer-text', unless there's a real bottleneck.
+///
+/// If MAX-LENGTH is non-nil, and NODE spans more than MAX-LENGTH bytes, signal a
+/// `tsc-node-text-too-long' error instead of creating the string.
+///
+/// This function must be called with narrowing disabled, e.g. within a
+/// `tsc--without-restriction' block.
+#[defun]
+fn _node_buffer_text<'e>(
+    env: &'e Env,
+    node: &RNode,
+    max_length: Option<usize>,
+) -> Result<Value<'e>> {
+    let (beg, end, length) = {
+        let inner = node.borrow()?;
+        (inner.lisp_start_byte(), inner.lisp_end_byte(), inner.end_byte() - inner.start_byte())
+    };
+    if let Some(max_length) = max_length.filter(|&max_length| length > max_length) {
+        return error::signal_node_text_too_long(env, length, max_length);
+    }
+    // The module API cannot read buffer text, so Emacs copies it into the string, once.
+    let beg = byte_to_position.bind(env).call((beg,))?;
+    let end = byte_to_position.bind(env).call((end,))?;
+    buffer_substring_no_properties.bind(env).call((beg, end))
 }
 
 /// Return t if two nodes are identical.
+///
+/// Nodes are identical if they have the same `tsc-node-id', even if they are
+/// different node objects, e.g. returned by different queries. This is also a hash
+/// table test, so (make-hash-table :test 'tsc-node-eq) can be used to cache data per
+/// node.
 #[defun]
 fn node_eq(node1: &RNode, node2: &RNode) -> Result<bool> {
     Ok(node1 == node2)
 }
 
+/// Return NODE's numeric id, which is unique among the nodes of its tree.
+///
+/// All node objects that refer to the same node have the same id. A node that is
+/// reused by an incremental parse keeps its id in the new tree.
+#[defun]
+fn node_id(node: &RNode) -> Result<usize> {
+    Ok(node.borrow()?.id())
+}
+
 /// Apply FUNCTION to each of NODE's children, for side effects only.
 #[defun]
 fn mapc_children(function: Value, node: &RNode) -> Result<()> {
-    let inner = node.borrow();
-    // TODO: Reuse cursor.
-    let cursor = &mut inner.walk();
-    for child in inner.children(cursor) {
-        let child = node.map(|_| child);
+    // Collect the children first, so that the tree is not borrowed while FUNCTION runs, since it
+    // may edit the tree.
+    let children: Vec<RNode> = {
+        let inner = node.borrow()?;
+        // TODO: Reuse cursor.
+        let cursor = &mut inner.walk();
+        inner.children(cursor).map(|child| node.map(|_| child)).collect()
+    };
+    for child in children {
         function.call((child,))?;
     }
     Ok(())
 }
 
 // TODO: named_children.
-// TODO: children_by_field_name.
-// TODO: children_by_field_id.
+
+fn children_vector<'e>(env: &'e Env, children: Vec<RNode>) -> Result<Vector<'e>> {
+    let vector = env.make_vector(children.len(), ())?;
+    for (i, child) in children.into_iter().enumerate() {
+        vector.set(i, child)?;
+    }
+    Ok(vector)
+}
+
+/// Return a vector of NODE's children associated with the given FIELD-NAME string.
+#[defun]
+fn _children_by_field_name<'e>(
+    env: &'e Env,
+    node: &RNode,
+    field_name: String,
+) -> Result<Vector<'e>> {
+    let children = {
+        let inner = node.borrow()?;
+        let cursor = &mut inner.walk();
+        inner.children_by_field_name(&field_name, cursor)
+            .map(|child| node.map(|_| child))
+            .collect()
+    };
+    children_vector(env, children)
+}
+
+/// Return a vector of NODE's children associated with the numerical FIELD-ID.
+/// Unlike `tsc-get-child-by-field-id', this returns all of the children of a
+/// repeated field.
+#[defun]
+fn children_by_field_id<'e>(env: &'e Env, node: &RNode, field_id: u16) -> Result<Vector<'e>> {
+    let children = {
+        let inner = node.borrow()?;
+        let cursor = &mut inner.walk();
+        inner.children_by_field_id(field_id, cursor).map(|child| node.map(|_| child)).collect()
+    };
+    children_vector(env, children)
+}
+
+/// Return the field associated with NODE's child at the given 0-based INDEX, as a
+/// keyword. Return nil if the child is not associated with a field.
+#[defun]
+fn field_name_for_child(node: &RNode, index: usize) -> Result<Option<&'static GlobalRef>> {
+    let inner = node.borrow()?;
+    let language: Language = inner.language().into();
+    Ok(inner.field_name_for_child(index as u32)
+        .and_then(|name| language.0.field_id_for_name(name))
+        .and_then(|id| language.info().ok()?.field_name(id)))
+}
+
+/// Return NODE's first child (or first NAMED child) that extends beyond BYTE.
+fn child_for_byte(node: &RNode, byte: usize, named: bool) -> Result<Option<RNode>> {
+    let inner = node.borrow()?;
+    let mut cursor = inner.walk();
+    if cursor.goto_first_child_for_byte(byte).is_none() {
+        return Ok(None);
+    }
+    loop {
+        let child = cursor.node();
+        if !named || child.is_named() {
+            return Ok(Some(node.map(|_| child)));
+        }
+        if !cursor.goto_next_sibling() {
+            return Ok(None);
+        }
+    }
+}
+
+/// Return NODE's first child that extends beyond the given BYTEPOS.
+/// This is faster than scanning NODE's children one by one.
+#[defun]
+fn first_child_for_byte(node: &RNode, bytepos: BytePos) -> Result<Option<RNode>> {
+    child_for_byte(node, bytepos.into(), false)
+}
+
+/// Return NODE's first named child that extends beyond the given BYTEPOS.
+/// This is faster than scanning NODE's named children one by one.
+#[defun]
+fn first_named_child_for_byte(node: &RNode, bytepos: BytePos) -> Result<Option<RNode>> {
+    child_for_byte(node, bytepos.into(), true)
+}
 
 defun_node_navs! {
     /// Return NODE's parent node.
@@ -371,6 +516,317 @@ fn edit_node(
         old_end_position: old_end_point.into(),
         new_end_position: new_end_point.into(),
     };
-    node.borrow_mut().edit(&edit);
+    node.borrow_mut()?.edit(&edit);
     Ok(())
 }
+
+// -------------------------------------------------------------------------------------------------
+// Node refs
+
+/// Compact reference to a node, in the form of [TREE-ID START-BYTEPOS TYPE-ID CHILD-INDEX].
+///
+/// Bulk APIs can return these plain vectors instead of node objects, to avoid creating thousands
+/// of `user-ptr' objects, which put a lot of pressure on the garbage collector.
+pub(crate) struct NodeRef {
+    tree_id: usize,
+    start_byte: BytePos,
+    kind_id: u16,
+    child_index: usize,
+}
+
+impl NodeRef {
+    pub(crate) fn new(tree_id: usize, node: Node, child_index: usize) -> Self {
+        Self { tree_id, start_byte: node.lisp_start_byte(), kind_id: node.kind_id(), child_index }
+    }
+}
+
+impl IntoLisp<'_> for NodeRef {
+    fn into_lisp(self, env: &Env) -> Result<Value> {
+        env.vector((self.tree_id, self.start_byte, self.kind_id, self.child_index))
+    }
+}
+
+impl FromLisp<'_> for NodeRef {
+    fn from_lisp(value: Value) -> Result<NodeRef> {
+        let vector: Vector = value.into_rust()?;
+        Ok(NodeRef {
+            tree_id: vector.get(0)?,
+            start_byte: vector.get(1)?,
+            kind_id: vector.get(2)?,
+            child_index: vector.get(3)?,
+        })
+    }
+}
+
+/// Return the 0-based position of NODE within its parent's children.
+pub(crate) fn child_index(node: Node) -> usize {
+    match node.parent() {
+        None => 0,
+        Some(parent) => {
+            let cursor = &mut parent.walk();
+            let index = parent.children(cursor).position(|child| child == node);
+            index.unwrap_or(0)
+        }
+    }
+}
+
+/// Return whether NODE intersects the range of byte offsets from BEG to END. A zero-width node,
+/// e.g. a MISSING node, intersects the range if it's inside it, or at its boundaries.
+#[inline]
+pub(crate) fn intersects(node: Node, beg: usize, end: usize) -> bool {
+    let (start, stop) = (node.start_byte(), node.end_byte());
+    if start == stop {
+        beg <= start && start <= end
+    } else {
+        start < end && stop > beg
+    }
+}
+
+fn find_by_ref<'t>(root: Node<'t>, node_ref: &NodeRef) -> Option<Node<'t>> {
+    let start: usize = node_ref.start_byte.into();
+    let is_target = |node: Node, index: usize| {
+        node.start_byte() == start && node.kind_id() == node_ref.kind_id
+            && index == node_ref.child_index
+    };
+    if is_target(root, 0) {
+        return Some(root);
+    }
+    let mut node = root;
+    loop {
+        let mut next = None;
+        let cursor = &mut node.walk();
+        for (i, child) in node.children(cursor).enumerate() {
+            if is_target(child, i) {
+                return Some(child);
+            }
+            if next.is_none() && child.start_byte() <= start && start < child.end_byte() {
+                next = Some(child);
+            }
+        }
+        node = next?;
+    }
+}
+
+/// Return a compact reference to NODE, which can be resolved back with
+/// `tsc-resolve-node-ref'.
+#[defun]
+fn node_ref(node: &RNode) -> Result<NodeRef> {
+    let inner = node.borrow()?;
+    Ok(NodeRef::new(node.tree_id(), *inner, child_index(*inner)))
+}
+
+/// Return the node in TREE identified by the compact reference NODE-REF.
+///
+/// NODE-REF has the form [TREE-ID START-BYTEPOS TYPE-ID CHILD-INDEX]. It is
+/// returned by `tsc-node-ref', or by traversal functions when the property
+/// `:node-ref' is requested. See `tsc-valid-node-props'.
+///
+/// Return nil if NODE-REF was not obtained from TREE (or one of its shallow
+/// copies), or if the node cannot be found. When there are nested nodes of the
+/// same type that start at the same position, and are all first children, the
+/// outermost one is returned.
+#[defun]
+fn resolve_node_ref(tree: Borrowed<Tree>, node_ref: NodeRef) -> Result<Option<RNode>> {
+    let tree = match tree::find_tree(node_ref.tree_id) {
+        Some(found) if Rc::ptr_eq(&found, tree) => found,
+        _ => return Ok(None),
+    };
+    let root = RNode::new(tree, |tree| tree.root_node());
+    let found = find_by_ref(*root.borrow()?, &node_ref);
+    Ok(found.map(|node| root.map(|_| node)))
+}
+
+// -------------------------------------------------------------------------------------------------
+// Remapping
+
+/// A step from a node down to one of its children: the child's field, type, and index among the
+/// children with the same field and type, and its byte range.
+struct Step {
+    field_id: Option<u16>,
+    kind_id: u16,
+    index: usize,
+    start: usize,
+    end: usize,
+}
+
+fn children_with_fields(node: Node) -> Vec<(Node, Option<u16>)> {
+    let mut children = vec![];
+    let mut cursor = node.walk();
+    if cursor.goto_first_child() {
+        loop {
+            children.push((cursor.node(), cursor.field_id()));
+            if !cursor.goto_next_sibling() {
+                break;
+            }
+        }
+    }
+    children
+}
+
+/// Return the steps from the root of NODE's tree down to NODE.
+fn path_to(node: Node) -> Vec<Step> {
+    let mut path = vec![];
+    let mut node = node;
+    while let Some(parent) = node.parent() {
+        let children = children_with_fields(parent);
+        let position = match children.iter().position(|&(child, _)| child == node) {
+            Some(position) => position,
+            None => break,
+        };
+        let field_id = children[position].1;
+        let index = children[..position].iter()
+            .filter(|&&(child, f)| f == field_id && child.kind_id() == node.kind_id())
+            .count();
+        let (start, end) = (node.start_byte(), node.end_byte());
+        path.push(Step { field_id, kind_id: node.kind_id(), index, start, end });
+        node = parent;
+    }
+    path.reverse();
+    path
+}
+
+#[inline]
+fn distance(a: usize, b: usize) -> usize {
+    if a > b { a - b } else { b - a }
+}
+
+/// Follow PATH down from ROOT. At each step, pick the child with the step's field and type whose
+/// range is the closest to the step's, then whose index is. Return None if there is no such child.
+fn follow<'t>(root: Node<'t>, path: &[Step]) -> Option<Node<'t>> {
+    let mut node = root;
+    for step in path {
+        node = children_with_fields(node).into_iter()
+            .filter(|&(child, f)| f == step.field_id && child.kind_id() == step.kind_id)
+            .enumerate()
+            .min_by_key(|&(i, (child, _))| (
+                distance(child.start_byte(), step.start) + distance(child.end_byte(), step.end),
+                distance(i, step.index),
+            ))
+            .map(|(_, (child, _))| child)?;
+    }
+    Some(node)
+}
+
+/// Return the nodes under ROOT that span exactly from START to END, innermost first.
+fn nodes_spanning(root: Node, start: usize, end: usize) -> Vec<Node> {
+    let mut nodes = vec![];
+    let mut node = root.descendant_for_byte_range(start, end);
+    while let Some(n) = node {
+        if (n.start_byte(), n.end_byte()) != (start, end) {
+            break;
+        }
+        nodes.push(n);
+        node = n.parent();
+    }
+    nodes
+}
+
+/// Return the node of NEW-TREE that corresponds to NODE, a node of an older tree,
+/// or nil if there is none.
+///
+/// NODE's tree should have been edited with `tsc-edit-tree' before NEW-TREE was
+/// parsed, and NODE's positions should reflect the edits, see `tsc-edit-node'.
+///
+/// If NEW-TREE reused NODE, the reused node, which has the same `tsc-node-id', is
+/// returned. Otherwise, this returns the node of NODE's type at NODE's range, or
+/// failing that, the node reached by following NODE's path from the root, i.e. the
+/// fields and types of its ancestors, picking the nodes closest to their ranges.
+///
+/// This allows keeping overlays, or cached analyses, attached to nodes across
+/// re-parses, instead of re-querying from scratch.
+#[defun]
+fn remap_node(node: &RNode, new_tree: Borrowed<Tree>) -> Result<Option<RNode>> {
+    let inner = node.borrow()?;
+    let root = RNode::new(new_tree.clone(), |tree| tree.root_node());
+    let new_root = root.borrow()?;
+    let (start, end) = (inner.start_byte(), inner.end_byte());
+    let spanning = nodes_spanning(*new_root, start, end);
+    let found = spanning.iter().find(|n| n.id() == inner.id())
+        .or_else(|| spanning.iter().find(|n| n.kind_id() == inner.kind_id()))
+        .copied()
+        .or_else(|| follow(*new_root, &path_to(*inner)));
+    Ok(found.map(|n| root.map(|_| n)))
+}
+
+// -------------------------------------------------------------------------------------------------
+// Data sexps
+
+/// Return the list (TYPE FIELD BEG END) that starts the data sexp of NODE, whose FIELD-ID is
+/// given. See `node_sexp`.
+fn sexp_head<'e>(env: &'e Env, node: Node, field_id: Option<u16>) -> Result<Vec<Value<'e>>> {
+    let language: Language = node.language().into();
+    let field = match field_id {
+        Some(id) => language.info()?.field_name(id).map(|field| field.bind(env)),
+        None => None,
+    };
+    Ok(vec![
+        node.lisp_type()?.bind(env),
+        field.into_lisp(env)?,
+        node.lisp_start_byte().into_lisp(env)?,
+        node.lisp_end_byte().into_lisp(env)?,
+    ])
+}
+
+/// Return the data sexp of NODE's subtree. See `node_sexp`.
+pub(crate) fn data_sexp<'e>(
+    env: &'e Env,
+    node: Node,
+    anonymous: bool,
+    max_depth: Option<usize>,
+) -> Result<Value<'e>> {
+    let included = |node: Node| anonymous || node.is_named();
+    // The partial lists of the current node and its ancestors. The cursor is always at the node
+    // of the last one, except while looking for the next child, or sibling, to include.
+    let mut stack = vec![sexp_head(env, node, None)?];
+    let mut cursor = node.walk();
+    'walk: loop {
+        if max_depth.map_or(true, |max| stack.len() <= max) && cursor.goto_first_child() {
+            loop {
+                if included(cursor.node()) {
+                    stack.push(sexp_head(env, cursor.node(), cursor.field_id())?);
+                    continue 'walk;
+                }
+                if !cursor.goto_next_sibling() {
+                    break;
+                }
+            }
+            cursor.goto_parent();
+        }
+        loop {
+            let list = types::list(env, stack.pop().ok_or(Internal("Empty sexp stack"))?)?;
+            match stack.last_mut() {
+                None => return Ok(list),
+                Some(parent) => parent.push(list),
+            }
+            loop {
+                if !cursor.goto_next_sibling() {
+                    cursor.goto_parent();
+                    break;
+                }
+                if included(cursor.node()) {
+                    stack.push(sexp_head(env, cursor.node(), cursor.field_id())?);
+                    continue 'walk;
+                }
+            }
+        }
+    }
+}
+
+/// Return NODE's subtree as a Lisp data structure, instead of a string.
+///
+/// Each node is a list (TYPE FIELD BEG END . CHILDREN), where TYPE is the node's
+/// type, as returned by `tsc-node-type', FIELD is the keyword of the field the node
+/// is associated with in its parent, or nil, and BEG and END are its byte
+/// positions. NODE's own FIELD is always nil.
+///
+/// Anonymous nodes are omitted, unless ANONYMOUS is non-nil. If MAX-DEPTH is
+/// non-nil, the children of nodes at that depth are omitted, NODE being at depth 0.
+#[defun]
+fn node_sexp<'e>(
+    env: &'e Env,
+    node: &RNode,
+    anonymous: Option<bool>,
+    max_depth: Option<usize>,
+) -> Result<Value<'e>> {
+    data_sexp(env, *node.borrow()?, anonymous.unwrap_or(false), max_depth)
+}
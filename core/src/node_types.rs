@@ -0,0 +1,190 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use emacs::{defun, Env, IntoLisp, Result, Value};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+use crate::{
+    types,
+    lang::Language,
+    error,
+};
+
+emacs::use_symbols! {
+    _type       => ":type"
+    _named      => ":named"
+    _fields     => ":fields"
+    _children   => ":children"
+    _subtypes   => ":subtypes"
+    _supertypes => ":supertypes"
+    _multiple   => ":multiple"
+    _required   => ":required"
+    _types      => ":types"
+}
+
+/// A node type, as referred to in `node-types.json`.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+struct TypeRef {
+    #[serde(rename = "type")]
+    kind: String,
+    named: bool,
+}
+
+impl TypeRef {
+    /// Convert to a Lisp node type, like the ones returned by `tsc-node-type`: a symbol for a
+    /// named node type, a string for an anonymous one.
+    fn to_lisp<'e>(&self, env: &'e Env) -> Result<Value<'e>> {
+        if self.named {
+            env.intern(&self.kind)
+        } else {
+            self.kind.as_str().into_lisp(env)
+        }
+    }
+}
+
+/// The node types allowed in a field, or as children of a node, in `node-types.json`.
+#[derive(Deserialize, Debug)]
+struct ChildTypes {
+    multiple: bool,
+    required: bool,
+    types: Vec<TypeRef>,
+}
+
+impl ChildTypes {
+    fn to_lisp<'e>(&self, env: &'e Env) -> Result<Value<'e>> {
+        types::list(env, vec![
+            _multiple.bind(env), self.multiple.into_lisp(env)?,
+            _required.bind(env), self.required.into_lisp(env)?,
+            _types.bind(env), type_list(env, &self.types)?,
+        ])
+    }
+}
+
+/// An entry of `node-types.json`.
+#[derive(Deserialize, Debug)]
+struct NodeTypeInfo {
+    #[serde(flatten)]
+    type_ref: TypeRef,
+    #[serde(default)]
+    fields: BTreeMap<String, ChildTypes>,
+    children: Option<ChildTypes>,
+    /// Only supertypes, i.e. hidden rules that are choices of other node types, have these.
+    #[serde(default)]
+    subtypes: Vec<TypeRef>,
+}
+
+/// The contents of a language's `node-types.json`, indexed by node type.
+struct NodeTypes {
+    infos: HashMap<TypeRef, NodeTypeInfo>,
+    supertypes: HashMap<TypeRef, Vec<TypeRef>>,
+}
+
+impl NodeTypes {
+    fn new(infos: Vec<NodeTypeInfo>) -> Self {
+        let mut supertypes: HashMap<TypeRef, Vec<TypeRef>> = HashMap::new();
+        for info in &infos {
+            for subtype in &info.subtypes {
+                supertypes.entry(subtype.clone()).or_default().push(info.type_ref.clone());
+            }
+        }
+        let infos = infos.into_iter().map(|info| (info.type_ref.clone(), info)).collect();
+        Self { infos, supertypes }
+    }
+}
+
+/// The loaded node types of each language, by language id.
+static NODE_TYPES: Lazy<Mutex<HashMap<usize, NodeTypes>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Lock the node types registry. Like the language registry, it is never held across calls into
+/// Lisp.
+fn node_types() -> MutexGuard<'static, HashMap<usize, NodeTypes>> {
+    NODE_TYPES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn type_list<'e>(env: &'e Env, refs: &[TypeRef]) -> Result<Value<'e>> {
+    types::list(env, refs.iter().map(|r| r.to_lisp(env)).collect::<Result<_>>()?)
+}
+
+/// Load LANGUAGE's node types from FILE, which is usually the `src/node-types.json`
+/// file generated by the tree-sitter CLI, alongside the grammar's parser. This
+/// replaces the node types previously loaded for LANGUAGE, if any.
+///
+/// See `tsc-lang-node-type-info'.
+#[defun]
+fn lang_load_node_types(env: &Env, language: Language, file: String) -> Result<()> {
+    let json = match fs::read_to_string(&file) {
+        Ok(json) => json,
+        Err(err) => return error::signal_node_types_failed(env, err.to_string(), "read", &file),
+    };
+    let infos: Vec<NodeTypeInfo> = match serde_json::from_str(&json) {
+        Ok(infos) => infos,
+        Err(err) => return error::signal_node_types_failed(env, err.to_string(), "parse", &file),
+    };
+    node_types().insert(language.id(), NodeTypes::new(infos));
+    Ok(())
+}
+
+/// Return the schema of the node TYPE in LANGUAGE, according to the node types loaded
+/// with `tsc-lang-load-node-types', as a plist:
+///
+/// :type        TYPE.
+/// :named       Whether TYPE is a named node type.
+/// :fields      Alist of (FIELD . CHILD-TYPES), where FIELD is a keyword, sorted by
+///              field name.
+/// :children    CHILD-TYPES of the children that are not in a field, or nil.
+/// :subtypes    If TYPE is a supertype, the list of node types it stands for.
+/// :supertypes  The list of supertypes that TYPE is a subtype of.
+///
+/// CHILD-TYPES is a plist of the form (:multiple BOOL :required BOOL :types TYPES).
+///
+/// TYPE should be a symbol for a named node type, a string for an anonymous one, like
+/// the ones returned by `tsc-node-type'. Return nil if LANGUAGE doesn't have TYPE.
+/// Signal an error if LANGUAGE's node types were not loaded.
+#[defun]
+fn lang_node_type_info<'e>(
+    env: &'e Env,
+    language: Language,
+    node_type: Value<'e>,
+) -> Result<Option<Value<'e>>> {
+    let type_ref = match node_type.into_rust::<String>() {
+        Ok(kind) => TypeRef { kind, named: false },
+        Err(_) => {
+            let kind = env.call("symbol-name", (node_type,))?.into_rust()?;
+            TypeRef { kind, named: true }
+        }
+    };
+    let registry = node_types();
+    let node_types = match registry.get(&language.id()) {
+        Some(node_types) => node_types,
+        None => return env.signal(error::tsc_error, (
+            "Node types were not loaded",
+            error::_kind, error::kind(env, "node-types-not-loaded")?,
+        )),
+    };
+    let info = match node_types.infos.get(&type_ref) {
+        Some(info) => info,
+        None => return Ok(None),
+    };
+    let mut fields = vec![];
+    for (name, child_types) in &info.fields {
+        fields.push(env.cons(env.intern(&format!(":{}", name))?, child_types.to_lisp(env)?)?);
+    }
+    let children = match &info.children {
+        Some(children) => children.to_lisp(env)?,
+        None => ().into_lisp(env)?,
+    };
+    let supertypes = node_types.supertypes.get(&type_ref).map_or(&[][..], |s| &s[..]);
+    Ok(Some(types::list(env, vec![
+        _type.bind(env), type_ref.to_lisp(env)?,
+        _named.bind(env), type_ref.named.into_lisp(env)?,
+        _fields.bind(env), types::list(env, fields)?,
+        _children.bind(env), children,
+        _subtypes.bind(env), type_list(env, &info.subtypes)?,
+        _supertypes.bind(env), type_list(env, supertypes)?,
+    ])?))
+}
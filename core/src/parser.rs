@@ -1,24 +1,277 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::RefCell,
+    fs,
+    ops::{Deref, DerefMut},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+#[cfg(unix)]
+use std::fs::OpenOptions;
 
-use emacs::{defun, Result, Value, Vector, Env, ResultExt};
-use tree_sitter::{Parser, Tree};
+use emacs::{defun, Result, Value, Vector, Env, GlobalRef, IntoLisp};
+use tree_sitter::{LogType, Parser, Tree};
 
 use crate::{
-    types::{BytePos, Point, Range, Shared},
+    types::{self, BytePos, Point, Range, Shared},
     lang::Language,
-    error,
+    tree::{self, Borrowed},
+    instrument::{self, Timer, PARSE_MICROS},
+    live::{Live, PARSERS},
+    error::{self, Internal},
 };
 
-fn shared<T>(t: T) -> Shared<T> {
-    Rc::new(RefCell::new(t))
+emacs::use_symbols! {
+    file_error
+}
+
+pub(crate) fn shared_tree(tree: Tree) -> Shared<Tree> {
+    let tree = Rc::new(RefCell::new(tree));
+    tree::track_tree(&tree);
+    tree
+}
+
+/// Return an empty tree of LANGUAGE.
+pub(crate) fn empty_tree(language: tree_sitter::Language) -> Option<Tree> {
+    let mut parser = Parser::new();
+    parser.set_language(language).ok()?;
+    parser.parse("", None)
+}
+
+/// Wrapper around `tree_sitter::Parser` that keeps track of its liveness.
+pub struct RParser {
+    inner: Parser,
+    trace: Option<Trace>,
+    /// Keeps the cancellation flag set with `tsc-set-cancellation-flag` alive, since the
+    /// underlying parser only holds a pointer to it.
+    cancellation_flag: Option<Arc<AtomicUsize>>,
+    /// The ranges set with `tsc-set-included-ranges`, which `Parser` does not return.
+    included_ranges: Vec<tree_sitter::Range>,
+    /// Whether a background parse is using a copy of this parser. See `detach`.
+    busy: Arc<AtomicBool>,
+    /// Whether this parser is idle in the parser pool. See `tsc-checkin-parser`.
+    pooled: bool,
+    released: bool,
+    _live: Live,
+}
+
+/// Log events collected during a parse, to be delivered to Lisp afterwards. The logger cannot
+/// call into Lisp, so deliveries are batched.
+struct Trace {
+    sink: Sink,
+    events: Rc<RefCell<Vec<(&'static str, String)>>>,
+}
+
+/// Where a trace's events are delivered.
+enum Sink {
+    /// The name of a buffer. See `tsc-trace-parse`.
+    Buffer(String),
+    /// A function. See `tsc-set-parser-logger`. The global reference is freed when logging stops,
+    /// or when the parser is released, or garbage-collected.
+    Function(GlobalRef),
+}
+
+impl RParser {
+    pub(crate) fn new() -> Self {
+        Self {
+            inner: Parser::new(),
+            trace: None,
+            cancellation_flag: None,
+            included_ranges: vec![],
+            busy: Arc::new(AtomicBool::new(false)),
+            pooled: false,
+            released: false,
+            _live: Live::new(&PARSERS),
+        }
+    }
+
+    /// Free the underlying parser's resources, by replacing it with a fresh one.
+    pub(crate) fn release(&mut self, env: &Env) -> Result<()> {
+        self.set_trace(env, None)?;
+        self.inner = Parser::new();
+        self.cancellation_flag = None;
+        self.included_ranges = vec![];
+        self.released = true;
+        Ok(())
+    }
+
+    /// Deliver the log events collected since the last flush, if any.
+    fn flush_trace(&self, env: &Env) -> Result<()> {
+        let trace = match &self.trace {
+            Some(trace) => trace,
+            None => return Ok(()),
+        };
+        let events = trace.events.replace(vec![]);
+        if events.is_empty() {
+            return Ok(());
+        }
+        match &trace.sink {
+            Sink::Buffer(buffer_name) => {
+                let mut text = String::new();
+                for (log_type, message) in events {
+                    text.push_str(log_type);
+                    text.push_str(": ");
+                    text.push_str(&message);
+                    text.push('\n');
+                }
+                env.call("tsc--trace-insert", (buffer_name, text))?;
+            }
+            Sink::Function(function) => {
+                let function = function.bind(env);
+                for (log_type, message) in events {
+                    function.call((env.intern(log_type)?, message))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Deliver the log events of the next parses to SINK, or stop logging if it's None.
+    fn set_trace(&mut self, env: &Env, sink: Option<Sink>) -> Result<()> {
+        if let Some(Trace { sink: Sink::Function(function), .. }) = self.trace.take() {
+            function.free(env)?;
+        }
+        let sink = match sink {
+            Some(sink) => sink,
+            None => {
+                self.inner.set_logger(None);
+                return Ok(());
+            }
+        };
+        let events = Rc::new(RefCell::new(vec![]));
+        let collected = events.clone();
+        self.inner.set_logger(Some(Box::new(move |log_type, message| {
+            let log_type = match log_type {
+                LogType::Lex => "lex",
+                LogType::Parse => "parse",
+            };
+            collected.borrow_mut().push((log_type, message.to_owned()));
+        })));
+        self.trace = Some(Trace { sink, events });
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn is_released(&self) -> bool {
+        self.released
+    }
+
+    /// Return the ranges set with `tsc-set-included-ranges`.
+    #[inline]
+    pub(crate) fn included_ranges(&self) -> &[tree_sitter::Range] {
+        &self.included_ranges
+    }
+
+    /// Wrap TREE, which was just produced by this parser, recording its included ranges.
+    fn share(&self, tree: Tree) -> Shared<Tree> {
+        let tree = shared_tree(tree);
+        tree::record_included_ranges(&tree, &self.included_ranges);
+        tree
+    }
+
+    /// Signal `tsc-error` if a background parse is using a copy of this parser, or if it was
+    /// checked in to the parser pool.
+    pub(crate) fn check_idle(&self, env: &Env) -> Result<()> {
+        if self.pooled {
+            error::signal_parser_pooled(env)
+        } else if self.busy.load(Ordering::SeqCst) {
+            error::signal_parser_busy(env)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Make this parser like a new parser of the same language, before it's checked in to the
+    /// parser pool: discard its unfinished parse, included ranges, timeout, cancellation flag,
+    /// and logger. It cannot parse until it's checked out again.
+    pub(crate) fn check_in(&mut self, env: &Env) -> Result<()> {
+        self.check_idle(env)?;
+        self.inner.reset();
+        self.inner.set_included_ranges(&[]).ok();
+        self.included_ranges.clear();
+        self.inner.set_timeout_micros(0);
+        // SAFETY: Clearing the flag cannot leave a dangling pointer.
+        unsafe { self.inner.set_cancellation_flag(None) };
+        self.cancellation_flag = None;
+        self.set_trace(env, None)?;
+        #[cfg(unix)]
+        self.inner.stop_printing_dot_graphs();
+        self.pooled = true;
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn check_out(&mut self) {
+        self.pooled = false;
+    }
+
+    /// Return a copy of this parser, with the same language, included ranges, timeout, and
+    /// cancellation flag, to be used on a worker thread. This parser is busy until the copy is
+    /// dropped.
+    pub(crate) fn detach(&self, env: &Env) -> Result<Detached> {
+        self.check_idle(env)?;
+        let mut parser = Parser::new();
+        if let Some(language) = self.language() {
+            parser.set_language(language).or_else(|err| error::signal_lang_abi_error(env, err))?;
+        }
+        parser.set_included_ranges(&self.included_ranges).ok();
+        parser.set_timeout_micros(self.timeout_micros());
+        let flag = self.cancellation_flag.clone();
+        // SAFETY: The flag is kept alive by the copy, until after the parser is dropped.
+        unsafe { parser.set_cancellation_flag(flag.as_deref()) };
+        self.busy.store(true, Ordering::SeqCst);
+        Ok(Detached { parser, _flag: flag, busy: self.busy.clone() })
+    }
+}
+
+/// A copy of an `RParser`, which is busy until the copy is dropped. See `RParser::detach`.
+pub(crate) struct Detached {
+    pub(crate) parser: Parser,
+    _flag: Option<Arc<AtomicUsize>>,
+    busy: Arc<AtomicBool>,
+}
+
+impl Drop for Detached {
+    fn drop(&mut self) {
+        self.busy.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Drop for RParser {
+    fn drop(&mut self) {
+        // Without an `Env`, the logging function is freed later.
+        if let Some(Trace { sink: Sink::Function(function), .. }) = self.trace.take() {
+            types::free_later(function);
+        }
+    }
+}
+
+impl Deref for RParser {
+    type Target = Parser;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
 }
 
-impl_pred!(parser_p, &RefCell<Parser>);
+impl DerefMut for RParser {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl_pred!(parser_p, &RefCell<RParser>);
 
 /// Create a new parser.
 #[defun(user_ptr)]
-fn make_parser() -> Result<Parser> {
-    Ok(Parser::new())
+fn make_parser() -> Result<RParser> {
+    Ok(RParser::new())
 }
 
 /// Set the LANGUAGE that PARSER should use for parsing.
@@ -26,20 +279,57 @@ fn make_parser() -> Result<Parser> {
 /// This may fail if there was a version mismatch: the loaded LANGUAGE was generated
 /// with an incompatible version of tree-sitter-cli.
 #[defun]
-fn set_language(parser: &mut Parser, language: Language, env: &Env) -> Result<()> {
-    parser.set_language(language.into()).or_signal(env, error::tsc_lang_abi_error)
+fn set_language(parser: &mut RParser, language: Language, env: &Env) -> Result<()> {
+    parser.check_idle(env)?;
+    parser.set_language(language.into()).or_else(|err| error::signal_lang_abi_error(env, err))
 }
 
 /// Return PARSER's current language.
 #[defun(mod_in_name = true)]
-fn language(parser: &Parser) -> Result<Option<Language>> {
+fn language(parser: &RParser) -> Result<Option<Language>> {
     Ok(parser.language().map(|l| l.into()))
 }
 
-// TODO: Add a version that reuses a single byte buffer to avoid multiple allocations. Also allow
-// `parse` to pass a soft size limit to the input function.
+/// The most recently returned chunk of source code. Tree-sitter frequently re-reads overlapping
+/// positions (especially around edits), which can then be served without calling back into Lisp.
+#[derive(Default)]
+struct ChunkCache {
+    start: usize,
+    text: Rc<Vec<u8>>,
+}
 
-// TODO: Add parse_buffer.
+/// The source code returned to tree-sitter: a chunk, from some offset. This shares the chunk's
+/// bytes, so serving multiple reads from the same chunk does not copy it.
+struct ChunkSlice {
+    text: Rc<Vec<u8>>,
+    offset: usize,
+}
+
+impl AsRef<[u8]> for ChunkSlice {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        &self.text[self.offset..]
+    }
+}
+
+impl ChunkCache {
+    /// Return the cached text from BYTE to the end of the chunk, if BYTE is within the chunk.
+    #[inline]
+    fn get(&self, byte: usize) -> Option<ChunkSlice> {
+        if byte >= self.start && byte < self.start + self.text.len() {
+            Some(ChunkSlice { text: self.text.clone(), offset: byte - self.start })
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    fn set(&mut self, start: usize, text: Vec<u8>) -> ChunkSlice {
+        self.start = start;
+        self.text = Rc::new(text);
+        ChunkSlice { text: self.text.clone(), offset: 0 }
+    }
+}
 
 /// Parse source code chunks generated by INPUT-FUNCTION with PARSER; return a tree.
 ///
@@ -48,6 +338,9 @@ fn language(parser: &Parser) -> Result<Option<Language>> {
 /// either BYTEPOS or (LINE-NUMBER . BYTE-COLUMN). It should return an empty string
 /// to signal the end of the source code.
 ///
+/// The most recently returned fragment is cached, so INPUT-FUNCTION is not called
+/// again for positions within it. It should therefore depend only on the position.
+///
 /// BYTEPOS is Emacs's 1-based byte position.
 ///
 /// LINE-NUMBER is the number returned by `line-number-at-pos', which counts from 1.
@@ -60,8 +353,18 @@ fn language(parser: &Parser) -> Result<Option<Language>> {
 /// be reused. This will save time and memory. For this to work correctly, you must
 /// have already edited it using `tsc-edit-tree' function in a way that exactly
 /// matches the source code changes.
+///
+/// If CHUNK-SIZE is non-nil, INPUT-FUNCTION is called with a 4th parameter: a soft
+/// limit on the number of bytes it should return. Small fragments make re-parsing
+/// after an edit read less text, at the cost of more calls.
 #[defun]
-fn parse_chunks(parser: &mut Parser, input_function: Value, old_tree: Option<&Shared<Tree>>) -> Result<Shared<Tree>> {
+fn parse_chunks(
+    parser: &mut RParser,
+    input_function: Value,
+    old_tree: Option<&Shared<Tree>>,
+    chunk_size: Option<usize>,
+) -> Result<Shared<Tree>> {
+    parser.check_idle(input_function.env)?;
     let old_tree = match old_tree {
         Some(v) => Some(v.try_borrow()?),
         _ => None,
@@ -70,62 +373,386 @@ fn parse_chunks(parser: &mut Parser, input_function: Value, old_tree: Option<&Sh
         Some(r) => Some(&**r),
         _ => None,
     };
+    let tree = parse_input(parser, input_function, old_tree, chunk_size);
+    parser.flush_trace(input_function.env)?;
+    Ok(parser.share(tree?))
+}
+
+/// Parse the source code chunks returned by FETCH with PARSER, reusing OLD_TREE if provided.
+///
+/// FETCH is called with a byte offset, and its point. It should return the chunk of source code
+/// starting there, or an empty chunk at the end. If it fails, the parse is aborted.
+fn parse_fetched<F>(
+    parser: &mut Parser,
+    env: &Env,
+    old_tree: Option<&Tree>,
+    mut fetch: F,
+) -> Result<Tree>
+where
+    F: FnMut(usize, tree_sitter::Point) -> Result<Vec<u8>>,
+{
     // This is used to hold potential error, because the callback cannot return a Result, and
     // unwinding across FFI boundary during a panic is UB (future Rust versions will abort).
     // See https://github.com/rust-lang/rust/issues/52652.
     let mut input_error = None;
-    let input = &mut |byte: usize, point: tree_sitter::Point| -> String {
+    let mut bytes_read = 0;
+    let mut cache = ChunkCache::default();
+    let input = &mut |byte: usize, point: tree_sitter::Point| -> ChunkSlice {
+        if let Some(text) = cache.get(byte) {
+            return text;
+        }
+        let chunk = fetch(byte, point).unwrap_or_else(|e| {
+            input_error = Some(e);
+            vec![]
+        });
+        bytes_read += chunk.len();
+        cache.set(byte, chunk)
+    };
+    let reused = old_tree.is_some();
+    let tree = {
+        let _timer = Timer::new(&PARSE_MICROS);
+        parser.parse_with(input, old_tree)
+    };
+    instrument::record_parse(bytes_read, reused);
+    match (input_error, tree) {
+        (Some(e), _) => Err(e),
+        (None, Some(tree)) => Ok(tree),
+        (None, None) => error::signal_parse_failed(env, failure_reason(parser)),
+    }
+}
+
+/// Parse the source code chunks generated by INPUT_FUNCTION with PARSER, reusing OLD_TREE if
+/// provided, and passing CHUNK_SIZE, if any, as a size hint. See `parse_chunks`.
+pub(crate) fn parse_input(
+    parser: &mut Parser,
+    input_function: Value,
+    old_tree: Option<&Tree>,
+    chunk_size: Option<usize>,
+) -> Result<Tree> {
+    parse_fetched(parser, input_function.env, old_tree, |byte, point| {
         let bytepos: BytePos = byte.into();
         let point: Point = point.into();
-        input_function.call((bytepos, point.line_number(), point.byte_column()))
-            .and_then(|v| v.into_rust())
-            .unwrap_or_else(|e| {
-                input_error = Some(e);
-                "".to_owned()
-            })
+        let (line_number, byte_column) = (point.line_number(), point.byte_column());
+        let chunk = match chunk_size {
+            None => input_function.call((bytepos, line_number, byte_column))?,
+            Some(size) => input_function.call((bytepos, line_number, byte_column, size))?,
+        };
+        Ok(chunk.into_rust::<String>()?.into_bytes())
+    })
+}
+
+/// Number of bytes of buffer text read at a time by `parse_buffer`.
+const BUFFER_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Parse the current buffer's text from BEG to END with PARSER; return a tree.
+///
+/// This is like `tsc-parse-chunks' with `tsc--buffer-input', but the text is read
+/// in larger chunks, without calling back into Lisp code. BEG and END should be
+/// in the buffer's accessible portion. BEG is the tree's byte position 1, which is
+/// recorded unless the whole buffer is parsed, see `tsc-tree-offset'. See
+/// `tsc-parse-buffer'.
+///
+/// OLD-TREE has the same meaning as in `tsc-parse-chunks'.
+#[defun]
+fn _parse_buffer<'e>(
+    env: &'e Env,
+    parser: &mut RParser,
+    old_tree: Option<Borrowed<'e, Tree>>,
+    beg: usize,
+    end: usize,
+) -> Result<Shared<Tree>> {
+    parser.check_idle(env)?;
+    let old_tree = match old_tree {
+        Some(tree) => Some(tree.try_borrow()?),
+        None => None,
     };
-    // TODO: Support error cases (None).
-    let tree = parser.parse_with(input, old_tree).unwrap();
-    match input_error {
-        None => Ok(shared(tree)),
-        Some(e) => Err(e),
+    let end_byte: usize = env.call("position-bytes", (end,))?.into_rust()?;
+    let base_byte: usize = env.call("position-bytes", (beg,))?.into_rust()?;
+    let tree = parse_fetched(parser, env, old_tree.as_deref(), |byte, _| {
+        let beg_byte = byte + base_byte;
+        if beg_byte >= end_byte {
+            return Ok(vec![]);
+        }
+        let to_position = |bytepos: usize| -> Result<Value<'e>> {
+            let position: Value = env.call("byte-to-position", (bytepos,))?;
+            Ok(if position.is_not_nil() { position } else { end.into_lisp(env)? })
+        };
+        let beg = to_position(beg_byte)?;
+        let end = to_position(end_byte.min(beg_byte + BUFFER_CHUNK_SIZE))?;
+        let chunk: String = env.call("buffer-substring-no-properties", (beg, end))?
+            .into_rust()?;
+        // BYTE may be in the middle of a multibyte character, whose start `byte-to-position'
+        // returns. Drop the character's leading bytes.
+        let actual_beg: usize = env.call("position-bytes", (beg,))?.into_rust()?;
+        let mut chunk = chunk.into_bytes();
+        chunk.drain(..beg_byte - actual_beg);
+        Ok(chunk)
+    });
+    parser.flush_trace(env)?;
+    let tree = parser.share(tree?);
+    let buffer_size: usize = env.call("buffer-size", ((),))?.into_rust()?;
+    if beg > 1 || end <= buffer_size {
+        tree::record_offset(&tree, base_byte);
     }
+    Ok(tree)
 }
 
 /// Use PARSER to parse the INPUT string, returning a tree.
 #[defun]
-fn parse_string(parser: &mut Parser, input: String) -> Result<Shared<Tree>> {
-    let tree = parser.parse(input, None).unwrap();
-    Ok(shared(tree))
+fn parse_string(parser: &mut RParser, input: String, env: &Env) -> Result<Shared<Tree>> {
+    parser.check_idle(env)?;
+    let bytes = input.len();
+    let tree = {
+        let _timer = Timer::new(&PARSE_MICROS);
+        parser.parse(input, None)
+    };
+    instrument::record_parse(bytes, false);
+    parser.flush_trace(env)?;
+    match tree {
+        Some(tree) => Ok(parser.share(tree)),
+        None => error::signal_parse_failed(env, failure_reason(parser)),
+    }
 }
 
-/// Instruct PARSER to start the next parse from the beginning.
+/// Parse each of SOURCES with LANGUAGE, on a pool of worker threads, returning a
+/// vector of the trees, in the same order. If FILES is non-nil, SOURCES are the
+/// names of the files to read the source code from, instead of the source code.
 ///
-/// If PARSER previously failed because of a timeout or a cancellation, then by
-/// default, it will resume where it left off on the next parse. If you don't want
-/// to resume, and instead intend to use PARSER to parse some other code, you must
-/// call this function first.
+/// Signal `file-error' if a file can't be read. See `tsc-parse-strings-parallel'.
+#[defun]
+fn _parse_parallel<'e>(
+    env: &'e Env,
+    language: Language,
+    sources: Vector<'e>,
+    files: Value<'e>,
+) -> Result<Vector<'e>> {
+    let sources = (0..sources.len()).map(|i| sources.get(i)).collect::<Result<Vec<String>>>()?;
+    let files = files.is_not_nil();
+    let workers = thread::available_parallelism().map_or(1, |n| n.get()).min(sources.len());
+    let mut parsers = Vec::with_capacity(workers);
+    for _ in 0..workers {
+        let mut parser = Parser::new();
+        parser.set_language(language.into()).or_else(|err| error::signal_lang_abi_error(env, err))?;
+        parsers.push(parser);
+    }
+    // The index of the next source to parse, shared by the workers.
+    let next = AtomicUsize::new(0);
+    let _timer = Timer::new(&PARSE_MICROS);
+    // A tree, or None if parsing failed, and the number of parsed bytes. Or a file error.
+    type Parsed = std::result::Result<(Option<Tree>, usize), String>;
+    let mut results: Vec<Option<Parsed>> = thread::scope(|scope| {
+        let handles: Vec<_> = parsers.into_iter().map(|mut parser| {
+            let (sources, next) = (&sources, &next);
+            scope.spawn(move || {
+                let mut results = vec![];
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let source = match sources.get(i) {
+                        Some(source) => source,
+                        None => break,
+                    };
+                    let parsed = if files {
+                        fs::read(source).map_err(|err| err.to_string())
+                            .map(|bytes| (parser.parse(&bytes, None), bytes.len()))
+                    } else {
+                        Ok((parser.parse(source, None), source.len()))
+                    };
+                    results.push((i, parsed));
+                }
+                results
+            })
+        }).collect();
+        let mut results: Vec<_> = sources.iter().map(|_| None).collect();
+        for handle in handles {
+            let parsed = handle.join().map_err(|_| Internal("Failed to parse on a worker thread"))?;
+            for (i, result) in parsed {
+                results[i] = Some(result);
+            }
+        }
+        Ok::<_, Internal>(results)
+    })?;
+    let vector = env.make_vector(sources.len(), ())?;
+    for (i, result) in results.iter_mut().enumerate() {
+        match result.take() {
+            Some(Ok((Some(tree), bytes))) => {
+                instrument::record_parse(bytes, false);
+                vector.set(i, shared_tree(tree))?;
+            }
+            Some(Err(message)) => {
+                return env.signal(file_error, ("Reading file", message, &*sources[i]));
+            }
+            _ => return error::signal_parse_failed(env, "parse-failed"),
+        }
+    }
+    Ok(vector)
+}
+
+/// Write PARSER's log events into the buffer named BUFFER-NAME, creating it if
+/// necessary. If BUFFER-NAME is nil, stop tracing.
+///
+/// Each event is a line, prefixed with "lex: " or "parse: ". The events of a parse
+/// are written in a single batch, after the parse finishes.
+#[defun]
+fn trace_parse(env: &Env, parser: &mut RParser, buffer_name: Option<String>) -> Result<()> {
+    parser.set_trace(env, buffer_name.map(Sink::Buffer))
+}
+
+/// Make PARSER pass its log events to LOGGER, or stop logging if LOGGER is nil.
+///
+/// LOGGER is a function called with 2 arguments: the event's type, either `lex' or
+/// `parse', and its message. The events of a parse are passed after the parse
+/// finishes, because the dynamic module cannot call into Lisp code during the
+/// parse. LOGGER can also be the name of a buffer, in which case this is the same
+/// as `tsc-trace-parse'.
+///
+/// This shows the parser's internal decisions, e.g. the tokens it tried, and how it
+/// recovered from errors, which is useful when debugging grammars.
+#[defun]
+fn set_parser_logger(parser: &mut RParser, logger: Value) -> Result<()> {
+    let env = logger.env;
+    types::free_dropped_refs(env)?;
+    let sink = if logger.is_not_nil() {
+        Some(match logger.into_rust::<String>() {
+            Ok(buffer_name) => Sink::Buffer(buffer_name),
+            Err(_) => Sink::Function(logger.make_global_ref()),
+        })
+    } else {
+        None
+    };
+    parser.set_trace(env, sink)
+}
+
+/// Make PARSER append a graph of its stack to FILE, in the DOT language, at each
+/// step of the next parses. Return nil if the platform does not support this.
+///
+/// This shows how the parser recovers from errors, which is useful when developing
+/// grammars. The output is very large, so this should only be used on small inputs.
+/// See `tsc-stop-printing-dot-graphs'.
+#[cfg(unix)]
+#[defun(mod_in_name = true)]
+fn print_dot_graphs(env: &Env, parser: &mut RParser, file: Value) -> Result<bool> {
+    let file: String = env.call("expand-file-name", (file,))?.into_rust()?;
+    let file = OpenOptions::new().create(true).append(true).open(file)?;
+    // The underlying parser writes to a duplicate of the file descriptor.
+    parser.inner.print_dot_graphs(&file);
+    Ok(true)
+}
+
+/// Make PARSER append a graph of its stack to FILE, in the DOT language, at each
+/// step of the next parses. Return nil if the platform does not support this.
+///
+/// This shows how the parser recovers from errors, which is useful when developing
+/// grammars. The output is very large, so this should only be used on small inputs.
+/// See `tsc-stop-printing-dot-graphs'.
+#[cfg(not(unix))]
+#[defun(mod_in_name = true)]
+fn print_dot_graphs(_parser: &mut RParser, _file: Value) -> Result<bool> {
+    Ok(false)
+}
+
+/// Make PARSER stop printing the DOT graphs requested by `tsc-parser-print-dot-graphs'.
+#[defun]
+fn stop_printing_dot_graphs(parser: &mut RParser) -> Result<()> {
+    #[cfg(unix)]
+    parser.inner.stop_printing_dot_graphs();
+    Ok(())
+}
+
+/// Return why PARSER did not return a tree: `no-language`, `cancelled`, or `timeout`.
+pub(crate) fn failure_reason(parser: &Parser) -> &'static str {
+    if parser.language().is_none() {
+        return "no-language";
+    }
+    // SAFETY: The flag is kept alive by the `RParser` it was set on. Other parsers never have one.
+    let flag = unsafe { parser.cancellation_flag() };
+    if flag.map_or(false, |flag| flag.load(Ordering::SeqCst) != 0) {
+        "cancelled"
+    } else if parser.timeout_micros() > 0 {
+        "timeout"
+    } else {
+        "parse-failed"
+    }
+}
+
+/// Instruct PARSER to start the next parse from the beginning.
 ///
-/// Note: timeout and cancellation are not yet properly supported.
+/// If a parse is aborted because it ran out of time (see `tsc-set-timeout-micros'),
+/// or because its cancellation flag was set (see `tsc-set-cancellation-flag'), the
+/// parsing functions signal `tsc-parse-failed', with the `:kind' `timeout' or
+/// `cancelled'.
+/// By default, PARSER resumes where it left off on the next parse, which must then
+/// be given the same source code, and the same OLD-TREE. If you don't want to
+/// resume, and instead intend to use PARSER to parse some other code, you must call
+/// this function first.
 #[defun]
-fn _reset_parser(parser: &mut Parser) -> Result<()> {
+fn reset_parser(parser: &mut RParser, env: &Env) -> Result<()> {
+    parser.check_idle(env)?;
     Ok(parser.reset())
 }
 
 /// Return the duration in microseconds that PARSER is allowed to take each parse.
-/// Note: timeout and cancellation are not yet properly supported.
+/// 0 means there is no limit.
 #[defun]
-fn _timeout_micros(parser: &Parser) -> Result<u64> {
+fn timeout_micros(parser: &RParser) -> Result<u64> {
     Ok(parser.timeout_micros())
 }
 
 /// Set MAX-DURATION in microseconds that PARSER is allowed to take each parse.
-/// Note: timeout and cancellation are not yet properly supported.
+///
+/// A parse that takes longer is aborted, and can be resumed, or discarded with
+/// `tsc-reset-parser'. 0 means there is no limit, which is the default.
 #[defun]
-fn _set_timeout_micros(parser: &mut Parser, max_duration: u64) -> Result<()> {
+fn set_timeout_micros(parser: &mut RParser, max_duration: u64) -> Result<()> {
     Ok(parser.set_timeout_micros(max_duration))
 }
 
+/// A flag that aborts the parses of the parsers it is set on, when it is non-zero. It can be
+/// shared with the worker threads of background operations.
+pub struct CancellationFlag(Arc<AtomicUsize>);
+
+impl_pred!(cancellation_flag_p, &RefCell<CancellationFlag>);
+
+/// Create a new cancellation flag, which is not set.
+#[defun(user_ptr)]
+fn make_cancellation_flag() -> Result<CancellationFlag> {
+    Ok(CancellationFlag(Arc::new(AtomicUsize::new(0))))
+}
+
+/// Set FLAG, aborting the ongoing and subsequent parses of the parsers it is set on.
+///
+/// The parsers check the flag periodically. Since Emacs only runs timers and
+/// process filters while waiting, this is typically called from within the input
+/// function of `tsc-parse-chunks', e.g. when `input-pending-p' returns non-nil.
+#[defun]
+fn cancel(flag: &CancellationFlag) -> Result<()> {
+    Ok(flag.0.store(1, Ordering::SeqCst))
+}
+
+/// Clear FLAG, so that the parsers it is set on can parse, or resume, again.
+#[defun]
+fn reset_cancellation_flag(flag: &CancellationFlag) -> Result<()> {
+    Ok(flag.0.store(0, Ordering::SeqCst))
+}
+
+/// Return t if FLAG is set.
+#[defun]
+fn cancelled_p(flag: &CancellationFlag) -> Result<bool> {
+    Ok(flag.0.load(Ordering::SeqCst) != 0)
+}
+
+/// Make PARSER check FLAG during each parse, aborting it when FLAG is set. If FLAG is nil,
+/// PARSER stops checking the flag previously set.
+///
+/// See `tsc-cancel' and `tsc-reset-parser'.
+#[defun]
+fn set_cancellation_flag(parser: &mut RParser, flag: Option<&CancellationFlag>) -> Result<()> {
+    let flag = flag.map(|flag| flag.0.clone());
+    // SAFETY: The flag is kept alive by PARSER, until it's replaced, or the parser is dropped.
+    unsafe { parser.inner.set_cancellation_flag(flag.as_deref()) };
+    parser.cancellation_flag = flag;
+    Ok(())
+}
+
 /// Set the RANGES of text that PARSER should include when parsing.
 ///
 /// By default, PARSER will always include entire documents. This function allows
@@ -135,14 +762,31 @@ fn _set_timeout_micros(parser: &mut Parser, max_duration: u64) -> Result<()> {
 ///
 /// This is useful for parsing multi-language documents.
 #[defun]
-fn set_included_ranges(parser: &mut Parser, ranges: Vector) -> Result<()> {
+fn set_included_ranges(parser: &mut RParser, ranges: Vector) -> Result<()> {
+    parser.check_idle(ranges.value().env)?;
     let len = ranges.len();
-    let included = &mut Vec::with_capacity(len);
+    let mut included = Vec::with_capacity(len);
     for i in 0..len {
         let range: Range = ranges.get(i)?;
         included.push(range.into());
     }
-    parser.set_included_ranges(included).or_else(|error| {
-        ranges.value().env.signal(error::tsc_invalid_ranges, (error.0, ))
-    })
+    parser.set_included_ranges(&included).or_else(|err| {
+        error::signal_invalid_ranges(ranges.value().env, err.0, ranges.get(err.0)?)
+    })?;
+    parser.included_ranges = included;
+    Ok(())
+}
+
+/// Return the ranges of text that PARSER includes when parsing, as a vector of
+/// ranges, in the format accepted by `tsc-set-included-ranges'.
+///
+/// If PARSER includes entire documents, which is the default, return an empty
+/// vector.
+#[defun(mod_in_name = true)]
+fn included_ranges<'e>(env: &'e Env, parser: &RParser) -> Result<Vector<'e>> {
+    let vec = env.make_vector(parser.included_ranges.len(), ())?;
+    for (i, range) in parser.included_ranges.iter().enumerate() {
+        vec.set(i, Range(*range))?;
+    }
+    Ok(vec)
 }
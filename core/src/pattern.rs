@@ -0,0 +1,147 @@
+use emacs::{defun, Env, Result, Value, Vector};
+
+use crate::error;
+
+emacs::use_symbols! {
+    cons
+    string
+    symbol
+    vector
+    integer
+}
+
+/// The source of a query, either given as a string, or serialized from patterns written as Lisp
+/// data.
+pub(crate) struct Patterns<'e> {
+    pub(crate) source: String,
+    /// The serialized forms, and their byte ranges in `source`, in post-order.
+    forms: Vec<(usize, usize, Value<'e>)>,
+}
+
+impl<'e> Patterns<'e> {
+    /// Convert PATTERNS, a string, or a sequence of patterns as Lisp data, into a query source.
+    pub(crate) fn new(patterns: Value<'e>) -> Result<Self> {
+        if let Ok(source) = patterns.into_rust::<String>() {
+            return Ok(Self { source, forms: vec![] });
+        }
+        let mut serializer = Serializer { env: patterns.env, source: String::new(), forms: vec![] };
+        let elements = serializer.elements(patterns)?;
+        for (i, element) in elements.into_iter().enumerate() {
+            if i > 0 {
+                serializer.source.push('\n');
+            }
+            serializer.write(element)?;
+        }
+        Ok(Self { source: serializer.source, forms: serializer.forms })
+    }
+
+    /// Return the innermost serialized form containing the byte OFFSET of the source, or ending
+    /// at it. Return None if the source was given as a string.
+    pub(crate) fn form_at(&self, offset: usize) -> Option<Value<'e>> {
+        self.forms.iter()
+            .filter(|&&(beg, end, _)| beg <= offset && offset <= end)
+            .min_by_key(|&&(beg, end, _)| end - beg)
+            .map(|&(_, _, form)| form)
+    }
+}
+
+struct Serializer<'e> {
+    env: &'e Env,
+    source: String,
+    forms: Vec<(usize, usize, Value<'e>)>,
+}
+
+impl<'e> Serializer<'e> {
+    fn invalid<T>(&self, form: Value<'e>) -> Result<T> {
+        let env = self.env;
+        env.signal(error::tsc_query_invalid_syntax, (
+            "Invalid pattern element",
+            error::_kind, error::kind(env, "syntax")?,
+            error::_form, form,
+        ))
+    }
+
+    /// Return the elements of SEQUENCE, a list or a vector.
+    fn elements(&self, sequence: Value<'e>) -> Result<Vec<Value<'e>>> {
+        let env = self.env;
+        let mut elements = vec![];
+        if let Ok(vector) = sequence.into_rust::<Vector>() {
+            for i in 0..vector.len() {
+                elements.push(vector.get(i)?);
+            }
+            return Ok(elements);
+        }
+        let mut rest = sequence;
+        while rest.is_not_nil() {
+            if !env.call("type-of", (rest,))?.eq(cons.bind(env)) {
+                return self.invalid(sequence);
+            }
+            elements.push(env.call("car", (rest,))?);
+            rest = env.call("cdr", (rest,))?;
+        }
+        Ok(elements)
+    }
+
+    fn write_elements(&mut self, open: char, form: Value<'e>, close: char) -> Result<()> {
+        self.source.push(open);
+        for (i, element) in self.elements(form)?.into_iter().enumerate() {
+            if i > 0 {
+                self.source.push(' ');
+            }
+            self.write(element)?;
+        }
+        self.source.push(close);
+        Ok(())
+    }
+
+    /// Serialize FORM. Symbols are written as they are, except for keywords, which are written as
+    /// field names, e.g. `:name' as "name:". Strings are quoted. Lists are written as
+    /// parenthesized nodes, groups, or predicates, and vectors as alternations.
+    fn write(&mut self, form: Value<'e>) -> Result<()> {
+        let env = self.env;
+        let beg = self.source.len();
+        let type_of = env.call("type-of", (form,))?;
+        if type_of.eq(symbol.bind(env)) {
+            let name: String = env.call("symbol-name", (form,))?.into_rust()?;
+            match name.strip_prefix(':') {
+                Some(field) if !field.is_empty() => {
+                    self.source.push_str(field);
+                    self.source.push(':');
+                }
+                _ => self.source.push_str(&name),
+            }
+        } else if type_of.eq(string.bind(env)) {
+            let text: String = form.into_rust()?;
+            self.source.push('"');
+            for c in text.chars() {
+                match c {
+                    '"' => self.source.push_str("\\\""),
+                    '\\' => self.source.push_str("\\\\"),
+                    '\n' => self.source.push_str("\\n"),
+                    '\r' => self.source.push_str("\\r"),
+                    '\t' => self.source.push_str("\\t"),
+                    '\0' => self.source.push_str("\\0"),
+                    c => self.source.push(c),
+                }
+            }
+            self.source.push('"');
+        } else if type_of.eq(integer.bind(env)) {
+            self.source.push_str(&form.into_rust::<i64>()?.to_string());
+        } else if type_of.eq(cons.bind(env)) {
+            self.write_elements('(', form, ')')?;
+        } else if type_of.eq(vector.bind(env)) {
+            self.write_elements('[', form, ']')?;
+        } else {
+            return self.invalid(form);
+        }
+        self.forms.push((beg, self.source.len(), form));
+        Ok(())
+    }
+}
+
+/// Serialize PATTERNS, a sequence of patterns as Lisp data, into a query string.
+/// See `tsc-make-query' for how Lisp data is converted.
+#[defun]
+fn _serialize_patterns(patterns: Value) -> Result<String> {
+    Ok(Patterns::new(patterns)?.source)
+}
@@ -0,0 +1,203 @@
+use std::fs;
+
+use emacs::{defun, Env, Result, Value};
+use tree_sitter::{Node, Tree, TreeCursor};
+
+use crate::{
+    types::BytePos,
+    node::RNode,
+    tree::Borrowed,
+};
+
+/// A node rendered in the playground text: its byte range in the source code, and the range of
+/// its parenthesized form in the text, in characters.
+struct Rendered {
+    beg: usize,
+    end: usize,
+    text_beg: usize,
+    text_end: usize,
+}
+
+/// The text of a playground being rendered, and the positions of its nodes.
+#[derive(Default)]
+struct Renderer {
+    text: String,
+    /// Length of `text` in characters.
+    len: usize,
+    rendered: Vec<Rendered>,
+    /// Indices of the rendered nodes that are not closed yet.
+    open: Vec<usize>,
+}
+
+impl Renderer {
+    fn push(&mut self, s: &str) {
+        self.text.push_str(s);
+        self.len += s.chars().count();
+    }
+
+    fn open(&mut self, node: Node, field: Option<&str>) {
+        if !self.rendered.is_empty() {
+            self.push("\n");
+            self.push(&"  ".repeat(self.open.len()));
+        }
+        if let Some(field) = field {
+            self.push(field);
+            self.push(": ");
+        }
+        let text_beg = self.len;
+        match (node.is_missing(), node.is_named()) {
+            (true, true) => self.push(&format!("(MISSING {}", node.kind())),
+            (true, false) => self.push(&format!("(MISSING {:?}", node.kind())),
+            _ => self.push(&format!("({}", node.kind())),
+        }
+        self.open.push(self.rendered.len());
+        self.rendered.push(Rendered {
+            beg: node.start_byte(),
+            end: node.end_byte(),
+            text_beg,
+            text_end: text_beg,
+        });
+    }
+
+    fn close(&mut self) {
+        self.push(")");
+        if let Some(i) = self.open.pop() {
+            self.rendered[i].text_end = self.len;
+        }
+    }
+}
+
+/// Render TREE's sexp, with one node per line, indented by depth, and with each node's field
+/// in front of it. This also returns the position of each node, in pre-order.
+fn render(tree: &Tree) -> (String, Vec<Rendered>) {
+    let mut renderer = Renderer::default();
+    // For each ancestor of the current node, whether it was rendered, i.e. whether it must be
+    // closed when the cursor leaves it.
+    let mut ancestors: Vec<bool> = vec![];
+    let mut cursor = tree.walk();
+    'walk: loop {
+        let node = cursor.node();
+        let visible = node.is_named() || node.is_missing();
+        if visible {
+            renderer.open(node, cursor.field_name());
+        }
+        if cursor.goto_first_child() {
+            ancestors.push(visible);
+            continue;
+        }
+        if visible {
+            renderer.close();
+        }
+        loop {
+            if cursor.goto_next_sibling() {
+                continue 'walk;
+            }
+            if !cursor.goto_parent() {
+                break 'walk;
+            }
+            if ancestors.pop() == Some(true) {
+                renderer.close();
+            }
+        }
+    }
+    (renderer.text, renderer.rendered)
+}
+
+/// Render the pretty-printed sexp of TREE, for playground buffers; return (TEXT . MAPPING).
+///
+/// TEXT has one named node per line, indented by depth, with its field in front
+/// of it, e.g. "name: (identifier)". MISSING nodes are included.
+///
+/// MAPPING is a vector of [BEG END TEXT-BEG TEXT-END], one for each rendered node, in
+/// the order they appear in TEXT. BEG and END are the node's byte positions in the
+/// source code. TEXT-BEG and TEXT-END are the positions of the node's parenthesized
+/// form in a buffer containing only TEXT. This allows highlighting the source code
+/// of the node under point in the playground buffer, and vice versa. See
+/// `tsc-playground-source-range' and `tsc-playground-text-range'.
+#[defun]
+fn render_playground<'e>(env: &'e Env, tree: Borrowed<'e, Tree>) -> Result<Value<'e>> {
+    let (text, rendered) = render(&tree.try_borrow()?);
+    let mapping = env.make_vector(rendered.len(), ())?;
+    for (i, node) in rendered.into_iter().enumerate() {
+        let (beg, end): (BytePos, BytePos) = (node.beg.into(), node.end.into());
+        mapping.set(i, env.vector((beg, end, node.text_beg + 1, node.text_end + 1))?)?;
+    }
+    env.cons(text, mapping)
+}
+
+// -------------------------------------------------------------------------------------------------
+// DOT graphs
+
+/// Return S as a quoted string of the DOT language.
+fn dot_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+}
+
+/// Append the DOT statements of the subtree at CURSOR's current node to DOT. Its id is NEXT_ID,
+/// which is incremented for each node.
+fn write_dot(dot: &mut String, cursor: &mut TreeCursor, next_id: &mut usize) -> usize {
+    let node = cursor.node();
+    let id = *next_id;
+    *next_id += 1;
+    let kind = match (node.is_missing(), node.is_named()) {
+        (true, true) => format!("MISSING {}", node.kind()),
+        (true, false) => format!("MISSING {:?}", node.kind()),
+        (false, true) => node.kind().to_owned(),
+        (false, false) => format!("{:?}", node.kind()),
+    };
+    // Byte positions are 1-based.
+    let label = format!("{}\n{}-{}", kind, node.start_byte() + 1, node.end_byte() + 1);
+    let style = match (node.is_error(), node.is_missing(), node.is_named()) {
+        (true, _, _) => ", color=red, fontcolor=red",
+        (_, true, _) => ", style=dashed, color=red, fontcolor=red",
+        (_, _, false) => ", shape=plaintext",
+        _ => "",
+    };
+    dot.push_str(&format!("  node{} [label={}{}]\n", id, dot_quote(&label), style));
+    if cursor.goto_first_child() {
+        loop {
+            let field = cursor.field_name();
+            let child_id = write_dot(dot, cursor, next_id);
+            dot.push_str(&match field {
+                Some(field) => {
+                    format!("  node{} -> node{} [label={}]\n", id, child_id, dot_quote(field))
+                }
+                None => format!("  node{} -> node{}\n", id, child_id),
+            });
+            if !cursor.goto_next_sibling() {
+                break;
+            }
+        }
+        cursor.goto_parent();
+    }
+    id
+}
+
+/// Return the subtree rooted at NODE as a graph in the DOT language, for Graphviz.
+/// If FILE is non-nil, write the graph to FILE instead, and return nil.
+///
+/// Each node is labeled with its type, and its byte positions. Edges are labeled with
+/// the children's fields. Anonymous nodes are drawn without a box. ERROR and MISSING
+/// nodes are drawn in red, the latter with a dashed box.
+///
+/// Unlike `tsc-parser-print-dot-graphs', which shows the parser's stack while it
+/// parses, this shows the resulting concrete syntax tree, e.g. of a region where
+/// the parser recovered from errors.
+#[defun]
+fn node_to_dot(env: &Env, node: &RNode, file: Option<Value>) -> Result<Option<String>> {
+    let mut dot = String::from("digraph tree {\n  edge [arrowhead=none]\n");
+    {
+        let inner = node.borrow()?;
+        let mut cursor = inner.walk();
+        write_dot(&mut dot, &mut cursor, &mut 0);
+    }
+    dot.push_str("}\n");
+    match file {
+        Some(file) => {
+            let file: String = env.call("expand-file-name", (file,))?.into_rust()?;
+            fs::write(file, dot)?;
+            Ok(None)
+        }
+        None => Ok(Some(dot)),
+    }
+}
@@ -0,0 +1,219 @@
+use std::cell::RefCell;
+
+use emacs::{defun, Env, Result};
+
+use crate::types::BytePos;
+
+emacs::use_symbols! {
+    args_out_of_range
+}
+
+/// A run of `count` consecutive multibyte characters, all `width` bytes long, starting at byte
+/// offset `byte`, and character offset `char`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Run {
+    byte: usize,
+    char: usize,
+    width: usize,
+    count: usize,
+}
+
+impl Run {
+    #[inline]
+    fn end_byte(&self) -> usize {
+        self.byte + self.width * self.count
+    }
+
+    #[inline]
+    fn end_char(&self) -> usize {
+        self.char + self.count
+    }
+}
+
+/// Mapping between the byte offsets and the character offsets of a text.
+///
+/// Only the runs of multibyte characters are recorded. Between them, bytes and characters
+/// correspond one to one, so a mostly ASCII text has a very small map.
+pub struct PositionMap {
+    runs: Vec<Run>,
+    bytes: usize,
+    chars: usize,
+}
+
+impl_pred!(position_map_p, &RefCell<PositionMap>);
+
+/// Return the runs of multibyte characters of TEXT, which starts at byte offset BYTE, and
+/// character offset CHAR.
+fn runs(text: &str, byte: usize, char: usize) -> Vec<Run> {
+    let mut runs: Vec<Run> = vec![];
+    for (i, (offset, c)) in text.char_indices().enumerate() {
+        let width = c.len_utf8();
+        if width == 1 {
+            continue;
+        }
+        let (byte, char) = (byte + offset, char + i);
+        match runs.last_mut() {
+            Some(run) if run.width == width && run.end_byte() == byte => run.count += 1,
+            _ => runs.push(Run { byte, char, width, count: 1 }),
+        }
+    }
+    runs
+}
+
+impl PositionMap {
+    fn new(text: &str) -> Self {
+        Self { runs: runs(text, 0, 0), bytes: text.len(), chars: text.chars().count() }
+    }
+
+    /// Return the index of the last run starting at or before BYTE.
+    fn run_at_byte(&self, byte: usize) -> Option<usize> {
+        self.runs.partition_point(|run| run.byte <= byte).checked_sub(1)
+    }
+
+    /// Return the offset of the character containing BYTE.
+    fn char_at(&self, byte: usize) -> usize {
+        match self.run_at_byte(byte).map(|i| self.runs[i]) {
+            None => byte,
+            Some(run) if byte < run.end_byte() => run.char + (byte - run.byte) / run.width,
+            Some(run) => run.end_char() + (byte - run.end_byte()),
+        }
+    }
+
+    /// Return the byte offset of the character at CHAR.
+    fn byte_at(&self, char: usize) -> usize {
+        let i = self.runs.partition_point(|run| run.char <= char).checked_sub(1);
+        match i.map(|i| self.runs[i]) {
+            None => char,
+            Some(run) if char < run.end_char() => run.byte + (char - run.char) * run.width,
+            Some(run) => run.end_byte() + (char - run.end_char()),
+        }
+    }
+
+    /// Return whether BYTE is the offset of a character boundary.
+    fn is_boundary(&self, byte: usize) -> bool {
+        byte <= self.bytes && self.byte_at(self.char_at(byte)) == byte
+    }
+
+    /// Split the run containing BYTE, which must be a character boundary, so that no run
+    /// crosses it.
+    fn split_at(&mut self, byte: usize) {
+        if let Some(i) = self.run_at_byte(byte) {
+            let run = self.runs[i];
+            if run.byte < byte && byte < run.end_byte() {
+                let count = (byte - run.byte) / run.width;
+                self.runs[i].count = count;
+                self.runs.insert(i + 1, Run {
+                    byte,
+                    char: run.char + count,
+                    width: run.width,
+                    count: run.count - count,
+                });
+            }
+        }
+    }
+
+    /// Replace the bytes from BEG to OLD_END with TEXT.
+    fn edit(&mut self, beg: usize, old_end: usize, text: &str) {
+        self.split_at(beg);
+        self.split_at(old_end);
+        let (beg_char, old_end_char) = (self.char_at(beg), self.char_at(old_end));
+        let new_chars = text.chars().count();
+        let first = self.runs.partition_point(|run| run.byte < beg);
+        let last = self.runs.partition_point(|run| run.byte < old_end);
+        let inserted = runs(text, beg, beg_char);
+        self.runs.splice(first..last, inserted.iter().copied());
+        for run in &mut self.runs[first + inserted.len()..] {
+            run.byte = run.byte - old_end + beg + text.len();
+            run.char = run.char - old_end_char + beg_char + new_chars;
+        }
+        self.bytes = self.bytes - (old_end - beg) + text.len();
+        self.chars = self.chars - (old_end_char - beg_char) + new_chars;
+    }
+}
+
+/// Create a map between the byte positions and the character positions of TEXT,
+/// which is usually a buffer's whole text. See `tsc-buffer-position-map'.
+///
+/// Converting positions with it is faster than with `byte-to-position' and
+/// `position-bytes', especially in large multibyte buffers.
+#[defun(user_ptr)]
+fn make_position_map(text: String) -> Result<PositionMap> {
+    Ok(PositionMap::new(&text))
+}
+
+/// Update MAP after the text from BEG-BYTEPOS to OLD-END-BYTEPOS was replaced with
+/// TEXT. This is usually called from `after-change-functions', like
+/// `tsc-edit-tree'.
+///
+/// Signal `args-out-of-range' if the positions are not character boundaries of the
+/// mapped text.
+#[defun]
+fn position_map_edit(
+    env: &Env,
+    map: &mut PositionMap,
+    beg_bytepos: BytePos,
+    old_end_bytepos: BytePos,
+    text: String,
+) -> Result<()> {
+    let (beg, old_end): (usize, usize) = (beg_bytepos.into(), old_end_bytepos.into());
+    if beg > old_end || !map.is_boundary(beg) || !map.is_boundary(old_end) {
+        return env.signal(args_out_of_range, (beg_bytepos, old_end_bytepos));
+    }
+    map.edit(beg, old_end, &text);
+    Ok(())
+}
+
+/// Return the position of the character at BYTEPOS, according to MAP, like
+/// `byte-to-position'. Return nil if BYTEPOS is out of range.
+#[defun]
+fn byte_to_position(map: &PositionMap, bytepos: BytePos) -> Result<Option<usize>> {
+    let byte: usize = bytepos.into();
+    Ok(if byte <= map.bytes { Some(map.char_at(byte) + 1) } else { None })
+}
+
+/// Return the byte position of the character at POSITION, according to MAP, like
+/// `position-bytes'. Return nil if POSITION is out of range.
+#[defun]
+fn position_to_byte(map: &PositionMap, position: usize) -> Result<Option<BytePos>> {
+    Ok(match position.checked_sub(1) {
+        Some(char) if char <= map.chars => Some(map.byte_at(char).into()),
+        _ => None,
+    })
+}
+
+// -------------------------------------------------------------------------------------------------
+// UTF-16 columns, used by LSP.
+
+/// Return the byte offset of the character boundary at or before BYTE in TEXT.
+fn floor_boundary(text: &str, byte: usize) -> usize {
+    let mut byte = byte.min(text.len());
+    while !text.is_char_boundary(byte) {
+        byte -= 1;
+    }
+    byte
+}
+
+/// Return the UTF-16 column of BYTE-COLUMN in LINE, the text of a line without its
+/// terminator, i.e. the number of UTF-16 code units before it, like an LSP
+/// position's `character'. A BYTE-COLUMN past the end of LINE is clamped to it.
+#[defun]
+fn utf16_column(line: String, byte_column: usize) -> Result<usize> {
+    let end = floor_boundary(&line, byte_column);
+    Ok(line[..end].chars().map(char::len_utf16).sum())
+}
+
+/// Return the byte column of the UTF-16 COLUMN in LINE, the text of a line without
+/// its terminator. This is the inverse of `tsc-utf16-column'. A COLUMN past the end
+/// of LINE is clamped to it, like LSP requires. A COLUMN in the middle of a
+/// surrogate pair is rounded up to the next character.
+#[defun]
+fn utf16_column_to_byte(line: String, column: usize) -> Result<usize> {
+    let mut units = 0;
+    for (offset, c) in line.char_indices() {
+        if units >= column {
+            return Ok(offset);
+        }
+        units += c.len_utf16();
+    }
+    Ok(line.len())
+}
@@ -1,15 +1,36 @@
-use std::{cell::RefCell, iter};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    iter, mem,
+    rc::Rc,
+    sync::{Mutex, MutexGuard, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
 
 use emacs::{defun, Env, Error, GlobalRef, IntoLisp, Result, Value, Vector};
-use tree_sitter::{Node, QueryCursor, QueryErrorKind, TextProvider};
+use once_cell::sync::Lazy;
+use tree_sitter::{
+    CaptureQuantifier, Node, QueryErrorKind, QueryMatch, QueryMatches, QueryPredicateArg,
+    TextProvider, Tree,
+};
 
 use crate::{
-    types::{BytePos, Point},
+    types::{self, BytePos, Point, Shared},
     lang::Language,
     node::{RNode, LispUtils},
-    error,
+    tree::Borrowed,
+    pattern::Patterns,
+    spans::{self, Highlight},
+    instrument::{Timer, QUERY_EXECUTIONS, QUERY_MICROS},
+    live::{Live, QUERIES, QUERY_BYTES, QUERY_CURSORS},
+    error::{self, Internal},
 };
 
+emacs::use_symbols! {
+    args_out_of_range
+}
+
 fn vec_to_vector<'e, T: IntoLisp<'e>>(env: &'e Env, vec: Vec<T>) -> Result<Vector<'e>> {
     let vector = env.make_vector(vec.len(), ())?;
     for (i, v) in vec.into_iter().enumerate() {
@@ -21,14 +42,83 @@ fn vec_to_vector<'e, T: IntoLisp<'e>>(env: &'e Env, vec: Vec<T>) -> Result<Vecto
 // -------------------------------------------------------------------------------------------------
 // Query
 
-struct Query {
-    pub(crate) raw: tree_sitter::Query,
+pub(crate) struct Query {
+    /// The compiled query. It is shared with the ongoing streaming executions, if any, during
+    /// which it cannot be changed.
+    pub(crate) raw: Rc<tree_sitter::Query>,
     pub(crate) capture_tags: Vec<GlobalRef>,
+    /// Number identifying the current patterns of `raw`. It changes whenever they are changed, so
+    /// that what was computed from them can be invalidated.
+    pub(crate) version: usize,
+    language: tree_sitter::Language,
+    /// Estimated number of bytes held by `raw`.
+    bytes: usize,
+    released: bool,
+    _live: Live,
+}
+
+thread_local! {
+    static NEXT_VERSION: Cell<usize> = Cell::new(1);
+}
+
+fn next_version() -> usize {
+    NEXT_VERSION.with(|next| next.replace(next.get() + 1))
+}
+
+impl Query {
+    /// Return the estimated number of bytes held by RAW, compiled from SOURCE_LEN bytes of
+    /// source. Compiled steps are roughly proportional to the source's length.
+    fn estimate_bytes(raw: &tree_sitter::Query, source_len: usize) -> usize {
+        let names: usize = raw.capture_names().iter().map(|name| name.len()).sum();
+        source_len * 4 + raw.pattern_count() * 64 + names
+    }
+
+    /// Free the underlying query's resources, by replacing it with one that has no patterns.
+    /// The caller must check that it is not streamed, see `is_streamed`.
+    pub(crate) fn release(&mut self) -> Result<()> {
+        self.raw = Rc::new(tree_sitter::Query::new(self.language, "")?);
+        self.version = next_version();
+        self.capture_tags.clear();
+        QUERY_BYTES.sub(self.bytes);
+        self.bytes = 0;
+        self.released = true;
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn is_released(&self) -> bool {
+        self.released
+    }
+
+    /// Return whether the query is being executed by a streaming query cursor.
+    #[inline]
+    pub(crate) fn is_streamed(&self) -> bool {
+        Rc::strong_count(&self.raw) > 1
+    }
+
+    /// Return the compiled query, for changing it. Signal an error if it is being streamed.
+    fn raw_mut(&mut self, env: &Env) -> Result<&mut tree_sitter::Query> {
+        match Rc::get_mut(&mut self.raw) {
+            Some(raw) => {
+                self.version = next_version();
+                Ok(raw)
+            }
+            None => error::signal_query_streamed(env),
+        }
+    }
+}
+
+impl Drop for Query {
+    fn drop(&mut self) {
+        QUERY_BYTES.sub(self.bytes);
+    }
 }
 
 impl_pred!(query_p, &RefCell<Query>);
 
 /// Create a new query from a SOURCE containing one or more S-expression patterns.
+/// SOURCE is a string, or a sequence of patterns as Lisp data, which is serialized
+/// as described in `tsc-make-query'.
 ///
 /// The query is associated with LANGUAGE, and can only be run on syntax nodes
 /// parsed with LANGUAGE.
@@ -38,23 +128,54 @@ impl_pred!(query_p, &RefCell<Query>);
 /// (e.g. "function.builtin"), and return a tag value. If the return value is nil,
 /// the associated capture name is disabled.
 #[defun(user_ptr)]
-fn _make_query(language: Language, source: String, tag_assigner: Value) -> Result<Query> {
-    let mut raw = tree_sitter::Query::new(language.into(), &source).or_else(|err| {
-        let symbol = match err.kind {
-            QueryErrorKind::Syntax => error::tsc_query_invalid_syntax,
-            QueryErrorKind::NodeType => error::tsc_query_invalid_node_type,
-            QueryErrorKind::Field => error::tsc_query_invalid_field,
-            QueryErrorKind::Capture => error::tsc_query_invalid_capture,
-            QueryErrorKind::Predicate => error::tsc_query_invalid_predicate,
-            QueryErrorKind::Structure => error::tsc_query_invalid_structure,
-            QueryErrorKind::Language => error::tsc_lang_abi_error,
+fn _make_query(language: Language, source: Value, tag_assigner: Value) -> Result<Query> {
+    make_query(language, &Patterns::new(source)?, tag_assigner)
+}
+
+pub(crate) fn make_query(
+    language: Language,
+    patterns: &Patterns,
+    tag_assigner: Value,
+) -> Result<Query> {
+    let source = &patterns.source;
+    let mut raw = tree_sitter::Query::new(language.into(), source).or_else(|err| {
+        let (symbol, kind) = match err.kind {
+            QueryErrorKind::Syntax => (error::tsc_query_invalid_syntax, "syntax"),
+            QueryErrorKind::NodeType => (error::tsc_query_invalid_node_type, "node-type"),
+            QueryErrorKind::Field => (error::tsc_query_invalid_field, "field"),
+            QueryErrorKind::Capture => (error::tsc_query_invalid_capture, "capture"),
+            QueryErrorKind::Predicate => (error::tsc_query_invalid_predicate, "predicate"),
+            QueryErrorKind::Structure => (error::tsc_query_invalid_structure, "structure"),
+            QueryErrorKind::Language => (error::tsc_lang_abi_error, "language"),
         };
+        let env = tag_assigner.env;
         let byte_pos: BytePos = err.offset.into();
         let point: Point = tree_sitter::Point { row: err.row, column: err.column }.into();
+        // For these kinds, the message is the offending name.
+        let (message, actual) = match err.kind {
+            QueryErrorKind::NodeType => {
+                (format!("Invalid node type {:?}", err.message), Some(err.message))
+            }
+            QueryErrorKind::Field => {
+                (format!("Invalid field name {:?}", err.message), Some(err.message))
+            }
+            QueryErrorKind::Capture => {
+                (format!("Invalid capture name {:?}", err.message), Some(err.message))
+            }
+            _ => (err.message, None),
+        };
         // TODO: Character position?
         // TODO: Convert named node types and field names to symbols and keywords?
-        tag_assigner.env.signal(symbol, (err.message, point, byte_pos))
+        env.signal(symbol, (
+            message,
+            error::_kind, error::kind(env, kind)?,
+            error::_point, point,
+            error::_bytepos, byte_pos,
+            error::_actual, actual,
+            error::_form, patterns.form_at(err.offset),
+        ))
     })?;
+    check_general_predicates(tag_assigner.env, &raw)?;
     let capture_names = raw.capture_names().to_vec();
     let mut capture_tags = vec![];
     for name in &capture_names {
@@ -64,7 +185,17 @@ fn _make_query(language: Language, source: String, tag_assigner: Value) -> Resul
         }
         capture_tags.push(value.make_global_ref())
     }
-    Ok(Query { raw, capture_tags })
+    let bytes = Query::estimate_bytes(&raw, source.len());
+    QUERY_BYTES.add(bytes);
+    Ok(Query {
+        raw: Rc::new(raw),
+        capture_tags,
+        version: next_version(),
+        language: language.into(),
+        bytes,
+        released: false,
+        _live: Live::new(&QUERIES),
+    })
 }
 
 macro_rules! defun_query_methods {
@@ -80,13 +211,34 @@ macro_rules! defun_query_methods {
 }
 
 defun_query_methods! {
-    /// Return the byte position where the NTH pattern starts in QUERY's source.
-    "-query-start-byte-for-pattern" fn start_byte_for_pattern(nth: usize) -> BytePos; into
-
     /// Return the number of patterns in QUERY.
     "query-count-patterns" fn pattern_count -> usize
 }
 
+/// Return the byte position where the NTH pattern starts in QUERY's source.
+///
+/// This maps a pattern index, e.g. the PATTERN-INDEX of a match, back to its
+/// location in the source file of QUERY. Signal `args-out-of-range' if QUERY has
+/// fewer than NTH+1 patterns.
+#[defun]
+fn query_start_byte_for_pattern(env: &Env, query: &Query, nth: usize) -> Result<BytePos> {
+    if nth >= query.raw.pattern_count() {
+        return env.signal(args_out_of_range, (nth, query.raw.pattern_count()));
+    }
+    Ok(query.raw.start_byte_for_pattern(nth).into())
+}
+
+/// Return t if the step at BYTEPOS in QUERY's source is guaranteed to match, once
+/// the pattern containing it has started matching.
+///
+/// BYTEPOS should be the position of a node, field, or capture in a pattern. This
+/// helps finding the parts of a big query that make it slow, since patterns whose
+/// steps are not guaranteed keep more in-progress matches.
+#[defun]
+fn query_pattern_guaranteed_at_step_p(query: &Query, bytepos: BytePos) -> Result<bool> {
+    Ok(query.raw.is_pattern_guaranteed_at_step(bytepos.into()))
+}
+
 /// Return the names of the captures used in QUERY.
 #[defun]
 fn _query_capture_names(query: Value) -> Result<Vector> {
@@ -100,6 +252,36 @@ fn _query_capture_names(query: Value) -> Result<Vector> {
     Ok(vec)
 }
 
+/// Return the number of captures used in QUERY. Capture indices, e.g. those used by
+/// `tsc-query-capture-quantifier', range from 0 to this number, exclusive.
+#[defun]
+fn query_capture_count(query: &Query) -> Result<usize> {
+    Ok(query.raw.capture_names().len())
+}
+
+/// Return the name of the capture at CAPTURE-INDEX in QUERY, without the prefix "@".
+/// Signal `args-out-of-range' if QUERY has fewer than CAPTURE-INDEX+1 captures.
+///
+/// The index is also the position of the capture's tag in `tsc-query-capture-tags'.
+#[defun]
+fn query_capture_name_for_id(env: &Env, query: &Query, capture_index: usize) -> Result<String> {
+    let names = query.raw.capture_names();
+    match names.get(capture_index) {
+        Some(name) => Ok(name.clone()),
+        None => env.signal(args_out_of_range, (capture_index, names.len())),
+    }
+}
+
+/// Return the index of the capture named NAME in QUERY, or nil if QUERY doesn't use it.
+/// NAME should not have the prefix "@".
+///
+/// This allows resolving capture names once, e.g. to faces in a vector indexed by
+/// capture index, instead of handling their tags for each capture.
+#[defun]
+fn query_capture_index_for_name(query: &Query, name: String) -> Result<Option<u32>> {
+    Ok(query.raw.capture_index_for_name(&name))
+}
+
 /// Return all of QUERY's available capture tags.
 /// See `tsc-make-query' for an explanation of capture tagging.
 #[defun(mod_in_name = true)]
@@ -114,16 +296,148 @@ fn capture_tags<'e>(env: &'e Env, query: &Query) -> Result<Vector<'e>> {
 /// Disable a certain capture within QUERY, by specifying its NAME.
 ///
 /// This prevents the capture from being returned in matches, and also avoids any
-/// resource usage associated with recording the capture.
-#[defun]
-fn _disable_capture(query: &mut Query, name: String) -> Result<()> {
-    query.raw.disable_capture(&name);
+/// resource usage associated with recording the capture. This is much cheaper
+/// than compiling a new query without the capture, e.g. to turn off a highlighting
+/// feature. It cannot be undone. Signal `tsc-error' if QUERY is being executed by
+/// a streaming query cursor, see `tsc-query-cursor-exec'.
+#[defun(mod_in_name = true)]
+fn disable_capture(env: &Env, query: &mut Query, name: String) -> Result<()> {
+    query.raw_mut(env)?.disable_capture(&name);
+    Ok(())
+}
+
+/// Disable the NTH pattern within QUERY, so that it is not matched anymore.
+///
+/// This also avoids any resource usage associated with the pattern. It cannot
+/// be undone. Signal `args-out-of-range' if QUERY has fewer than NTH+1 patterns,
+/// and `tsc-error' if it is being executed by a streaming query cursor.
+#[defun(mod_in_name = true)]
+fn disable_pattern(env: &Env, query: &mut Query, nth: usize) -> Result<()> {
+    if nth >= query.raw.pattern_count() {
+        return env.signal(args_out_of_range, (nth, query.raw.pattern_count()));
+    }
+    query.raw_mut(env)?.disable_pattern(nth);
     Ok(())
 }
 
+/// Return how many times the capture at CAPTURE-INDEX can occur in a match of the
+/// pattern at PATTERN-INDEX within QUERY. Indices are 0-based, and CAPTURE-INDEX is
+/// the position of the capture's tag in `tsc-query-capture-tags'.
+///
+/// The returned symbol is one of `one', `zero-or-one' (a capture quantified with
+/// "?"), `zero-or-more' ("*"), `one-or-more' ("+"), or `zero' (the pattern does
+/// not use the capture). This tells whether a match's captures with the same tag
+/// should be collected into a list, e.g. for a function's @parameter captures.
+#[defun(mod_in_name = true)]
+fn capture_quantifier(
+    env: &Env,
+    query: &Query,
+    pattern_index: usize,
+    capture_index: usize,
+) -> Result<Value<'_>> {
+    if pattern_index >= query.raw.pattern_count() {
+        return env.signal(args_out_of_range, (pattern_index, query.raw.pattern_count()));
+    }
+    let quantifiers = query.raw.capture_quantifiers(pattern_index);
+    let quantifier = match quantifiers.get(capture_index) {
+        Some(quantifier) => quantifier,
+        None => return env.signal(args_out_of_range, (capture_index, quantifiers.len())),
+    };
+    let name = match quantifier {
+        CaptureQuantifier::Zero => "zero",
+        CaptureQuantifier::ZeroOrOne => "zero-or-one",
+        CaptureQuantifier::ZeroOrMore => "zero-or-more",
+        CaptureQuantifier::One => "one",
+        CaptureQuantifier::OneOrMore => "one-or-more",
+    };
+    env.intern(name)
+}
+
 // -------------------------------------------------------------------------------------------------
 // QueryCursor
 
+pub(crate) struct QueryCursor {
+    raw: tree_sitter::QueryCursor,
+    /// The ongoing streaming execution, started by `tsc-query-cursor-exec'. It holds the
+    /// underlying cursor in the meantime, `raw` being a placeholder.
+    stream: Option<Stream>,
+    /// The time after which an execution is aborted. See `tsc-query-cursor-set-time-budget'.
+    time_budget: Option<Duration>,
+    /// Whether the latest execution was aborted because it exceeded the time budget.
+    timed_out: bool,
+    released: bool,
+    _live: Live,
+}
+
+impl QueryCursor {
+    pub(crate) fn new() -> Self {
+        Self {
+            raw: tree_sitter::QueryCursor::new(),
+            stream: None,
+            time_budget: None,
+            timed_out: false,
+            released: false,
+            _live: Live::new(&QUERY_CURSORS),
+        }
+    }
+
+    /// Undo the settings of `tsc-query-cursor-set-byte-range` and the like, keeping the
+    /// underlying cursor's allocations. This ends the ongoing streaming execution, if any.
+    pub(crate) fn reset(&mut self, env: &Env) -> Result<()> {
+        let raw = self.raw_mut(env)?;
+        let max = u32::MAX as usize;
+        raw.set_byte_range(0..max);
+        raw.set_point_range(
+            tree_sitter::Point { row: 0, column: 0 }..tree_sitter::Point { row: max, column: max },
+        );
+        raw.set_match_limit(u32::MAX);
+        self.time_budget = None;
+        self.timed_out = false;
+        Ok(())
+    }
+
+    /// Free the underlying cursor's resources, by replacing it with a fresh one. This ends the
+    /// ongoing streaming execution, if any.
+    pub(crate) fn release(&mut self, env: &Env) -> Result<()> {
+        if let Some(stream) = self.stream.take() {
+            stream.finish(env)?;
+        }
+        self.raw = tree_sitter::QueryCursor::new();
+        self.released = true;
+        Ok(())
+    }
+
+    /// Return the underlying cursor.
+    fn raw(&self) -> &tree_sitter::QueryCursor {
+        self.stream.as_ref().map_or(&self.raw, |stream| &stream.raw)
+    }
+
+    /// Return the underlying cursor, for a new execution, or for changing its settings. This
+    /// ends the ongoing streaming execution, if any.
+    fn raw_mut(&mut self, env: &Env) -> Result<&mut tree_sitter::QueryCursor> {
+        if let Some(stream) = self.stream.take() {
+            self.raw = stream.finish(env)?;
+        }
+        Ok(&mut self.raw)
+    }
+
+    #[inline]
+    pub(crate) fn is_released(&self) -> bool {
+        self.released
+    }
+
+    /// Return the deadline of an execution starting now, if there is a time budget.
+    fn start_execution(&mut self) -> Option<Instant> {
+        self.timed_out = false;
+        self.time_budget.map(|budget| Instant::now() + budget)
+    }
+}
+
+#[inline]
+fn is_past(deadline: Option<Instant>) -> bool {
+    deadline.map_or(false, |deadline| Instant::now() >= deadline)
+}
+
 impl_pred!(query_cursor_p, &RefCell<QueryCursor>);
 
 /// Create a new cursor for executing a given query.
@@ -134,7 +448,7 @@ fn make_query_cursor() -> Result<QueryCursor> {
     Ok(QueryCursor::new())
 }
 
-fn text_callback<'e>(
+pub(crate) fn text_callback<'e>(
     text_function: Value<'e>,
     error: &'e RefCell<Option<Error>>,
 ) -> impl TextProvider<'e> {
@@ -149,6 +463,215 @@ fn text_callback<'e>(
     }
 }
 
+/// Return the text of NODE, as returned by TEXT_FUNCTION, which is the same as for
+/// `text_callback`.
+pub(crate) fn node_text(text_function: Value, node: Node) -> Result<Vec<u8>> {
+    let text: String = text_function.call((node.lisp_start_byte(), node.lisp_end_byte()))?
+        .into_rust()?;
+    Ok(text.into_bytes())
+}
+
+/// Return whether the captures of M satisfy the predicates of its pattern that tree-sitter does
+/// not evaluate itself: `#any-of?` and `#not-any-of?`. TEXT returns the text of a node.
+///
+/// Other general predicates are ignored, so that they can be interpreted by clients.
+pub(crate) fn general_predicates_hold<F>(
+    query: &tree_sitter::Query,
+    m: &QueryMatch,
+    mut text: F,
+) -> Result<bool>
+where
+    F: FnMut(Node) -> Result<Vec<u8>>,
+{
+    for predicate in query.general_predicates(m.pattern_index) {
+        let negated = match &*predicate.operator {
+            "any-of?" => false,
+            "not-any-of?" => true,
+            _ => continue,
+        };
+        // The arguments were validated when the query was created.
+        let (capture, values) = match predicate.args.split_first() {
+            Some((QueryPredicateArg::Capture(capture), values)) => (*capture, values),
+            _ => continue,
+        };
+        for c in m.captures.iter().filter(|c| c.index == capture) {
+            let node_text = text(c.node)?;
+            let found = values.iter().any(|value| match value {
+                QueryPredicateArg::String(value) => value.as_bytes() == &node_text[..],
+                QueryPredicateArg::Capture(_) => false,
+            });
+            if found == negated {
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Return whether the captures of M, a match of QUERY on TREE, satisfy the predicates of its
+/// pattern that tree-sitter does not evaluate itself, like `#any-of?`, and the custom predicates
+/// defined with `tsc-define-query-predicate`. TEXT_FUNCTION is the same as for `text_callback`.
+pub(crate) fn predicates_hold(
+    query: &tree_sitter::Query,
+    m: &QueryMatch,
+    text_function: Value,
+    tree: &Shared<Tree>,
+) -> Result<bool> {
+    let hold = general_predicates_hold(query, m, |node| node_text(text_function, node))?;
+    Ok(hold && custom_predicates_hold(text_function.env, query, m, tree)?)
+}
+
+/// Signal `tsc-query-invalid-predicate` if a `#any-of?` or `#not-any-of?` predicate of RAW does
+/// not have a capture followed by strings as its arguments.
+fn check_general_predicates(env: &Env, raw: &tree_sitter::Query) -> Result<()> {
+    for i in 0..raw.pattern_count() {
+        for predicate in raw.general_predicates(i) {
+            if !matches!(&*predicate.operator, "any-of?" | "not-any-of?") {
+                continue;
+            }
+            let valid = match predicate.args.split_first() {
+                Some((QueryPredicateArg::Capture(_), values)) => values.iter().all(|value| {
+                    matches!(value, QueryPredicateArg::String(_))
+                }),
+                _ => false,
+            };
+            if !valid {
+                let byte_pos: BytePos = raw.start_byte_for_pattern(i).into();
+                return env.signal(error::tsc_query_invalid_predicate, (
+                    format!("#{} expects a capture, followed by strings", predicate.operator),
+                    error::_kind, error::kind(env, "predicate")?,
+                    error::_bytepos, byte_pos,
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// -------------------------------------------------------------------------------------------------
+// Custom predicates and directives
+
+/// The functions evaluating custom predicates, by operator, e.g. "lua-match?".
+static PREDICATES: Lazy<Mutex<HashMap<String, GlobalRef>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Lock the custom predicates registry. It is never held across calls into Lisp.
+fn predicates() -> MutexGuard<'static, HashMap<String, GlobalRef>> {
+    PREDICATES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Return whether the captures of M satisfy the custom predicates of its pattern, by calling
+/// their functions with the predicates' arguments. A capture is passed as the first node it
+/// captured in M, or nil.
+fn custom_predicates_hold(
+    env: &Env,
+    query: &tree_sitter::Query,
+    m: &QueryMatch,
+    tree: &Shared<Tree>,
+) -> Result<bool> {
+    for predicate in query.general_predicates(m.pattern_index) {
+        let function = match predicates().get(&*predicate.operator) {
+            Some(function) => function.bind(env),
+            None => continue,
+        };
+        let mut args = vec![];
+        for arg in &predicate.args {
+            args.push(match arg {
+                QueryPredicateArg::Capture(capture) => {
+                    match m.captures.iter().find(|c| c.index == *capture) {
+                        Some(c) => RNode::new(tree.clone(), |_| c.node).into_lisp(env)?,
+                        None => ().into_lisp(env)?,
+                    }
+                }
+                QueryPredicateArg::String(value) => (&**value).into_lisp(env)?,
+            });
+        }
+        if !env.call("apply", (function, types::list(env, args)?))?.is_not_nil() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Return whether QUERY uses custom predicates, which can only be evaluated where Lisp can be
+/// called.
+fn has_custom_predicates(query: &tree_sitter::Query) -> bool {
+    let predicates = predicates();
+    (0..query.pattern_count()).any(|i| {
+        query.general_predicates(i).iter().any(|p| predicates.contains_key(&*p.operator))
+    })
+}
+
+/// Make FUNCTION evaluate the predicate OPERATOR, e.g. "lua-match?", or stop
+/// evaluating it if FUNCTION is nil. See `tsc-define-query-predicate'.
+#[defun]
+fn _define_query_predicate(env: &Env, operator: String, function: Value) -> Result<()> {
+    let old = if function.is_not_nil() {
+        predicates().insert(operator, function.make_global_ref())
+    } else {
+        predicates().remove(&operator)
+    };
+    if let Some(old) = old {
+        old.free(env)?;
+    }
+    Ok(())
+}
+
+/// Convert ARG, an argument of a directive, to Lisp. A capture is converted to its tag. A string
+/// that is an integer, e.g. an argument of `#offset!`, is converted to the integer.
+fn directive_arg<'e>(env: &'e Env, query: &Query, arg: &QueryPredicateArg) -> Result<Value<'e>> {
+    match arg {
+        QueryPredicateArg::Capture(capture) => Ok(query.capture_tags[*capture as usize].bind(env)),
+        QueryPredicateArg::String(value) => match value.parse::<i64>() {
+            Ok(n) => n.into_lisp(env),
+            Err(_) => (&**value).into_lisp(env),
+        },
+    }
+}
+
+/// Return the directives of the pattern at PATTERN-INDEX in QUERY, as a list of
+/// (NAME . ARGS), the `#set!' ones first. NAME is a symbol, e.g. `set!' or `offset!'. Captures in
+/// ARGS are replaced with their tags, and integers are converted.
+///
+/// A (#set! KEY VALUE) directive is returned as (set! nil KEY VALUE), and a
+/// (#set! @CAPTURE KEY VALUE) directive as (set! CAPTURE-TAG KEY VALUE). VALUE is
+/// nil if omitted.
+#[defun]
+fn _query_pattern_directives<'e>(
+    env: &'e Env,
+    query: &Query,
+    pattern_index: usize,
+) -> Result<Value<'e>> {
+    let raw = &query.raw;
+    if pattern_index >= raw.pattern_count() {
+        return env.signal(args_out_of_range, (pattern_index, raw.pattern_count()));
+    }
+    let mut directives = vec![];
+    for property in raw.property_settings(pattern_index) {
+        let capture = match property.capture_id {
+            Some(capture) => query.capture_tags[capture].bind(env),
+            None => ().into_lisp(env)?,
+        };
+        directives.push(types::list(env, vec![
+            env.intern("set!")?,
+            capture,
+            (&*property.key).into_lisp(env)?,
+            property.value.as_deref().into_lisp(env)?,
+        ])?);
+    }
+    for predicate in raw.general_predicates(pattern_index) {
+        if !predicate.operator.ends_with('!') {
+            continue;
+        }
+        let mut directive = vec![env.intern(&predicate.operator)?];
+        for arg in &predicate.args {
+            directive.push(directive_arg(env, query, arg)?);
+        }
+        directives.push(types::list(env, directive)?);
+    }
+    types::list(env, directives)
+}
+
 #[defun]
 fn _query_cursor_matches<'e>(
     cursor: &mut QueryCursor,
@@ -157,33 +680,57 @@ fn _query_cursor_matches<'e>(
     text_function: Value<'e>,
 ) -> Result<Vector<'e>> {
     let raw = &query.raw;
+    QUERY_EXECUTIONS.incr();
+    let _timer = Timer::new(&QUERY_MICROS);
     let error = RefCell::new(None);
-    let matches = cursor.matches(
+    // Keep the tree borrowed, so that TEXT-FUNCTION cannot edit it while the query runs.
+    let inner = node.borrow()?;
+    let tree = node.clone_tree();
+    let env = text_function.env;
+    let deadline = cursor.start_execution();
+    let mut timed_out = false;
+    let matches = cursor.raw_mut(env)?.matches(
         raw,
-        node.borrow().clone(),
+        *inner,
         text_callback(text_function, &error),
     );
     let mut vec = vec![];
-    let env = text_function.env;
     for m in matches {
+        if is_past(deadline) {
+            timed_out = true;
+            break;
+        }
         if let Some(error) = error.borrow_mut().take() {
             return Err(error);
         }
-        let captures = env.make_vector(m.captures.len(), ())?;
-        for (ci, c) in m.captures.iter().enumerate() {
-            let captured_node = node.map(|_| c.node);
-            let capture = env.cons(
-                &query.capture_tags[c.index as usize],
-                captured_node
-            )?;
-            captures.set(ci, capture)?;
+        if !predicates_hold(raw, &m, text_function, &tree)? {
+            continue;
         }
-        let _match = env.cons(m.pattern_index, captures)?;
-        vec.push(_match);
+        vec.push(match_to_lisp(env, query, node, &m)?);
     }
+    cursor.timed_out = timed_out;
     vec_to_vector(env, vec)
 }
 
+/// Return M, a match of QUERY on NODE, as (PATTERN-INDEX . CAPTURES).
+fn match_to_lisp<'e>(
+    env: &'e Env,
+    query: &Query,
+    node: &RNode,
+    m: &QueryMatch,
+) -> Result<Value<'e>> {
+    let captures = env.make_vector(m.captures.len(), ())?;
+    for (ci, c) in m.captures.iter().enumerate() {
+        let captured_node = node.map(|_| c.node);
+        let capture = env.cons(
+            &query.capture_tags[c.index as usize],
+            captured_node
+        )?;
+        captures.set(ci, capture)?;
+    }
+    env.cons(m.pattern_index, captures)
+}
+
 // TODO: Make _query_cursor_captures accept a `capture_type` instead, e.g. node type, byte range.
 #[defun]
 fn _query_cursor_captures_1<'e>(
@@ -192,20 +739,33 @@ fn _query_cursor_captures_1<'e>(
     node: &RNode,
     text_function: Value<'e>,
 ) -> Result<Vector<'e>> {
-    let query = query.into_rust::<&RefCell<Query>>()?.borrow();
+    let query = query.into_rust::<&RefCell<Query>>()?.try_borrow()?;
     let raw = &query.raw;
+    QUERY_EXECUTIONS.incr();
+    let _timer = Timer::new(&QUERY_MICROS);
     let error = RefCell::new(None);
-    let captures = cursor.captures(
+    let inner = node.borrow()?;
+    let tree = node.clone_tree();
+    let env = text_function.env;
+    let deadline = cursor.start_execution();
+    let mut timed_out = false;
+    let captures = cursor.raw_mut(env)?.captures(
         raw,
-        node.borrow().clone(),
+        *inner,
         text_callback(text_function, &error),
     );
     let mut vec = vec![];
-    let env = text_function.env;
     for (m, capture_index) in captures {
+        if is_past(deadline) {
+            timed_out = true;
+            break;
+        }
         if let Some(error) = error.borrow_mut().take() {
             return Err(error);
         }
+        if !predicates_hold(raw, &m, text_function, &tree)? {
+            continue;
+        }
         let c = m.captures[capture_index];
         let capture = env.cons(
             &query.capture_tags[c.index as usize],
@@ -213,6 +773,7 @@ fn _query_cursor_captures_1<'e>(
         )?;
         vec.push((m.pattern_index, capture));
     }
+    cursor.timed_out = timed_out;
     // Prioritize captures from earlier patterns.
     vec.sort_unstable_by_key(|(i, _)| *i);
     let vector = env.make_vector(vec.len(), ())?;
@@ -229,20 +790,33 @@ fn _query_cursor_captures<'e>(
     node: &RNode,
     text_function: Value<'e>,
 ) -> Result<Vector<'e>> {
-    let query = query.into_rust::<&RefCell<Query>>()?.borrow();
+    let query = query.into_rust::<&RefCell<Query>>()?.try_borrow()?;
     let raw = &query.raw;
+    QUERY_EXECUTIONS.incr();
+    let _timer = Timer::new(&QUERY_MICROS);
     let error = RefCell::new(None);
-    let captures = cursor.captures(
+    let inner = node.borrow()?;
+    let tree = node.clone_tree();
+    let env = text_function.env;
+    let deadline = cursor.start_execution();
+    let mut timed_out = false;
+    let captures = cursor.raw_mut(env)?.captures(
         raw,
-        node.borrow().clone(),
+        *inner,
         text_callback(text_function, &error),
     );
     let mut vec = vec![];
-    let env = text_function.env;
     for (m, capture_index) in captures {
+        if is_past(deadline) {
+            timed_out = true;
+            break;
+        }
         if let Some(error) = error.borrow_mut().take() {
             return Err(error);
         }
+        if !predicates_hold(raw, &m, text_function, &tree)? {
+            continue;
+        }
         let c = m.captures[capture_index];
         let captured_node = node.map(|_| c.node);
         let capture = env.cons(
@@ -251,6 +825,7 @@ fn _query_cursor_captures<'e>(
         )?;
         vec.push(capture);
     }
+    cursor.timed_out = timed_out;
 
     // XXX
     let vector = env.make_vector(vec.len(), ())?;
@@ -260,19 +835,537 @@ fn _query_cursor_captures<'e>(
     Ok(vector)
 }
 
+/// Execute QUERY on NODE, within the range of byte positions from BEG to END, stopping
+/// after the first N captures.
+///
+/// This leaves CURSOR's byte range restricted to (BEG . END).
+#[defun]
+fn _query_cursor_first_n_captures<'e>(
+    cursor: &mut QueryCursor,
+    query: Value<'e>,
+    node: &RNode,
+    beg: BytePos,
+    end: BytePos,
+    n: usize,
+    text_function: Value<'e>,
+) -> Result<Vector<'e>> {
+    let query = query.into_rust::<&RefCell<Query>>()?.try_borrow()?;
+    let raw = &query.raw;
+    QUERY_EXECUTIONS.incr();
+    let _timer = Timer::new(&QUERY_MICROS);
+    let error = RefCell::new(None);
+    let env = text_function.env;
+    let deadline = cursor.start_execution();
+    let mut timed_out = false;
+    let raw_cursor = cursor.raw_mut(env)?;
+    raw_cursor.set_byte_range(beg.into()..end.into());
+    let inner = node.borrow()?;
+    let tree = node.clone_tree();
+    let captures = raw_cursor.captures(
+        raw,
+        *inner,
+        text_callback(text_function, &error),
+    );
+    let mut vec = Vec::with_capacity(n);
+    // Since the iterator is lazy, the query execution itself stops early.
+    for (m, capture_index) in captures {
+        if vec.len() == n {
+            break;
+        }
+        if is_past(deadline) {
+            timed_out = true;
+            break;
+        }
+        if let Some(error) = error.borrow_mut().take() {
+            return Err(error);
+        }
+        if !predicates_hold(raw, &m, text_function, &tree)? {
+            continue;
+        }
+        let c = m.captures[capture_index];
+        let captured_node = node.map(|_| c.node);
+        let capture = env.cons(
+            &query.capture_tags[c.index as usize],
+            captured_node
+        )?;
+        vec.push(capture);
+    }
+    cursor.timed_out = timed_out;
+    vec_to_vector(env, vec)
+}
+
+/// Sort RANGES, merging those that overlap, or are adjacent.
+fn merge_ranges(ranges: &mut Vec<(usize, usize)>) {
+    ranges.sort_unstable();
+    let mut merged: Vec<(usize, usize)> = Vec::with_capacity(ranges.len());
+    for &(beg, end) in ranges.iter() {
+        match merged.last_mut() {
+            Some(last) if beg <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((beg, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+/// Execute QUERY on NEW-TREE, only within the ranges that changed since OLD-TREE,
+/// and the EDITED-RANGES, a vector of (BEG-BYTE . END-BYTE). Return (INVALIDATED .
+/// CAPTURES). See `tsc-requery'.
+#[defun]
+fn _requery<'e>(
+    query: Value<'e>,
+    old_tree: Value<'e>,
+    new_tree: Borrowed<'e, Tree>,
+    edited_ranges: Vector<'e>,
+    text_function: Value<'e>,
+) -> Result<Value<'e>> {
+    let env = text_function.env;
+    let query = query.into_rust::<&RefCell<Query>>()?.try_borrow()?;
+    let raw = &query.raw;
+    let mut ranges: Vec<(usize, usize)> = {
+        let old_tree = old_tree.into_rust::<Borrowed<Tree>>()?.try_borrow()?;
+        let new_tree = new_tree.try_borrow()?;
+        old_tree.changed_ranges(&new_tree).map(|r| (r.start_byte, r.end_byte)).collect()
+    };
+    for i in 0..edited_ranges.len() {
+        let range: Value = edited_ranges.get(i)?;
+        let (beg, end): (BytePos, BytePos) = (range.car()?, range.cdr()?);
+        ranges.push((beg.into(), end.into()));
+    }
+    let root = RNode::new(new_tree.clone(), |tree| tree.root_node());
+    let inner = root.borrow()?;
+    let _timer = Timer::new(&QUERY_MICROS);
+    let error = RefCell::new(None);
+    let mut cursor = tree_sitter::QueryCursor::new();
+    let mut captures = vec![];
+    // A capture crossing the boundary of an invalidated range extends it, so that the captures
+    // can replace whatever the caller computed for the invalidated ranges. The extended ranges
+    // are queried again, until all captures are within them.
+    loop {
+        merge_ranges(&mut ranges);
+        captures.clear();
+        let mut extended = false;
+        for range in &mut ranges {
+            QUERY_EXECUTIONS.incr();
+            cursor.set_byte_range(range.0..range.1);
+            let found = cursor.captures(raw, *inner, text_callback(text_function, &error));
+            for (m, capture_index) in found {
+                if let Some(error) = error.borrow_mut().take() {
+                    return Err(error);
+                }
+                if !predicates_hold(raw, &m, text_function, new_tree)? {
+                    continue;
+                }
+                let c = m.captures[capture_index];
+                let (beg, end) = (c.node.start_byte(), c.node.end_byte());
+                if beg < range.0 || end > range.1 {
+                    *range = (range.0.min(beg), range.1.max(end));
+                    extended = true;
+                }
+                captures.push(c);
+            }
+        }
+        if let Some(error) = error.borrow_mut().take() {
+            return Err(error);
+        }
+        if !extended {
+            break;
+        }
+    }
+    let invalidated = env.make_vector(ranges.len(), ())?;
+    for (i, &(beg, end)) in ranges.iter().enumerate() {
+        let (beg, end): (BytePos, BytePos) = (beg.into(), end.into());
+        invalidated.set(i, env.cons(beg, end)?)?;
+    }
+    let vector = env.make_vector(captures.len(), ())?;
+    for (i, c) in captures.into_iter().enumerate() {
+        let captured_node = root.map(|_| c.node);
+        vector.set(i, env.cons(&query.capture_tags[c.index as usize], captured_node)?)?;
+    }
+    env.cons(invalidated, vector)
+}
+
+/// Return the byte range covered by the children of ROOT that intersect the range from BEG to
+/// END, extended to include that range.
+fn top_level_extent(root: Node, beg: usize, end: usize) -> (usize, usize) {
+    let (mut extent_beg, mut extent_end) = (beg, end);
+    let mut cursor = root.walk();
+    for child in root.children(&mut cursor) {
+        if child.start_byte() < end && child.end_byte() > beg {
+            extent_beg = extent_beg.min(child.start_byte());
+            extent_end = extent_end.max(child.end_byte());
+        }
+    }
+    (extent_beg, extent_end)
+}
+
+/// Return the highlight of the capture at index I of M, found by the job at index LAYER.
+#[inline]
+fn capture_highlight(layer: usize, m: &QueryMatch, i: usize) -> Highlight {
+    let c = m.captures[i];
+    Highlight {
+        beg: c.node.start_byte(),
+        end: c.node.end_byte(),
+        layer,
+        capture: c.index,
+        pattern: m.pattern_index,
+    }
+}
+
+/// Execute each of the JOBS on a separate thread, within the range of byte positions from BEG to
+/// END. Return the merged captures, as a vector of non-overlapping spans
+/// (BEG-BYTE END-BYTE . CAPTURE-TAG), sorted by position.
+///
+/// Each job is a (QUERY . TREE) pair, where TREE is typically one of a buffer's injected trees.
+/// Later jobs are painted over earlier ones. Within a job, inner captures take precedence over
+/// outer ones, and for the same node, the earlier pattern wins.
+///
+/// Since Lisp cannot be called from other threads, TEXT-FUNCTION is called only once beforehand,
+/// for the text of the top-level nodes that intersect the range. Text-based predicates are
+/// evaluated against that text. A job whose predicates need text outside of it is executed again
+/// on the main thread, calling TEXT-FUNCTION for each node. So is a job whose query uses custom
+/// predicates, which call Lisp. TEXT-FUNCTION is the same as for `tsc-query-captures'.
+#[defun]
+fn _query_captures_parallel<'e>(
+    env: &'e Env,
+    jobs: Vector<'e>,
+    beg: BytePos,
+    end: BytePos,
+    text_function: Value<'e>,
+) -> Result<Vector<'e>> {
+    let (beg, end): (usize, usize) = (beg.into(), end.into());
+    let mut queries = Vec::with_capacity(jobs.len());
+    let mut shared_trees = Vec::with_capacity(jobs.len());
+    let mut trees = Vec::with_capacity(jobs.len());
+    for i in 0..jobs.len() {
+        let job: Value = jobs.get(i)?;
+        queries.push(job.car::<&RefCell<Query>>()?.try_borrow()?);
+        let tree = job.cdr::<&Shared<Tree>>()?;
+        // Shallow copies, which can be sent to the worker threads.
+        trees.push(tree.try_borrow()?.clone());
+        shared_trees.push(tree);
+    }
+    QUERY_EXECUTIONS.add(queries.len() as u64);
+    let _timer = Timer::new(&QUERY_MICROS);
+    let (text_beg, text_end) = trees.iter().fold((beg, end), |(b, e), tree| {
+        let (tree_beg, tree_end) = top_level_extent(tree.root_node(), beg, end);
+        (b.min(tree_beg), e.max(tree_end))
+    });
+    let (lisp_beg, lisp_end): (BytePos, BytePos) = (text_beg.into(), text_end.into());
+    let text: String = text_function.call((lisp_beg, lisp_end))?.into_rust()?;
+    let text = text.as_bytes();
+    // The captures of each job, or None if the job needed text that was not fetched.
+    let layers: Vec<Option<Vec<Highlight>>> = thread::scope(|scope| {
+        let handles: Vec<_> = queries.iter().zip(&trees).enumerate().map(|(layer, (query, tree))| {
+            let raw: &tree_sitter::Query = &query.raw;
+            let custom = has_custom_predicates(raw);
+            scope.spawn(move || -> Result<Option<Vec<Highlight>>> {
+                if custom {
+                    return Ok(None);
+                }
+                let complete = Cell::new(true);
+                let node_text = |node: Node| {
+                    let text = node.start_byte().checked_sub(text_beg)
+                        .and_then(|b| text.get(b..node.end_byte() - text_beg));
+                    complete.set(complete.get() && text.is_some());
+                    text.unwrap_or(&[])
+                };
+                let mut cursor = tree_sitter::QueryCursor::new();
+                cursor.set_byte_range(beg..end);
+                let text_callback = |node: Node| iter::once(node_text(node));
+                let mut highlights = vec![];
+                for (m, i) in cursor.captures(raw, tree.root_node(), text_callback) {
+                    if general_predicates_hold(raw, &m, |node| Ok(node_text(node).to_vec()))? {
+                        highlights.push(capture_highlight(layer, &m, i));
+                    }
+                }
+                Ok(if complete.get() { Some(highlights) } else { None })
+            })
+        }).collect();
+        handles.into_iter().map(|h| {
+            h.join().map_err(|_| Internal("Failed to execute query on a worker thread"))?
+        }).collect::<Result<Vec<_>>>()
+    })?;
+    let mut merged = Vec::with_capacity(layers.len());
+    for (layer, highlights) in layers.into_iter().enumerate() {
+        if let Some(highlights) = highlights {
+            merged.push(highlights);
+            continue;
+        }
+        let raw: &tree_sitter::Query = &queries[layer].raw;
+        let error = RefCell::new(None);
+        let mut cursor = tree_sitter::QueryCursor::new();
+        cursor.set_byte_range(beg..end);
+        let captures = cursor.captures(
+            raw,
+            trees[layer].root_node(),
+            text_callback(text_function, &error),
+        );
+        let mut highlights = vec![];
+        for (m, i) in captures {
+            if let Some(error) = error.borrow_mut().take() {
+                return Err(error);
+            }
+            if predicates_hold(raw, &m, text_function, shared_trees[layer])? {
+                highlights.push(capture_highlight(layer, &m, i));
+            }
+        }
+        if let Some(error) = error.into_inner() {
+            return Err(error);
+        }
+        merged.push(highlights);
+    }
+    let spans = spans::merge(merged, beg, end);
+    let vector = env.make_vector(spans.len(), ())?;
+    for (i, h) in spans.iter().enumerate() {
+        let (span_beg, span_end): (BytePos, BytePos) = (h.beg.into(), h.end.into());
+        let tag = &queries[h.layer].capture_tags[h.capture as usize];
+        vector.set(i, env.cons(span_beg, env.cons(span_end, tag)?)?)?;
+    }
+    Ok(vector)
+}
+
 /// Limit CURSOR's query executions to the range of byte positions, from BEG to END.
+///
+/// Only the matches that intersect the range are returned, e.g. those for the
+/// visible part of a window. The range stays in effect for later executions with
+/// CURSOR, until it is changed again.
 #[defun]
-fn _query_cursor_set_byte_range(cursor: &mut QueryCursor, beg: BytePos, end: BytePos) -> Result<()> {
-    cursor.set_byte_range(beg.into()..end.into());
+fn query_cursor_set_byte_range(
+    env: &Env,
+    cursor: &mut QueryCursor,
+    beg: BytePos,
+    end: BytePos,
+) -> Result<()> {
+    cursor.raw_mut(env)?.set_byte_range(beg.into()..end.into());
     Ok(())
 }
 
 /// Limit CURSOR's query executions to the point range, from BEG to END.
 ///
 /// A "point" in this context is a (LINE-NUMBER . BYTE-COLUMN) pair. See
-/// `tsc-parse-chunks' for a more detailed explanation.
+/// `tsc-parse-chunks' for a more detailed explanation. Like the byte range, the point
+/// range stays in effect for later executions with CURSOR.
 #[defun]
-fn _query_cursor_set_point_range(cursor: &mut QueryCursor, beg: Point, end: Point) -> Result<()> {
-    cursor.set_point_range(beg.into()..end.into());
+fn query_cursor_set_point_range(
+    env: &Env,
+    cursor: &mut QueryCursor,
+    beg: Point,
+    end: Point,
+) -> Result<()> {
+    cursor.raw_mut(env)?.set_point_range(beg.into()..end.into());
     Ok(())
 }
+
+/// Set the maximum number of in-progress matches of CURSOR to LIMIT.
+///
+/// When a query execution needs more, the oldest in-progress matches are dropped,
+/// and `tsc-query-cursor-did-exceed-match-limit-p' returns t afterwards. This
+/// bounds the time and memory used by pathological patterns on huge files.
+#[defun]
+fn query_cursor_set_match_limit(env: &Env, cursor: &mut QueryCursor, limit: u32) -> Result<()> {
+    cursor.raw_mut(env)?.set_match_limit(limit);
+    Ok(())
+}
+
+/// Return the maximum number of in-progress matches of CURSOR.
+/// See `tsc-query-cursor-set-match-limit'.
+#[defun]
+fn query_cursor_match_limit(cursor: &QueryCursor) -> Result<u32> {
+    Ok(cursor.raw().match_limit())
+}
+
+/// Return t if CURSOR's latest query execution dropped some in-progress matches,
+/// because there were more than its match limit. Its results are then incomplete.
+#[defun]
+fn query_cursor_did_exceed_match_limit_p(cursor: &QueryCursor) -> Result<bool> {
+    Ok(cursor.raw().did_exceed_match_limit())
+}
+
+/// Limit each of CURSOR's query executions by `tsc-query-matches',
+/// `tsc-query-captures', and `tsc-first-n-captures' to MICROS microseconds, or
+/// remove the limit if MICROS is nil.
+///
+/// When an execution takes longer, it is aborted, returning the results found so
+/// far, and `tsc-query-cursor-did-time-out-p' returns t afterwards. Together with
+/// the match limit, this gives hard upper bounds to queries run during redisplay.
+/// The time is checked between matches, so a single match can still overrun it.
+#[defun]
+fn query_cursor_set_time_budget(cursor: &mut QueryCursor, micros: Option<u64>) -> Result<()> {
+    cursor.time_budget = micros.map(Duration::from_micros);
+    Ok(())
+}
+
+/// Return CURSOR's time budget in microseconds, or nil if it has none.
+/// See `tsc-query-cursor-set-time-budget'.
+#[defun]
+fn query_cursor_time_budget(cursor: &QueryCursor) -> Result<Option<u64>> {
+    Ok(cursor.time_budget.map(|budget| budget.as_micros() as u64))
+}
+
+/// Return t if CURSOR's latest query execution was aborted, because it exceeded the
+/// time budget. Its results are then incomplete.
+#[defun]
+fn query_cursor_did_time_out_p(cursor: &QueryCursor) -> Result<bool> {
+    Ok(cursor.timed_out)
+}
+
+// -------------------------------------------------------------------------------------------------
+// Streaming
+
+/// The text provider of a streaming execution. Since it outlives the call that creates it, it
+/// reads the text function, bound to the `Env` of the ongoing call to
+/// `tsc-query-cursor-next-match`, from a shared cell.
+type StreamText = Box<dyn FnMut(Node) -> iter::Once<Vec<u8>>>;
+
+/// An execution of a query whose matches are found one at a time, by
+/// `tsc-query-cursor-next-match`.
+struct Stream {
+    // Declared first, so that it is dropped before the cursor and the query it refers to.
+    matches: QueryMatches<'static, 'static, StreamText>,
+    raw: Box<tree_sitter::QueryCursor>,
+    /// The compiled query being executed. While it is shared, the query cannot be released or
+    /// changed.
+    _raw_query: Rc<tree_sitter::Query>,
+    /// The Lisp query object being executed, kept alive by this reference, and the text function.
+    /// They are freed when the execution ends.
+    refs: Option<(GlobalRef, GlobalRef)>,
+    node: RNode,
+    /// A copy of NODE's tree, sharing its nodes. While it is alive, editing the tree copies the
+    /// nodes being traversed, instead of modifying them in place.
+    _snapshot: Tree,
+    text_function: Rc<Cell<Option<Value<'static>>>>,
+    error: Rc<RefCell<Option<Error>>>,
+}
+
+impl Stream {
+    /// End the execution, returning the underlying cursor.
+    fn finish(mut self, env: &Env) -> Result<tree_sitter::QueryCursor> {
+        if let Some((query, text_function)) = self.refs.take() {
+            query.free(env)?;
+            text_function.free(env)?;
+        }
+        // The placeholder is dropped along with the rest of the execution.
+        Ok(mem::replace(&mut *self.raw, tree_sitter::QueryCursor::new()))
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        // Without an `Env`, e.g. when the cursor is garbage-collected, the references are freed
+        // later.
+        if let Some((query, text_function)) = self.refs.take() {
+            types::free_later(query);
+            types::free_later(text_function);
+        }
+    }
+}
+
+/// Start executing QUERY on NODE with CURSOR, without finding any match yet.
+///
+/// The matches are then returned one at a time by `tsc-query-cursor-next-match',
+/// which is cheaper than `tsc-query-matches' when only the first few matches are
+/// needed. TEXT-FUNCTION is the same as for `tsc-query-matches'. It is called
+/// during `tsc-query-cursor-next-match'.
+///
+/// Editing NODE's tree does not affect the ongoing execution. Starting another
+/// execution with CURSOR, or changing its settings, ends it. Until then, QUERY
+/// cannot be released or changed.
+#[defun]
+fn query_cursor_exec<'e>(
+    cursor: &mut QueryCursor,
+    query: Value<'e>,
+    node: &RNode,
+    text_function: Value<'e>,
+) -> Result<()> {
+    let env = text_function.env;
+    types::free_dropped_refs(env)?;
+    let mut raw = Box::new(mem::replace(cursor.raw_mut(env)?, tree_sitter::QueryCursor::new()));
+    let raw_query = query.into_rust::<&RefCell<Query>>()?.try_borrow()?.raw.clone();
+    let inner = node.borrow()?;
+    QUERY_EXECUTIONS.incr();
+    let current: Rc<Cell<Option<Value<'static>>>> = Rc::new(Cell::new(None));
+    let error = Rc::new(RefCell::new(None));
+    let text: StreamText = {
+        let (current, error) = (current.clone(), error.clone());
+        Box::new(move |child: Node| {
+            let text = match current.get() {
+                Some(text_function) => text_function
+                    .call((child.lisp_start_byte(), child.lisp_end_byte()))
+                    .and_then(|v| v.into_rust()),
+                None => Err(Internal("Query text was requested outside of a call").into()),
+            };
+            iter::once(text.unwrap_or_else(|e| {
+                error.borrow_mut().replace(e);
+                "".to_owned()
+            }).into_bytes())
+        })
+    };
+    // The cursor is boxed, and the compiled query is shared with the stream, so they do not move
+    // or disappear while the stream uses them.
+    let matches = unsafe {
+        let raw: &'static mut tree_sitter::QueryCursor = mem::transmute(raw.as_mut());
+        let node: Node<'static> = mem::transmute(*inner);
+        raw.matches(types::erase_lifetime(&*raw_query), node, text)
+    };
+    cursor.stream = Some(Stream {
+        matches,
+        raw,
+        _raw_query: raw_query,
+        refs: Some((query.make_global_ref(), text_function.make_global_ref())),
+        node: node.clone(),
+        _snapshot: node.clone_tree().try_borrow()?.clone(),
+        text_function: current,
+        error,
+    });
+    Ok(())
+}
+
+/// Return the next match of the execution started by `tsc-query-cursor-exec' on
+/// CURSOR, in the same form as those returned by `tsc-query-matches'.
+///
+/// Return nil if there are no more matches, or if the execution was ended.
+#[defun]
+fn query_cursor_next_match<'e>(
+    env: &'e Env,
+    cursor: &mut QueryCursor,
+) -> Result<Option<Value<'e>>> {
+    let stream = match cursor.stream.as_mut() {
+        Some(stream) => stream,
+        None => return Ok(None),
+    };
+    let (query_value, text_function) = match &stream.refs {
+        Some((query, text_function)) => (query.bind(env), text_function.bind(env)),
+        None => return Err(Internal("Streaming execution has no query").into()),
+    };
+    let query = query_value.into_rust::<&RefCell<Query>>()?.try_borrow()?;
+    let found = {
+        let _timer = Timer::new(&QUERY_MICROS);
+        stream.text_function.set(Some(unsafe { mem::transmute(text_function) }));
+        let tree = stream.node.clone_tree();
+        let mut found = Ok(None);
+        while let Some(m) = stream.matches.next() {
+            if let Some(error) = stream.error.borrow_mut().take() {
+                found = Err(error);
+                break;
+            }
+            match predicates_hold(&query.raw, &m, text_function, &tree) {
+                Ok(true) => {
+                    found = match_to_lisp(env, &query, &stream.node, &m).map(Some);
+                    break;
+                }
+                Ok(false) => continue,
+                Err(error) => {
+                    found = Err(error);
+                    break;
+                }
+            }
+        }
+        stream.text_function.set(None);
+        found?
+    };
+    if found.is_none() {
+        drop(query);
+        cursor.raw_mut(env)?;
+    }
+    Ok(found)
+}
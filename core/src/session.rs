@@ -0,0 +1,393 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use emacs::{defun, Env, IntoLisp, Result, Value};
+use tree_sitter::{Parser, Tree};
+
+use crate::{
+    types::{BytePos, Point, Range, Shared},
+    lang::Language,
+    node::RNode,
+    parser,
+    tree::{self, Borrowed},
+    instrument::{self, Timer, PARSE_MICROS},
+    live::{Live, SESSIONS},
+    channel,
+    error,
+};
+
+// -------------------------------------------------------------------------------------------------
+// Checksum
+
+/// FNV-1a hash of a document's text, computed incrementally, chunk by chunk.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Checksum {
+    len: usize,
+    hash: u64,
+}
+
+impl Checksum {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    /// Compute the checksum of the whole text generated by INPUT_FUNCTION. See `parse_chunks`.
+    fn of_input(input_function: Value) -> Result<Self> {
+        let mut checksum = Self { len: 0, hash: Self::OFFSET_BASIS };
+        for_each_chunk(input_function, |chunk| {
+            for &byte in chunk.as_bytes() {
+                checksum.hash = (checksum.hash ^ byte as u64).wrapping_mul(Self::PRIME);
+            }
+            checksum.len += chunk.len();
+        })?;
+        Ok(checksum)
+    }
+}
+
+/// Call F with each chunk of the whole text generated by INPUT_FUNCTION, in order. See
+/// `parse_chunks`.
+pub(crate) fn for_each_chunk<F: FnMut(&str)>(input_function: Value, mut f: F) -> Result<()> {
+    for_each_chunk_from(input_function, 0, tree_sitter::Point { row: 0, column: 0 }, |chunk| {
+        f(chunk);
+        true
+    })
+}
+
+/// Call F with each chunk of the text generated by INPUT_FUNCTION, from byte offset BYTE, at
+/// POINT, in order, until F returns false.
+pub(crate) fn for_each_chunk_from<F: FnMut(&str) -> bool>(
+    input_function: Value,
+    mut byte: usize,
+    mut point: tree_sitter::Point,
+    mut f: F,
+) -> Result<()> {
+    loop {
+        let bytepos: BytePos = byte.into();
+        let lisp_point: Point = point.into();
+        let chunk: String = input_function.call((
+            bytepos, lisp_point.line_number(), lisp_point.byte_column(),
+        ))?.into_rust()?;
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        for &b in chunk.as_bytes() {
+            if b == b'\n' {
+                point.row += 1;
+                point.column = 0;
+            } else {
+                point.column += 1;
+            }
+        }
+        byte += chunk.len();
+        if !f(&chunk) {
+            return Ok(());
+        }
+    }
+}
+
+// -------------------------------------------------------------------------------------------------
+// Session
+
+/// A parser, together with the latest tree it produced for a document.
+pub(crate) struct Session {
+    pub(crate) parser: Parser,
+    pub(crate) tree: Option<Shared<Tree>>,
+    checksummed: bool,
+    checksum: Option<Checksum>,
+    /// Number of parses that led to the current tree.
+    generation: u64,
+    /// The byte range parsed by the latest parse, if it did not parse everything.
+    window: Option<(usize, usize)>,
+    /// The valid tokens computed for the tree whose id is the first element, by byte offset.
+    /// See `completion::_session_valid_tokens_at`.
+    pub(crate) valid_tokens: Option<(usize, HashMap<usize, Vec<&'static str>>)>,
+    _live: Live,
+}
+
+impl_pred!(session_p, &RefCell<Session>);
+
+impl Session {
+    /// Return the session's language.
+    pub(crate) fn language(&self) -> Option<tree_sitter::Language> {
+        self.parser.language()
+    }
+
+    /// Return a copy of the session's latest tree.
+    pub(crate) fn tree_copy(&self) -> Result<Option<Tree>> {
+        match &self.tree {
+            Some(tree) => Ok(Some(tree.try_borrow()?.clone())),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Create a new parsing session for LANGUAGE.
+///
+/// A session holds a parser, and the latest syntax tree it produced. Each parse
+/// reuses the previous tree, which must have been kept in sync with the source
+/// code's changes through `tsc-edit-tree'.
+///
+/// If CHECKSUMMED is non-nil, the session also records a checksum of the text of
+/// each parse, so that `tsc-session-in-sync-p' can detect changes that were not
+/// passed to `tsc-edit-tree' (e.g. because `inhibit-modification-hooks' was bound).
+/// This requires reading the whole text after each parse.
+#[defun(user_ptr)]
+fn make_session(
+    language: Language,
+    checksummed: Option<Value>,
+    env: &Env,
+) -> Result<Session> {
+    let mut parser = Parser::new();
+    parser.set_language(language.into()).or_else(|err| error::signal_lang_abi_error(env, err))?;
+    Ok(Session {
+        parser,
+        tree: None,
+        checksummed: checksummed.is_some(),
+        checksum: None,
+        generation: 0,
+        window: None,
+        valid_tokens: None,
+        _live: Live::new(&SESSIONS),
+    })
+}
+
+/// Return SESSION's language.
+#[defun]
+fn session_language(session: &Session) -> Result<Option<Language>> {
+    Ok(session.parser.language().map(|l| l.into()))
+}
+
+/// Return SESSION's latest syntax tree, or nil if it hasn't parsed anything yet.
+#[defun]
+fn session_tree(session: &Session) -> Result<Option<Shared<Tree>>> {
+    Ok(session.tree.clone())
+}
+
+/// Parse source code chunks generated by INPUT-FUNCTION with SESSION; return the
+/// new tree, which also becomes SESSION's latest tree.
+///
+/// See `tsc-parse-chunks' for the meaning of INPUT-FUNCTION.
+#[defun]
+fn session_parse(session: &mut Session, input_function: Value) -> Result<Shared<Tree>> {
+    let tree = {
+        let old_tree = match &session.tree {
+            Some(tree) => Some(tree.try_borrow()?),
+            None => None,
+        };
+        parser::parse_input(&mut session.parser, input_function, old_tree.as_deref(), None)?
+    };
+    let tree = parser::shared_tree(tree);
+    session.tree = Some(tree.clone());
+    session.generation += 1;
+    session.window = None;
+    if session.checksummed {
+        session.checksum = Some(Checksum::of_input(input_function)?);
+    }
+    Ok(tree)
+}
+
+/// Parse only WINDOW, a range, of the source code generated by INPUT-FUNCTION with
+/// SESSION; return the new tree, which also becomes SESSION's latest tree.
+///
+/// Nodes outside WINDOW are absent from the tree, but the positions of those inside
+/// are still relative to the whole source code. This makes huge files parseable
+/// around the region being looked at, at the cost of more errors near WINDOW's
+/// edges. See `tsc-session-parse-visible'.
+#[defun]
+fn _session_parse_window<'e>(
+    session: &mut Session,
+    input_function: Value<'e>,
+    window: Value<'e>,
+) -> Result<Shared<Tree>> {
+    let range: Range = window.into_rust()?;
+    session.parser.set_included_ranges(&[range.into()]).or_else(|err| {
+        error::signal_invalid_ranges(window.env, err.0, window)
+    })?;
+    let tree = session_parse(session, input_function);
+    session.parser.set_included_ranges(&[]).ok();
+    let tree = tree?;
+    tree::record_included_ranges(&tree, &[range.into()]);
+    session.window = Some((range.0.start_byte, range.0.end_byte));
+    Ok(tree)
+}
+
+/// Return the byte range (BEG . END) parsed by SESSION's latest parse, or nil if it
+/// parsed the whole source code. See `tsc--session-parse-window'.
+#[defun]
+fn session_window(env: &Env, session: &Session) -> Result<Option<Value>> {
+    match session.window {
+        Some((beg, end)) => {
+            let (beg, end): (BytePos, BytePos) = (beg.into(), end.into());
+            Ok(Some(env.cons(beg, end)?))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Start parsing TEXT on a worker thread, with SESSION's language, reusing SESSION's
+/// latest tree, which must be in sync with TEXT. Return the operation's id, which
+/// should be passed to `tsc-async-then'. The result is a tree.
+///
+/// SESSION is not changed. The tree should be installed with
+/// `tsc--session-install-tree', if the source code was not changed in the
+/// meantime. See `tsc-session-parse-async'.
+#[defun]
+fn _session_parse_async(session: &Session, text: String) -> Result<u64> {
+    let language = session.parser.language();
+    let old_tree = match &session.tree {
+        Some(tree) => Some(tree.try_borrow()?.clone()),
+        None => None,
+    };
+    Ok(channel::spawn(move || {
+        let mut parser = Parser::new();
+        if let Some(language) = language {
+            // If this fails, the parser has no language, which is reported as the failure's reason.
+            parser.set_language(language).ok();
+        }
+        let bytes = text.len();
+        let tree = {
+            let _timer = Timer::new(&PARSE_MICROS);
+            parser.parse(text, old_tree.as_ref())
+        };
+        instrument::record_parse(bytes, old_tree.is_some());
+        tree.ok_or_else(|| parser::failure_reason(&parser))
+    }, |env, tree| match tree {
+        Ok(tree) => parser::shared_tree(tree).into_lisp(env),
+        Err(reason) => error::signal_parse_failed(env, reason),
+    }))
+}
+
+/// Make TREE SESSION's latest tree, as if it was produced by a parse of the text
+/// generated by INPUT-FUNCTION. TREE must have been parsed from that text.
+#[defun]
+fn _session_install_tree(
+    session: &mut Session,
+    tree: Borrowed<Tree>,
+    input_function: Value,
+) -> Result<()> {
+    if session.parser.language() != Some(tree.try_borrow()?.language()) {
+        let env = input_function.env;
+        return env.signal(error::tsc_error, (
+            "Tree is of a different language",
+            error::_kind, error::kind(env, "language-mismatch")?,
+        ));
+    }
+    session.tree = Some(tree.clone());
+    session.generation += 1;
+    session.window = None;
+    if session.checksummed {
+        session.checksum = Some(Checksum::of_input(input_function)?);
+    }
+    Ok(())
+}
+
+/// Return t if the text generated by INPUT-FUNCTION is the text of SESSION's latest
+/// parse, as recorded by its checksum. Return nil otherwise, in which case the
+/// session's tree should not be trusted, and the text should be fully reparsed.
+///
+/// Since the checksum covers the text of the latest parse, this is nil if the
+/// text changed since, even through `tsc-edit-tree'. Use it before editing, or
+/// right before a parse.
+///
+/// SESSION must have been created with CHECKSUMMED non-nil. See `tsc-make-session'.
+#[defun]
+fn _session_in_sync_p(session: &Session, input_function: Value) -> Result<bool> {
+    if !session.checksummed {
+        let env = input_function.env;
+        return env.signal(error::tsc_error, (
+            "Session does not record checksums",
+            error::_kind, error::kind(env, "no-checksum")?,
+        ));
+    }
+    match session.checksum {
+        None => Ok(false),
+        Some(checksum) => Ok(checksum == Checksum::of_input(input_function)?),
+    }
+}
+
+/// Return the deepest named node of SESSION's latest tree that contains BYTEPOS.
+#[defun]
+fn _session_named_node_at(session: &Session, bytepos: BytePos) -> Result<Option<RNode>> {
+    let byte: usize = bytepos.into();
+    let tree = match &session.tree {
+        Some(tree) => tree.clone(),
+        None => return Ok(None),
+    };
+    Ok(Some(RNode::new(tree, |tree| {
+        let root = tree.root_node();
+        root.named_descendant_for_byte_range(byte, byte).unwrap_or(root)
+    })))
+}
+
+// -------------------------------------------------------------------------------------------------
+// Snapshots
+
+/// The state of a session at some point: a copy of its tree, and the checksum and generation
+/// that go with it.
+pub(crate) struct SessionSnapshot {
+    tree: Option<Tree>,
+    language: Option<tree_sitter::Language>,
+    checksum: Option<Checksum>,
+    generation: u64,
+    window: Option<(usize, usize)>,
+}
+
+/// Return the number of parses that led to SESSION's latest tree. Restoring a
+/// snapshot restores its generation as well.
+#[defun]
+fn session_generation(session: &Session) -> Result<u64> {
+    Ok(session.generation)
+}
+
+/// Return a snapshot of SESSION's state, which can later be restored with
+/// `tsc-session-restore', e.g. when undoing a large group of changes.
+///
+/// The snapshot holds a copy of SESSION's latest tree, which is cheap, since
+/// the copy shares the tree's nodes. If the tree was edited since the latest
+/// parse, the snapshot includes these edits.
+#[defun(user_ptr)]
+fn session_snapshot(session: &Session) -> Result<SessionSnapshot> {
+    let tree = match &session.tree {
+        Some(tree) => Some(tree.try_borrow()?.clone()),
+        None => None,
+    };
+    Ok(SessionSnapshot {
+        tree,
+        language: session.parser.language(),
+        checksum: session.checksum,
+        generation: session.generation,
+        window: session.window,
+    })
+}
+
+/// Return the generation of SNAPSHOT's session when it was taken. See
+/// `tsc-session-generation'.
+#[defun]
+fn session_snapshot_generation(snapshot: &SessionSnapshot) -> Result<u64> {
+    Ok(snapshot.generation)
+}
+
+/// Restore SNAPSHOT, taken by `tsc-session-snapshot', into SESSION; return the
+/// restored tree, which becomes SESSION's latest tree.
+///
+/// The source code must be back to its state when SNAPSHOT was taken, e.g.
+/// after undoing the changes made since. The next parse then reuses the
+/// restored tree, instead of replaying the edits, or parsing everything again.
+/// SNAPSHOT is not consumed, so it can be restored several times.
+#[defun]
+fn session_restore(
+    env: &Env,
+    session: &mut Session,
+    snapshot: &SessionSnapshot,
+) -> Result<Option<Shared<Tree>>> {
+    if snapshot.language != session.parser.language() {
+        return env.signal(error::tsc_error, (
+            "Snapshot is of a different language",
+            error::_kind, error::kind(env, "language-mismatch")?,
+        ));
+    }
+    let tree = snapshot.tree.clone().map(parser::shared_tree);
+    session.tree = tree.clone();
+    session.checksum = snapshot.checksum;
+    session.generation = snapshot.generation;
+    session.window = snapshot.window;
+    Ok(tree)
+}
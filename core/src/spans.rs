@@ -0,0 +1,123 @@
+use std::cmp::Reverse;
+
+/// A highlighted byte range. Its tag is the capture at index `capture` of the query of layer
+/// `layer`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Highlight {
+    pub(crate) beg: usize,
+    pub(crate) end: usize,
+    pub(crate) layer: usize,
+    pub(crate) capture: u32,
+    /// Index of the pattern that captured the range, which decides between captures of the
+    /// same range.
+    pub(crate) pattern: usize,
+}
+
+impl Highlight {
+    #[inline]
+    fn same_tag(&self, other: &Highlight) -> bool {
+        self.layer == other.layer && self.capture == other.capture
+    }
+}
+
+/// Append the range from BEG to END, highlighted like H, to SPANS, merging it with the last
+/// span if they are adjacent, and have the same tag.
+fn push_span(spans: &mut Vec<Highlight>, beg: usize, end: usize, h: &Highlight) {
+    if beg >= end {
+        return;
+    }
+    if let Some(last) = spans.last_mut() {
+        if last.end == beg && last.same_tag(h) {
+            last.end = end;
+            return;
+        }
+    }
+    spans.push(Highlight { beg, end, ..*h });
+}
+
+/// Pop the highlights of STACK that end at or before LIMIT, appending what is left of them
+/// after POS to SPANS.
+fn close(stack: &mut Vec<Highlight>, spans: &mut Vec<Highlight>, pos: &mut usize, limit: usize) {
+    while let Some(top) = stack.last().copied() {
+        if top.end > limit {
+            break;
+        }
+        push_span(spans, *pos, top.end, &top);
+        *pos = top.end;
+        stack.pop();
+    }
+}
+
+/// Turn HIGHLIGHTS, which may nest, into sorted, non-overlapping spans. Inner highlights take
+/// precedence over outer ones. For highlights of the same range, the earliest pattern wins.
+fn flatten(mut highlights: Vec<Highlight>) -> Vec<Highlight> {
+    highlights.sort_by_key(|h| (h.beg, Reverse(h.end), h.pattern));
+    let mut spans = vec![];
+    // The highlights containing the current position, innermost last. Their ends are clipped
+    // to their parents', so that they nest properly.
+    let mut stack: Vec<Highlight> = vec![];
+    let mut pos = 0;
+    let mut previous: Option<(usize, usize)> = None;
+    for h in highlights {
+        if previous == Some((h.beg, h.end)) {
+            continue;
+        }
+        previous = Some((h.beg, h.end));
+        close(&mut stack, &mut spans, &mut pos, h.beg);
+        if let Some(top) = stack.last() {
+            push_span(&mut spans, pos, h.beg, top);
+        }
+        pos = h.beg;
+        let end = stack.last().map_or(h.end, |top| h.end.min(top.end));
+        stack.push(Highlight { end, ..h });
+    }
+    close(&mut stack, &mut spans, &mut pos, usize::MAX);
+    spans
+}
+
+/// Paint TOP over BASE. Both must be sorted and non-overlapping.
+fn overlay(base: &[Highlight], top: &[Highlight]) -> Vec<Highlight> {
+    let mut spans = Vec::with_capacity(base.len() + top.len());
+    // Position up to which BASE was painted over.
+    let mut pos = 0;
+    let mut i = 0;
+    for t in top {
+        while let Some(b) = base.get(i) {
+            if b.beg >= t.beg {
+                break;
+            }
+            push_span(&mut spans, b.beg.max(pos), b.end.min(t.beg), b);
+            if b.end > t.beg {
+                break;
+            }
+            i += 1;
+        }
+        push_span(&mut spans, t.beg, t.end, t);
+        pos = t.end;
+        while base.get(i).map_or(false, |b| b.end <= t.end) {
+            i += 1;
+        }
+    }
+    for b in &base[i..] {
+        push_span(&mut spans, b.beg.max(pos), b.end, b);
+    }
+    spans
+}
+
+/// Merge the highlights of LAYERS into sorted, non-overlapping spans, clipped to the range from
+/// BEG to END. Later layers are painted over earlier ones. Within a layer, highlights are
+/// flattened as described in `flatten`.
+pub(crate) fn merge<I>(layers: I, beg: usize, end: usize) -> Vec<Highlight>
+where
+    I: IntoIterator<Item = Vec<Highlight>>,
+{
+    let mut spans: Vec<Highlight> = vec![];
+    for highlights in layers {
+        let top = flatten(highlights);
+        spans = if spans.is_empty() { top } else { overlay(&spans, &top) };
+    }
+    spans.into_iter()
+        .filter(|h| h.end > beg && h.beg < end)
+        .map(|h| Highlight { beg: h.beg.max(beg), end: h.end.min(end), ..h })
+        .collect()
+}
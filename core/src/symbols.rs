@@ -0,0 +1,158 @@
+use std::{cell::RefCell, cmp::Reverse};
+
+use emacs::{defun, Env, IntoLisp, Result, Value};
+use tree_sitter::Node;
+
+use crate::{
+    types,
+    node::LispUtils,
+    query::{self, Query},
+    session::Session,
+};
+
+emacs::use_symbols! {
+    _name            => ":name"
+    _detail          => ":detail"
+    _symbol_kind     => ":kind"
+    _range           => ":range"
+    _selection_range => ":selection-range"
+    _children        => ":children"
+}
+
+/// A definition captured by a symbols query, together with the definitions nested in it.
+struct DocumentSymbol<'t> {
+    definition: Node<'t>,
+    kind: &'t str,
+    name: Node<'t>,
+    detail: Option<Node<'t>>,
+    children: Vec<DocumentSymbol<'t>>,
+}
+
+impl<'t> DocumentSymbol<'t> {
+    fn contains(&self, other: &DocumentSymbol) -> bool {
+        self.definition.start_byte() <= other.definition.start_byte()
+            && other.definition.end_byte() <= self.definition.end_byte()
+    }
+
+    /// Nest SYMBOLS, which must be sorted by position, outer ones first. Return the outermost
+    /// symbols.
+    fn nest(symbols: Vec<DocumentSymbol<'t>>) -> Vec<DocumentSymbol<'t>> {
+        let mut roots = vec![];
+        // The symbols enclosing the current one.
+        let mut stack: Vec<DocumentSymbol<'t>> = vec![];
+        let pop = |stack: &mut Vec<DocumentSymbol<'t>>, roots: &mut Vec<DocumentSymbol<'t>>| {
+            if let Some(symbol) = stack.pop() {
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(symbol),
+                    None => roots.push(symbol),
+                }
+            }
+        };
+        for symbol in symbols {
+            while stack.last().map_or(false, |top| !top.contains(&symbol)) {
+                pop(&mut stack, &mut roots);
+            }
+            stack.push(symbol);
+        }
+        while !stack.is_empty() {
+            pop(&mut stack, &mut roots);
+        }
+        roots
+    }
+
+    fn into_lisp<'e>(self, env: &'e Env, text_function: Value<'e>) -> Result<Value<'e>> {
+        let text = |node: Node| -> Result<Value<'e>> {
+            text_function.call((node.lisp_start_byte(), node.lisp_end_byte()))
+        };
+        let range = |node: Node| env.cons(node.lisp_start_byte(), node.lisp_end_byte());
+        let detail = match self.detail {
+            Some(detail) => text(detail)?,
+            None => ().into_lisp(env)?,
+        };
+        let children = self.children.into_iter()
+            .map(|child| child.into_lisp(env, text_function))
+            .collect::<Result<_>>()?;
+        types::list(env, vec![
+            _name.bind(env), text(self.name)?,
+            _detail.bind(env), detail,
+            _symbol_kind.bind(env), env.intern(self.kind)?,
+            _range.bind(env), range(self.definition)?,
+            _selection_range.bind(env), range(self.name)?,
+            _children.bind(env), types::list(env, children)?,
+        ])
+    }
+}
+
+/// Return the symbols defined in SESSION's latest tree, as a nested list of plists,
+/// analogous to LSP's DocumentSymbol:
+///
+/// :name             The symbol's name.
+/// :detail           Additional text, e.g. a function's signature, or nil.
+/// :kind             The symbol's kind, e.g. `function'.
+/// :range            The (BEG . END) byte positions of the whole definition.
+/// :selection-range  The (BEG . END) byte positions of the name.
+/// :children         The symbols defined inside this one.
+///
+/// SYMBOLS-QUERY's patterns should capture a definition's name as `@name', optionally
+/// its detail as `@detail', and the definition itself with a capture named after the
+/// symbol's kind, for example:
+///
+///   (function_item name: (identifier) @name parameters: (_) @detail) @function
+///
+/// TEXT-FUNCTION is called with 2 byte positions, and should return the source code
+/// between them. It is also used by text-based predicates.
+#[defun]
+fn _session_document_symbols<'e>(
+    session: &Session,
+    symbols_query: &Query,
+    text_function: Value<'e>,
+) -> Result<Value<'e>> {
+    let env = text_function.env;
+    let shared = match &session.tree {
+        Some(tree) => tree,
+        None => return ().into_lisp(env),
+    };
+    let tree = shared.try_borrow()?;
+    let names = symbols_query.raw.capture_names();
+    let index_of = |name: &str| names.iter().position(|n| n == name);
+    let (name_index, detail_index) = (index_of("name"), index_of("detail"));
+    let mut symbols = vec![];
+    let error = RefCell::new(None);
+    let mut cursor = tree_sitter::QueryCursor::new();
+    let matches = cursor.matches(
+        &symbols_query.raw,
+        tree.root_node(),
+        query::text_callback(text_function, &error),
+    );
+    for m in matches {
+        if !query::predicates_hold(&symbols_query.raw, &m, text_function, shared)? {
+            continue;
+        }
+        let captured = |index| m.captures.iter().find(|c| Some(c.index as usize) == index);
+        let definition = m.captures.iter().find(|c| {
+            let index = Some(c.index as usize);
+            index != name_index && index != detail_index
+        });
+        if let (Some(definition), Some(name)) = (definition, captured(name_index)) {
+            symbols.push(DocumentSymbol {
+                definition: definition.node,
+                kind: &names[definition.index as usize],
+                name: name.node,
+                detail: captured(detail_index).map(|c| c.node),
+                children: vec![],
+            });
+        }
+    }
+    if let Some(error) = error.borrow_mut().take() {
+        return Err(error);
+    }
+    symbols.sort_by_key(|s| {
+        (s.definition.start_byte(), Reverse(s.definition.end_byte()))
+    });
+    symbols.dedup_by_key(|s| s.definition.id());
+
+    let roots = DocumentSymbol::nest(symbols).into_iter()
+        .map(|symbol| symbol.into_lisp(env, text_function))
+        .collect::<Result<_>>()?;
+    types::list(env, roots)
+}
@@ -0,0 +1,118 @@
+use std::{cell::RefCell, cmp::Reverse};
+
+use emacs::{defun, Env, IntoLisp, Result, Value, Vector};
+use tree_sitter::{QueryCursor, Tree};
+
+use crate::{
+    types::BytePos,
+    tree::Borrowed,
+    query::{self, Query},
+};
+
+/// A definition of, or a reference to, a named entity, found by a tags query.
+struct Tag {
+    beg: usize,
+    end: usize,
+    name_beg: usize,
+    name_end: usize,
+    /// The capture's name, without the `definition.` or `reference.` prefix.
+    kind: String,
+    is_definition: bool,
+}
+
+/// Return the symbol index of TREE, according to the tags QUERY, as a vector of
+/// [NAME KIND DEFINITION-P BEG END NAME-BEG NAME-END PARENT]. See `tsc-tags'.
+///
+/// Each pattern of QUERY should capture the entity's name as @name, and the whole
+/// entity as @definition.KIND or @reference.KIND, following the conventions of
+/// tree-sitter's tags.scm files. KIND is returned as a symbol, e.g. `function'.
+/// Matches without both captures are ignored.
+///
+/// The tags are sorted by position. PARENT is the index of the innermost definition
+/// that encloses the tag, or nil. Positions are byte positions.
+///
+/// TEXT-FUNCTION is called for the text of each tag's name, and of the nodes needed
+/// by QUERY's predicates. It is the same as for `tsc-query-captures'.
+#[defun]
+fn _tags<'e>(
+    env: &'e Env,
+    tree: Borrowed<'e, Tree>,
+    query: &Query,
+    text_function: Value<'e>,
+) -> Result<Vector<'e>> {
+    let raw = &query.raw;
+    let shared = tree;
+    let tree = tree.try_borrow()?;
+    let names = raw.capture_names();
+    let mut tags = vec![];
+    let error = RefCell::new(None);
+    let mut cursor = QueryCursor::new();
+    let matches = cursor.matches(
+        raw,
+        tree.root_node(),
+        query::text_callback(text_function, &error),
+    );
+    for m in matches {
+        if let Some(error) = error.borrow_mut().take() {
+            return Err(error);
+        }
+        if !query::predicates_hold(raw, &m, text_function, shared)? {
+            continue;
+        }
+        let (mut name, mut entity) = (None, None);
+        for c in m.captures {
+            let capture_name = names[c.index as usize].as_str();
+            if capture_name == "name" {
+                name = Some(c.node);
+            } else if let Some(kind) = capture_name.strip_prefix("definition.") {
+                entity = Some((c.node, kind, true));
+            } else if let Some(kind) = capture_name.strip_prefix("reference.") {
+                entity = Some((c.node, kind, false));
+            }
+        }
+        if let (Some(name), Some((node, kind, is_definition))) = (name, entity) {
+            tags.push(Tag {
+                beg: node.start_byte(),
+                end: node.end_byte(),
+                name_beg: name.start_byte(),
+                name_end: name.end_byte(),
+                kind: kind.to_owned(),
+                is_definition,
+            });
+        }
+    }
+    if let Some(error) = error.into_inner() {
+        return Err(error);
+    }
+    tags.sort_by_key(|t| (t.beg, Reverse(t.end), !t.is_definition));
+    // Definitions enclosing the current tag, innermost last.
+    let mut scopes: Vec<usize> = vec![];
+    let vector = env.make_vector(tags.len(), ())?;
+    for (i, tag) in tags.iter().enumerate() {
+        while scopes.last().map_or(false, |&s| tags[s].end < tag.end || tags[s].end <= tag.beg) {
+            scopes.pop();
+        }
+        let parent = scopes.last().copied();
+        if tag.is_definition {
+            scopes.push(i);
+        }
+        let position = |byte: usize| BytePos::from(byte).into_lisp(env);
+        let name = text_function.call((position(tag.name_beg)?, position(tag.name_end)?))?;
+        let fields: [Value; 8] = [
+            name,
+            env.intern(&tag.kind)?,
+            tag.is_definition.into_lisp(env)?,
+            position(tag.beg)?,
+            position(tag.end)?,
+            position(tag.name_beg)?,
+            position(tag.name_end)?,
+            parent.into_lisp(env)?,
+        ];
+        let entry = env.make_vector(fields.len(), ())?;
+        for (j, field) in fields.iter().enumerate() {
+            entry.set(j, *field)?;
+        }
+        vector.set(i, entry)?;
+    }
+    Ok(vector)
+}
@@ -0,0 +1,96 @@
+use std::{cell::RefCell, iter};
+
+use emacs::{defun, Result, Value};
+use tree_sitter::Node;
+
+use crate::{
+    types::BytePos,
+    query::{self, Query},
+    session::Session,
+    editing,
+};
+
+/// Return the byte range (BEG . END) of the text object OBJECT at BYTEPOS in SESSION's latest
+/// tree, or nil if there is none.
+///
+/// If QUERY is non-nil, it should follow the conventions of text object queries:
+/// OBJECT is a name like "function", whose ranges are captured as `@function.outer'
+/// and `@function.inner'. The smallest match containing BYTEPOS is used. Otherwise,
+/// OBJECT is a node type, and the smallest node of that type containing BYTEPOS is
+/// used as the outer range.
+///
+/// If INNER is non-nil, return the inner range. When QUERY has no inner capture for
+/// the match, it is computed from the outer node by trimming its delimiters (or its
+/// body's delimiters, e.g. a function's braces), and whitespace.
+///
+/// TEXT-FUNCTION is used by text-based predicates. See `tsc-query-captures'.
+#[defun]
+fn _session_text_object_range<'e>(
+    session: &Session,
+    bytepos: BytePos,
+    object: String,
+    inner: Option<Value>,
+    query: Value<'e>,
+    text_function: Value<'e>,
+) -> Result<Option<Value<'e>>> {
+    let env = text_function.env;
+    let shared = match &session.tree {
+        Some(tree) => tree,
+        None => return Ok(None),
+    };
+    let tree = shared.try_borrow()?;
+    let byte: usize = bytepos.into();
+    let contains = |node: &Node| node.start_byte() <= byte && byte < node.end_byte();
+    // The outer node, and the inner node if it has been captured.
+    let mut found: Option<(Node, Option<Node>)> = None;
+    if query.is_not_nil() {
+        let query: &RefCell<Query> = query.into_rust()?;
+        let query = query.try_borrow()?;
+        let index = |suffix| {
+            let name = format!("{}.{}", object, suffix);
+            query.raw.capture_names().iter().position(|n| *n == name)
+        };
+        let (outer_index, inner_index) = (index("outer"), index("inner"));
+        let error = RefCell::new(None);
+        let mut cursor = tree_sitter::QueryCursor::new();
+        cursor.set_byte_range(byte..byte + 1);
+        let matches = cursor.matches(
+            &query.raw,
+            tree.root_node(),
+            query::text_callback(text_function, &error),
+        );
+        for m in matches {
+            if !query::predicates_hold(&query.raw, &m, text_function, shared)? {
+                continue;
+            }
+            let captured = |index: Option<usize>| {
+                m.captures.iter().find(|c| Some(c.index as usize) == index).map(|c| c.node)
+            };
+            let inner_node = captured(inner_index);
+            let outer_node = match captured(outer_index).or(inner_node) {
+                Some(node) if contains(&node) => node,
+                _ => continue,
+            };
+            let len = |node: Node| node.end_byte() - node.start_byte();
+            if found.map_or(true, |(node, _)| len(outer_node) < len(node)) {
+                found = Some((outer_node, inner_node));
+            }
+        }
+        if let Some(error) = error.borrow_mut().take() {
+            return Err(error);
+        }
+    } else {
+        let node = tree.root_node().descendant_for_byte_range(byte, byte);
+        found = iter::successors(node, |n| n.parent())
+            .find(|n| n.kind() == object && contains(n))
+            .map(|node| (node, None));
+    }
+    let (beg, end) = match (found, inner) {
+        (None, _) => return Ok(None),
+        (Some((outer, _)), None) => (outer.start_byte(), outer.end_byte()),
+        (Some((_, Some(inner))), Some(_)) => (inner.start_byte(), inner.end_byte()),
+        (Some((outer, None)), Some(_)) => editing::inner_range(outer),
+    };
+    let (beg, end): (BytePos, BytePos) = (beg.into(), end.into());
+    Ok(Some(env.cons(beg, end)?))
+}
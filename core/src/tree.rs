@@ -1,11 +1,18 @@
-use emacs::{defun, Value, Result, Vector};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::{Rc, Weak},
+};
+
+use emacs::{defun, Env, IntoLisp, Value, Result, Vector};
 
 use tree_sitter::{InputEdit, Tree};
 
 use crate::{
-    types::{Shared, BytePos, Point, Range},
+    types::{self, Shared, BytePos, Point, Range},
     lang::Language,
-    node::RNode,
+    node::{self, RNode},
+    parser,
 };
 
 // XXX: If we pass a &, #[defun] will assume it's refcell-wrapped. If we pass a Value, we need
@@ -14,16 +21,259 @@ pub(crate) type Borrowed<'e, T> = &'e Shared<T>;
 
 impl_pred!(tree_p, &Shared<Tree>);
 
+// -------------------------------------------------------------------------------------------------
+// Metadata.
+//
+// The bundled tree-sitter has no room for extra data in a tree, so what the module records about a
+// tree is kept in a side table, keyed by the tree's address. The table holds weak references, which
+// keep the addresses of its entries from being reused while they are there.
+
+/// What the module records about a tree. It is shared by all of the tree's shallow copies.
+struct TreeInfo {
+    tree: Weak<RefCell<Tree>>,
+    /// Number identifying the tree, assigned the first time it's asked for.
+    id: Option<usize>,
+    /// Whether the tree was released by `tsc-release'.
+    released: bool,
+    /// Ranges of the document that the tree was parsed from, or empty if it covers the whole
+    /// document.
+    included_ranges: Vec<tree_sitter::Range>,
+    /// Byte position of `point-min' in the buffer, if the tree was parsed from a narrowed buffer
+    /// with NARROWING `offset'.
+    offset: Option<usize>,
+    /// Number of times the tree was edited, which invalidates what was computed from it.
+    edits: usize,
+}
+
+impl TreeInfo {
+    fn new(tree: &Shared<Tree>) -> Self {
+        Self {
+            tree: Rc::downgrade(tree),
+            id: None,
+            released: false,
+            included_ranges: vec![],
+            offset: None,
+            edits: 0,
+        }
+    }
+}
+
+thread_local! {
+    static TREES: RefCell<HashMap<*const RefCell<Tree>, TreeInfo>> = RefCell::new(HashMap::new());
+    static PURGE_THRESHOLD: Cell<usize> = Cell::new(64);
+}
+
+/// Call F with the metadata of TREE, recording an empty one first if there is none. Entries of
+/// dead trees are purged whenever the table has doubled in size.
+fn with_info<T, F: FnOnce(&mut TreeInfo) -> T>(tree: &Shared<Tree>, f: F) -> T {
+    TREES.with(|table| {
+        let mut table = table.borrow_mut();
+        let key = Rc::as_ptr(tree);
+        if !table.contains_key(&key) {
+            PURGE_THRESHOLD.with(|threshold| {
+                if table.len() >= threshold.get() {
+                    table.retain(|_, info| info.tree.strong_count() > 0);
+                    threshold.set((table.len() * 2).max(64));
+                }
+            });
+        }
+        f(table.entry(key).or_insert_with(|| TreeInfo::new(tree)))
+    })
+}
+
+/// Call F with the recorded metadata of TREE, if any.
+fn read_info<T, F: FnOnce(&TreeInfo) -> T>(tree: &Shared<Tree>, f: F) -> Option<T> {
+    TREES.with(|table| table.borrow().get(&Rc::as_ptr(tree)).map(f))
+}
+
+// -------------------------------------------------------------------------------------------------
+// Liveness.
+//
+// Trees are shared through `Rc`, so they cannot be counted by a `live::Gauge`. Instead, every
+// created tree is recorded, and the alive ones are enumerated from the table.
+
+/// Start tracking the liveness of a newly created TREE.
+pub(crate) fn track_tree(tree: &Shared<Tree>) {
+    with_info(tree, |_| ())
+}
+
+/// Call F with each alive tree, and whether it was released.
+pub(crate) fn for_each_tree<F: FnMut(&Shared<Tree>, bool)>(mut f: F) {
+    let trees: Vec<_> = TREES.with(|table| {
+        table.borrow().values()
+            .filter_map(|info| Some((info.tree.upgrade()?, info.released)))
+            .collect()
+    });
+    for (tree, released) in &trees {
+        f(tree, *released);
+    }
+}
+
+pub(crate) fn mark_tree_released(tree: &Shared<Tree>) {
+    with_info(tree, |info| info.released = true)
+}
+
+pub(crate) fn is_tree_released(tree: &Shared<Tree>) -> bool {
+    read_info(tree, |info| info.released).unwrap_or(false)
+}
+
+// -------------------------------------------------------------------------------------------------
+// Ids.
+//
+// A tree's address cannot identify it, since it can be reused by another tree after the first one
+// is garbage-collected. Instead, each tree is given an increasing number the first time it's asked
+// for one.
+
+thread_local! {
+    static NEXT_ID: Cell<usize> = Cell::new(1);
+}
+
+/// Return a number identifying TREE, which is shared by all of its shallow copies. The number is
+/// never reused for another tree.
+pub(crate) fn tree_id(tree: &Shared<Tree>) -> usize {
+    with_info(tree, |info| {
+        *info.id.get_or_insert_with(|| NEXT_ID.with(|next| next.replace(next.get() + 1)))
+    })
+}
+
+/// Return TREE's id, and the number of times it was edited. Together, they identify the current
+/// state of TREE.
+pub(crate) fn tree_version(tree: &Shared<Tree>) -> (usize, usize) {
+    let id = tree_id(tree);
+    (id, read_info(tree, |info| info.edits).unwrap_or(0))
+}
+
+/// Return the live tree identified by ID, if any.
+pub(crate) fn find_tree(id: usize) -> Option<Shared<Tree>> {
+    TREES.with(|table| {
+        table.borrow().values()
+            .find(|info| info.id == Some(id))
+            .and_then(|info| info.tree.upgrade())
+    })
+}
+
+// -------------------------------------------------------------------------------------------------
+// Included ranges.
+//
+// The bundled tree-sitter does not expose a tree's included ranges, so they are recorded when the
+// tree is created, and edited along with it.
+
+/// Record the RANGES that TREE was parsed from. If RANGES is empty, TREE covers its whole
+/// document.
+pub(crate) fn record_included_ranges(tree: &Shared<Tree>, ranges: &[tree_sitter::Range]) {
+    if ranges.is_empty() {
+        return;
+    }
+    with_info(tree, |info| info.included_ranges = ranges.to_vec())
+}
+
+/// Return the recorded included ranges of TREE, or an empty vector if it covers its whole
+/// document.
+fn recorded_included_ranges(tree: &Shared<Tree>) -> Vec<tree_sitter::Range> {
+    read_info(tree, |info| info.included_ranges.clone()).unwrap_or_default()
+}
+
+/// Return the point P after the EDIT that ends before it.
+fn shift_point(p: tree_sitter::Point, edit: &InputEdit) -> tree_sitter::Point {
+    let (old_end, new_end) = (edit.old_end_position, edit.new_end_position);
+    if p.row > old_end.row {
+        tree_sitter::Point { row: new_end.row + p.row - old_end.row, column: p.column }
+    } else {
+        tree_sitter::Point { row: new_end.row, column: new_end.column + p.column - old_end.column }
+    }
+}
+
+/// Record that TREE was edited by EDIT, adjusting its recorded included ranges the same way
+/// tree-sitter does.
+pub(crate) fn record_edit(tree: &Shared<Tree>, edit: &InputEdit) {
+    with_info(tree, |info| {
+        info.edits += 1;
+        for range in &mut info.included_ranges {
+            if range.end_byte >= edit.old_end_byte {
+                range.end_byte = edit.new_end_byte + (range.end_byte - edit.old_end_byte);
+                range.end_point = shift_point(range.end_point, edit);
+            } else if range.end_byte > edit.start_byte {
+                range.end_byte = edit.start_byte;
+                range.end_point = edit.start_position;
+            }
+            if range.start_byte >= edit.old_end_byte {
+                range.start_byte = edit.new_end_byte + (range.start_byte - edit.old_end_byte);
+                range.start_point = shift_point(range.start_point, edit);
+            } else if range.start_byte > edit.start_byte {
+                range.start_byte = edit.start_byte;
+                range.start_point = edit.start_position;
+            }
+        }
+    })
+}
+
+/// Return the ranges of the document that TREE was parsed from, as a vector of
+/// ranges, in the format accepted by `tsc-set-included-ranges'. The ranges are
+/// adjusted by `tsc-edit-tree'.
+///
+/// If TREE covers its whole document, which is the default, return an empty vector.
+#[defun(mod_in_name = true)]
+fn included_ranges<'e>(env: &'e Env, tree: Borrowed<'e, Tree>) -> Result<Vector<'e>> {
+    let ranges = recorded_included_ranges(tree);
+    let vec = env.make_vector(ranges.len(), ())?;
+    for (i, range) in ranges.into_iter().enumerate() {
+        vec.set(i, Range(range))?;
+    }
+    Ok(vec)
+}
+
+// -------------------------------------------------------------------------------------------------
+// Offsets.
+//
+// A tree parsed from a narrowed buffer, treating `point-min' as offset 0, records the byte position
+// of `point-min'.
+
+/// Record that TREE's byte 0 is at the 1-based byte position BYTEPOS of its buffer.
+pub(crate) fn record_offset(tree: &Shared<Tree>, bytepos: usize) {
+    with_info(tree, |info| info.offset = Some(bytepos))
+}
+
+fn recorded_offset(tree: &Shared<Tree>) -> Option<usize> {
+    read_info(tree, |info| info.offset).flatten()
+}
+
+/// Return the byte position of the buffer text that TREE's positions start from, if
+/// it was parsed from a narrowed buffer by `tsc-parse-buffer', with NARROWING
+/// `offset'. TREE's byte position 1 is then at the returned byte position.
+///
+/// Return nil if TREE's positions are those of its buffer, which is the default.
+#[defun(mod_in_name = true)]
+fn offset(tree: Borrowed<Tree>) -> Result<Option<usize>> {
+    Ok(recorded_offset(tree))
+}
+
+// -------------------------------------------------------------------------------------------------
+
 /// Return the language that was used to parse the syntax TREE.
 #[defun(mod_in_name = true)]
 fn language(tree: Borrowed<Tree>) -> Result<Language> {
-    Ok(tree.borrow().language().into())
+    Ok(tree.try_borrow()?.language().into())
 }
 
 /// Return the sexp representation of the syntax TREE, in a string.
 #[defun(mod_in_name = true)]
 fn to_sexp(tree: Borrowed<Tree>) -> Result<String> {
-    Ok(tree.borrow().root_node().to_sexp())
+    Ok(tree.try_borrow()?.root_node().to_sexp())
+}
+
+/// Return the syntax TREE as a Lisp data structure, instead of a string.
+///
+/// This is `tsc-node-sexp' applied to TREE's root node. ANONYMOUS and MAX-DEPTH have
+/// the same meaning.
+#[defun]
+fn tree_sexp<'e>(
+    env: &'e Env,
+    tree: Borrowed<'e, Tree>,
+    anonymous: Option<bool>,
+    max_depth: Option<usize>,
+) -> Result<Value<'e>> {
+    let tree = tree.try_borrow()?;
+    node::data_sexp(env, tree.root_node(), anonymous.unwrap_or(false), max_depth)
 }
 
 /// Return the root node of the syntax TREE.
@@ -32,6 +282,32 @@ fn root_node(tree: Borrowed<Tree>) -> Result<RNode> {
     Ok(RNode::new(tree.clone(), |tree| tree.root_node()))
 }
 
+/// Return the root node of the syntax TREE, as if its source code started BYTES
+/// bytes, and POINT, into an enclosing document.
+///
+/// This is useful when TREE was parsed from a string extracted from a buffer, e.g.
+/// a code block of an Org buffer, to get the nodes' positions in that buffer. POINT
+/// is the (LINE-NUMBER . BYTE-COLUMN) of the string's start in the buffer, and BYTES
+/// is the number of bytes before it, i.e. its byte position minus 1.
+///
+/// The node belongs to a shifted copy of TREE, which is not affected by later edits
+/// of TREE. Since the copy is shifted by an edit, `tsc-node-has-changes-p' returns
+/// t for its nodes, and the copy should not be used as OLD-TREE when parsing.
+#[defun]
+fn root_node_with_offset(tree: Borrowed<Tree>, bytes: usize, point: Point) -> Result<RNode> {
+    let mut copy = tree.try_borrow()?.clone();
+    let start = tree_sitter::Point { row: 0, column: 0 };
+    copy.edit(&InputEdit {
+        start_byte: 0,
+        old_end_byte: 0,
+        new_end_byte: bytes,
+        start_position: start,
+        old_end_position: start,
+        new_end_position: point.into(),
+    });
+    Ok(RNode::new(parser::shared_tree(copy), |tree| tree.root_node()))
+}
+
 /// Edit the syntax TREE to keep it in sync with source code that has been edited.
 ///
 /// You must describe the edit both in terms of byte positions and in terms of
@@ -60,24 +336,113 @@ fn edit_tree(
         old_end_position: old_end_point.into(),
         new_end_position: new_end_point.into(),
     };
-    tree.borrow_mut().edit(&edit);
+    tree.try_borrow_mut()?.edit(&edit);
+    record_edit(tree, &edit);
     Ok(())
 }
 
+/// Convert RECORD, a sequence of the arguments of `tsc-edit-tree' after the tree, into an edit.
+fn input_edit(record: Value) -> Result<InputEdit> {
+    let env = record.env;
+    let nth = |n: usize| env.call("elt", (record, n));
+    Ok(InputEdit {
+        start_byte: nth(0)?.into_rust::<BytePos>()?.into(),
+        old_end_byte: nth(1)?.into_rust::<BytePos>()?.into(),
+        new_end_byte: nth(2)?.into_rust::<BytePos>()?.into(),
+        start_position: nth(3)?.into_rust::<Point>()?.into(),
+        old_end_position: nth(4)?.into_rust::<Point>()?.into(),
+        new_end_position: nth(5)?.into_rust::<Point>()?.into(),
+    })
+}
+
+/// Apply EDITS to the syntax TREE, in order, like calling `tsc-edit-tree' with
+/// each of them.
+///
+/// EDITS is a vector of edits, each of which is a list (START-BYTEPOS
+/// OLD-END-BYTEPOS NEW-END-BYTEPOS START-POINT OLD-END-POINT NEW-END-POINT). Each
+/// edit's positions must take the preceding edits into account. If an edit is
+/// invalid, none of them are applied.
+///
+/// This is useful when many changes are made before the next parse, e.g. by
+/// `replace-regexp', since it avoids calling into the dynamic module once per
+/// change.
+#[defun(name = "edit-tree*")]
+fn edit_tree_batch(tree: Borrowed<Tree>, edits: Vector) -> Result<()> {
+    let edits = (0..edits.len())
+        .map(|i| input_edit(edits.get(i)?))
+        .collect::<Result<Vec<_>>>()?;
+    let mut inner = tree.try_borrow_mut()?;
+    for edit in &edits {
+        inner.edit(edit);
+        record_edit(tree, edit);
+    }
+    Ok(())
+}
+
+/// Return the point at the end of TEXT, which starts at START.
+fn end_point(start: tree_sitter::Point, text: &str) -> tree_sitter::Point {
+    match text.rfind('\n') {
+        Some(i) => tree_sitter::Point {
+            row: start.row + text.bytes().filter(|&b| b == b'\n').count(),
+            column: text.len() - i - 1,
+        },
+        None => tree_sitter::Point { row: start.row, column: start.column + text.len() },
+    }
+}
+
+/// Compute the edit that replaced OLD-TEXT with NEW-TEXT, at START-BYTEPOS and
+/// START-POINT, and apply it to TREE, unless it's nil. Return the edit, as a list
+/// (START-BYTEPOS OLD-END-BYTEPOS NEW-END-BYTEPOS START-POINT OLD-END-POINT
+/// NEW-END-POINT), like the elements accepted by `tsc-edit-tree*'.
+///
+/// See `tsc-record-change'.
+#[defun]
+fn _record_change<'e>(
+    env: &'e Env,
+    tree: Option<Borrowed<'e, Tree>>,
+    start_bytepos: BytePos,
+    start_point: Point,
+    old_text: String,
+    new_text: String,
+) -> Result<Value<'e>> {
+    let (start_byte, start_position): (usize, tree_sitter::Point) =
+        (start_bytepos.into(), start_point.into());
+    let edit = InputEdit {
+        start_byte,
+        old_end_byte: start_byte + old_text.len(),
+        new_end_byte: start_byte + new_text.len(),
+        start_position,
+        old_end_position: end_point(start_position, &old_text),
+        new_end_position: end_point(start_position, &new_text),
+    };
+    if let Some(tree) = tree {
+        tree.try_borrow_mut()?.edit(&edit);
+        record_edit(tree, &edit);
+    }
+    let byte = |b: usize| BytePos::from(b).into_lisp(env);
+    let point = |p: tree_sitter::Point| Point::from(p).into_lisp(env);
+    types::list(env, vec![
+        byte(edit.start_byte)?, byte(edit.old_end_byte)?, byte(edit.new_end_byte)?,
+        point(edit.start_position)?, point(edit.old_end_position)?, point(edit.new_end_position)?,
+    ])
+}
+
 /// Compare an edited OLD-TREE to NEW-TREE, both representing the same document.
 ///
-/// This function returns a sequence of ranges whose syntactic structure has changed.
+/// This function returns a vector of ranges whose syntactic structure has changed,
+/// in the format accepted by `tsc-set-included-ranges'. Re-highlighting only these
+/// ranges is enough to keep up with the new tree.
 ///
 /// For this to work correctly, OLD-TREE must have been edited such that its ranges
 /// match up to NEW-TREE. Generally, you'll want to call this function right after
 /// calling one of the parsing functions, passing in the old tree that was passed
-/// as a parameter and the new tree that was returned.
+/// as a parameter and the new tree that was returned. The order matters: the
+/// returned positions are those of the new document.
 #[defun]
 fn changed_ranges<'e>(old_tree: Value<'e>, new_tree: Borrowed<'e, Tree>) -> Result<Vector<'e>> {
     let env = old_tree.env;
-    let old_tree = old_tree.into_rust::<Borrowed<Tree>>()?.borrow();
-    let new_tree = new_tree.borrow();
-    // TODO: Add a test to show that order is importance.
+    let old_tree = old_tree.into_rust::<Borrowed<Tree>>()?.try_borrow()?;
+    let new_tree = new_tree.try_borrow()?;
     let ranges = old_tree.changed_ranges(&*new_tree);
     let vec = env.make_vector(ranges.len(), ())?;
     for (i, range) in ranges.enumerate() {
@@ -86,6 +451,46 @@ fn changed_ranges<'e>(old_tree: Value<'e>, new_tree: Borrowed<'e, Tree>) -> Resu
     Ok(vec)
 }
 
+/// Like `tsc-changed-ranges', but return only the byte positions of the changed
+/// ranges, as a vector of (START-BYTEPOS . END-BYTEPOS).
+///
+/// Prefer this when the ranges' points are not needed, e.g. to invalidate a region
+/// of text, as it avoids allocating the (LINE-NUMBER . BYTE-COLUMN) pairs. Nodes
+/// have the same split, see `tsc-node-byte-range' and `tsc-node-range'.
+#[defun]
+fn changed_byte_ranges<'e>(
+    old_tree: Value<'e>,
+    new_tree: Borrowed<'e, Tree>,
+) -> Result<Vector<'e>> {
+    let env = old_tree.env;
+    let old_tree = old_tree.into_rust::<Borrowed<Tree>>()?.try_borrow()?;
+    let new_tree = new_tree.try_borrow()?;
+    let ranges = old_tree.changed_ranges(&*new_tree);
+    let vec = env.make_vector(ranges.len(), ())?;
+    for (i, range) in ranges.enumerate() {
+        let beg: BytePos = range.start_byte.into();
+        let end: BytePos = range.end_byte.into();
+        vec.set(i, env.cons(beg, end)?)?;
+    }
+    Ok(vec)
+}
+
+/// Return an independent copy of the syntax TREE.
+///
+/// Edits of TREE do not affect the copy, and vice versa, so the copy can e.g. be
+/// kept as a reference, or handed to a background operation, while TREE keeps
+/// being edited along with its buffer. Copying is cheap, since the copies share
+/// their unchanged parts.
+#[defun]
+fn copy_tree(tree: Borrowed<Tree>) -> Result<Shared<Tree>> {
+    let copy = parser::shared_tree(tree.try_borrow()?.clone());
+    record_included_ranges(&copy, &recorded_included_ranges(tree));
+    if let Some(bytepos) = recorded_offset(tree) {
+        record_offset(&copy, bytepos);
+    }
+    Ok(copy)
+}
+
 /// Create a shallow copy of the syntax TREE.
 ///
 /// This is not very useful currently, as Emacs Lisp threads are subjected to a GIL.
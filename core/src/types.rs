@@ -4,7 +4,7 @@ use std::{
     rc::Rc,
 };
 
-use emacs::{defun, Env, FromLisp, IntoLisp, Result, Value, Vector};
+use emacs::{defun, Env, FromLisp, GlobalRef, IntoLisp, Result, Value, Vector};
 
 pub type Shared<T> = Rc<RefCell<T>>;
 
@@ -12,6 +12,35 @@ pub unsafe fn erase_lifetime<'t, T>(x: &'t T) -> &'static T {
     mem::transmute(x)
 }
 
+/// Build a Lisp list from VALUES.
+pub fn list<'e>(env: &'e Env, values: Vec<Value<'e>>) -> Result<Value<'e>> {
+    let mut list = ().into_lisp(env)?;
+    for value in values.into_iter().rev() {
+        list = env.cons(value, list)?;
+    }
+    Ok(list)
+}
+
+thread_local! {
+    /// Global references whose owners were dropped without an `Env`, e.g. by the garbage collector.
+    static DROPPED_REFS: RefCell<Vec<GlobalRef>> = RefCell::new(Vec::new());
+}
+
+/// Free GLOBAL later, by the next call to `free_dropped_refs`, because its owner is being dropped
+/// without an `Env`.
+pub(crate) fn free_later(global: GlobalRef) {
+    DROPPED_REFS.with(|refs| refs.borrow_mut().push(global));
+}
+
+/// Free the global references passed to `free_later`.
+pub(crate) fn free_dropped_refs(env: &Env) -> Result<()> {
+    let refs = DROPPED_REFS.with(|refs| mem::take(&mut *refs.borrow_mut()));
+    for global in refs {
+        global.free(env)?;
+    }
+    Ok(())
+}
+
 macro_rules! impl_pred {
     ($name:ident, $type:ty) => {
         #[defun]
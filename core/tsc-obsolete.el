@@ -109,7 +109,7 @@
 (define-obsolete-function-alias 'ts--dyn-version 'tsc--dyn-version "2020-10-13")
 (define-obsolete-function-alias 'ts--get-child-by-field-name 'tsc--get-child-by-field-name "2020-10-13")
 (define-obsolete-function-alias 'ts--invalid-node-step 'tsc--invalid-node-step "2020-10-13")
-(define-obsolete-function-alias 'ts--lang-field-id-for-name 'tsc--lang-field-id-for-name "2020-10-13")
+(define-obsolete-function-alias 'ts--lang-field-id-for-name 'tsc-lang-field-id-for-name "2020-10-13")
 (define-obsolete-function-alias 'ts--lang-load-file 'tsc--lang-load-file "2020-10-13")
 (define-obsolete-function-alias 'ts--lang-symbol 'tsc--lang-symbol "2020-10-13")
 (define-obsolete-function-alias 'ts--lang-type-id-for-name 'tsc--lang-type-id-for-name "2020-10-13")
@@ -133,6 +133,15 @@
 (define-obsolete-function-alias 'ts--timeout-micros 'tsc--timeout-micros "2020-10-13")
 (define-obsolete-function-alias 'ts--try-load-dyn 'tsc--try-load-dyn "2020-10-13")
 (define-obsolete-function-alias 'ts--without-restriction 'tsc--without-restriction "2020-10-13")
+;;; Formerly internal.
+(define-obsolete-function-alias 'tsc--reset-parser 'tsc-reset-parser "2026-10-14")
+(define-obsolete-function-alias 'tsc--set-timeout-micros 'tsc-set-timeout-micros "2026-10-14")
+(define-obsolete-function-alias 'tsc--timeout-micros 'tsc-timeout-micros "2026-10-14")
+(define-obsolete-function-alias 'tsc--query-start-byte-for-pattern 'tsc-query-start-byte-for-pattern "2026-10-14")
+(define-obsolete-function-alias 'tsc--disable-capture 'tsc-query-disable-capture "2026-10-14")
+(define-obsolete-function-alias 'tsc--query-cursor-set-byte-range 'tsc-query-cursor-set-byte-range "2026-10-14")
+(define-obsolete-function-alias 'tsc--query-cursor-set-point-range 'tsc-query-cursor-set-point-range "2026-10-14")
+(define-obsolete-function-alias 'tsc--lang-field-id-for-name 'tsc-lang-field-id-for-name "2026-10-14")
 
 (provide 'tsc-obsolete)
 ;;; tsc-obsolete.el ends here
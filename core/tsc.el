@@ -45,6 +45,19 @@
      (widen)
      ,@body))
 
+(defmacro tsc--with-query-cursor (spec &rest body)
+  "Execute BODY with VAR bound to CURSOR, or to a pooled query cursor if it's nil.
+SPEC has the form (VAR CURSOR). A pooled cursor is returned to the pool
+afterward, so it must not be used outside of BODY."
+  (declare (indent 1))
+  (let ((given (make-symbol "given")))
+    `(let* ((,given ,(cadr spec))
+            (,(car spec) (or ,given (tsc--checkout-query-cursor))))
+       (unwind-protect
+           (progn ,@body)
+         (unless ,given
+           (tsc--checkin-query-cursor ,(car spec)))))))
+
 (defmacro tsc--save-context (&rest body)
   "Execute BODY wrapped in a `save-excursion', with narrowing disabled."
   (declare (indent 0))
@@ -87,20 +100,71 @@ A \"point\" in this context is a (LINE-NUMBER . BYTE-COLUMN) pair. See
       (forward-line (- line-number 1))
       (byte-to-position (+ byte-column (position-bytes (line-beginning-position)))))))
 
+(defun tsc--line-text (line-number &optional object)
+  "Return (START-BYTEPOS . TEXT) of the line at LINE-NUMBER in OBJECT.
+OBJECT is a buffer, or a string, and defaults to the current buffer. TEXT does
+not include the line's terminator."
+  (if (stringp object)
+      (let ((start 0))
+        (dotimes (_ (1- line-number))
+          (setq start (if (string-match "\n" object start)
+                          (match-end 0)
+                        (length object))))
+        (cons (1+ (string-bytes (substring object 0 start)))
+              (substring-no-properties
+               object start (or (string-match "\n" object start) (length object)))))
+    (with-current-buffer (or object (current-buffer))
+      (tsc--save-context
+        (goto-char 1)
+        (forward-line (1- line-number))
+        (cons (position-bytes (point))
+              (buffer-substring-no-properties (point) (line-end-position)))))))
+
+(defun tsc-point-to-lsp-position (point &optional object)
+  "Convert tree-sitter POINT to an LSP position, in OBJECT.
+The result is a plist (:line LINE :character CHARACTER), where LINE counts from
+0, and CHARACTER is the number of UTF-16 code units before POINT on its line, as
+required by the Language Server Protocol.
+
+OBJECT is the buffer, or the string, that POINT refers to. It defaults to the
+current buffer."
+  (pcase-let ((`(,_ . ,text) (tsc--line-text (car point) object)))
+    (list :line (1- (car point))
+          :character (tsc-utf16-column text (cdr point)))))
+
+(defun tsc-lsp-position-to-byte (position &optional object)
+  "Convert the LSP POSITION to a byte position in OBJECT.
+POSITION is a plist (:line LINE :character CHARACTER), where LINE counts from 0,
+and CHARACTER is a number of UTF-16 code units, as sent by language servers. A
+CHARACTER past the end of the line means the line's end.
+
+OBJECT is the buffer, or the string, that POSITION refers to. It defaults to the
+current buffer. See `tsc-point-to-lsp-position'."
+  (pcase-let ((`(,start . ,text)
+               (tsc--line-text (1+ (plist-get position :line)) object)))
+    (+ start (tsc-utf16-column-to-byte text (plist-get position :character)))))
+
+(defun tsc--kind-vector (kinds)
+  "Convert KINDS, a list of node types, to a vector.
+Like those returned by `tsc-node-type', the types of named nodes are symbols,
+and those of anonymous nodes are strings, e.g. \"fn\"."
+  (vconcat kinds))
+
 
 ;;; Extracting buffer's text.
 
 (defvar tsc--buffer-input-chunk-size 4096)
 
-(defun tsc--buffer-input (bytepos _line-number _byte-column)
+(defun tsc--buffer-input (bytepos _line-number _byte-column &optional size)
   "Return a portion of the current buffer's text, starting from BYTEPOS.
-BYTEPOS is automatically clamped to the range valid for the current buffer.
+BYTEPOS is automatically clamped to the range valid for the current buffer. The
+portion is at most SIZE bytes long, or `tsc--buffer-input-chunk-size'.
 
 This function must be called with narrowing disabled, e.g. within a
 `tsc--without-restriction' block."
   (let* ((max-pos (point-max))
          (beg-byte (max 1 bytepos))
-         (end-byte (+ tsc--buffer-input-chunk-size beg-byte))
+         (end-byte (+ (or size tsc--buffer-input-chunk-size) beg-byte))
          ;; nil means > max-pos, since we already made sure they are non-negative.
          (beg-pos (or (byte-to-position beg-byte) max-pos))
          (end-pos (or (byte-to-position end-byte) max-pos)))
@@ -114,19 +178,36 @@ This function must be called with narrowing disabled, e.g. within a
    (byte-to-position beg-byte)
    (byte-to-position end-byte)))
 
-(defun tsc--node-text (node)
+(defun tsc--node-text (node &optional max-length)
   "Return NODE's text, assuming it's from the current buffer's syntax tree.
 Prefer `tsc-node-text', unless there's a real bottleneck.
 
+If MAX-LENGTH is non-nil, and NODE spans more than MAX-LENGTH bytes, signal a
+`tsc-node-text-too-long' error instead of creating the string.
+
 This function must be called with narrowing disabled, e.g. within a
 `tsc--without-restriction' block."
-  (pcase-let ((`(,beg . ,end) (tsc-node-position-range node)))
-    (buffer-substring-no-properties beg end)))
+  (tsc--node-buffer-text node max-length))
+
+(defun tsc-node-text (node &optional max-length)
+  "Return NODE's text, assuming it's from the current buffer's syntax tree.
 
-(defun tsc-node-text (node)
-  "Return NODE's text, assuming it's from the current buffer's syntax tree."
+If the optional arg MAX-LENGTH is non-nil, and NODE spans more than MAX-LENGTH
+bytes, signal a `tsc-node-text-too-long' error, whose `:length' and
+`:max-length' properties are the byte lengths (see `tsc-error-property'). This
+guards against accidentally creating huge strings, e.g. for whole-file nodes.
+The length is checked before the text is taken from the buffer."
   (tsc--without-restriction
-    (tsc--node-text node)))
+    (tsc--node-text node max-length)))
+
+(defun tsc-node-buffer-text (node &optional buffer)
+  "Return NODE's text, taken from BUFFER, which defaults to the current buffer.
+NODE should be from BUFFER's syntax tree. Narrowing is ignored. The conversion
+of NODE's byte positions, and the extraction of the text, are done in a single
+call into the dynamic module."
+  (with-current-buffer (or buffer (current-buffer))
+    (tsc--without-restriction
+      (tsc--node-buffer-text node nil))))
 
 
 ;;; Convenient versions of some functions.
@@ -147,28 +228,101 @@ This function must be called in NODE's source buffer."
    (position-bytes beg)
    (position-bytes end)))
 
+(defun tsc--descendant-for-range (node start end map named)
+  "Return the smallest (NAMED) node within NODE that spans START to END.
+See `tsc-descendant-for-point-range'."
+  (cond
+   ((and (consp start) (consp end))
+    (funcall (if named
+                 #'tsc-get-named-descendant-for-point-range
+               #'tsc-get-descendant-for-point-range)
+             node start end))
+   ((and (integerp start) (integerp end))
+    (let ((convert (if map
+                       (lambda (position) (tsc-position-to-byte map position))
+                     #'position-bytes)))
+      (funcall (if named
+                   #'tsc-get-named-descendant-for-byte-range
+                 #'tsc-get-descendant-for-byte-range)
+               node (funcall convert start) (funcall convert end))))
+   (t (signal 'wrong-type-argument (list 'consp (if (consp start) end start))))))
+
+(defun tsc-descendant-for-point-range (node start end &optional map)
+  "Return the smallest node within NODE that spans the range from START to END.
+
+START and END are either points, of the form (LINE-NUMBER . BYTE-COLUMN), with
+LINE-NUMBER counting from 1, and BYTE-COLUMN from 0, or positions. Positions
+are converted with MAP, if it's non-nil, see `tsc-make-position-map'. Otherwise,
+this function must be called in NODE's source buffer."
+  (tsc--descendant-for-range node start end map nil))
+
+(defun tsc-named-descendant-for-point-range (node start end &optional map)
+  "Return the smallest named node within NODE that spans START to END.
+See `tsc-descendant-for-point-range' for the meaning of START, END and MAP."
+  (tsc--descendant-for-range node start end map t))
+
+;; Allow nodes to be used as hash table keys.
+(define-hash-table-test 'tsc-node-eq #'tsc-node-eq #'tsc-node-id)
+
 (defun tsc-get-child-by-field (node field)
   "Return NODE's child associated with FIELD, which should be a keyword."
   (unless (keywordp field)
     (signal 'wrong-type-argument (list 'keywordp field)))
   (tsc--get-child-by-field-name node (substring (symbol-name field) 1)))
 
-(defun tsc-node-start-position (node)
-  "Return NODE's start position.
-This function must be called in NODE's source buffer."
-  (byte-to-position (tsc-node-start-byte node)))
+(defun tsc-child-by-field-id (node field-id)
+  "Return NODE's child associated with the numeric FIELD-ID.
+Looking up the id once with `tsc-field-id-for-name', then using this function,
+is faster than `tsc-get-child-by-field' in hot loops."
+  (tsc-get-child-by-field-id node field-id))
 
-(defun tsc-node-end-position (node)
+(defun tsc-children-by-field (node field)
+  "Return a vector of all of NODE's children associated with FIELD.
+FIELD is either a keyword, or a numeric field id. Unlike with
+`tsc-get-child-by-field', all of the children of a repeated field are returned."
+  (cond
+   ((integerp field) (tsc-children-by-field-id node field))
+   ((keywordp field) (tsc--children-by-field-name node (substring (symbol-name field) 1)))
+   (t (signal 'wrong-type-argument (list 'keywordp field)))))
+
+(defun tsc-buffer-position-map (&optional buffer)
+  "Return a map between the byte positions and the positions of BUFFER's text.
+BUFFER defaults to the current buffer. Narrowing is ignored. The map must be
+kept up to date with `tsc-position-map-edit'. See `tsc-make-position-map'."
+  (with-current-buffer (or buffer (current-buffer))
+    (save-restriction
+      (widen)
+      (tsc-make-position-map (buffer-substring-no-properties (point-min) (point-max))))))
+
+(defun tsc-node-start-position (node &optional map)
+  "Return NODE's start position.
+If MAP is non-nil, it is used to convert the byte position, see
+`tsc-make-position-map'. Otherwise, this function must be called in NODE's
+source buffer."
+  (if map
+      (tsc-byte-to-position map (tsc-node-start-byte node))
+    (byte-to-position (tsc-node-start-byte node))))
+
+(defun tsc-node-end-position (node &optional map)
   "Return NODE's end position.
-This function must be called in NODE's source buffer."
-  (byte-to-position (tsc-node-end-byte node)))
-
-(defun tsc-node-position-range (node)
+If MAP is non-nil, it is used to convert the byte position, see
+`tsc-make-position-map'. Otherwise, this function must be called in NODE's
+source buffer."
+  (if map
+      (tsc-byte-to-position map (tsc-node-end-byte node))
+    (byte-to-position (tsc-node-end-byte node))))
+
+(defun tsc-node-position-range (node &optional map)
   "Return NODE's (START-POSITION . END-POSITION).
-This function must be called in NODE's source buffer."
-  (let ((range (tsc-node-byte-range node)))
-    (cl-callf byte-to-position (car range))
-    (cl-callf byte-to-position (cdr range))
+If MAP is non-nil, it is used to convert the byte positions, see
+`tsc-make-position-map'. Otherwise, this function must be called in NODE's
+source buffer."
+  (let ((range (tsc-node-byte-range node))
+        (convert (if map
+                     (lambda (bytepos) (tsc-byte-to-position map bytepos))
+                   #'byte-to-position)))
+    (setcar range (funcall convert (car range)))
+    (setcdr range (funcall convert (cdr range)))
     range))
 
 (defun tsc-goto-first-child-for-position (cursor position)
@@ -189,17 +343,35 @@ only that property, and OUTPUT is ignored.
 
 See `tsc-valid-node-props' for the list of available properties."
   (tsc--check-node-props props)
-  ;; TODO: Fix this.
-  (when (or (eq props :depth)
-            (and (seqp props) (cl-find :depth props)))
-    (error "Cursor doesn't currently support :depth property"))
   (tsc--current-node cursor props output))
 
+(defun tsc-lang-abi-version (language)
+  "Return the ABI version that LANGUAGE was generated with.
+It must be between `tsc-min-supported-abi' and `tsc-max-supported-abi'. This is
+the same as `tsc-lang-version'."
+  (tsc-lang-version language))
+
+(defun tsc-min-supported-abi ()
+  "Return the oldest language ABI version that the dynamic module can load.
+Languages generated by an older tree-sitter CLI must be regenerated."
+  (car (tsc-supported-abi-range)))
+
+(defun tsc-max-supported-abi ()
+  "Return the newest language ABI version that the dynamic module can load.
+Languages generated by a newer tree-sitter CLI require a newer dynamic module."
+  (cdr (tsc-supported-abi-range)))
+
 (defun tsc-lang-field-id (language field)
   "Return the numeric id of FIELD in LANGUAGE. FIELD should be a keyword."
   (unless (keywordp field)
     (signal 'wrong-type-argument (list 'keywordp field)))
-  (tsc--lang-field-id-for-name language (substring (symbol-name field) 1)))
+  (tsc-lang-field-id-for-name language (substring (symbol-name field) 1)))
+
+(defun tsc-field-id-for-name (language field)
+  "Return the numeric id of FIELD in LANGUAGE, or nil if there's no such field.
+FIELD is either a keyword, e.g. :name, or a string, e.g. \"name\"."
+  (tsc-lang-field-id-for-name
+   language (if (keywordp field) (substring (symbol-name field) 1) field)))
 
 (defun tsc-lang-node-type-id (language node-type)
   "Return the numeric id of NODE-TYPE in LANGUAGE.
@@ -210,19 +382,217 @@ NODE-TYPE should be a symbol (named nodes) or a string (anonymous nodes)."
    (t
     (tsc--lang-type-id-for-name language node-type nil))))
 
+
+;;; Parsing.
+
+(defun tsc-parse-buffer (parser &optional buffer old-tree narrowing)
+  "Parse BUFFER's whole text with PARSER; return a tree.
+BUFFER defaults to the current buffer. OLD-TREE has the same meaning as in
+`tsc-parse-chunks'.
+
+NARROWING specifies how BUFFER's narrowing is treated:
+
+nil      Narrowing is ignored. This is the default.
+`offset' Only the accessible portion is parsed, as if it were the whole text,
+         e.g. an org-src block's code. `point-min' becomes the tree's position
+         1, as returned by `tsc-tree-offset'.
+`ranges' Only the accessible portion is parsed, but the tree's positions are
+         still those of BUFFER. PARSER's included ranges are temporarily set to
+         the accessible portion, which the tree's `tsc-tree-included-ranges'
+         returns.
+
+Use `tsc-tree-partial-p' to check whether the tree covers only part of BUFFER.
+
+This is faster than `tsc-parse-chunks' with a Lisp input function, since the
+text is read in larger chunks, directly by the dynamic module."
+  (with-current-buffer (or buffer (current-buffer))
+    (pcase narrowing
+      ('nil
+       (tsc--without-restriction
+         (tsc--parse-buffer parser old-tree (point-min) (point-max))))
+      ('offset
+       (tsc--parse-buffer parser old-tree (point-min) (point-max)))
+      ('ranges
+       (if (not (buffer-narrowed-p))
+           (tsc-parse-buffer parser nil old-tree)
+         (let ((accessible (list (cons (point-min) (point-max)))))
+           (tsc--save-context
+             (let ((included (tsc-parser-included-ranges parser)))
+               (tsc-set-included-ranges parser (tsc--hunk-ranges accessible))
+               (unwind-protect
+                   (tsc--parse-buffer parser old-tree (point-min) (point-max))
+                 (tsc-set-included-ranges parser included)))))))
+      (_ (signal 'wrong-type-argument (list '(member nil offset ranges) narrowing))))))
+
+(defun tsc-tree-partial-p (tree)
+  "Return non-nil if TREE was parsed from only part of its source code.
+This is the case for trees parsed with included ranges, e.g. with
+`tsc-parse-hunks', and for trees parsed from a narrowed buffer by
+`tsc-parse-buffer', unless NARROWING was nil."
+  (or (tsc-tree-offset tree)
+      (> (length (tsc-tree-included-ranges tree)) 0)))
+
+(defun tsc--hunk-ranges (hunks)
+  "Convert HUNKS, a list of (BEG . END) positions, into a vector of ranges.
+Overlapping and adjacent hunks are merged. This function must be called within a
+`tsc--save-context' block."
+  (let (merged)
+    (pcase-dolist (`(,beg . ,end) (sort (copy-sequence hunks) #'car-less-than-car))
+      (if (and merged (<= beg (cdar merged)))
+          (setcdr (car merged) (max end (cdar merged)))
+        (push (cons beg end) merged)))
+    (apply #'vector
+           (mapcar (pcase-lambda (`(,beg . ,end))
+                     (vector (position-bytes beg) (position-bytes end)
+                             (tsc--point-from-position beg)
+                             (tsc--point-from-position end)))
+                   (nreverse merged)))))
+
+(defun tsc-parse-hunks (parser buffer hunks &optional old-tree)
+  "Parse only the HUNKS of BUFFER with PARSER; return a tree.
+HUNKS is a non-empty list of (BEG . END) positions in BUFFER, e.g. the regions
+of a diff's hunks. The tree's positions are still relative to the whole buffer.
+
+This is useful when only a few regions of a large file need to be annotated, so
+a full tree is not needed. OLD-TREE has the same meaning as in
+`tsc-parse-chunks'. PARSER's included ranges are restored afterward."
+  (unless hunks
+    (signal 'wrong-type-argument (list 'consp hunks)))
+  (with-current-buffer buffer
+    (tsc--save-context
+      (let ((included (tsc-parser-included-ranges parser)))
+        (tsc-set-included-ranges parser (tsc--hunk-ranges hunks))
+        (unwind-protect
+            (tsc-parse-chunks parser #'tsc--buffer-input old-tree)
+          (tsc-set-included-ranges parser included))))))
+
+(defun tsc-parse-strings-parallel (language sources &optional files)
+  "Parse each of SOURCES with LANGUAGE in parallel; return the list of trees.
+SOURCES is a list of strings. If FILES is non-nil, they are the names of the
+files to read the source code from, which is then not copied into Emacs.
+
+The sources are parsed on a pool of threads, one per available CPU. This
+function returns when all of them are parsed. It is meant for tools that
+index whole projects, e.g. xref backends, which would otherwise parse
+hundreds of files one by one.
+
+Signal `file-error' if a file can't be read."
+  (append (tsc--parse-parallel
+           language
+           (vconcat (if files (mapcar #'expand-file-name sources) sources))
+           files)
+          nil))
+
+(defvar-local tsc--text-before-change nil
+  "(BEG . TEXT) of the region about to be changed, see `tsc-record-before-change'.")
+
+(defun tsc-record-before-change (beg end)
+  "Record the text from BEG to END, which is about to be changed.
+This should be added to `before-change-functions', for `tsc-record-change'."
+  (setq tsc--text-before-change
+        (cons beg (tsc--without-restriction (buffer-substring-no-properties beg end)))))
+
+(defun tsc-record-change (beg end old-len &optional tree)
+  "Apply the change of the current buffer's text from BEG to END to TREE.
+OLD-LEN is the length of the replaced text, in characters. These are the
+arguments of `after-change-functions', so this function can be called from
+there, e.g. (lambda (beg end old-len) (tsc-record-change beg end old-len tree)).
+
+Return the edit, as a list (START-BYTEPOS OLD-END-BYTEPOS NEW-END-BYTEPOS
+START-POINT OLD-END-POINT NEW-END-POINT), the arguments of `tsc-edit-tree' after
+the tree. If TREE is nil, the edit is only returned, e.g. to be applied to
+several trees, or batched with `tsc-edit-tree*'.
+
+The bytes and points of the replaced text's end can't be known from the changed
+buffer. So unless the change was a pure insertion, the replaced text must have
+been recorded by `tsc-record-before-change', which should be on
+`before-change-functions'."
+  (tsc--save-context
+    (let ((old-text
+           (if (= old-len 0)
+               ""
+             (pcase tsc--text-before-change
+               ((and `(,recorded-beg . ,text)
+                     (guard (<= recorded-beg beg))
+                     (guard (<= (+ (- beg recorded-beg) old-len) (length text))))
+                (substring text (- beg recorded-beg) (+ (- beg recorded-beg) old-len)))
+               (_ (error "The text replaced at %s was not recorded before the change" beg))))))
+      (tsc--record-change tree (position-bytes beg) (tsc--point-from-position beg)
+                          old-text (buffer-substring-no-properties beg end)))))
+
+(defun tsc-quick-parse (language string &optional output)
+  "Parse STRING with LANGUAGE, using a temporary parser.
+OUTPUT specifies the returned representation of the syntax tree:
+
+`tree' (or nil)  The tree object.
+`sexp'           The tree's sexp, as a Lisp object, e.g. (source_file ...).
+`json'           A JSON string, as returned by `tsc-tree-to-json'.
+
+This is meant for scripts, tests, and experiments in `ielm'."
+  (let ((parser (tsc-make-parser)))
+    (tsc-set-language parser language)
+    (let ((tree (tsc-parse-string parser string)))
+      (pcase output
+        ((or 'nil 'tree) tree)
+        ('sexp (read (tsc-tree-to-sexp tree)))
+        ('json (tsc-tree-to-json tree))
+        (_ (signal 'wrong-type-argument (list '(member tree sexp json) output)))))))
+
+
+;;; Language detection.
+
+(defvar tsc-detect-language-trial-size 4096
+  "Maximum number of characters parsed by trial parses of `tsc-detect-language'.")
+
+(defun tsc-detect-language (buffer registry &optional trial)
+  "Return the name of the language of BUFFER's content, or nil.
+REGISTRY is a list of (NAME . PLIST). PLIST can have these keys:
+
+:extensions    File name extensions, e.g. (\"py\" \"pyw\").
+:interpreters  Interpreters named in shebang lines, e.g. (\"python\"). Version
+               suffixes are ignored, so \"python3.11\" also matches.
+:modes         Mode names in `-*-' lines, without the \"-mode\" suffix.
+               Defaults to NAME's name.
+:language      The language object, used for trial parses.
+
+The criteria are checked in the same order as `set-auto-mode': `-*-' line,
+shebang line, then extension of the buffer's file name (or of the buffer's name,
+if it does not visit a file). If TRIAL is non-nil, and several entries match the
+same criterion (or none does), the beginning of BUFFER (see
+`tsc-detect-language-trial-size') is parsed with their languages, and the one
+with the fewest errors wins.
+
+This is useful for scripts without extensions, or for source blocks of `org'."
+  (with-current-buffer buffer
+    (tsc--without-restriction
+      (let ((head (buffer-substring-no-properties
+                   (point-min) (save-excursion
+                                 (goto-char (point-min))
+                                 (line-end-position 2))))
+            (registry
+             (apply #'vector
+                    (mapcar (pcase-lambda (`(,name . ,plist))
+                              (vector name
+                                      (apply #'vector (plist-get plist :extensions))
+                                      (apply #'vector (plist-get plist :interpreters))
+                                      (apply #'vector (or (plist-get plist :modes)
+                                                          (list (symbol-name name))))
+                                      (plist-get plist :language)))
+                            registry))))
+        (tsc--detect-language
+         (or buffer-file-name (buffer-name)) head registry
+         (when trial
+           (buffer-substring-no-properties
+            (point-min) (min (point-max) (+ (point-min) tsc-detect-language-trial-size)))))))))
+
 
 ;;; Querying.
 
 (defun tsc--stringify-patterns (patterns)
   "Convert PATTERNS into a query string that can be passed to `tsc--make-query'."
-  (cond
-   ((stringp patterns) patterns)
-   ((sequencep patterns)
-    ;; XXX: This is hacky.
-    (thread-last (mapconcat (lambda (p) (format "%S" p)) patterns "\n")
-      (replace-regexp-in-string (regexp-quote "\\?") "?")
-      (replace-regexp-in-string (regexp-quote "\\.") ".")))
-   (t (error "Invalid patterns"))))
+  (if (stringp patterns)
+      patterns
+    (tsc--serialize-patterns patterns)))
 
 (defun tsc-make-query (language patterns &optional tag-assigner)
   "Create a new query for LANGUAGE from a sequence of S-expression PATTERNS.
@@ -236,11 +606,39 @@ that are captured as \"@function.builtin\" will be tagged with the symbol
 TAG-ASSIGNER, which should return a tag value when given a capture name (without
 the prefix \"@\"). If it returns nil, the associated capture name is disabled.
 
+PATTERNS is either a string in the query syntax of tree-sitter, or a sequence
+of patterns written as Lisp data, which is serialized into that syntax:
+
+- A list is a parenthesized node, group, or predicate, e.g. (identifier) or
+  (.eq? @name \"main\"). As the reader treats `#' specially, predicates are
+  written with the prefix `.', which tree-sitter also accepts.
+- A vector is an alternation, e.g. [(identifier) (type_identifier)].
+- A keyword is a field name, e.g. :name for \"name:\". A symbol ending in
+  \":\", e.g. `name:', works too.
+- A string is a literal, e.g. an anonymous node, or a predicate's argument. It
+  is quoted and escaped as needed.
+- Other symbols, e.g. captures (@name), quantifiers (`?', `*', `+'), the
+  wildcard `_', and the anchor `\\.', are written as they are. So are integers.
+
+Since the patterns are data, they can be built programmatically, e.g. with
+backquotes, without worrying about quoting. If such patterns are invalid, the
+signaled error's :form property is the innermost offending element, see
+`tsc-query-validate'.
+
 See also: `tsc-query-captures' and `tsc-query-matches'."
-  (tsc--make-query language (tsc--stringify-patterns patterns)
-                   (or tag-assigner #'intern)))
+  (tsc--make-query language patterns (or tag-assigner #'intern)))
 
-(defun tsc-query-matches (query node text-function &optional cursor)
+(defun tsc-make-shared-query (language patterns &optional tag-assigner)
+  "Return a query for LANGUAGE from PATTERNS, shared with other callers.
+This is like `tsc-make-query', except that queries compiled from the same
+PATTERNS, with the same (`eq') TAG-ASSIGNER, are compiled only once, and shared
+by all callers, e.g. all buffers of the same major mode. Therefore, the returned
+query must not be modified or released.
+
+See also: `tsc-cache-stats' and `tsc-clear-caches'."
+  (tsc--make-shared-query language patterns (or tag-assigner #'intern)))
+
+(defun tsc-query-matches (query node text-function &optional cursor beg-byte end-byte)
   "Execute QUERY on NODE and return a sequence of matches.
 Matches are sorted in the order they were found.
 
@@ -251,14 +649,26 @@ a sequence of captures associated with the match, similar to that returned by
 
 TEXT-FUNCTION is called to get nodes' texts (for text-based predicates). It
 should take 2 parameters: (BEG-BYTE END-BYTE), and return the corresponding
-chunk of text in the source code.
+chunk of text in the source code. The predicates `#eq?', `#match?', `#any-of?',
+and their `#not-' variants are evaluated before results are returned. Other
+predicates are left for the caller to interpret.
 
 If the optional arg CURSOR is non-nil, it is used as the query-cursor to execute
-QUERY. Otherwise, a newly created query-cursor is used."
-  (tsc--query-cursor-matches
-   (or cursor (tsc-make-query-cursor)) query node text-function))
-
-(defun tsc-query-captures (query node text-function &optional cursor)
+QUERY. Otherwise, an idle query-cursor from a pool is used, so that repeated calls
+don't allocate a new one each time.
+
+If the optional arg BEG-BYTE or END-BYTE is non-nil, only the matches that
+intersect the range between them are returned. They default to the start and
+end of NODE. The range is set on the query-cursor, see
+`tsc-query-cursor-set-byte-range'."
+  (tsc--with-query-cursor (cursor cursor)
+    (when (or beg-byte end-byte)
+      (tsc-query-cursor-set-byte-range cursor
+                                       (or beg-byte (tsc-node-start-byte node))
+                                       (or end-byte (tsc-node-end-byte node))))
+    (tsc--query-cursor-matches cursor query node text-function)))
+
+(defun tsc-query-captures (query node text-function &optional cursor beg-byte end-byte)
   "Execute QUERY on NODE and return a sequence of captures.
 Captures are sorted in the order they appear.
 
@@ -269,12 +679,190 @@ is the value returned by that function instead. See also: `tsc-make-query'.
 
 TEXT-FUNCTION is called to get nodes' texts (for text-based predicates). It
 should take 2 parameters: (BEG-BYTE END-BYTE), and return the corresponding
-chunk of text in the source code.
+chunk of text in the source code. The predicates `#eq?', `#match?', `#any-of?',
+and their `#not-' variants are evaluated before results are returned. Other
+predicates are left for the caller to interpret.
 
 If the optional arg CURSOR is non-nil, it is used as the query-cursor to execute
-QUERY. Otherwise, a newly created query-cursor is used."
-  (tsc--query-cursor-captures
-   (or cursor (tsc-make-query-cursor)) query node text-function))
+QUERY. Otherwise, an idle query-cursor from a pool is used, so that repeated calls
+don't allocate a new one each time.
+
+If the optional arg BEG-BYTE or END-BYTE is non-nil, only the captures that
+intersect the range between them are returned. They default to the start and
+end of NODE. The range is set on the query-cursor, see
+`tsc-query-cursor-set-byte-range'."
+  (tsc--with-query-cursor (cursor cursor)
+    (when (or beg-byte end-byte)
+      (tsc-query-cursor-set-byte-range cursor
+                                       (or beg-byte (tsc-node-start-byte node))
+                                       (or end-byte (tsc-node-end-byte node))))
+    (tsc--query-cursor-captures cursor query node text-function)))
+
+(defun tsc-first-n-captures (query node beg-byte end-byte n &optional text-function cursor)
+  "Execute QUERY on NODE from BEG-BYTE to END-BYTE; return the first N captures.
+
+Unlike `tsc-query-captures', this stops executing QUERY as soon as N captures
+are found, which makes it cheap to answer questions like \"is there any match
+here?\". The captures have the same form as those returned by
+`tsc-query-captures'.
+
+TEXT-FUNCTION defaults to extracting the current buffer's text. See
+`tsc-query-captures' for its meaning, and for the meaning of CURSOR."
+  (tsc--without-restriction
+    (tsc--with-query-cursor (cursor cursor)
+      (tsc--query-cursor-first-n-captures
+       cursor query node beg-byte end-byte n
+       (or text-function #'tsc--buffer-substring-no-properties)))))
+
+(defun tsc-requery (query old-tree new-tree &optional edited-ranges text-function)
+  "Execute QUERY on NEW-TREE, only where it differs from OLD-TREE.
+Return (INVALIDATED . CAPTURES), where INVALIDATED is a vector of
+non-overlapping (BEG-BYTE . END-BYTE), sorted by position, and CAPTURES is a
+vector of the captures that intersect them, in the same form as those returned
+by `tsc-query-captures'.
+
+The invalidated ranges are those returned by `tsc-changed-ranges', extended to
+cover the captures that cross their boundaries. To keep up with NEW-TREE, e.g.
+when highlighting, it is therefore enough to discard what was computed for the
+invalidated ranges, then apply CAPTURES.
+
+OLD-TREE must have been edited to match NEW-TREE, like for `tsc-changed-ranges'.
+Edits that don't change the syntactic structure, e.g. renaming an identifier,
+don't produce changed ranges, so the edited regions should be passed as
+EDITED-RANGES, a sequence of (BEG-BYTE . END-BYTE). They are invalidated too.
+
+TEXT-FUNCTION defaults to extracting the current buffer's text. See
+`tsc-query-captures' for its meaning."
+  (tsc--without-restriction
+    (tsc--requery query old-tree new-tree (vconcat edited-ranges)
+                  (or text-function #'tsc--buffer-substring-no-properties))))
+
+(defun tsc-define-query-predicate (name function)
+  "Make FUNCTION evaluate the custom query predicate NAME, e.g. \"lua-match?\".
+NAME is the predicate's operator, without the prefix \"#\".
+
+When a pattern using (#NAME ARGS...) matches, FUNCTION is called with ARGS,
+where each capture is replaced by the first node it captured in the match, or
+nil. The match is kept only if FUNCTION returns non-nil. If FUNCTION is nil,
+NAME is not evaluated anymore, so it always holds.
+
+This allows reusing query files written for other editors, e.g. those of
+nvim-treesitter, which use predicates like #lua-match? or #has-ancestor?.
+Custom predicates are evaluated by the functions that run queries with a text
+function, e.g. `tsc-query-matches' and `tsc-query-captures'.
+`tsc-query-captures-parallel' executes the queries using them on the main thread."
+  (tsc--define-query-predicate name function))
+
+(defun tsc-query-pattern-directives (query pattern-index)
+  "Return the directives of QUERY's pattern at PATTERN-INDEX, e.g. #offset!.
+The return value is a list of (NAME . ARGS), where NAME is a symbol, e.g.
+`offset!'. Captures in ARGS are replaced by their tags, and integers are
+converted. (#set! KEY VALUE) is returned as (set! nil KEY VALUE), and
+(#set! @CAPTURE KEY VALUE) as (set! CAPTURE-TAG KEY VALUE).
+
+See also: `tsc-query-match-metadata'."
+  (tsc--query-pattern-directives query pattern-index))
+
+(defun tsc-query-match-metadata (query match)
+  "Return the metadata that the directives of its pattern set for MATCH.
+MATCH is a match of QUERY, as returned by `tsc-query-matches'. The metadata is
+a plist:
+
+:properties  Alist of (KEY . VALUE), from (#set! KEY VALUE) directives.
+:captures    Alist of (CAPTURE-TAG . PLIST), for individual captures. PLIST can
+             have the keys :properties, an alist from (#set! @CAPTURE KEY
+             VALUE) directives, and :range, the captured node's range
+             (START-POINT . END-POINT), shifted by (#offset! @CAPTURE
+             START-ROW START-COLUMN END-ROW END-COLUMN).
+
+Other directives are left to `tsc-query-pattern-directives'."
+  (let (properties captures)
+    (cl-labels ((capture-entry (tag)
+                  (or (assq tag captures)
+                      (car (push (list tag) captures)))))
+      (pcase-dolist (`(,name . ,args) (tsc-query-pattern-directives query (car match)))
+        (pcase (cons name args)
+          (`(set! nil ,key ,value)
+           (push (cons key value) properties))
+          (`(set! ,tag ,key ,value)
+           (let ((entry (capture-entry tag)))
+             (setcdr entry (plist-put (cdr entry) :properties
+                                      (append (plist-get (cdr entry) :properties)
+                                              (list (cons key value)))))))
+          (`(offset! ,tag ,beg-rows ,beg-columns ,end-rows ,end-columns)
+           (let ((node (cdr (seq-find (lambda (capture) (eq (car capture) tag)) (cdr match)))))
+             (when node
+               (pcase-let ((`(,beg-line . ,beg-column) (tsc-node-start-point node))
+                           (`(,end-line . ,end-column) (tsc-node-end-point node))
+                           (entry (capture-entry tag)))
+                 (setcdr entry (plist-put (cdr entry) :range
+                                          (cons (cons (+ beg-line beg-rows)
+                                                      (+ beg-column beg-columns))
+                                                (cons (+ end-line end-rows)
+                                                      (+ end-column end-columns))))))))))))
+    (list :properties (nreverse properties)
+          :captures (nreverse captures))))
+
+(defun tsc-query-captures-parallel (jobs beg-byte end-byte &optional text-function)
+  "Execute JOBS in parallel, within the range from BEG-BYTE to END-BYTE.
+Return the merged captures, as a vector of non-overlapping spans
+\(BEG-BYTE END-BYTE . CAPTURE-TAG), sorted by position.
+
+JOBS should be a list of (QUERY . TREE), where the trees were parsed from the
+current buffer, e.g. the host tree and the injected trees of a template file.
+Each job is executed on a separate thread. Later jobs are painted over earlier
+ones. Within a job, inner captures take precedence over outer ones, and for the
+same node, the earlier pattern wins.
+
+Text-based predicates are evaluated against the text of the top-level nodes
+around the range, which TEXT-FUNCTION is called once for. A job whose predicates
+need other text is executed again on the main thread, as is a job whose query
+uses custom predicates. See `tsc-define-query-predicate'. TEXT-FUNCTION is the
+same as for `tsc-query-captures'."
+  (tsc--without-restriction
+    (tsc--query-captures-parallel
+     (vconcat jobs) beg-byte end-byte
+     (or text-function #'tsc--buffer-substring-no-properties))))
+
+(defun tsc-highlight-region (layers beg-byte end-byte &optional text-function)
+  "Return the highlighting of the current buffer from BEG-BYTE to END-BYTE.
+The result is a vector of non-overlapping spans (BEG-BYTE END-BYTE . CAPTURE-TAG),
+sorted by position. It is computed entirely by the dynamic module, which is much
+faster than applying each capture returned by `tsc-query-captures'.
+
+LAYERS should be a list of (HIGHLIGHTS TREE LOCALS), where TREE was parsed from
+the current buffer, HIGHLIGHTS is a query for TREE's language, and LOCALS is nil,
+or a query whose @local.scope, @local.definition, and @local.reference captures
+make references highlighted like their definitions. Later layers, e.g. those of
+injected trees, are painted over earlier ones.
+
+Within a layer, inner captures take precedence over outer ones. For captures of
+the same node, the earlier pattern wins.
+
+TEXT-FUNCTION is called with a node's start and end byte positions, to get the
+text needed by the predicates and LOCALS. It defaults to taking the text from
+the current buffer. Only those nodes' texts are fetched."
+  (tsc--without-restriction
+    (tsc--highlight-region
+     (vconcat (mapcar (pcase-lambda (`(,highlights ,tree ,locals))
+                        (vector highlights tree locals))
+                      layers))
+     beg-byte end-byte
+     (or text-function #'tsc--buffer-substring-no-properties))))
+
+(defun tsc-apply-spans (spans &optional property)
+  "Apply SPANS as text properties of the current buffer.
+SPANS should be a vector of (BEG-BYTE END-BYTE . VALUE), where VALUE is either a
+plist of text properties to set, or the value to set PROPERTY to. PROPERTY
+defaults to `face'. Spans whose VALUE is nil are skipped. Narrowing is ignored.
+
+The byte positions are converted, and the properties set, by the dynamic module,
+in a single call. This is much faster than a Lisp loop applying thousands of
+spans, e.g. those of `tsc-highlight-region', after mapping their capture tags to
+faces. Like with `put-text-property', the caller should usually wrap this in
+`with-silent-modifications'."
+  (tsc--without-restriction
+    (tsc--apply-spans spans (or property 'face))))
 
 
 ;;; Traversal.
@@ -286,7 +874,9 @@ QUERY. Otherwise, a newly created query-cursor is used."
     :named-p :extra-p :error-p :missing-p :has-error-p
     :start-byte :end-byte
     :start-point :end-point
-    :range :byte-range)
+    :range :byte-range
+    :node-ref ;compact reference, see `tsc-resolve-node-ref'
+    )
   "Node properties that the traversal functions can return.
 
 When dealing with a large number of nodes, working with node objects creates a
@@ -295,6 +885,10 @@ instead extract and work with individual node properties. Several functions can
 optionally take a vector of property names, and return a vector of property
 values.
 
+When node objects are needed later on, but only for a small subset of the
+nodes, request the property `:node-ref' instead. It is a small vector that can be
+resolved back to the node object with `tsc-resolve-node-ref'.
+
 This wouldn't be necessary if the runtime supported stack-allocated objects.
 e.g. automatically through escape analysis. How about porting ELisp to GraalVM?")
 
@@ -340,6 +934,40 @@ For example, to crudely render a syntax tree:
   (tsc--check-node-props props)
   (tsc--traverse-mapc func tree-or-node props))
 
+(defun tsc-traverse-filter (func tree-or-node filter &optional props)
+  "Call FUNC for each node of TREE-OR-NODE that passes FILTER.
+The traversal is depth-first pre-order, and is done entirely in the dynamic
+module, so no node objects are created for nodes that don't pass FILTER.
+
+FILTER is a plist, with these optional properties:
+
+:types      List of node types (see `tsc-node-type') to keep.
+:named      If non-nil, keep only named nodes.
+:min-depth  Keep only nodes at least this deep, relative to TREE-OR-NODE.
+:max-depth  Don't descend into nodes at this depth.
+:beg, :end  Keep only nodes intersecting this range of byte positions, and
+            don't descend into nodes outside of it.
+
+PROPS has the same meaning as in `tsc-traverse-mapc'. For example, with PROPS
+being [:type :start-byte :end-byte], FUNC receives a (reused) vector of these
+properties instead of a node object.
+
+If FUNC is nil, return a vector of what it would have been called with, i.e.
+nodes, or fresh vectors of properties. For example, to list the top-level
+functions of a Rust file, with their byte ranges:
+
+    (tsc-traverse-filter nil tree '(:types (function_item) :max-depth 1)
+                         [:type :start-byte :end-byte])"
+  (tsc--check-node-props props)
+  (tsc--traverse-filter func tree-or-node props
+                        (when-let ((types (plist-get filter :types)))
+                          (vconcat types))
+                        (plist-get filter :named)
+                        (plist-get filter :min-depth)
+                        (plist-get filter :max-depth)
+                        (plist-get filter :beg)
+                        (plist-get filter :end)))
+
 (defun tsc-traverse-iter (tree-or-node &optional props)
   "Return an iterator that traverse TREE-OR-NODE.
 The traversal is depth-first pre-order.
@@ -406,6 +1034,886 @@ For example, to crudely render a syntax tree:
                                      (aref ,output ,i))))
            ,@body)))))
 
+
+;;; Diagnostics.
+
+(defun tsc--describe-missing-token (node)
+  "Return a human-readable description of the token inserted for MISSING NODE."
+  (let ((token (tsc-missing-token node)))
+    (if (tsc-node-named-p node)
+        (replace-regexp-in-string "_" " " token)
+      (format "`%s'" token))))
+
+(defun tsc--error-excerpt (node)
+  "Return the first line of NODE's text, shortened for use in messages.
+This function must be called with narrowing disabled, e.g. within a
+`tsc--without-restriction' block."
+  (let ((text (car (split-string (tsc--node-text node 1000) "\n"))))
+    (truncate-string-to-width (string-trim text) 40 nil nil "...")))
+
+(defun tsc-diagnostics (tree &optional buffer)
+  "Return a vector of diagnostics for TREE's syntax errors.
+TREE must have been parsed from BUFFER, which defaults to the current buffer.
+
+Each diagnostic has the form (START END SEVERITY MESSAGE), where START and END
+are BUFFER's positions, and SEVERITY is `:error'. It can be passed to
+`flymake-make-diagnostic', for example.
+
+There is one diagnostic for each ERROR node (code that the parser could not
+incorporate into the tree), and for each MISSING node (a token that the parser
+inserted to recover from an error). The latter is zero-width, and its message
+names the inserted token (see `tsc-missing-token')."
+  (with-current-buffer (or buffer (current-buffer))
+    (tsc--without-restriction
+      (apply #'vector
+             (mapcar (lambda (node)
+                       (pcase-let ((`(,beg . ,end) (tsc-node-position-range node)))
+                         (list beg end :error
+                               (if (tsc-node-missing-p node)
+                                   (format "Missing %s" (tsc--describe-missing-token node))
+                                 (let ((excerpt (condition-case nil
+                                                    (tsc--error-excerpt node)
+                                                  (tsc-node-text-too-long nil))))
+                                   (if (and excerpt (not (string-empty-p excerpt)))
+                                       (format "Syntax error, unexpected `%s'" excerpt)
+                                     "Syntax error"))))))
+                     (tsc--error-nodes tree))))))
+
+(defun tsc-tree-errors (tree &optional beg end)
+  "Return a vector of TREE's ERROR and MISSING nodes, optionally from BEG to END.
+BEG and END are byte positions. See `tsc-node-errors' for the format of the
+vector's elements."
+  (tsc-node-errors (tsc-root-node tree) beg end))
+
+(defun tsc-verify-tree (tree &optional buffer samples)
+  "Check that TREE is in sync with BUFFER's text; return the first mismatch found.
+BUFFER defaults to the current buffer. SAMPLES is the maximum number of literal
+tokens (e.g. \"fn\", \";\") to compare against BUFFER's text. It defaults to 64.
+
+Return nil if no mismatch was found. Otherwise, return a plist of the form
+\(:position POSITION :reason REASON :expected EXPECTED :actual ACTUAL), where
+REASON is either `extent' (TREE does not end where BUFFER does, EXPECTED and
+ACTUAL being byte positions), or `token' (the token at POSITION does not match
+BUFFER's text).
+
+A mismatch usually means that `tsc-edit-tree' was called with positions that do
+not exactly match the buffer's changes."
+  (with-current-buffer (or buffer (current-buffer))
+    (tsc--without-restriction
+      (let ((end-byte (position-bytes (point-max)))
+            (tree-end-byte (tsc-node-end-byte (tsc-root-node tree))))
+        (if (/= tree-end-byte end-byte)
+            (list :position (or (byte-to-position tree-end-byte) (point-max))
+                  :reason 'extent
+                  :expected end-byte
+                  :actual tree-end-byte)
+          (catch 'tsc--mismatch
+            (seq-doseq (token (tsc--sample-tokens tree (or samples 64)))
+              (pcase-let* ((`(,beg-byte ,end-byte . ,expected) token)
+                           (beg (byte-to-position beg-byte))
+                           (actual (buffer-substring-no-properties
+                                    beg (byte-to-position end-byte))))
+                (unless (string= expected actual)
+                  (throw 'tsc--mismatch (list :position beg
+                                              :reason 'token
+                                              :expected expected
+                                              :actual actual)))))
+            nil))))))
+
+
+;;; Indentation.
+
+(defun tsc-indent-column (query tree &optional position indent-width)
+  "Return the indentation column of the line at POSITION, according to QUERY.
+TREE must be in sync with the current buffer. POSITION defaults to the point.
+INDENT-WIDTH is the number of columns per indentation level. It defaults to
+`tab-width'.
+
+QUERY is an indentation query, whose captures @indent, @dedent, @branch,
+@aligned, @zero, and @ignore assign indentation roles to nodes. The capture
+names of other editors' indents.scm files, e.g. @indent.begin and @outdent, are
+understood too. See `tsc--indent-column' for their meanings. For example, for
+Rust:
+
+  (tsc-make-query language
+                  [(block) @indent
+                   \"}\" @branch
+                   (arguments) @aligned])"
+  (save-excursion
+    (tsc--without-restriction
+      (goto-char (or position (point)))
+      (back-to-indentation)
+      (tsc--indent-column query tree
+                          (position-bytes (line-beginning-position))
+                          (position-bytes (point))
+                          (or indent-width tab-width)
+                          tab-width
+                          #'tsc--buffer-substring-no-properties))))
+
+(defun tsc-indent-line (query tree &optional indent-width)
+  "Indent the current line according to QUERY, using TREE.
+If the point is within the line's indentation, move it to the indentation's end.
+See `tsc-indent-column' for the meanings of the parameters."
+  (let ((column (tsc-indent-column query tree nil indent-width)))
+    (if (<= (current-column) (current-indentation))
+        (indent-line-to column)
+      (save-excursion
+        (indent-line-to column)))))
+
+
+;;; Folding.
+
+(defun tsc-fold-ranges (tree query &optional min-lines)
+  "Return the foldable regions of the current buffer, according to TREE and QUERY.
+The result is a vector of (BEG-BYTE END-BYTE . SUMMARY), sorted by position,
+where SUMMARY is the text of the region's first line, e.g. to be displayed when
+the region is folded. It is computed entirely by the dynamic module.
+
+QUERY should be a folds query, whose @fold captures are the foldable nodes. If
+it is nil, the block-like named nodes are used instead: those whose types
+contain \"block\" or \"comment\", and those delimited by brackets.
+
+Only the regions spanning at least MIN-LINES lines are returned. It defaults to
+2. Of the regions that start on the same line, only the longest one is kept."
+  (tsc--without-restriction
+    (tsc--fold-ranges tree query (or min-lines 2)
+                      #'tsc--buffer-substring-no-properties)))
+
+
+;;; Tags.
+
+(defun tsc-tags (tree query)
+  "Return the symbol index of the current buffer, according to TREE and QUERY.
+The result is a vector of [NAME KIND DEFINITION-P BEG-BYTE END-BYTE NAME-BEG-BYTE
+NAME-END-BYTE PARENT], sorted by position. It is computed entirely by the
+dynamic module.
+
+QUERY should be a tags query, following the conventions of tree-sitter's
+tags.scm files: each pattern captures an entity's name as @name, and the whole
+entity as @definition.KIND or @reference.KIND, e.g. @definition.function, or
+@reference.call. KIND is returned as a symbol, e.g. `function'.
+
+PARENT is the index, in the result, of the innermost definition enclosing the
+tag, or nil. See also `tsc-tags-imenu-index'."
+  (tsc--without-restriction
+    (tsc--tags tree query #'tsc--buffer-substring-no-properties)))
+
+(defun tsc-tags-imenu-index (tree query)
+  "Return an index of the current buffer's definitions, for `imenu'.
+The definitions are found by QUERY, like in `tsc-tags'. Those that enclose other
+definitions, e.g. classes with methods, become nested sub-alists."
+  (let* ((tags (tsc-tags tree query))
+         (children (make-vector (length tags) nil))
+         (roots nil))
+    (dotimes (i (length tags))
+      (pcase-let ((`[,_ ,_ ,definition-p ,_ ,_ ,_ ,_ ,parent] (aref tags i)))
+        (when definition-p
+          (if parent
+              (push i (aref children parent))
+            (push i roots)))))
+    (cl-labels ((entry (i)
+                  (pcase-let* ((`[,name ,_ ,_ ,_ ,_ ,name-beg ,_ ,_] (aref tags i))
+                               (item (cons name (byte-to-position name-beg)))
+                               (nested (aref children i)))
+                    (if nested
+                        (cons name (cons item (mapcar #'entry (nreverse nested))))
+                      item))))
+      (tsc--without-restriction
+        (mapcar #'entry (nreverse roots))))))
+
+
+;;; JSON export.
+
+(defun tsc-tree-to-json (tree-or-node &optional named-only)
+  "Return the syntax tree TREE-OR-NODE as a compact JSON string.
+Each node is an object with the keys \"type\", \"named\", \"start\" and \"end\"
+\(byte positions, as in Emacs), \"startPoint\" and \"endPoint\" ([LINE-NUMBER,
+BYTE-COLUMN] arrays), \"children\", and, when applicable, \"field\" and
+\"missing\". It is generated entirely by the dynamic module, e.g. to be piped to
+external tools.
+
+If NAMED-ONLY is non-nil, anonymous nodes are omitted."
+  (tsc--node-to-json (if (tsc-node-p tree-or-node)
+                         tree-or-node
+                       (tsc-root-node tree-or-node))
+                     named-only))
+
+(defun tsc-matches-to-json (query node &optional text-function)
+  "Execute QUERY on NODE, and return the matches as a compact JSON string.
+The result is an array of objects, with the keys \"pattern\", the matched
+pattern's index, and \"captures\", an array of the captured nodes, in the
+format of `tsc-tree-to-json', but without \"children\", and with the additional
+key \"name\", the capture's name.
+
+TEXT-FUNCTION defaults to extracting the current buffer's text. See
+`tsc-query-matches' for its meaning."
+  (tsc--without-restriction
+    (tsc--query-matches-to-json query node
+                                (or text-function #'tsc--buffer-substring-no-properties))))
+
+
+;;; Formatting.
+
+(defun tsc--edit-positions (edits)
+  "Convert EDITS, a vector of [BEG-BYTE END-BYTE TEXT], into a list of (BEG END TEXT).
+This function must be called with narrowing disabled."
+  (mapcar (pcase-lambda (`[,beg-byte ,end-byte ,text])
+            (list (byte-to-position beg-byte) (byte-to-position end-byte) text))
+          edits))
+
+(defun tsc-apply-edits (edits)
+  "Apply EDITS, a list of (BEG END TEXT) sorted by position, to the current buffer.
+Each edit replaces the text from BEG to END with TEXT. Since BEG and END refer to
+the text before any edit, the edits are applied backward."
+  (save-excursion
+    (pcase-dolist (`(,beg ,end ,text) (reverse edits))
+      (goto-char beg)
+      (delete-region beg end)
+      (insert text))))
+
+(defun tsc-format-edits (query tree beg end)
+  "Return the whitespace edits that format the region from BEG to END.
+The edits are directed by QUERY's `format' properties, see `tsc--format-edits'.
+TREE must be in sync with the current buffer.
+
+Return a list of (BEG END TEXT), sorted by position, meaning the text from BEG
+to END should be replaced with TEXT. BEG and END are positions."
+  (tsc--without-restriction
+    (tsc--edit-positions
+     (tsc--format-edits query tree (position-bytes beg) (position-bytes end)
+                        #'tsc--buffer-substring-no-properties))))
+
+(defun tsc-format-region (query tree beg end)
+  "Format the region from BEG to END, as directed by QUERY; return the number of edits.
+TREE must be in sync with the current buffer. It is not updated by this function,
+so it must be edited afterward, e.g. by `tree-sitter-mode'. See `tsc-format-edits'
+for how QUERY directs the formatting."
+  (let ((edits (tsc-format-edits query tree beg end)))
+    (tsc-apply-edits edits)
+    (length edits)))
+
+
+;;; Structural editing.
+
+;; These functions generalize paredit's operations to any grammar. A node's
+;; delimiters are its first and last children, if they are anonymous tokens, e.g.
+;; the parentheses of an argument list. Each function returns the edits to be
+;; applied with `tsc-apply-edits', or nil if the operation does not apply to NODE.
+;; NODE must be from the current buffer's syntax tree.
+
+(defmacro tsc--structural-edits (fn node)
+  "Call FN with NODE and a text function for the current buffer; convert the edits."
+  `(tsc--without-restriction
+     (tsc--edit-positions (,fn ,node #'tsc--buffer-substring-no-properties))))
+
+(defun tsc-raise-node (node)
+  "Return the edits that replace NODE's closest named ancestor with NODE."
+  (tsc--structural-edits tsc--raise-node node))
+
+(defun tsc-splice-node (node)
+  "Return the edits that remove NODE's delimiters, keeping its content."
+  (tsc--structural-edits tsc--splice-node node))
+
+(defun tsc-slurp-forward (node)
+  "Return the edits that move NODE's closing delimiter past its next named sibling."
+  (tsc--structural-edits tsc--slurp-forward node))
+
+(defun tsc-barf-forward (node)
+  "Return the edits that move NODE's closing delimiter before its last named child."
+  (tsc--structural-edits tsc--barf-forward node))
+
+(defun tsc-matching-delimiter (tree &optional position)
+  "Return the delimiters of the innermost node of TREE enclosing POSITION.
+POSITION defaults to the point. The return value has the form ((OPEN-BEG .
+OPEN-END) (CLOSE-BEG . CLOSE-END)), or nil if there is no such node.
+
+Unlike character syntax, this uses the grammar's structure, so brackets inside
+strings and comments are not matched. TREE must be in sync with the current
+buffer."
+  (tsc--without-restriction
+    (pcase (tsc--matching-delimiter tree (position-bytes (or position (point))))
+      (`[,open-beg ,open-end ,close-beg ,close-end]
+       (list (cons (byte-to-position open-beg) (byte-to-position open-end))
+             (cons (byte-to-position close-beg) (byte-to-position close-end)))))))
+
+
+;;; Syntax context.
+
+(defun tsc-syntax-context (tree &optional position kind-sets)
+  "Return the kinds of syntax that enclose POSITION in TREE, innermost first.
+POSITION defaults to the point. TREE must be in sync with the current buffer.
+
+KIND-SETS is an alist of (NAME . NODE-TYPES), for example:
+
+  \='((string string_literal raw_string_literal)
+    (comment line_comment block_comment))
+
+The return value is a list of the NAMEs whose NODE-TYPES have a node strictly
+enclosing POSITION. If KIND-SETS is nil, the sets `string' and `comment' are
+used, each containing the node types whose names contain it.
+
+This is a single call into the dynamic module, cheap enough to be used by
+`electric-pair-mode'-like features on each key press."
+  (tsc--without-restriction
+    (tsc--syntax-context
+     tree (position-bytes (or position (point)))
+     (when kind-sets
+       (apply #'vector
+              (mapcar (pcase-lambda (`(,name . ,types))
+                        (cons name (tsc--kind-vector types)))
+                      kind-sets))))))
+
+(defun tsc-expected-closer (tree &optional position)
+  "Return the text of the token that would close the unclosed construct at POSITION.
+POSITION defaults to the point. Return nil if there is no unclosed construct.
+TREE must be in sync with the current buffer.
+
+Unlike `electric-pair-mode''s character tables, this uses the grammar: MISSING
+nodes inserted by the parser, and unmatched brackets inside ERROR nodes."
+  (tsc--expected-closer tree (tsc--without-restriction
+                               (position-bytes (or position (point))))))
+
+(defun tsc-prose-regions (tree &optional kinds beg end)
+  "Return the regions of human text in TREE, between BEG and END.
+The return value is a sorted list of (BEG . END), with adjacent regions merged.
+BEG and END default to the accessible portion of the current buffer, which TREE
+must be in sync with.
+
+KINDS is a list of node types whose text is considered prose. It defaults to the
+node types whose names contain \"comment\" or \"string\". This is meant for
+spell checkers, which can then skip code in a single call."
+  (let ((beg (or beg (point-min)))
+        (end (or end (point-max))))
+    (tsc--without-restriction
+      (mapcar (pcase-lambda (`(,beg-byte . ,end-byte))
+                (cons (byte-to-position beg-byte) (byte-to-position end-byte)))
+              (tsc--prose-regions
+               tree
+               (when kinds (tsc--kind-vector kinds))
+               (position-bytes beg) (position-bytes end))))))
+
+(defun tsc-tokens-in-range (tree beg-byte end-byte &optional named-only types)
+  "Return the tokens of TREE between BEG-BYTE and END-BYTE.
+Tokens are the leaf nodes, e.g. identifiers, keywords, punctuation, or
+comments. The return value is a vector of [TYPE BEG-BYTE END-BYTE], in
+document order, including the tokens that cross the range's boundaries.
+
+If NAMED-ONLY is non-nil, anonymous tokens, e.g. \"fn\" or \";\", are skipped.
+If TYPES is non-nil, it is a list of node types that the returned tokens are
+restricted to, e.g. \='(line_comment string_content).
+
+This is a cheap token stream for features like spell checking comments, or
+highlighting delimiters, which don't need queries."
+  (tsc--tokens-in-range tree beg-byte end-byte named-only
+                        (when types (tsc--kind-vector types))))
+
+(defun tsc-current-construct-range (tree position kinds)
+  "Return the range (BEG . END) of the innermost node of KINDS containing POSITION.
+KINDS is a list of node types, e.g. \='(expression_statement let_declaration).
+Return nil if there is no such node. TREE must be in sync with the current
+buffer.
+
+This is cheap enough to be called from `post-command-hook', e.g. to highlight
+the current statement."
+  (tsc--without-restriction
+    (pcase (tsc--current-construct-range
+            tree (position-bytes position)
+            (tsc--kind-vector kinds))
+      (`(,beg-byte . ,end-byte)
+       (cons (byte-to-position beg-byte) (byte-to-position end-byte))))))
+
+(defun tsc-expansion-steps (tree beg end)
+  "Return the successively larger ranges enclosing the region from BEG to END.
+The return value is a list of (BEG . END), each strictly containing the previous
+one: the ranges of the enclosing nodes, each preceded by its inner range,
+without delimiters and surrounding whitespace. TREE must be in sync with the
+current buffer.
+
+This is meant for `expand-region'-like commands, which can compute all their
+steps with a single call."
+  (tsc--without-restriction
+    (mapcar (pcase-lambda (`(,beg-byte . ,end-byte))
+              (cons (byte-to-position beg-byte) (byte-to-position end-byte)))
+            (tsc--expansion-steps tree (position-bytes beg) (position-bytes end)))))
+
+(defun tsc-expand-region (node-or-range &optional tree)
+  "Return the range of the smallest named node strictly containing NODE-OR-RANGE.
+NODE-OR-RANGE is either a node, or a range (BEG . END) of positions in the
+current buffer, in which case TREE must be given, and be in sync with the
+buffer. The return value is a range (BEG . END), or nil if there is no larger
+node.
+
+Unlike `tsc-expansion-steps', this computes one step at a time, skipping inner
+ranges and anonymous nodes, with a single call into the dynamic module."
+  (tsc--without-restriction
+    (pcase-let ((`(,beg . ,end) (if (consp node-or-range)
+                                    node-or-range
+                                  (tsc-node-position-range node-or-range))))
+      (pcase (tsc--expand-region (or tree node-or-range)
+                                 (position-bytes beg) (position-bytes end))
+        (`(,beg-byte . ,end-byte)
+         (cons (byte-to-position beg-byte) (byte-to-position end-byte)))))))
+
+(defun tsc-next-sibling-of-type (node types &optional backward)
+  "Return the nearest sibling after NODE whose type is one of TYPES.
+If BACKWARD is non-nil, return the nearest one before NODE instead. TYPES is a
+list of node types, e.g. \='(function_item struct_item). Return nil if there is
+no such sibling."
+  (tsc--sibling-of-type node (tsc--kind-vector types) backward))
+
+(defun tsc-parent-until (node types)
+  "Return the nearest ancestor of NODE whose type is one of TYPES.
+TYPES is a list of node types, e.g. \='(block function_item). Return nil if
+there is no such ancestor."
+  (tsc--parent-until node (tsc--kind-vector types)))
+
+(defun tsc-node-at-position (tree position &optional named)
+  "Return the smallest node of TREE at POSITION, in the current buffer.
+If NAMED is non-nil, return the smallest named node instead. TREE must be in
+sync with the current buffer."
+  (tsc--node-at-position tree (tsc--without-restriction (position-bytes position)) named))
+
+(defun tsc-forward-construct (tree position &optional count)
+  "Return the position reached by moving over COUNT constructs from POSITION.
+COUNT defaults to 1. If it is negative, move backward. Return nil if the end of
+the current nesting level is reached first. TREE must be in sync with the
+current buffer.
+
+This is a grammar-generic `forward-sexp': the constructs are the nodes at
+POSITION's nesting level. Comments and punctuation (e.g. commas, operators) are
+skipped."
+  (tsc--without-restriction
+    (when-let ((bytepos (tsc--forward-construct tree (position-bytes position) (or count 1))))
+      (byte-to-position bytepos))))
+
+(defun tsc-backward-construct (tree position &optional count)
+  "Return the position reached by moving backward over COUNT constructs from POSITION.
+See `tsc-forward-construct'."
+  (tsc-forward-construct tree position (- (or count 1))))
+
+
+;;; Sessions.
+
+(defun tsc-session-parse-buffer (session)
+  "Parse the current buffer with SESSION; return the new tree.
+SESSION's latest tree is reused, so it must have been kept in sync with the
+buffer's changes through `tsc-edit-tree'."
+  (tsc--without-restriction
+    (tsc-session-parse session #'tsc--buffer-input)))
+
+(defvar tsc-session-window-threshold (* 16 1024 1024)
+  "Size, in characters, above which `tsc-session-parse-visible' parses only a window.")
+
+(defvar tsc-session-window-margin 100000
+  "Number of characters around the visible region included in parse windows.")
+
+(defun tsc--visible-region ()
+  "Return the region (BEG . END) visible in the windows displaying the current buffer.
+If the buffer is not displayed, the region is empty, at point."
+  (let ((windows (get-buffer-window-list nil nil t)))
+    (if windows
+        (cons (apply #'min (mapcar #'window-start windows))
+              (apply #'max (mapcar (lambda (w) (window-end w t)) windows)))
+      (cons (point) (point)))))
+
+(defun tsc--window-range (beg end)
+  "Return the range from BEG to END, extended by `tsc-session-window-margin'.
+The range is extended to whole lines. This function must be called within a
+`tsc--save-context' block."
+  (setq beg (progn (goto-char (max (point-min) (- beg tsc-session-window-margin)))
+                   (line-beginning-position))
+        end (progn (goto-char (min (point-max) (+ end tsc-session-window-margin)))
+                   (line-end-position)))
+  (vector (position-bytes beg) (position-bytes end)
+          (tsc--point-from-position beg) (tsc--point-from-position end)))
+
+(defun tsc-session-parse-visible (session)
+  "Parse the current buffer with SESSION; return the new tree.
+If the buffer is larger than `tsc-session-window-threshold', only a window is
+parsed: the region visible in the windows displaying the buffer, extended by
+`tsc-session-window-margin' characters on each side, to whole lines. The window
+is parsed again only if the visible region moved out of it. This gives basic
+syntax information for huge files (e.g. logs) without freezing Emacs.
+
+This function is meant to be called again when the buffer changes, or when it
+is scrolled, e.g. from `window-scroll-functions'. See also
+`tsc-session-window'."
+  (tsc--save-context
+    (if (<= (buffer-size) tsc-session-window-threshold)
+        (tsc-session-parse session #'tsc--buffer-input)
+      (pcase-let ((`(,beg . ,end) (tsc--visible-region))
+                  (current (tsc-session-window session))
+                  (tree (tsc-session-tree session)))
+        ;; Reuse the tree if it was not edited since the parse, and covers the visible region.
+        (if (and current tree (not (tsc-node-has-changes-p (tsc-root-node tree)))
+                 (<= (car current) (position-bytes beg))
+                 (<= (position-bytes end) (cdr current)))
+            tree
+          (tsc--session-parse-window session #'tsc--buffer-input
+                                     (tsc--window-range beg end)))))))
+
+(defvar tsc-session-reparse-idle-delay 0.5
+  "Idle time, in seconds, before `tsc-session-parse-async' parses a changed buffer again.")
+
+(defvar-local tsc--session-reparse-timer nil
+  "Idle timer of the pending re-parse scheduled by `tsc-session-parse-async'.")
+
+(defun tsc--session-schedule-reparse (session callback)
+  "Parse the current buffer again with SESSION, once Emacs is idle.
+Re-parses scheduled before the pending one runs are coalesced into it."
+  (when tsc--session-reparse-timer
+    (cancel-timer tsc--session-reparse-timer))
+  (let ((buffer (current-buffer)))
+    (setq tsc--session-reparse-timer
+          (run-with-idle-timer
+           tsc-session-reparse-idle-delay nil
+           (lambda ()
+             (when (buffer-live-p buffer)
+               (with-current-buffer buffer
+                 (setq tsc--session-reparse-timer nil)
+                 (tsc-session-parse-async session callback))))))))
+
+(defun tsc-session-parse-async (session callback &optional visible-first)
+  "Parse the current buffer with SESSION on a background thread.
+When the parse completes, its tree becomes SESSION's latest tree, and CALLBACK
+is called with it, in the parsed buffer. If the buffer changed in the meantime,
+it is parsed again once Emacs has been idle for `tsc-session-reparse-idle-delay'
+seconds, so CALLBACK is only called with an up-to-date tree. Errors are reported
+with `message'.
+
+If VISIBLE-FIRST is non-nil, the region visible in the windows displaying the
+buffer is parsed first, synchronously, and the resulting provisional tree is
+returned. See `tsc-session-parse-visible'. Otherwise, return nil.
+
+SESSION's latest tree must be kept in sync with the buffer's changes, through
+`tsc-edit-tree', as usual."
+  (let* ((buffer (current-buffer))
+         (tick (buffer-chars-modified-tick))
+         ;; Start before the provisional parse, to reuse the tree from before it.
+         (id (tsc--session-parse-async
+              session (tsc--without-restriction (buffer-substring-no-properties
+                                                 (point-min) (point-max)))))
+         (provisional (when visible-first
+                        (tsc--save-context
+                          (pcase-let ((`(,beg . ,end) (tsc--visible-region)))
+                            (tsc--session-parse-window session #'tsc--buffer-input
+                                                       (tsc--window-range beg end)))))))
+    (tsc-async-then
+     id
+     (lambda (tree err)
+       (cond
+        (err (message "tsc: Background parse failed: %s" (error-message-string err)))
+        ((not (buffer-live-p buffer)))
+        (t (with-current-buffer buffer
+             (if (/= tick (buffer-chars-modified-tick))
+                 (tsc--session-schedule-reparse session callback)
+               (tsc--without-restriction
+                 (tsc--session-install-tree session tree #'tsc--buffer-input))
+               (funcall callback tree)))))))
+    provisional))
+
+(defun tsc-session-in-sync-p (session)
+  "Return t if the current buffer's text is the text of SESSION's latest parse.
+If this returns nil, some changes were missed, and the buffer should be fully
+parsed again, with a new session. SESSION must record checksums, see
+`tsc-make-session'."
+  (tsc--without-restriction
+    (tsc--session-in-sync-p session #'tsc--buffer-input)))
+
+(defun tsc-session-node-at-position (session &optional position)
+  "Return the deepest named node of SESSION's latest tree at POSITION.
+POSITION defaults to the point. This function must be called in the buffer that
+SESSION parses."
+  (tsc--session-named-node-at session (position-bytes (or position (point)))))
+
+(defun tsc-fuzz-session (session iterations seed)
+  "Check incremental parsing of the current buffer with SESSION, with random edits.
+Apply ITERATIONS random edits, generated from the integer SEED, to a copy of the
+buffer's text, and of SESSION's latest tree, which is reparsed after each edit.
+Regularly compare it against a tree parsed from scratch. Neither the buffer nor
+SESSION is changed.
+
+Return nil if the trees never diverged. Otherwise, return a plist describing the
+first divergence, which can be reproduced with the same SEED. See
+`tsc--fuzz-session'."
+  (tsc--without-restriction
+    (tsc--fuzz-session session iterations seed #'tsc--buffer-input)))
+
+(defun tsc-valid-tokens-at (session &optional position)
+  "Return the literal tokens that the grammar allows at POSITION, as a list of
+strings, e.g. (\"fn\" \"struct\" \"{\"). POSITION defaults to the point.
+
+This must be called in the buffer that SESSION parses, and SESSION's latest tree
+must be in sync with it. Each candidate token requires a trial parse, so this is
+too slow for `post-command-hook'. Useful for completion of keywords."
+  (tsc--without-restriction
+    (append (tsc--session-valid-tokens-at
+             session (position-bytes (or position (point))) #'tsc--buffer-input)
+            nil)))
+
+(defun tsc-which-function (session position name-query &optional all)
+  "Return the name of the innermost definition enclosing POSITION, or nil.
+If ALL is non-nil, return the names of all the enclosing definitions instead,
+outermost first. This function must be called in the buffer that SESSION parses.
+
+NAME-QUERY's patterns should capture a definition's name as `@name', and the
+definition itself with another capture, e.g.:
+
+  (function_item name: (identifier) @name) @function
+
+To use it with `which-func-mode', add a function taking no arguments to
+`which-func-functions', e.g.:
+
+  (lambda () (tsc-which-function session (point) name-query))"
+  (let ((names (tsc--without-restriction
+                 (append (tsc--session-which-function
+                          session (position-bytes position) name-query
+                          #'tsc--buffer-substring-no-properties)
+                         nil))))
+    (if all names (car (last names)))))
+
+(defun tsc--symbol-positions (symbol)
+  "Convert the byte ranges of SYMBOL, a plist from `tsc--session-document-symbols'.
+Its children are converted as well. This function must be called with narrowing
+disabled."
+  (let ((to-positions (pcase-lambda (`(,beg-byte . ,end-byte))
+                        (cons (byte-to-position beg-byte) (byte-to-position end-byte)))))
+    (plist-put symbol :range (funcall to-positions (plist-get symbol :range)))
+    (plist-put symbol :selection-range
+               (funcall to-positions (plist-get symbol :selection-range)))
+    (plist-put symbol :children
+               (mapcar #'tsc--symbol-positions (plist-get symbol :children)))))
+
+(defun tsc-document-symbols (session symbols-query)
+  "Return the symbols defined in the current buffer, which SESSION parses.
+The return value is a list of plists, analogous to LSP's DocumentSymbol, with the
+keys :name, :detail, :kind, :range, :selection-range, and :children. Ranges have
+the form (BEG . END).
+
+SYMBOLS-QUERY's patterns should capture a definition's name as `@name',
+optionally its detail as `@detail', and the definition itself with a capture
+named after the symbol's kind, e.g.:
+
+  (function_item name: (identifier) @name parameters: (_) @detail) @function
+
+See `tsc--session-document-symbols'."
+  (tsc--without-restriction
+    (mapcar #'tsc--symbol-positions
+            (tsc--session-document-symbols session symbols-query
+                                           #'tsc--buffer-substring-no-properties))))
+
+(defun tsc-inspect-at (session &optional position query)
+  "Return a plist describing the syntax of the current buffer at POSITION.
+POSITION defaults to the point. The buffer must be the one SESSION parses. If
+QUERY is non-nil, the plist also includes the captures of QUERY at POSITION.
+
+This returns everything a \"describe syntax at point\" command needs, in 1
+call. The plist has the keys of `tsc--session-inspect-at', and :range, the
+node's range, as (BEG . END) positions. Return nil if SESSION has not parsed
+anything yet."
+  (tsc--without-restriction
+    (let ((info (tsc--session-inspect-at session (position-bytes (or position (point)))
+                                         query #'tsc--buffer-substring-no-properties)))
+      (pcase (plist-get info :byte-range)
+        (`(,beg . ,end)
+         (plist-put info :range (cons (byte-to-position beg) (byte-to-position end))))))))
+
+(defun tsc-text-object-range (session position object kind &optional query)
+  "Return the range (BEG . END) of the text object OBJECT at POSITION, or nil.
+KIND should be either `inner' or `outer'. This function must be called in the
+buffer that SESSION parses.
+
+If QUERY is non-nil, it should follow the conventions of text object queries:
+OBJECT is a name like \"function\", whose ranges are captured as
+`@function.outer' and `@function.inner'. Otherwise, OBJECT is a node type
+symbol, e.g. `function_item'.
+
+If there is no inner capture for the object, its inner range is computed by
+trimming delimiters and whitespace, e.g. the inside of a function's braces."
+  (unless (memq kind '(inner outer))
+    (signal 'wrong-type-argument (list '(member inner outer) kind)))
+  (tsc--without-restriction
+    (pcase (tsc--session-text-object-range
+            session (position-bytes position)
+            (if (symbolp object) (symbol-name object) object)
+            (eq kind 'inner) query #'tsc--buffer-substring-no-properties)
+      (`(,beg . ,end) (cons (byte-to-position beg) (byte-to-position end))))))
+
+
+;;; Injections.
+
+(defun tsc-injector-update (injector host-tree)
+  "Update INJECTOR's injected trees, after HOST-TREE was re-parsed.
+HOST-TREE must have been parsed from the current buffer. Each injected language
+whose regions changed, or whose tree was edited with `tsc-injector-edit', is
+re-parsed incrementally. Return the names of the re-parsed languages.
+
+See `tsc-make-injector' and `tsc-injector-trees'."
+  (tsc--without-restriction
+    (tsc--injector-update injector host-tree
+                          #'tsc--buffer-input #'tsc--buffer-substring-no-properties)))
+
+
+;;; Asynchronous operations.
+
+(defvar tsc--channel nil
+  "The network process through which background operations signal completion.
+It is nil if the channel has not been set up, or `polling' if the platform does
+not support Unix domain sockets.")
+
+(defvar tsc--channel-callbacks (make-hash-table :test #'eql)
+  "Callbacks of pending background operations, by operation id.")
+
+(defvar tsc--channel-timer nil
+  "Timer that polls for completions, when `tsc--channel' is `polling'.")
+
+(defvar tsc-channel-poll-interval 0.05
+  "Seconds between polls for completions, on platforms without Unix domain sockets.")
+
+(defun tsc--channel-ensure ()
+  "Set up the completion channel, if that has not been done yet."
+  (unless (or (process-live-p tsc--channel) (eq tsc--channel 'polling))
+    (let* ((dir (make-temp-file "tsc-channel" t))
+           (path (expand-file-name "socket" dir)))
+      (unwind-protect
+          (if (not (tsc--channel-listen path))
+              (setq tsc--channel 'polling)
+            (setq tsc--channel
+                  (make-network-process
+                   :name "tsc-channel" :family 'local :service path
+                   :coding 'binary :noquery t
+                   :filter (lambda (_proc _output) (tsc--channel-dispatch))))
+            (tsc--channel-accept))
+        (delete-directory dir t)))))
+
+(defun tsc--channel-dispatch ()
+  "Call the callbacks of completed background operations."
+  (mapc (lambda (completion)
+          (pcase-let* ((`[,id ,value ,err] completion)
+                       (callback (gethash id tsc--channel-callbacks)))
+            (remhash id tsc--channel-callbacks)
+            (when callback
+              (funcall callback value err))))
+        (tsc--channel-take))
+  (when (and tsc--channel-timer (zerop (hash-table-count tsc--channel-callbacks)))
+    (cancel-timer tsc--channel-timer)
+    (setq tsc--channel-timer nil)))
+
+(defun tsc-async-then (id callback)
+  "Call CALLBACK when the background operation ID completes.
+CALLBACK is called on the main thread, from a process filter or a timer, with 2
+arguments: the operation's result, and nil, or nil and the error that prevented
+the result from being produced, as (ERROR-SYMBOL . DATA), like the variable of
+`condition-case'. E.g. `tsc-error-property' can be used on a `tsc-parse-failed'
+error, and `error-message-string' on any error.
+
+Each operation has at most 1 callback. Registering another one replaces it."
+  (tsc--channel-ensure)
+  (puthash id callback tsc--channel-callbacks)
+  (when (and (eq tsc--channel 'polling) (not tsc--channel-timer))
+    (setq tsc--channel-timer (run-with-timer tsc-channel-poll-interval
+                                             tsc-channel-poll-interval
+                                             #'tsc--channel-dispatch)))
+  id)
+
+(defun tsc-parse-string-async (language string callback)
+  "Parse STRING with LANGUAGE on a background thread, then call CALLBACK.
+CALLBACK is called with the resulting tree and nil, or with nil and an error, as
+\(ERROR-SYMBOL . DATA). Return the operation's id. See `tsc-async-then'."
+  (tsc-async-then (tsc--parse-string-async language string) callback))
+
+(defun tsc-parse-buffer-async (parser callback &optional buffer old-tree)
+  "Parse BUFFER's text with PARSER on a background thread, then call CALLBACK.
+BUFFER defaults to the current buffer. Its whole text is copied, ignoring
+narrowing. OLD-TREE has the same meaning as in `tsc-parse-chunks'.
+
+CALLBACK is called with the resulting tree and nil, or with nil and an error, as
+\(ERROR-SYMBOL . DATA). PARSER cannot be used for parsing until then. Return the
+operation's id. See `tsc-async-then'."
+  (with-current-buffer (or buffer (current-buffer))
+    (let ((text (save-restriction
+                  (widen)
+                  (buffer-substring-no-properties (point-min) (point-max)))))
+      (tsc-async-then (tsc--parse-async parser text old-tree) callback))))
+
+
+;;; Compiling grammars.
+
+(defun tsc-compile-grammar (src-dir out-file)
+  "Compile the grammar in SRC-DIR into the shared lib OUT-FILE; return OUT-FILE.
+OUT-FILE can then be loaded with `tree-sitter-load'. Its directory is created if
+needed.
+
+SRC-DIR is either the grammar's repository, or its `src' directory, where the
+tree-sitter CLI generated `parser.c'. The grammar's external scanner, if any,
+`scanner.c' or `scanner.cc', is compiled too. This only needs a C (or C++)
+compiler, which is found like in Cargo build scripts, respecting the
+environment variables CC, CXX, CFLAGS, and CXXFLAGS.
+
+On failure, signal `tsc-grammar-compile-failed', whose :kind is `no-parser',
+`no-compiler', or `compiler'. In the last case, the message contains the
+compiler's output."
+  (let ((src-dir (expand-file-name src-dir))
+        (out-file (expand-file-name out-file)))
+    (make-directory (file-name-directory out-file) t)
+    (tsc--compile-grammar src-dir out-file)
+    out-file))
+
+
+;;; Errors.
+
+;; The errors signaled by the dynamic module (`tsc-lang-load-failed',
+;; `tsc-lang-abi-error', `tsc-invalid-ranges', `tsc-parse-failed',
+;; `tsc-node-text-too-long', `tsc-grammar-compile-failed', `tsc-query-invalid',
+;; and their sub-types) carry data of the form (MESSAGE . PLIST). PLIST always
+;; has the key :kind, a symbol identifying the specific failure. Other keys
+;; include :actual, :expected, :file, :symbol, :index, :range, :point, :bytepos,
+;; :form, :length, and :max-length, depending on the error.
+;;
+;; For `tsc-lang-abi-error' (and `tsc-lang-abi-too-old', `tsc-lang-abi-too-new'),
+;; :actual is the language's ABI version, and :expected is the supported range
+;; (MIN . MAX), see `tsc-min-supported-abi' and `tsc-max-supported-abi'.
+;;
+;; For `tsc-parse-failed', :kind is the reason the parser did not return a tree:
+;; `no-language', `timeout', `cancelled', or `parse-failed' for other reasons.
+
+(defun tsc-error-message (err)
+  "Return the message of ERR, a (ERROR-SYMBOL . DATA) signaled by the dynamic module."
+  (cadr err))
+
+(defun tsc-error-property (err prop)
+  "Return the value of PROP in the data of ERR, a (ERROR-SYMBOL . DATA) signaled
+by the dynamic module. For example:
+
+  (condition-case err
+      (tsc-make-query tree-sitter-language \"(foo)\")
+    (tsc-query-invalid
+     (list (tsc-error-property err :kind)
+           (tsc-error-property err :bytepos))))"
+  (plist-get (cddr err) prop))
+
+(defun tsc-query-validate (language patterns)
+  "Return nil if PATTERNS is a valid query for LANGUAGE, or describe its first error.
+PATTERNS has the same form as for `tsc-make-query'. The description is a plist
+with these keys:
+
+:message  A human-readable message, naming the invalid part.
+:kind     One of `syntax', `node-type', `field', `capture', `predicate', and
+          `structure'.
+:bytepos  Byte position of the error in the stringified PATTERNS.
+:point    Corresponding (LINE-NUMBER . BYTE-COLUMN).
+:actual   The invalid node type, field name, or capture name, if any.
+:form     If PATTERNS is Lisp data, its innermost element at the error, if any.
+
+This is useful for checking the query files of a language, e.g. highlights.scm,
+against the grammar that is actually loaded."
+  (condition-case err
+      (progn
+        (tsc-release (tsc-make-query language patterns))
+        nil)
+    (tsc-query-invalid
+     (cons :message (cons (tsc-error-message err) (cddr err))))))
+
 
 ;;; Utilities.
 
@@ -413,6 +1921,139 @@ For example, to crudely render a syntax tree:
   "Return the pretty-printed string of TREE's sexp."
   (pp-to-string (read (tsc-tree-to-sexp tree))))
 
+(defun tsc-playground-text-range (playground bytepos)
+  "Return the range (BEG . END) of the innermost node at BYTEPOS in PLAYGROUND's text.
+PLAYGROUND is returned by `tsc-render-playground'. BYTEPOS is a byte position in
+the source code. Return nil if no rendered node contains it."
+  (let (best)
+    (mapc (pcase-lambda ((and entry `[,beg ,end ,_ ,_]))
+            (when (and (<= beg bytepos) (if (= beg end) (= bytepos end) (< bytepos end))
+                       (or (null best) (<= (- end beg) (- (aref best 1) (aref best 0)))))
+              (setq best entry)))
+          (cdr playground))
+    (when best
+      (cons (aref best 2) (aref best 3)))))
+
+(defun tsc-playground-source-range (playground position)
+  "Return the byte range (BEG . END) of the innermost node at POSITION in PLAYGROUND.
+PLAYGROUND is returned by `tsc-render-playground'. POSITION is a position in a
+buffer containing only PLAYGROUND's text. Return nil if it is not on a node."
+  (let (best)
+    (mapc (pcase-lambda ((and entry `[,_ ,_ ,text-beg ,text-end]))
+            ;; Entries are in pre-order, so the last one containing POSITION is the innermost.
+            (when (and (<= text-beg position) (< position text-end))
+              (setq best entry)))
+          (cdr playground))
+    (when best
+      (cons (aref best 0) (aref best 1)))))
+
+(declare-function tree-sitter-load--locate "tree-sitter-load"
+                  (lang-symbol &optional file native-symbol-name))
+
+(defun tsc-doctor (language &optional queries source)
+  "Check whether LANGUAGE can be used, without loading it; return a report.
+LANGUAGE is either the path of a shared library, or a language name symbol. In
+the latter case, the library is searched for like `tree-sitter-load' does.
+
+QUERIES is an alist of (NAME . QUERY-SOURCE), whose sources should compile with
+LANGUAGE. SOURCE is a string of code to parse as a smoke test. It defaults to
+the empty string.
+
+The report is a list of checks, each of the form (CHECK OK . DETAILS), where
+OK is t or nil. See `tsc--doctor' for the list of checks.
+
+When called interactively, prompt for the language name, and display the report."
+  (interactive (list (intern (read-string "Language: "))))
+  (let* ((location (cond
+                    ((stringp language)
+                     (cons language
+                           (format "tree_sitter_%s"
+                                   (replace-regexp-in-string
+                                    "-" "_" (file-name-base language)))))
+                    ((fboundp 'tree-sitter-load--locate)
+                     (tree-sitter-load--locate language))
+                    (t (error "Cannot locate language %s without `tree-sitter-load'"
+                              language))))
+         (report (if (car location)
+                     (tsc--doctor (car location) (cdr location)
+                                  (vconcat queries) (or source ""))
+                   (list (list 'library nil (format "Cannot find shared library for %s"
+                                                    language))))))
+    (when (called-interactively-p 'interactive)
+      (pp-display-expression report "*tsc-doctor*"))
+    report))
+
+(defun tsc--trace-insert (buffer-name text)
+  "Append TEXT, a batch of parse log events, to the buffer named BUFFER-NAME.
+This is called by the dynamic module after each parse. See `tsc-trace-parse'."
+  (with-current-buffer (get-buffer-create buffer-name)
+    (save-excursion
+      (goto-char (point-max))
+      (let ((inhibit-read-only t))
+        (insert text)))))
+
+(defun tsc-release (object)
+  "Release the resources held by OBJECT immediately, without waiting for the
+garbage collector. OBJECT can be a tree, a parser, a query, a query cursor, or an
+injector.
+
+Return t if the resources were released. Return nil if OBJECT is a tree that is
+still referenced by other objects, i.e. its nodes, cursors, or shallow copies,
+even unreachable ones that were not garbage-collected yet, or a query that is
+being executed by a streaming query cursor. In that case, its memory cannot be
+reclaimed yet. Use `tsc-force-release' to collect garbage and try again, and to
+know why the release failed.
+
+OBJECT is marked as released (see `tsc-released-p'), but it can still be used
+safely. A released tree is empty. A released parser has no language, and no
+included ranges. A released query has no patterns. A released injector has no
+trees, and is not updated anymore."
+  (null (tsc--release object)))
+
+(defun tsc-force-release (object)
+  "Release the resources held by OBJECT, like `tsc-release', or signal an error.
+If OBJECT is a tree that is still referenced by other objects, or a query being
+streamed, run `garbage-collect', which frees those that are unreachable, then
+try once more.
+
+Return t if the resources were released. Otherwise, signal `tsc-error', whose
+`:kind' tells why, as described in `tsc--release'."
+  (let ((failure (tsc--release object)))
+    (when (memq (plist-get (cdr failure) :kind) '(referenced query-streamed))
+      (garbage-collect)
+      (setq failure (tsc--release object)))
+    (when failure
+      (signal 'tsc-error failure))
+    t))
+
+(defun tsc-memory-report (&optional owners)
+  "Return the estimated numbers of bytes held by the dynamic module, as a plist.
+
+The plist has the keys :trees, :queries, :languages, and :total, which cover
+all live objects. See `tsc--memory-report' for their meanings. These numbers are
+estimates, based on the sizes of tree-sitter's internal data structures.
+
+It also has the key :owners, which breaks down the trees' memory by owner, as an
+alist of (OWNER . BYTES). OWNERS should be an alist of (OWNER . TREE), e.g. the
+buffers and their syntax trees, see `tree-sitter-debug-memory-report'."
+  (append (tsc--memory-report)
+          (list :owners (mapcar (pcase-lambda (`(,owner . ,tree))
+                                  (cons owner (tsc--tree-memory tree)))
+                                owners))))
+
+(defun tsc-module-stats ()
+  "Return statistics about the dynamic module's objects, as a plist.
+This is a summary of what uses memory in a long-running session:
+
+:live    The counts of live objects, by type, see `tsc-live-objects'.
+:memory  The estimated numbers of bytes they hold, see `tsc-memory-report'.
+:caches  The usage of the shared caches, see `tsc-cache-stats'.
+
+Use `tsc-tree-stats' for the details of a single tree."
+  (list :live (tsc-live-objects)
+        :memory (tsc--memory-report)
+        :caches (tsc-cache-stats)))
+
 (defun tsc--node-steps (node)
   "Return the sequence of steps from the root node to NODE.
 
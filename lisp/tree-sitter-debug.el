@@ -150,6 +150,24 @@ build queries and cursors once, then reuse them."
           (tsc-query-matches query root-node #'tsc--buffer-substring-no-properties)
         (tsc-query-captures query root-node #'tsc--buffer-substring-no-properties)))))
 
+;;;###autoload
+(defun tree-sitter-debug-memory-report ()
+  "Return the estimated memory held by the dynamic module, as a plist.
+See `tsc-memory-report' for its keys. The trees' memory is broken down by the
+buffers that have a syntax tree, i.e. those with `tree-sitter-mode' enabled.
+
+When called interactively, display the report."
+  (interactive)
+  (let ((report (tsc-memory-report
+                 (delq nil (mapcar (lambda (buffer)
+                                     (let ((tree (buffer-local-value 'tree-sitter-tree buffer)))
+                                       (when tree
+                                         (cons buffer tree))))
+                                   (buffer-list))))))
+    (when (called-interactively-p 'interactive)
+      (pp-display-expression report "*tree-sitter-memory-report*"))
+    report))
+
 ;;; TODO: Kill tree-buffer when `tree-sitter' minor mode is turned off.
 
 (provide 'tree-sitter-debug)
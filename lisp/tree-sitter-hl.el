@@ -356,7 +356,7 @@ language symbols, not major mode symbols.")
   (unless tree-sitter-hl--query
     (setq tree-sitter-hl--query
           (when tree-sitter-hl-default-patterns
-            (tsc-make-query
+            (tsc-make-shared-query
              tree-sitter-language
              (mapconcat #'tsc--stringify-patterns
                         (append tree-sitter-hl--extra-patterns-list
@@ -552,9 +552,9 @@ If LOUDLY is non-nil, print debug messages."
             (query-region `(,beg . ,end)))
         (tree-sitter-hl--extend-regions hl-region query-region)
         (setf `(,beg . ,end) hl-region)
-        (tsc--query-cursor-set-byte-range tree-sitter-hl--query-cursor
-                                          (position-bytes (car query-region))
-                                          (position-bytes (cdr query-region))))
+        (tsc-query-cursor-set-byte-range tree-sitter-hl--query-cursor
+                                         (position-bytes (car query-region))
+                                         (position-bytes (cdr query-region))))
       (let* ((root-node (tsc-root-node tree-sitter-tree))
              (captures  (tsc--query-cursor-captures-1
                          tree-sitter-hl--query-cursor
@@ -598,9 +598,9 @@ Installed on `tree-sitter-after-change-functions'.
 OLD-TREE is the tree before the edit."
   (if old-tree
       ;; Incremental parse.
-      (seq-doseq (range (tsc-changed-ranges old-tree tree-sitter-tree))
+      (seq-doseq (range (tsc-changed-byte-ranges old-tree tree-sitter-tree))
         ;; TODO: How about invalidating a single large range?
-        (pcase-let* ((`[,beg-byte ,end-byte] range)
+        (pcase-let* ((`(,beg-byte . ,end-byte) range)
                      (beg (byte-to-position beg-byte))
                      (end (byte-to-position end-byte)))
           ;; TODO: How about calling `jit-lock-refontify' directly?
@@ -40,19 +40,10 @@ See `tree-sitter-require'.")
   "List of suffixes for shared libraries that define tree-sitter languages.")
 
 ;;; TODO: Allow specifying absolute path.
-(defun tree-sitter-load (lang-symbol &optional file native-symbol-name)
-  "Load a language grammar from FILE and register it under the name LANG-SYMBOL.
-If another language was already registered under the same name, override it.
-
-This function returns the loaded language object.
-
-FILE should be the base name (without extension) of the native shared library
-that exports the language as the native symbol NATIVE-SYMBOL-NAME.
-
-If FILE is nil, the base name is assumed to be LANG-SYMBOL's name.
-
-If NATIVE-SYMBOL-NAME is nil, the name of the exported native symbol is assumed
-to be LANG-SYMBOL's name, prefixed with \"tree_sitter_\"."
+(defun tree-sitter-load--locate (lang-symbol &optional file native-symbol-name)
+  "Locate the shared library of the language LANG-SYMBOL.
+Return (FULL-PATH . NATIVE-SYMBOL-NAME), where FULL-PATH is nil if the library
+cannot be found. See `tree-sitter-load' for the meanings of the parameters."
   (let* ((lang-name (symbol-name lang-symbol))
          ;; Example: c-sharp -> c_sharp.
          (fallback-name (replace-regexp-in-string "-" "_" lang-name))
@@ -73,10 +64,39 @@ to be LANG-SYMBOL's name, prefixed with \"tree_sitter_\"."
                                             tree-sitter-load-path
                                             tree-sitter-load-suffixes))
                              files)))
+    (cons full-path native-symbol-name)))
+
+(defun tree-sitter-load (lang-symbol &optional file native-symbol-name)
+  "Load a language grammar from FILE and register it under the name LANG-SYMBOL.
+If another language was already registered under the same name, override it.
+
+This function returns the loaded language object.
+
+FILE should be the base name (without extension) of the native shared library
+that exports the language as the native symbol NATIVE-SYMBOL-NAME.
+
+If FILE is nil, the base name is assumed to be LANG-SYMBOL's name.
+
+If NATIVE-SYMBOL-NAME is nil, the name of the exported native symbol is assumed
+to be LANG-SYMBOL's name, prefixed with \"tree_sitter_\"."
+  (pcase-let ((`(,full-path . ,native-symbol-name)
+               (tree-sitter-load--locate lang-symbol file native-symbol-name)))
     (unless full-path
       ;; TODO: Define custom error class.
       (error "Cannot find shared library for language: %S" lang-symbol))
-    (let ((language (tsc--load-language full-path native-symbol-name lang-symbol)))
+    (let ((language
+           (condition-case err
+               (tsc--load-language full-path native-symbol-name lang-symbol)
+             (tsc-lang-abi-error
+              (pcase-let ((`(,min . ,max) (tsc-error-property err :expected))
+                          (version (tsc-error-property err :actual)))
+                (signal (car err)
+                        (cons (format "Grammar %S has ABI version %s, but only %s to %s are supported. %s"
+                                      lang-symbol version min max
+                                      (if (< version min)
+                                          "Regenerate it with a newer tree-sitter CLI."
+                                        "Upgrade tree-sitter, or use an older build of the grammar."))
+                              (cddr err))))))))
       (setf (map-elt tree-sitter-languages lang-symbol) language)
       language)))
 
@@ -67,7 +67,8 @@
               ((tsc-query-invalid-node-type
                 tsc-query-invalid-field
                 tsc-query-invalid-capture)
-               (tree-sitter--echo "%s: %s" (get (car err) 'error-message) (cadr err))
+               (tree-sitter--echo "%s: %s" (get (car err) 'error-message)
+                                 (tsc-error-property err :actual))
                nil)
               (tsc-query-invalid
                (tree-sitter--echo "%s" (get (car err) 'error-message))
@@ -64,19 +64,11 @@ Use this to enable other minor modes that depends on the syntax tree."
 (defvar-local tree-sitter-language nil
   "Tree-sitter language.")
 
-(defvar-local tree-sitter--text-before-change nil)
-
-(defvar-local tree-sitter--beg-before-change nil)
-
 (defun tree-sitter--before-change (beg old-end)
   "Update relevant editing states. Installed on `before-change-functions'.
 BEG and OLD-END are the begin and end positions of the text to be changed."
-  (setq tree-sitter--beg-before-change beg)
-  (tsc--without-restriction
-    ;; TODO: Fallback to a full parse if this region is too big.
-    (setq tree-sitter--text-before-change
-          (when (> old-end beg)
-            (buffer-substring-no-properties beg old-end)))))
+  ;; TODO: Fallback to a full parse if this region is too big.
+  (tsc-record-before-change beg old-end))
 
 ;;; TODO: How do we batch *after* hooks to re-parse only once? Maybe using
 ;;; `run-with-idle-timer' with 0-second timeout?
@@ -91,51 +83,8 @@ BEG is the begin position of the change.
 NEW-END is the end position of the changed text.
 OLD-LEN is the char length of the old text."
   (when tree-sitter-tree
-    (let ((beg:byte (position-bytes beg))
-          (new-end:byte (position-bytes new-end))
-          old-end:byte
-          beg:point old-end:point new-end:point)
-      (tsc--save-context
-        (setq beg:point (tsc--point-from-position beg)
-              new-end:point (tsc--point-from-position new-end)))
-      ;; Compute the old text's end byte position, line number, byte column.
-      ;;
-      ;; Tree-sitter works with byte positions, line numbers, byte columns.
-      ;; Emacs primarily works with character positions. Converting the latter
-      ;; to the former, for the end of the old text, requires looking at the
-      ;; actual old text's content. Tree-sitter itself cannot do that, because
-      ;; it is designed to keep track of only the numbers, not a mirror of the
-      ;; buffer's text. Without re-designing Emacs's change tracking mechanism,
-      ;; we store the old text through `tree-sitter--before-change', and inspect
-      ;; it here. TODO XXX FIX: Improve change tracking in Emacs.
-      (if (= old-len 0)
-          (setq old-end:byte beg:byte
-                old-end:point beg:point)
-        (let ((old-text tree-sitter--text-before-change)
-              (rel-beg (1+ (- beg tree-sitter--beg-before-change))))
-          ;; FIX: Don't assume before-change's beg and after-change's beg are
-          ;; the same.
-          (with-temp-buffer
-            (insert old-text)
-            (pcase-let*
-                ((rel-old-end (+ rel-beg old-len))
-                 (old-len:byte (- (position-bytes rel-old-end)
-                                  (position-bytes rel-beg)))
-                 (`(,beg:linum . ,beg:bytecol) beg:point)
-                 (rel-beg:linum (line-number-at-pos rel-beg))
-                 (`(,rel-old-end:linum . ,rel-old-end:bytecol)
-                  (tsc--point-from-position rel-old-end))
-                 (old-diff:linum (- rel-old-end:linum rel-beg:linum))
-                 (old-end:linum (+ beg:linum old-diff:linum))
-                 (old-end:bytecol (if (> old-diff:linum 0)
-                                      rel-old-end:bytecol
-                                    (+ beg:bytecol old-len:byte))))
-              (setq old-end:byte (+ beg:byte old-len:byte)
-                    old-end:point `(,old-end:linum . ,old-end:bytecol))))))
-      (tsc-edit-tree tree-sitter-tree
-                     beg:byte old-end:byte new-end:byte
-                     beg:point old-end:point new-end:point)
-      (tree-sitter--do-parse))))
+    (tsc-record-change beg new-end old-len tree-sitter-tree)
+    (tree-sitter--do-parse)))
 
 (defun tree-sitter--do-parse ()
   "Parse the current buffer and update the syntax tree."
@@ -47,6 +47,34 @@
       (should-not (null (tsc-lang-node-type language (- type-count 1))))
       (should (null (tsc-lang-node-type language type-count))))))
 
+(ert-deftest language::node-type-info ()
+  (let ((language (tree-sitter-require 'rust)))
+    (should-error (tsc-lang-load-node-types language (tsc-test-full-path "data/no-such-file.json"))
+                  :type 'tsc-error)
+    (tsc-lang-load-node-types language (tsc-test-full-path "data/node-types.json"))
+    (let ((info (tsc-lang-node-type-info language 'call_expression)))
+      (should (eq 'call_expression (plist-get info :type)))
+      (should (eq t (plist-get info :named)))
+      (should (equal '(:arguments :function) (mapcar #'car (plist-get info :fields))))
+      (should (equal '(:multiple nil :required t :types (_expression))
+                     (alist-get :function (plist-get info :fields))))
+      (should (null (plist-get info :children)))
+      (should (equal '(_expression) (plist-get info :supertypes))))
+    (should (equal '(:multiple t :required nil :types (_expression))
+                   (plist-get (tsc-lang-node-type-info language 'arguments) :children)))
+    (should (equal '(call_expression identifier)
+                   (plist-get (tsc-lang-node-type-info language '_expression) :subtypes)))
+    (should (eq nil (plist-get (tsc-lang-node-type-info language "(") :named)))
+    (should (null (tsc-lang-node-type-info language "no_such_type")))))
+
+(ert-deftest language::abi-version ()
+  (let ((language (tree-sitter-require 'rust)))
+    (should (<= (tsc-min-supported-abi)
+                (tsc-lang-abi-version language)
+                (tsc-max-supported-abi)))
+    (should (equal (cons (tsc-min-supported-abi) (tsc-max-supported-abi))
+                   (tsc-supported-abi-range)))))
+
 (ert-deftest language::fields ()
   (let* ((language (tree-sitter-require 'rust))
          (field-count (tsc-lang-count-fields language)))
@@ -62,6 +90,46 @@
       (should (keywordp (tsc-lang-field language field-count)))
       (should (null (tsc-lang-field language (1+ field-count)))))))
 
+(ert-deftest language::node-kinds ()
+  (let* ((language (tree-sitter-require 'rust))
+         (kind-count (tsc-lang-node-kind-count language)))
+    (should (= kind-count (tsc-lang-count-types language)))
+    (should (= (tsc-lang-field-count language) (tsc-lang-count-fields language)))
+    (ert-info ("Node kinds should round trip through their ids")
+      (should (equal "end" (tsc-lang-node-kind-for-id language 0)))
+      (should (eql 0 (tsc-lang-id-for-node-kind language "end" nil)))
+      (let ((id (tsc-lang-id-for-node-kind language "function_item" t)))
+        (should (equal "function_item" (tsc-lang-node-kind-for-id language id))))
+      (let ((id (tsc-lang-id-for-node-kind language "fn" nil)))
+        (should (equal "fn" (tsc-lang-node-kind-for-id language id))))
+      (should (null (tsc-lang-id-for-node-kind language "no_such_kind" t)))
+      (should (null (tsc-lang-node-kind-for-id language kind-count))))
+    (ert-info ("Field names should round trip through their ids")
+      (let ((id (tsc-lang-field-id-for-name language "name")))
+        (should (equal "name" (tsc-lang-field-name-for-id language id))))
+      (should (null (tsc-lang-field-id-for-name language "no_such_field")))
+      (should (null (tsc-lang-field-name-for-id language 0))))))
+
+(ert-deftest language::detect ()
+  (let ((registry `((rust :extensions ("rs") :language ,(tree-sitter-require 'rust))
+                    (c :extensions ("c" "h") :language ,(tree-sitter-require 'c))
+                    (python :interpreters ("python"))
+                    (bash :interpreters ("bash" "sh")))))
+    (with-temp-buffer
+      (insert "#!/usr/bin/env python3\nprint(1)\n")
+      (should (eq 'python (tsc-detect-language (current-buffer) registry))))
+    (with-temp-buffer
+      (insert "#!/bin/sh\n# -*- mode: rust -*-\n")
+      (should (eq 'rust (tsc-detect-language (current-buffer) registry))))
+    (with-temp-buffer
+      (setq buffer-file-name "/tmp/foo.h")
+      (should (eq 'c (tsc-detect-language (current-buffer) registry)))
+      (set-buffer-modified-p nil))
+    (with-temp-buffer
+      (insert "fn main() { let x = 1; }\n")
+      (should (null (tsc-detect-language (current-buffer) registry)))
+      (should (eq 'rust (tsc-detect-language (current-buffer) registry :trial))))))
+
 (ert-deftest parsing::rust-string ()
   (tsc-test-with rust parser
     (let ((tree (tsc-parse-string parser "fn foo() {}")))
@@ -72,10 +140,513 @@
                         parameters: (parameters)
                         body: (block))))))))
 
+(ert-deftest parsing::quick-parse ()
+  (let ((rust (tree-sitter-require 'rust)))
+    (should (tsc-tree-p (tsc-quick-parse rust "fn foo() {}")))
+    (should (equal (tsc-quick-parse rust "fn foo() {}" 'sexp)
+                   '(source_file
+                     (function_item
+                      name: (identifier)
+                      parameters: (parameters)
+                      body: (block)))))
+    (require 'json)
+    (let* ((json-object-type 'alist)
+           (json-array-type 'list)
+           (root (json-read-from-string (tsc-quick-parse rust "fn foo() {}" 'json)))
+           (item (car (alist-get 'children root))))
+      (should (equal "source_file" (alist-get 'type root)))
+      (should (equal '(1 12) (list (alist-get 'start item) (alist-get 'end item))))
+      (should (equal "name" (alist-get 'field (nth 1 (alist-get 'children item))))))
+    (should-error (tsc-quick-parse rust "" 'xml) :type 'wrong-type-argument)))
+
+(ert-deftest parsing::node-to-dot ()
+  (tsc-test-with rust parser
+    (let* ((tree (tsc-parse-string parser "fn foo() {}"))
+           (node (tsc-get-child-by-field (tsc-get-nth-named-child (tsc-root-node tree) 0)
+                                         :parameters))
+           (dot (tsc-node-to-dot node))
+           (file (make-temp-file "tsc-dot" nil ".dot")))
+      (should (string-prefix-p "digraph tree {" dot))
+      (should (string-match-p (regexp-quote "node0 [label=\"parameters\\n7-9\"]") dot))
+      (should (string-match-p (regexp-quote "node1 [label=\"\\\"(\\\"\\n7-8\", shape=plaintext]")
+                              dot))
+      (should (string-match-p "node0 -> node2" dot))
+      (should-not (tsc-node-to-dot (tsc-root-node tree) file))
+      (should (string-match-p "function_item" (with-temp-buffer
+                                                 (insert-file-contents file)
+                                                 (buffer-string)))))))
+
+(ert-deftest parsing::playground ()
+  (tsc-test-with rust parser
+    (let ((playground (tsc-render-playground (tsc-parse-string parser "fn foo() {}"))))
+      (should (equal (car playground)
+                     (concat "(source_file\n"
+                             "  (function_item\n"
+                             "    name: (identifier)\n"
+                             "    parameters: (parameters)\n"
+                             "    body: (block)))")))
+      (should (= 5 (length (cdr playground))))
+      (should (equal '(41 . 53) (tsc-playground-text-range playground 4)))
+      (should (equal '(4 . 7) (tsc-playground-source-range playground 45)))
+      (should (equal '(1 . 12) (tsc-playground-source-range playground 20)))
+      (should (null (tsc-playground-source-range playground 1000))))))
+
+(ert-deftest parsing::tree-stats ()
+  (tsc-test-with rust parser
+    (let ((stats (tsc-tree-stats (tsc-parse-string parser "fn foo() { 1 + }"))))
+      (should (> (plist-get stats :nodes) (plist-get stats :named) 0))
+      (should (> (+ (plist-get stats :errors) (plist-get stats :missing)) 0))
+      (should (>= (plist-get stats :depth) 3))
+      (should (> (plist-get stats :bytes) 0)))
+    (let ((stats (tsc-tree-stats (tsc-parse-string parser "fn foo() {}"))))
+      (should (= (plist-get stats :errors) (plist-get stats :missing) 0)))
+    (let ((stats (tsc-module-stats)))
+      (should (integerp (plist-get (plist-get stats :live) :trees)))
+      (should (integerp (plist-get (plist-get stats :memory) :total))))))
+
+(ert-deftest parsing::parallel ()
+  (let* ((language (tree-sitter-require 'rust))
+         (sources (mapcar (lambda (i) (format "fn f%d() {}" i)) (number-sequence 1 20)))
+         (file (make-temp-file "tsc-parallel" nil ".rs")))
+    (unwind-protect
+        (tsc-test-with rust parser
+          (with-temp-file file
+            (insert "struct S;"))
+          (should (equal (mapcar #'tsc-tree-to-sexp (tsc-parse-strings-parallel language sources))
+                         (mapcar (lambda (s) (tsc-tree-to-sexp (tsc-parse-string parser s)))
+                                 sources)))
+          (should (equal (tsc-tree-to-sexp (car (tsc-parse-strings-parallel language
+                                                                          (list file) t)))
+                         (tsc-tree-to-sexp (tsc-parse-string parser "struct S;"))))
+          (should-not (tsc-parse-strings-parallel language nil))
+          (should-error (tsc-parse-strings-parallel language (list (concat file ".missing")) t)
+                        :type 'file-error))
+      (delete-file file))))
+
+(ert-deftest parsing::remap-node ()
+  (tsc-test-with rust parser
+    (let* ((old-tree (tsc-parse-string parser "fn foo() { 1 }\nfn bar() { 2 }"))
+           (literal (lambda (tree)
+                      (tsc-get-nth-named-child
+                       (tsc-get-child-by-field (tsc-get-nth-named-child (tsc-root-node tree) 1)
+                                               :body)
+                       0)))
+           (node (funcall literal old-tree)))
+      (should (equal (tsc-node-byte-range node) '(27 . 28)))
+      (tsc-edit-tree old-tree 1 1 13 '(1 . 0) '(1 . 0) '(2 . 0))
+      (tsc-edit-node node 1 1 13 '(1 . 0) '(1 . 0) '(2 . 0))
+      (let* ((new-tree (tsc-parse-string parser "fn baz() {}\nfn foo() { 1 }\nfn bar() { 2 }"
+                                         old-tree))
+             (remapped (tsc-remap-node node new-tree)))
+        (should (eq (tsc-node-type remapped) 'integer_literal))
+        (should (equal (tsc-node-byte-range remapped) '(39 . 40)))
+        (ert-info ("An unrelated node should be remapped by its path")
+          (let ((other-tree (tsc-parse-string parser "fn f() { x }\nfn g() { 3 }")))
+            (should (equal (tsc-node-byte-range (tsc-remap-node node other-tree))
+                           (tsc-node-byte-range (funcall literal other-tree))))))))))
+
+(ert-deftest parsing::record-change ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn ĉ() {}\nfn foo() {}")
+      (let ((tree (tsc-parse-chunks parser #'tsc--buffer-input nil))
+            edits)
+        (add-hook 'before-change-functions #'tsc-record-before-change nil :local)
+        (add-hook 'after-change-functions
+                  (lambda (beg end old-len)
+                    (push (tsc-record-change beg end old-len tree) edits))
+                  nil :local)
+        (goto-char (point-min))
+        (search-forward "ĉ() {}\nfn foo")
+        (replace-match "bar")
+        (should (equal edits '((4 18 7 (1 . 3) (2 . 6) (1 . 6)))))
+        (goto-char (point-max))
+        (insert "\n")
+        (should (equal (car edits) '(12 12 13 (1 . 11) (1 . 11) (2 . 0))))
+        (should (equal (tsc-tree-to-sexp (tsc-parse-chunks parser #'tsc--buffer-input tree))
+                       (tsc-tree-to-sexp (tsc-parse-string parser (buffer-string)))))))))
+
 (ert-deftest parsing::without-setting-language ()
-  (ert-skip "Need to distinguish between this and timeout/cancellation")
   (let ((parser (tsc-make-parser)))
-    (should-error (tsc-parse-string parser "fn foo() {}") :type 'rust-panic)))
+    (should-error (tsc-parse-string parser "fn foo() {}") :type 'tsc-error)))
+
+(ert-deftest parsing::chunk-cache ()
+  (tsc-test-with rust parser
+    (let* ((source "fn foo() {}")
+           (bytepos-list)
+           (tree (tsc-parse-chunks parser
+                                   (lambda (bytepos _l _c)
+                                     (push bytepos bytepos-list)
+                                     (substring source (1- (min bytepos (1+ (length source))))))
+                                   nil)))
+      (should (equal (tsc-tree-to-sexp tree)
+                     (tsc-tree-to-sexp (tsc-parse-string parser source))))
+      (ert-info ("Positions within the last chunk should be served from the cache")
+        ;; The end of input is not cached, since it's an empty chunk.
+        (let ((within (seq-filter (lambda (p) (<= p (length source))) bytepos-list)))
+          (should (equal within (delete-dups (copy-sequence within)))))))))
+
+(ert-deftest parsing::changed-ranges ()
+  (tsc-test-with rust parser
+    (let* ((old-tree (tsc-parse-string parser "fn foo() {}"))
+           (_ (tsc-edit-tree old-tree 8 8 12 '(1 . 7) '(1 . 7) '(1 . 11)))
+           (new-tree (tsc-parse-chunks parser (lambda (bytepos _l _c)
+                                                (substring "fn foo(a: T) {}" (1- (min bytepos 16))))
+                                       old-tree))
+           (ranges (tsc-changed-ranges old-tree new-tree)))
+      (should (< 0 (length ranges)))
+      (pcase-let ((`[,beg ,end ,beg-point ,end-point] (aref ranges 0)))
+        (ert-info ("The range should cover the inserted parameter, in the new document")
+          (should (<= beg 8))
+          (should (>= end 12))
+          (should (equal (car beg-point) 1))
+          (should (equal (car end-point) 1))))
+      (should (equal [] (tsc-changed-ranges new-tree new-tree))))))
+
+(ert-deftest parsing::changed-byte-ranges ()
+  (tsc-test-with rust parser
+    (let* ((old-tree (tsc-parse-string parser "fn foo() {}"))
+           (_ (tsc-edit-tree old-tree 8 8 12 '(1 . 7) '(1 . 7) '(1 . 11)))
+           (new-tree (tsc-parse-chunks parser (lambda (bytepos _l _c)
+                                                (substring "fn foo(a: T) {}" (1- (min bytepos 16))))
+                                       old-tree)))
+      (should (equal (seq-map (lambda (range) (cons (aref range 0) (aref range 1)))
+                              (tsc-changed-ranges old-tree new-tree))
+                     (append (tsc-changed-byte-ranges old-tree new-tree) nil))))))
+
+(ert-deftest conversion::lsp-position ()
+  ;; "é" is 2 bytes, and 1 UTF-16 code unit. "😀" is 4 bytes, and 2 UTF-16 code units.
+  (let ((text "fn main() {}\nlet é😀x = 1;"))
+    (with-temp-buffer
+      (insert text)
+      (dolist (object (list nil text))
+        (should (equal '(:line 1 :character 7)
+                       (tsc-point-to-lsp-position '(2 . 10) object)))
+        (should (equal '(:line 0 :character 3)
+                       (tsc-point-to-lsp-position '(1 . 3) object)))
+        (should (= (+ 14 10) (tsc-lsp-position-to-byte '(:line 1 :character 7) object)))
+        (ert-info ("Columns past the end of a line should be clamped")
+          (should (= 13 (tsc-lsp-position-to-byte '(:line 0 :character 100) object))))))))
+
+(ert-deftest conversion::json ()
+  (require 'json)
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo() {}")
+      (let* ((tree (tsc-parse-chunks parser #'tsc--buffer-input nil))
+             (json (json-read-from-string (tsc-tree-to-json tree t)))
+             (function (aref (alist-get 'children json) 0)))
+        (should (equal "source_file" (alist-get 'type json)))
+        (should (equal [1 0] (alist-get 'startPoint json)))
+        (should (equal "function_item" (alist-get 'type function)))
+        (ert-info ("Anonymous nodes should be omitted")
+          (should (equal '("identifier" "parameters" "block")
+                         (seq-map (lambda (child) (alist-get 'type child))
+                                  (alist-get 'children function)))))
+        (should (equal "name" (alist-get 'field (aref (alist-get 'children function) 0))))
+        (let* ((query (tsc-make-query (tsc-parser-language parser) [(identifier) @id]))
+               (matches (json-read-from-string
+                         (tsc-matches-to-json query (tsc-root-node tree))))
+               (capture (aref (alist-get 'captures (aref matches 0)) 0)))
+          (should (= 1 (length matches)))
+          (should (equal "id" (alist-get 'name capture)))
+          (should (equal '(4 . 7) (cons (alist-get 'start capture)
+                                        (alist-get 'end capture)))))))))
+
+(ert-deftest parsing::tree-diff ()
+  (tsc-test-with rust parser
+    (let* ((old-text "fn foo() { a(); }\nfn bar() {}")
+           (new-text "fn bar() {}\nfn foo() { b(); }\nstruct S;")
+           (diffs (tsc-tree-diff (tsc-parse-string parser old-text) old-text
+                                 (tsc-parse-string parser new-text) new-text))
+           (text (lambda (node source)
+                   (substring source (1- (tsc-node-start-byte node))
+                              (1- (tsc-node-end-byte node)))))
+           (actions (seq-map (lambda (diff) (aref diff 0)) diffs)))
+      (should (equal '(move update insert) actions))
+      (pcase-let ((`[,_ ,old ,new] (aref diffs 0)))
+        (ert-info ("Reordered functions should be moved")
+          (should (eq 'function_item (tsc-node-type old)))
+          (should (equal "fn bar() {}" (funcall text new new-text)))))
+      (pcase-let ((`[,_ ,old ,new] (aref diffs 1)))
+        (should (equal "a" (funcall text old old-text)))
+        (should (equal "b" (funcall text new new-text))))
+      (pcase-let ((`[,_ ,old ,new] (aref diffs 2)))
+        (should-not old)
+        (should (eq 'struct_item (tsc-node-type new))))
+      (should (equal [] (tsc-tree-diff (tsc-parse-string parser new-text) new-text
+                                       (tsc-parse-string parser new-text) new-text))))))
+
+(ert-deftest parsing::trace ()
+  (tsc-test-with rust parser
+    (let ((buffer-name " *tsc-test-trace*"))
+      (unwind-protect
+          (progn
+            (tsc-trace-parse parser buffer-name)
+            (tsc-parse-string parser "fn foo() {}")
+            (let ((lines (with-current-buffer buffer-name
+                           (split-string (buffer-string) "\n" t))))
+              (should lines)
+              (should (seq-every-p (lambda (line) (string-match-p "^\\(lex\\|parse\\): " line))
+                                   lines)))
+            (ert-info ("Tracing should stop when BUFFER-NAME is nil")
+              (tsc-trace-parse parser nil)
+              (with-current-buffer buffer-name
+                (erase-buffer))
+              (tsc-parse-string parser "fn bar() {}")
+              (should (= 0 (buffer-size (get-buffer buffer-name))))))
+        (tsc-trace-parse parser nil)
+        (when (get-buffer buffer-name)
+          (kill-buffer buffer-name))))))
+
+(ert-deftest parsing::logger ()
+  (tsc-test-with rust parser
+    (let ((events nil))
+      (unwind-protect
+          (progn
+            (tsc-set-parser-logger parser (lambda (type message) (push (cons type message) events)))
+            (tsc-parse-string parser "fn foo() {}")
+            (should events)
+            (should (seq-every-p (pcase-lambda (`(,type . ,message))
+                                   (and (memq type '(lex parse)) (stringp message)))
+                                 events))
+            (ert-info ("Logging should stop when LOGGER is nil")
+              (tsc-set-parser-logger parser nil)
+              (setq events nil)
+              (tsc-parse-string parser "fn bar() {}")
+              (should (null events))))
+        (tsc-set-parser-logger parser nil)))))
+
+(ert-deftest parsing::dot-graphs ()
+  (skip-unless (memq system-type '(gnu/linux darwin berkeley-unix)))
+  (tsc-test-with rust parser
+    (let ((file (make-temp-file "tsc-dot-graphs")))
+      (unwind-protect
+          (progn
+            (should (tsc-parser-print-dot-graphs parser file))
+            (tsc-parse-string parser "fn foo() {}")
+            (tsc-stop-printing-dot-graphs parser)
+            (let ((size (nth 7 (file-attributes file))))
+              (should (> size 0))
+              (ert-info ("Printing should stop")
+                (tsc-parse-string parser "fn bar() {}")
+                (should (= size (nth 7 (file-attributes file)))))))
+        (tsc-stop-printing-dot-graphs parser)
+        (delete-file file)))))
+
+(ert-deftest parsing::buffer ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (dotimes (i 2000)
+        (insert (format "fn f%d() { \"ăâ%d\" }\n" i i)))
+      (let ((expected (tsc-tree-to-sexp (tsc-parse-chunks parser #'tsc--buffer-input nil))))
+        (narrow-to-region 5 10)
+        (let ((tree (tsc-parse-buffer parser)))
+          (should (equal expected (tsc-tree-to-sexp tree)))
+          (should (= 2000 (tsc-count-named-children (tsc-root-node tree)))))))))
+
+(ert-deftest parsing::narrowing ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn a() {}\nfn b() {}\nfn c() {}\n")
+      (narrow-to-region 11 21)
+      (let ((tree (tsc-parse-buffer parser)))
+        (should-not (tsc-tree-partial-p tree))
+        (should (= 3 (tsc-count-named-children (tsc-root-node tree)))))
+      (let* ((tree (tsc-parse-buffer parser nil nil 'offset))
+             (root-node (tsc-root-node tree)))
+        (should (tsc-tree-partial-p tree))
+        (should (= 11 (tsc-tree-offset tree)))
+        (should (= 1 (tsc-count-named-children root-node)))
+        (should (= 1 (tsc-node-start-byte (tsc-get-nth-named-child root-node 0)))))
+      (let* ((tree (tsc-parse-buffer parser nil nil 'ranges))
+             (root-node (tsc-root-node tree)))
+        (should (tsc-tree-partial-p tree))
+        (should-not (tsc-tree-offset tree))
+        (should (= 1 (tsc-count-named-children root-node)))
+        (should (= 11 (tsc-node-start-byte (tsc-get-nth-named-child root-node 0))))
+        (should (equal [] (tsc-parser-included-ranges parser)))))))
+
+(ert-deftest parsing::chunk-size ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo() {}\nfn bar() {}\n")
+      (let* ((sizes nil)
+             (input (lambda (bytepos line column &optional size)
+                      (push size sizes)
+                      (tsc--buffer-input bytepos line column size)))
+             (tree (tsc-parse-chunks parser input nil 5)))
+        (should (= 2 (tsc-count-named-children (tsc-root-node tree))))
+        (should (equal '(5) (delete-dups sizes)))
+        (ert-info ("The text should be read in small fragments")
+          (should (< 4 (length sizes))))))))
+
+(ert-deftest parsing::hunks ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn a() {}\nfn b() {}\nfn c() {}\n")
+      (let* ((tree (tsc-parse-hunks parser (current-buffer) '((11 . 21) (19 . 21))))
+             (root (tsc-root-node tree)))
+        (should (= 1 (tsc-count-named-children root)))
+        (should (equal (tsc-node-position-range (tsc-get-nth-named-child root 0)) '(11 . 20))))
+      (ert-info ("Included ranges should be reset afterward")
+        (should (= 3 (tsc-count-named-children
+                      (tsc-root-node (tsc-parse-chunks parser #'tsc--buffer-input nil)))))))))
+
+(ert-deftest parsing::async ()
+  (let* ((result nil)
+         (id (tsc-parse-string-async (tree-sitter-require 'rust) "fn foo() {}"
+                                     (lambda (tree err) (setq result (list tree err)))))
+         (deadline (+ (float-time) 5)))
+    (should (integerp id))
+    (while (and (not result) (< (float-time) deadline))
+      (accept-process-output nil 0.05))
+    (pcase-let ((`(,tree ,err) result))
+      (should (null err))
+      (should (equal (read (tsc-tree-to-sexp tree))
+                     '(source_file
+                       (function_item
+                        name: (identifier)
+                        parameters: (parameters)
+                        body: (block))))))))
+
+(ert-deftest parsing::cancellation ()
+  (tsc-test-with rust parser
+    (let ((flag (tsc-make-cancellation-flag))
+          (kind (lambda (f)
+                  (condition-case err
+                      (progn (funcall f) nil)
+                    (tsc-parse-failed (tsc-error-property err :kind))))))
+      (tsc-set-cancellation-flag parser flag)
+      (should-not (tsc-cancelled-p flag))
+      (ert-info ("A parse should be aborted once the flag is set")
+        (should (eq 'cancelled
+                    (funcall kind (lambda ()
+                                    (tsc-parse-chunks
+                                     parser
+                                     (lambda (bytepos &rest _)
+                                       (tsc-cancel flag)
+                                       (if (> bytepos 20000) "" "fn foo() {}\n"))
+                                     nil)))))
+        (should (tsc-cancelled-p flag)))
+      (ert-info ("After reset, the parser should parse other code")
+        (tsc-reset-cancellation-flag flag)
+        (tsc-reset-parser parser)
+        (should (equal (read (tsc-tree-to-sexp (tsc-parse-string parser "fn bar() {}")))
+                       '(source_file
+                         (function_item
+                          name: (identifier)
+                          parameters: (parameters)
+                          body: (block))))))
+      (tsc-set-cancellation-flag parser nil)
+      (tsc-cancel flag)
+      (should (tsc-parse-string parser "fn bar() {}"))))
+  (should (eq 'no-language
+              (condition-case err
+                  (tsc-parse-string (tsc-make-parser) "fn foo() {}")
+                (tsc-parse-failed (tsc-error-property err :kind))))))
+
+(ert-deftest parsing::buffer-async ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo() {}")
+      (let* ((result nil)
+             (deadline (+ (float-time) 5)))
+        (tsc-parse-buffer-async parser (lambda (tree err) (setq result (list tree err))))
+        (while (and (not result) (< (float-time) deadline))
+          (accept-process-output nil 0.05))
+        (pcase-let ((`(,tree ,err) result))
+          (should (null err))
+          (should (equal (read (tsc-tree-to-sexp tree))
+                         '(source_file
+                           (function_item
+                            name: (identifier)
+                            parameters: (parameters)
+                            body: (block))))))
+        (ert-info ("The parser should be usable again after completion")
+          (should (tsc-parse-string parser "fn bar() {}")))
+        (ert-info ("Failures should be delivered as (ERROR-SYMBOL . DATA)")
+          (setq result nil
+                deadline (+ (float-time) 5))
+          (tsc-parse-buffer-async (tsc-make-parser)
+                                  (lambda (tree err) (setq result (list tree err))))
+          (while (and (not result) (< (float-time) deadline))
+            (accept-process-output nil 0.05))
+          (pcase-let ((`(,tree ,err) result))
+            (should (null tree))
+            (should (eq 'tsc-parse-failed (car err)))
+            (should (eq 'no-language (tsc-error-property err :kind)))))))))
+
+(ert-deftest parsing::included-ranges ()
+  (tsc-test-with rust parser
+    (should (equal (tsc-parser-included-ranges parser) []))
+    (let ((ranges [[1 12 (1 . 0) (1 . 11)] [20 31 (2 . 0) (2 . 11)]]))
+      (tsc-set-included-ranges parser ranges)
+      (should (equal (tsc-parser-included-ranges parser) ranges))
+      (ert-info ("Invalid ranges should leave the previous ones in place")
+        (should-error (tsc-set-included-ranges parser [[20 31 (2 . 0) (2 . 11)]
+                                                        [1 12 (1 . 0) (1 . 11)]])
+                      :type 'tsc-invalid-ranges)
+        (should (equal (tsc-parser-included-ranges parser) ranges))))
+    (tsc-set-included-ranges parser [])
+    (should (equal (tsc-parser-included-ranges parser) []))))
+
+(ert-deftest parsing::tree-included-ranges ()
+  (tsc-test-with rust parser
+    (should (equal [] (tsc-tree-included-ranges (tsc-parse-string parser "fn foo() {}"))))
+    (let ((ranges [[1 12 (1 . 0) (1 . 11)] [20 31 (2 . 0) (2 . 11)]]))
+      (tsc-set-included-ranges parser ranges)
+      (let ((tree (tsc-parse-string parser "fn foo() {}\nfn bar() {}")))
+        (tsc-set-included-ranges parser [])
+        (should (equal ranges (tsc-tree-included-ranges tree)))
+        (ert-info ("Ranges should be adjusted by edits")
+          ;; Insert "x\n" at the start.
+          (tsc-edit-tree tree 1 1 3 '(1 . 0) '(1 . 0) '(2 . 0))
+          (should (equal [[3 14 (2 . 0) (2 . 11)] [22 33 (3 . 0) (3 . 11)]]
+                         (tsc-tree-included-ranges tree))))))))
+
+(ert-deftest parsing::root-node-with-offset ()
+  (tsc-test-with rust parser
+    (let* ((tree (tsc-parse-string parser "fn foo() {\n}"))
+           ;; As if the code started at column 4 of line 3, after 30 bytes.
+           (root (tsc-root-node-with-offset tree 30 '(3 . 4)))
+           (item (tsc-get-nth-named-child root 0)))
+      (should (equal (tsc-node-byte-range item) '(31 . 43)))
+      (should (equal (tsc-node-start-point item) '(3 . 4)))
+      (should (equal (tsc-node-end-point item) '(4 . 1)))
+      (ert-info ("The original tree should not be shifted")
+        (should (equal (tsc-node-byte-range (tsc-root-node tree)) '(1 . 13)))))))
+
+(ert-deftest parsing::copy-tree ()
+  (tsc-test-with rust parser
+    (let* ((tree (tsc-parse-string parser "fn foo() {}"))
+           (copy (tsc-copy-tree tree)))
+      (should (equal (tsc-tree-to-sexp tree) (tsc-tree-to-sexp copy)))
+      (tsc-edit-tree tree 1 1 3 '(1 . 0) '(1 . 0) '(1 . 2))
+      (ert-info ("Edits should not affect the copy")
+        (should (equal (tsc-node-byte-range (tsc-root-node tree)) '(3 . 14)))
+        (should (equal (tsc-node-byte-range (tsc-root-node copy)) '(1 . 12)))
+        (should-not (tsc-node-has-changes-p (tsc-root-node copy)))))))
+
+(ert-deftest parsing::tree-sexp ()
+  (tsc-test-with rust parser
+    (let ((tree (tsc-parse-string parser "fn foo() {}")))
+      (should (equal (tsc-tree-sexp tree)
+                     '(source_file nil 1 12
+                                   (function_item nil 1 12
+                                                  (identifier :name 4 7)
+                                                  (parameters :parameters 7 9)
+                                                  (block :body 10 12)))))
+      (ert-info ("Depth should be limited")
+        (should (equal (tsc-tree-sexp tree nil 1)
+                       '(source_file nil 1 12 (function_item nil 1 12)))))
+      (ert-info ("Anonymous nodes should be included on demand")
+        (should (equal (tsc-node-sexp (tsc-get-nth-named-child (tsc-root-node tree) 0) t)
+                       '(function_item nil 1 12
+                                       ("fn" nil 1 3)
+                                       (identifier :name 4 7)
+                                       (parameters :parameters 7 9 ("(" nil 7 8) (")" nil 8 9))
+                                       (block :body 10 12 ("{" nil 10 11) ("}" nil 11 12)))))))))
 
 (ert-deftest parsing::rust-buffer ()
   (tsc-test-with rust parser
@@ -93,6 +664,253 @@
           (ert-info ("Incremental parsing should be faster than initial")
             (should (> (car initial) (car reparse)))))))))
 
+(ert-deftest instrumentation::counters ()
+  (tsc-test-with rust parser
+    (tsc-reset-instrumentation)
+    (tsc-parse-string parser "fn foo() {}")
+    (let ((counters (tsc-instrumentation)))
+      (should (= 1 (plist-get counters :parses)))
+      (should (= 0 (plist-get counters :reparses)))
+      (should (= 11 (plist-get counters :bytes-parsed))))))
+
+(ert-deftest live::release ()
+  (tsc-test-with rust parser
+    (let* ((tree (tsc-parse-string parser "fn foo() {}"))
+           (node (tsc-root-node tree)))
+      ;; Nodes keep the tree alive.
+      (should-not (tsc-release tree))
+      (should-not (tsc-released-p tree))
+      (should (tsc-node-p node))
+      (ert-info ("Forced release should report why it failed")
+        (let ((err (should-error (tsc-force-release tree) :type 'tsc-error)))
+          (should (eq 'referenced (tsc-error-property err :kind)))
+          (should (<= 1 (tsc-error-property err :references))))))
+    (let* ((tree (tsc-parse-string parser "fn foo() {}"))
+           (trees (plist-get (tsc-live-objects) :trees)))
+      (should (> (plist-get (tsc-live-objects) :parsers) 0))
+      (should (tsc-release tree))
+      (should (tsc-released-p tree))
+      (should (= (1- trees) (plist-get (tsc-live-objects) :trees)))
+      (should (equal (tsc-node-to-sexp (tsc-root-node tree)) '(source_file))))
+    (should (tsc-force-release (tsc-parse-string parser "")))
+    (tsc-set-included-ranges parser [[1 5 (1 . 0) (1 . 4)]])
+    (should (tsc-release parser))
+    (should (tsc-released-p parser))
+    (should-not (tsc-parser-language parser))
+    (should (equal [] (tsc-parser-included-ranges parser)))))
+
+(ert-deftest live::memory-report ()
+  (tsc-test-with rust parser
+    (let* ((small (tsc-parse-string parser "fn foo() {}"))
+           (large (tsc-parse-string parser "fn foo() {} fn bar() { baz(1, 2); }"))
+           (report (tsc-memory-report `((small . ,small) (large . ,large))))
+           (owners (plist-get report :owners)))
+      (should (> (plist-get report :trees) 0))
+      (should (>= (plist-get report :total) (plist-get report :trees)))
+      (should (> (alist-get 'large owners) (alist-get 'small owners)))))
+  (tsc-test-lang-with-file rust "data/types.rs"
+    (let ((owners (plist-get (tree-sitter-debug-memory-report) :owners)))
+      (should (> (alist-get (current-buffer) owners) 0)))))
+
+(ert-deftest session::node-at-position ()
+  (tsc-test-with-file "data/types.rs"
+    (let ((session (tsc-make-session (tree-sitter-require 'rust))))
+      (tsc-session-parse-buffer session)
+      (tsc-traverse-mapc
+       (lambda (node)
+         (pcase-let ((`(,beg . ,end) (tsc-node-position-range node)))
+           ;; Positions inside named leaves are unaffected by boundary rules.
+           (when (and (tsc-node-named-p node)
+                      (= 0 (tsc-count-children node))
+                      (> (- end beg) 1))
+             (should (tsc-node-eq node (tsc-session-node-at-position session (1+ beg)))))))
+       (tsc-session-tree session)))))
+
+(ert-deftest session::in-sync-p ()
+  (with-temp-buffer
+    (insert "fn foo() {}\n")
+    (let ((session (tsc-make-session (tree-sitter-require 'rust) :checksummed)))
+      (should-not (tsc-session-in-sync-p session))
+      (tsc-session-parse-buffer session)
+      (should (tsc-session-in-sync-p session))
+      (ert-info ("Changes that were not parsed should be detected")
+        (let ((inhibit-modification-hooks t))
+          (goto-char 4)
+          (delete-char 3)
+          (insert "bar"))
+        (should-not (tsc-session-in-sync-p session))))
+    (should-error (tsc-session-in-sync-p (tsc-make-session (tree-sitter-require 'rust)))
+                  :type 'tsc-error)))
+
+(ert-deftest session::snapshot ()
+  (with-temp-buffer
+    (insert "fn foo() {}\n")
+    (let ((session (tsc-make-session (tree-sitter-require 'rust) :checksummed)))
+      (tsc-session-parse-buffer session)
+      (let ((snapshot (tsc-session-snapshot session))
+            (sexp (tsc-tree-to-sexp (tsc-session-tree session))))
+        (goto-char (point-max))
+        (insert "struct Foo;\n")
+        (tsc-session-parse-buffer session)
+        (should (= 2 (tsc-session-generation session)))
+        (delete-region 13 (point-max))
+        (tsc-session-restore session snapshot)
+        (should (= 1 (tsc-session-generation session)))
+        (should (equal sexp (tsc-tree-to-sexp (tsc-session-tree session))))
+        (should (tsc-session-in-sync-p session))
+        (should-error (tsc-session-restore (tsc-make-session (tree-sitter-require 'c))
+                                           snapshot)
+                      :type 'tsc-error)))))
+
+(ert-deftest session::parse-visible ()
+  (with-temp-buffer
+    (dotimes (i 100)
+      (insert (format "fn f%d() {}\n" i)))
+    (let ((session (tsc-make-session (tree-sitter-require 'rust))))
+      (ert-info ("Small buffers should be fully parsed")
+        (tsc-session-parse-visible session)
+        (should (null (tsc-session-window session))))
+      (let ((tsc-session-window-threshold 100)
+            (tsc-session-window-margin 20))
+        (goto-char (point-min))
+        (forward-line 50)
+        (let* ((tree (tsc-session-parse-visible session))
+               (window (tsc-session-window session))
+               (root (tsc-root-node tree)))
+          (should window)
+          (should (< 1 (car window) (cdr window) (position-bytes (point-max))))
+          (should (< (tsc-count-named-children root) 100))
+          (should (eq tree (tsc-session-parse-visible session))))))))
+
+(ert-deftest session::parse-async ()
+  (with-temp-buffer
+    (dotimes (i 100)
+      (insert (format "fn f%d() {}\n" i)))
+    (goto-char (point-min))
+    (let* ((session (tsc-make-session (tree-sitter-require 'rust)))
+           (tsc-session-window-margin 20)
+           (complete nil)
+           (provisional (tsc-session-parse-async session (lambda (tree) (setq complete tree))
+                                                 :visible-first))
+           (deadline (+ (float-time) 5)))
+      (should (< (tsc-count-named-children (tsc-root-node provisional)) 100))
+      (should (tsc-session-window session))
+      (while (and (not complete) (< (float-time) deadline))
+        (accept-process-output nil 0.05))
+      (should complete)
+      (should (eq complete (tsc-session-tree session)))
+      (should (null (tsc-session-window session)))
+      (should (= 100 (tsc-count-named-children (tsc-root-node complete)))))))
+
+(ert-deftest session::fuzz ()
+  (with-temp-buffer
+    (insert "fn foo(a: u32) -> u32 {\n    a + 1\n}\n")
+    (let ((session (tsc-make-session (tree-sitter-require 'rust))))
+      (tsc-session-parse-buffer session)
+      (let ((report (tsc-fuzz-session session 30 42)))
+        (should (or (null report) (eq 42 (plist-get report :seed))))
+        (ert-info ("Results should be reproducible")
+          (should (equal report (tsc-fuzz-session session 30 42)))))
+      (ert-info ("Missed changes should be reported before any random edit")
+        (let ((inhibit-modification-hooks t))
+          (goto-char (point-max))
+          (insert "struct Foo;\n"))
+        (let ((report (tsc-fuzz-session session 10 1)))
+          (should (eq 0 (plist-get report :iteration)))
+          (should (equal [] (plist-get report :edits))))))))
+
+(ert-deftest session::inspect-at ()
+  (with-temp-buffer
+    (insert "fn foo() {}\n")
+    (let* ((language (tree-sitter-require 'rust))
+           (session (tsc-make-session language))
+           (query (tsc-make-query language [(function_item) @fn (identifier) @id])))
+      (should (null (tsc-inspect-at session 4)))
+      (tsc-session-parse-buffer session)
+      (let ((info (tsc-inspect-at session 4 query)))
+        (should (tsc-node-p (plist-get info :node)))
+        (should (eq 'identifier (plist-get info :type)))
+        (should (eq :name (plist-get info :field)))
+        (should (equal '(4 . 7) (plist-get info :range)))
+        (should (equal '((function_item nil 1 . 12) (source_file nil 1 . 13))
+                       (plist-get info :ancestors)))
+        (should (equal '((fn 1 . 12) (id 4 . 7)) (plist-get info :captures)))))))
+
+(ert-deftest session::valid-tokens-at ()
+  (with-temp-buffer
+    (insert "fn foo() {}\n")
+    (let ((session (tsc-make-session (tree-sitter-require 'rust))))
+      (tsc-session-parse-buffer session)
+      (let ((tokens (tsc-valid-tokens-at session (point-max))))
+        (should (member "fn" tokens))
+        (should (member "struct" tokens))
+        (should-not (member "}" tokens))))
+    (erase-buffer)
+    (insert "fn foo() {}\nfn bar() {\n    baz(1);\n}\n")
+    (let ((session (tsc-make-session (tree-sitter-require 'rust)))
+          (bytepos (position-bytes (- (point-max) 3)))
+          (fetched nil))
+      (tsc-session-parse-buffer session)
+      (cl-flet ((valid-tokens ()
+                  (append (tsc--session-valid-tokens-at
+                           session bytepos
+                           (lambda (bytepos &rest args)
+                             (push bytepos fetched)
+                             (apply #'tsc--buffer-input bytepos args)))
+                          nil)))
+        (ert-info ("Trial parses should only read the statement around the position")
+          (should (member "let" (valid-tokens)))
+          (should fetched)
+          (should-not (memq 1 fetched)))
+        (ert-info ("Results should be cached until the next parse")
+          (setq fetched nil)
+          (should (member "let" (valid-tokens)))
+          (should-not fetched))))))
+
+(ert-deftest session::text-object-range ()
+  (with-temp-buffer
+    (insert "fn foo() {\n  bar(1, 2);\n}\n")
+    (let ((session (tsc-make-session (tree-sitter-require 'rust))))
+      (tsc-session-parse-buffer session)
+      (should (equal (tsc-text-object-range session 16 'function_item 'outer) '(1 . 26)))
+      (should (equal (tsc-text-object-range session 16 'function_item 'inner) '(14 . 24)))
+      (should (equal (tsc-text-object-range session 18 'arguments 'inner) '(18 . 22)))
+      (let ((query (tsc-make-query (tsc-session-language session)
+                                   "(function_item) @function.outer
+                                    (parameters) @parameter.outer")))
+        (should (equal (tsc-text-object-range session 16 "function" 'inner query) '(14 . 24)))
+        (should-not (tsc-text-object-range session 16 "parameter" 'outer query))))))
+
+(ert-deftest session::which-function ()
+  (with-temp-buffer
+    (insert "impl Foo {\n  fn bar() {\n    1\n  }\n}\n")
+    (let* ((session (tsc-make-session (tree-sitter-require 'rust)))
+           (query (tsc-make-query (tsc-session-language session)
+                                  "(impl_item type: (type_identifier) @name) @impl
+                                   (function_item name: (identifier) @name) @function")))
+      (tsc-session-parse-buffer session)
+      (should (equal (tsc-which-function session 30 query) "bar"))
+      (should (equal (tsc-which-function session 30 query :all) '("Foo" "bar")))
+      (should (equal (tsc-which-function session 5 query :all) '("Foo")))
+      (should-not (tsc-which-function session (point-max) query)))))
+
+(ert-deftest session::document-symbols ()
+  (with-temp-buffer
+    (insert "impl Foo {\n  fn bar(x: u8) {}\n}\nfn baz() {}\n")
+    (let* ((session (tsc-make-session (tree-sitter-require 'rust)))
+           (query (tsc-make-query (tsc-session-language session)
+                                  "(impl_item type: (type_identifier) @name) @class
+                                   (function_item name: (identifier) @name
+                                                  parameters: (_) @detail) @function")))
+      (tsc-session-parse-buffer session)
+      (should (equal (tsc-document-symbols session query)
+                     '((:name "Foo" :detail nil :kind class :range (1 . 32)
+                        :selection-range (6 . 9)
+                        :children ((:name "bar" :detail "(x: u8)" :kind function :range (14 . 30)
+                                    :selection-range (17 . 20) :children nil)))
+                       (:name "baz" :detail "()" :kind function :range (33 . 44)
+                        :selection-range (36 . 39) :children nil)))))))
+
 (ert-deftest minor-mode::basic-editing ()
   (with-temp-buffer
     (tsc-test-use-lang 'rust)
@@ -162,6 +980,43 @@
       (should (tsc-node-eq node1 node2))
       (should-not (tsc-node-eq node1 (tsc-get-nth-child node1 0))))))
 
+(ert-deftest node::ref ()
+  (tsc-test-with rust parser
+    (let* ((tree (tsc-parse-string parser "fn foo() {}"))
+           (refs (let (refs)
+                   (tsc-traverse-mapc (lambda (ref) (push ref refs)) tree :node-ref)
+                   (nreverse refs)))
+           (nodes (let (nodes)
+                    (tsc-traverse-mapc (lambda (node) (push node nodes)) tree)
+                    (nreverse nodes))))
+      (ert-info ("Traversal refs should resolve to the traversed nodes")
+        (cl-loop for ref in refs
+                 for node in nodes
+                 do (should (tsc-node-eq node (tsc-resolve-node-ref tree ref)))
+                 do (should (equal ref (tsc-node-ref node)))))
+      (ert-info ("Refs should not resolve against other trees")
+        (should (null (tsc-resolve-node-ref
+                       (tsc-parse-string parser "fn foo() {}")
+                       (car refs)))))
+      (ert-info ("Tree ids should not be reused after garbage collection")
+        (let ((id (aref (car refs) 0)))
+          (setq tree nil nodes nil)
+          (garbage-collect)
+          (should (/= id (aref (tsc-node-ref
+                                (tsc-root-node (tsc-parse-string parser "fn foo() {}")))
+                               0))))))))
+
+(ert-deftest node::text-max-length ()
+  (tsc-test-lang-with-file rust "data/types.rs"
+    (let ((root (tsc-root-node tree-sitter-tree))
+          (node (tsc-get-descendant-for-byte-range (tsc-root-node tree-sitter-tree) 1 1)))
+      (should (equal (tsc-node-text node) (tsc-node-text node 100)))
+      (let ((err (should-error (tsc-node-text root 100) :type 'tsc-node-text-too-long)))
+        (should (memq 'tsc-error (get (car err) 'error-conditions)))
+        (should (eq 'too-long (tsc-error-property err :kind)))
+        (should (= 100 (tsc-error-property err :max-length)))
+        (should (> (tsc-error-property err :length) 100))))))
+
 (ert-deftest node::using-without-tree ()
   "Test that a tree's nodes are still usable after no direct reference to the
 tree is held (since nodes internally reference the tree)."
@@ -196,6 +1051,348 @@ tree is held (since nodes internally reference the tree)."
           (should (equal (tsc-node-type semi) ";")))
         (should (tsc-node-missing-p semi))))))
 
+(ert-deftest node::missing-token ()
+  (tsc-test-with rust parser
+    (let* ((root (tsc-root-node (tsc-parse-string parser "let x = 1")))
+           (decl (tsc-get-nth-child root 0))
+           (semi (tsc-get-nth-child decl (1- (tsc-count-children decl)))))
+      (should (equal (tsc-missing-token semi) ";"))
+      (should-not (tsc-missing-token decl)))))
+
+(ert-deftest diagnostics::basic ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "let x = 1")
+      (let ((diagnostics (tsc-diagnostics (tsc-parse-string parser (buffer-string)))))
+        (should (equal diagnostics [(10 10 :error "Missing `;'")]))))
+    (with-temp-buffer
+      (insert "fn")
+      (pcase-let ((`[(,beg ,end ,severity ,message)]
+                   (tsc-diagnostics (tsc-parse-string parser (buffer-string)))))
+        (should (equal (list beg end severity) '(1 3 :error)))
+        (should (equal message "Syntax error, unexpected `fn'"))))))
+
+(ert-deftest diagnostics::error-metrics ()
+  (tsc-test-with rust parser
+    (let ((metrics (tsc-error-metrics (tsc-parse-string parser "fn foo() {}"))))
+      (should (= 0 (plist-get metrics :error-count)))
+      (should (= 0 (plist-get metrics :error-bytes)))
+      (should (= 11 (plist-get metrics :total-bytes))))
+    (let ((metrics (tsc-error-metrics (tsc-parse-string parser "fn"))))
+      (should (= 1 (plist-get metrics :error-count)))
+      (should (= 2 (plist-get metrics :error-bytes)))
+      (should (= 2 (plist-get metrics :largest-error)))
+      (should (= 1 (plist-get metrics :skipped-tokens))))
+    (let ((metrics (tsc-error-metrics (tsc-parse-string parser "let x = 1"))))
+      (should (= 1 (plist-get metrics :missing-count))))))
+
+(ert-deftest diagnostics::tree-errors ()
+  (tsc-test-with rust parser
+    (let ((tree (tsc-parse-string parser "let x = 1")))
+      (should (equal [[10 10 ";" t]] (tsc-tree-errors tree)))
+      (ert-info ("Only the errors in the given range should be returned")
+        (should (equal [] (tsc-tree-errors tree 1 5)))
+        (should (equal [[10 10 ";" t]] (tsc-tree-errors tree 5 10)))))
+    (let ((tree (tsc-parse-string parser "fn")))
+      (should (equal [[1 3 ERROR nil]] (tsc-tree-errors tree)))
+      (should (equal [] (tsc-node-errors (tsc-root-node tree) 3 3))))))
+
+(ert-deftest diagnostics::explain-error ()
+  (tsc-test-with rust parser
+    (let* ((source "fn main() {\n  let x = 1\n}")
+           (tree (tsc-parse-string parser source))
+           (node (aref (tsc--error-nodes tree) 0))
+           (lines (split-string (tsc-explain-error node source) "\n")))
+      (should (equal (seq-take lines 5)
+                     '("missing `;' at 2:11"
+                       "1 | fn main() {"
+                       "2 |   let x = 1"
+                       "  |            ^"
+                       "3 | }")))
+      (should (string-prefix-p "in: (" (nth 5 lines))))))
+
+(ert-deftest diagnostics::verify-tree ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo() {}\n")
+      (let ((tree (tsc-parse-chunks parser #'tsc--buffer-input nil)))
+        (should-not (tsc-verify-tree tree))
+        (ert-info ("Same-length changes should be caught by token sampling")
+          (goto-char 1)
+          (delete-char 2)
+          (insert "xy")
+          (should (equal (tsc-verify-tree tree)
+                         '(:position 1 :reason token :expected "fn" :actual "xy"))))
+        (ert-info ("Other changes should be caught by comparing extents")
+          (goto-char (point-max))
+          (insert "\n")
+          (should (eq 'extent (plist-get (tsc-verify-tree tree) :reason))))))))
+
+(ert-deftest indent::column ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo() {\n"
+              "let x = bar(1,\n"
+              "2);\n"
+              "x\n"
+              "}\n")
+      (let ((query (tsc-make-query (tsc-parser-language parser)
+                                   [(block) @indent
+                                    "}" @branch
+                                    (arguments) @aligned]))
+            (tree))
+        (goto-char (point-min))
+        (dolist (expected '(0 4 16 4 0))
+          (setq tree (tsc-parse-chunks parser #'tsc--buffer-input nil))
+          (should (= expected (tsc-indent-column query tree nil 4)))
+          (indent-line-to expected)
+          (forward-line 1))
+        (should (equal (buffer-string)
+                       (concat "fn foo() {\n"
+                               "    let x = bar(1,\n"
+                               "                2);\n"
+                               "    x\n"
+                               "}\n")))))))
+
+(ert-deftest indent::conventions ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo() {\n"
+              "let s = \"a\n"
+              "   b\";\n"
+              "}\n")
+      (let ((query (tsc-make-query (tsc-parser-language parser)
+                                   [(block) @indent.begin
+                                    "}" @indent.branch
+                                    (string_literal) @indent.ignore]))
+            (tree))
+        (goto-char (point-min))
+        (dolist (expected '(0 4 3 0))
+          (setq tree (tsc-parse-chunks parser #'tsc--buffer-input nil))
+          (should (= expected (tsc-indent-column query tree nil 4)))
+          (indent-line-to expected)
+          (forward-line 1))))))
+
+(ert-deftest folding::ranges ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo() {\n"
+              "    bar(1,\n"
+              "        2);\n"
+              "}\n"
+              "fn baz() {}\n")
+      (let ((tree (tsc-parse-chunks parser #'tsc--buffer-input nil)))
+        (ert-info ("Block-like nodes should be used without a query")
+          (should (equal [(10 36 . "fn foo() {") (19 33 . "bar(1,")]
+                         (tsc-fold-ranges tree nil))))
+        (ert-info ("Only @fold captures should be used")
+          (let ((query (tsc-make-query (tsc-parser-language parser)
+                                       [(function_item) @fold
+                                        (arguments) @other])))
+            (should (equal [(1 36 . "fn foo() {")] (tsc-fold-ranges tree query)))
+            (should (equal [] (tsc-fold-ranges tree query 5)))))))))
+
+(ert-deftest tags::index ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "impl S {\n"
+              "    fn foo() { bar(); }\n"
+              "}\n"
+              "fn bar() {}\n")
+      (let ((tree (tsc-parse-chunks parser #'tsc--buffer-input nil))
+            (query (tsc-make-query (tsc-parser-language parser)
+                                   [(impl_item type: (type_identifier) @name) @definition.class
+                                    (function_item name: (identifier) @name) @definition.function
+                                    (call_expression function: (identifier) @name) @reference.call])))
+        (should (equal [["S" class t 1 35 6 7 nil]
+                        ["foo" function t 14 33 17 20 0]
+                        ["bar" call nil 25 30 25 28 1]
+                        ["bar" function t 36 47 39 42 nil]]
+                       (tsc-tags tree query)))
+        (should (equal '(("S" ("S" . 6) ("foo" . 17)) ("bar" . 39))
+                       (tsc-tags-imenu-index tree query)))))))
+
+(ert-deftest formatting::region ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo(a : u8) { bar( a ) }")
+      (let ((query (tsc-make-query
+                    (tsc-parser-language parser)
+                    "((\"{\" @open) (#set! @open format \"newline-after\"))
+                     ((\"}\" @close) (#set! @close format \"newline-before\"))
+                     ((\":\" @colon) (#set! @colon format \"no-space-before\"))
+                     ((arguments \"(\" @open) (#set! @open format \"no-space-after\"))
+                     ((arguments \")\" @close) (#set! @close format \"no-space-before\"))"))
+            (tree (tsc-parse-chunks parser #'tsc--buffer-input nil)))
+        (should (= 5 (tsc-format-region query tree (point-min) (point-max))))
+        (should (equal (buffer-string) "fn foo(a: u8) {\nbar(a)\n}"))
+        (ert-info ("Formatting should be idempotent")
+          (setq tree (tsc-parse-chunks parser #'tsc--buffer-input nil))
+          (should (= 0 (tsc-format-region query tree (point-min) (point-max)))))))))
+
+(ert-deftest editing::tree-batch ()
+  (tsc-test-with rust parser
+    (let* ((text "fn a() {}\nfn b() {}\n")
+           (one-by-one (tsc-parse-string parser text))
+           (batched (tsc-parse-string parser text))
+           ;; Insert "xx" after "fn a", then delete "fn " on line 2.
+           (edits [(5 5 7 (1 . 4) (1 . 4) (1 . 6))
+                   (13 16 13 (2 . 0) (2 . 3) (2 . 0))])
+           (ranges (lambda (tree)
+                     (let ((root (tsc-root-node tree)))
+                       (mapcar (lambda (i)
+                                 (tsc-node-byte-range (tsc-get-nth-named-child root i)))
+                               '(0 1))))))
+      (mapc (lambda (edit) (apply #'tsc-edit-tree one-by-one edit)) edits)
+      (tsc-edit-tree* batched edits)
+      (should (equal (funcall ranges batched) (funcall ranges one-by-one)))
+      (should (equal (funcall ranges batched) '((1 . 12) (13 . 19))))
+      (ert-info ("Invalid edits should not be applied")
+        (should-error (tsc-edit-tree* batched [(1 1 2 (1 . 0) (1 . 0) (1 . 1)) (1 2 x)]))
+        (should (equal (funcall ranges batched) '((1 . 12) (13 . 19))))))))
+
+(ert-deftest editing::structural ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn f() { g(x(a), b); }")
+      (let* ((tree (tsc-parse-chunks parser #'tsc--buffer-input nil))
+             (root (tsc-root-node tree))
+             (args-at (lambda (text)
+                        (goto-char (point-min))
+                        (search-forward text)
+                        (tsc-get-named-descendant-for-position-range
+                         root (match-beginning 0) (match-end 0))))
+             (apply-to (lambda (fn text)
+                         (let ((before (buffer-string))
+                               (edits (funcall fn (funcall args-at text))))
+                           (tsc-apply-edits edits)
+                           (prog1 (buffer-string)
+                             (erase-buffer)
+                             (insert before))))))
+        (should (equal (funcall apply-to #'tsc-splice-node "(a)")
+                       "fn f() { g(xa, b); }"))
+        (should (equal (funcall apply-to #'tsc-slurp-forward "(a)")
+                       "fn f() { g(x(a, b)); }"))
+        (should (equal (funcall apply-to #'tsc-barf-forward "(x(a), b)")
+                       "fn f() { g(x(a)), b; }"))
+        (should (equal (funcall apply-to #'tsc-raise-node "x(a)")
+                       "fn f() { gx(a); }"))
+        (ert-info ("Operations that don't apply should return nil")
+          (should-not (tsc-slurp-forward (funcall args-at "(x(a), b)"))))))))
+
+(ert-deftest editing::matching-delimiter ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn f() { g(\"(\", [1]); }")
+      (let ((tree (tsc-parse-chunks parser #'tsc--buffer-input nil)))
+        (should (equal (tsc-matching-delimiter tree 11) '((11 . 12) (20 . 21))))
+        (ert-info ("Brackets inside strings should not be matched")
+          (should (equal (tsc-matching-delimiter tree 13) '((12 . 13) (14 . 15)))))
+        (should (equal (tsc-matching-delimiter tree 18) '((17 . 18) (19 . 20))))
+        (should (equal (tsc-matching-delimiter tree 3) nil))))))
+
+(ert-deftest syntax-context::basic ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn f() { g(\"a\"); } // b")
+      (let ((tree (tsc-parse-chunks parser #'tsc--buffer-input nil)))
+        (should (equal (tsc-syntax-context tree 13) '(string)))
+        (should (equal (tsc-syntax-context tree 23) '(comment)))
+        (ert-info ("Positions at the boundaries should be outside")
+          (should (equal (tsc-syntax-context tree 12) nil)))
+        (should (equal (tsc-syntax-context tree 13 '((str string_literal) (call call_expression)
+                                                     (fun function_item)))
+                       '(str call fun)))))))
+
+(ert-deftest syntax-context::expected-closer ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn f() { g(1")
+      (should (equal (tsc-expected-closer (tsc-parse-chunks parser #'tsc--buffer-input nil))
+                     ")"))
+      (erase-buffer)
+      (insert "fn f() {}")
+      (should-not (tsc-expected-closer (tsc-parse-chunks parser #'tsc--buffer-input nil) 9)))))
+
+(ert-deftest navigation::expansion-steps ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn f() { g(a, b); }")
+      (let ((tree (tsc-parse-chunks parser #'tsc--buffer-input nil)))
+        (should (equal (tsc-expansion-steps tree 12 13)
+                       '((12 . 16) (11 . 17) (10 . 17) (10 . 18) (8 . 20) (1 . 20))))
+        (should (equal (tsc-expansion-steps tree 1 20) nil))))))
+
+(ert-deftest navigation::forward-construct ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn f() { g(a, /* c */ b); }")
+      (let ((tree (tsc-parse-chunks parser #'tsc--buffer-input nil)))
+        (should (equal (tsc-forward-construct tree 12) 13))
+        (ert-info ("Comments and separators should be skipped")
+          (should (equal (tsc-forward-construct tree 13) 24))
+          (should (equal (tsc-backward-construct tree 24) 23))
+          (should (equal (tsc-backward-construct tree 23) 12)))
+        (should (equal (tsc-forward-construct tree 12 2) 24))
+        (ert-info ("Should stop at the end of the nesting level")
+          (should-not (tsc-forward-construct tree 24)))
+        (should (equal (tsc-forward-construct tree 1) 28))))))
+
+(ert-deftest syntax-context::tokens-in-range ()
+  (tsc-test-with rust parser
+    (let ((tree (tsc-parse-string parser "fn foo() {}\nfn bar() {}")))
+      (should (equal (tsc-tokens-in-range tree 1 5)
+                     [["fn" 1 3] [identifier 4 7]]))
+      (should (equal (tsc-tokens-in-range tree 1 24 t)
+                     [[identifier 4 7] [identifier 16 19]]))
+      (should (equal (tsc-tokens-in-range tree 5 24 nil '("{"))
+                     [["{" 10 11] ["{" 22 23]]))
+      (should (equal (tsc-tokens-in-range tree 12 13) [])))))
+
+(ert-deftest syntax-context::structural-navigation ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo() { let x = 1; }\nstruct S;\nfn bar() {}")
+      (let* ((tree (tsc-parse-chunks parser #'tsc--buffer-input nil))
+             (literal (tsc-node-at-position tree 20)))
+        (should (eq (tsc-node-type literal) 'integer_literal))
+        (should (eq (tsc-node-type (tsc-node-at-position tree 16)) 'identifier))
+        (should (equal (tsc-node-type (tsc-node-at-position tree 21)) ";"))
+        (should (eq (tsc-node-type (tsc-node-at-position tree 21 t)) 'let_declaration))
+        (should (equal (tsc-expand-region literal) '(12 . 22)))
+        (should (equal (tsc-expand-region '(12 . 22) tree) '(10 . 24)))
+        (should-not (tsc-expand-region (cons (point-min) (point-max)) tree))
+        (let ((function (tsc-parent-until literal '(function_item))))
+          (should (equal (tsc-node-position-range function) '(1 . 24)))
+          (ert-info ("Strings should only match anonymous node types")
+            (should-not (tsc-parent-until literal '("function_item"))))
+          (should-not (tsc-parent-until function '(function_item)))
+          (should (equal (tsc-node-position-range
+                          (tsc-next-sibling-of-type function '(function_item)))
+                         '(35 . 46)))
+          (should-not (tsc-next-sibling-of-type function '(function_item) t)))))))
+
+(ert-deftest syntax-context::prose-regions ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "// a\nfn f() { g(\"b\"); } /* c */")
+      (let ((tree (tsc-parse-chunks parser #'tsc--buffer-input nil)))
+        (should (equal (tsc-prose-regions tree) '((1 . 5) (17 . 20) (25 . 32))))
+        (should (equal (tsc-prose-regions tree '(block_comment)) '((25 . 32))))
+        (ert-info ("Regions should be clipped")
+          (should (equal (tsc-prose-regions tree nil 3 18) '((3 . 5) (17 . 18)))))))))
+
+(ert-deftest navigation::current-construct-range ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn f() { let a = 1; g(a); }")
+      (let ((tree (tsc-parse-chunks parser #'tsc--buffer-input nil))
+            (kinds '(let_declaration expression_statement)))
+        (should (equal (tsc-current-construct-range tree 14 kinds) '(10 . 20)))
+        (should (equal (tsc-current-construct-range tree 23 kinds) '(21 . 26)))
+        (should (equal (tsc-current-construct-range tree 3 kinds) nil))
+        (should (equal (tsc-current-construct-range tree 3 '(function_item)) '(1 . 28)))))))
+
 (ert-deftest cursor::walk ()
   (tsc-test-with rust parser
     (let* ((tree (tsc-parse-string parser "fn foo() {}"))
@@ -215,8 +1412,34 @@ tree is held (since nodes internally reference the tree)."
       (should (equal (tsc-current-node cursor :type) 'source_file))
       (should (equal (tsc-current-node cursor [:start-byte :end-byte :type])
                      `[,(point-min) ,(point-max) source_file]))
-      (should-error (tsc-current-node cursor :depth))
-      (should-error (tsc-current-node cursor [:depth])))))
+      (should (= 0 (tsc-current-node cursor :depth)))
+      (should (equal [0] (tsc-current-node cursor [:depth]))))))
+
+(ert-deftest cursor::navigation ()
+  (tsc-test-with rust parser
+    (let* ((tree (tsc-parse-string parser "fn foo() {}\nfn bar(x: u8) {}\n"))
+           (cursor (tsc-make-cursor tree))
+           (other (tsc-make-cursor tree)))
+      (ert-info ("Moving to the first child for a point")
+        (should (= 1 (tsc-goto-first-child-for-point cursor '(2 . 3))))
+        (should (= 1 (tsc-cursor-depth cursor)))
+        (should (equal '(2 . 0) (tsc-current-node cursor :start-point))))
+      (ert-info ("Depth and descendant index should be relative to the starting node")
+        (tsc-goto-first-child cursor)
+        (should (= 2 (tsc-current-node cursor :depth)))
+        (should (equal "fn" (tsc-current-node cursor :type)))
+        (let ((index (tsc-cursor-descendant-index cursor)))
+          (should (tsc-goto-descendant other index))
+          (should (tsc-node-eq (tsc-current-node cursor) (tsc-current-node other))))
+        (should (= 0 (progn (tsc-goto-descendant other 0) (tsc-cursor-descendant-index other))))
+        (should-not (tsc-goto-descendant other 1000))
+        (should (eq 'source_file (tsc-current-node other :type))))
+      (ert-info ("Resetting to another cursor should allow moving back up")
+        (tsc-reset-cursor-to other cursor)
+        (should (equal "fn" (tsc-current-node other :type)))
+        (should (tsc-goto-parent other))
+        (should (tsc-goto-parent other))
+        (should (eq 'source_file (tsc-current-node other :type)))))))
 
 (ert-deftest cursor::using-without-tree ()
   (tsc-test-with rust parser
@@ -310,6 +1533,44 @@ source_file (1 . 20)
         (should (equal do-result mapc-result))
         (should (equal do-result iter-result))))))
 
+(ert-deftest cursor::traverse:filter ()
+  (tsc-test-lang-with-file rust "data/types.rs"
+    (let ((tree tree-sitter-tree)
+          mapc-result
+          filter-result)
+      (ert-info ("Filtering should give the same nodes as filtering in Lisp")
+        (tsc-traverse-mapc
+         (lambda (props)
+           (pcase-let ((`[,type ,named-p ,depth] props))
+             (when (and named-p (>= depth 2) (memq type '(identifier type_identifier)))
+               (push (list type depth) mapc-result))))
+         tree [:type :named-p :depth])
+        (tsc-traverse-filter
+         (lambda (props)
+           (push (append props nil) filter-result))
+         tree '(:types (identifier type_identifier) :named t :min-depth 2)
+         [:type :depth])
+        (should mapc-result)
+        (should (equal mapc-result filter-result)))
+      (ert-info ("Without a function, the results should be returned")
+        (let ((items (tsc-traverse-filter nil tree '(:max-depth 1 :named t) [:depth])))
+          (should (equal (aref items 0) [0]))
+          (should (seq-every-p (lambda (item) (equal item [1])) (seq-drop items 1)))
+          (should (= (1+ (tsc-count-named-children (tsc-root-node tree)))
+                     (length items)))))
+      (ert-info ("Anonymous node types should be strings, and ranges should be respected")
+        (let* ((root (tsc-root-node tree))
+               (second (tsc-get-nth-named-child root 1))
+               (nodes (tsc-traverse-filter
+                       nil tree (list :types '(";" "{")
+                                      :beg (tsc-node-start-byte second)
+                                      :end (tsc-node-end-byte second)))))
+          (should (> (length nodes) 0))
+          (seq-doseq (node nodes)
+            (should (member (tsc-node-type node) '(";" "{")))
+            (should (<= (tsc-node-start-byte second) (tsc-node-start-byte node)))
+            (should (<= (tsc-node-end-byte node) (tsc-node-end-byte second)))))))))
+
 (ert-deftest conversion::position<->tsc-point ()
   (tsc-test-with-file "tree-sitter-tests.el"
     (ert-info ("Testing buffer boundaries")
@@ -323,6 +1584,32 @@ source_file (1 . 20)
         (let ((p (1+ (random (buffer-size)))))
           (should (= p (tsc-point-to-position (tsc-point-from-position p)))))))))
 
+(ert-deftest conversion::position-map ()
+  (with-temp-buffer
+    (insert "fn main() { \"Tuấn-Anh Nguyễn\" }\n// 日本語 ok 😀😀\n")
+    (let ((map (tsc-buffer-position-map))
+          (check (lambda (map)
+                   (dotimes (i (buffer-size))
+                     (let ((p (1+ i)))
+                       (should (= (position-bytes p) (tsc-position-to-byte map p)))))
+                   (dotimes (i (1- (position-bytes (point-max))))
+                     (let ((b (1+ i)))
+                       (should (= (byte-to-position b) (tsc-byte-to-position map b)))))
+                   (should (null (tsc-position-to-byte map (1+ (point-max))))))))
+      (funcall check map)
+      (ert-info ("The map should be kept in sync with edits")
+        (pcase-dolist (`(,beg ,end ,text) '((14 18 "ŭ日") (1 3 "") (30 31 "x😀y")))
+          (let ((beg-byte (position-bytes beg))
+                (end-byte (position-bytes end)))
+            (delete-region beg end)
+            (goto-char beg)
+            (insert text)
+            (tsc-position-map-edit map beg-byte end-byte text)
+            (funcall check map))))
+      (ert-info ("Edits must start and end at character boundaries")
+        (should-error (tsc-position-map-edit map (1+ (position-bytes 12)) (position-bytes 13) "")
+                      :type 'args-out-of-range)))))
+
 (ert-deftest buffer-input::non-ascii-characters ()
   (with-temp-buffer
     (insert "\"Tuấn-Anh Nguyễn\";")
@@ -391,7 +1678,7 @@ source_file (1 . 20)
                                       cursor query root-node #'tsc--buffer-substring-no-properties))
                        capture-names)))
       (ert-info ("Querying with range restriction")
-        (tsc--query-cursor-set-byte-range cursor 1 28)
+        (tsc-query-cursor-set-byte-range cursor 1 28)
         (should (equal (mapcar #'car (tsc-query-captures
                                       query root-node #'tsc--buffer-substring-no-properties cursor))
                        capture-names))
@@ -399,6 +1686,591 @@ source_file (1 . 20)
                                       cursor query root-node #'tsc--buffer-substring-no-properties))
                        capture-names))))))
 
+(ert-deftest query::byte-range-args ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo() {} fn bar() {}")
+      (let* ((tree (tsc-parse-string parser (buffer-string)))
+             (root-node (tsc-root-node tree))
+             (query (tsc-make-query (tsc-parser-language parser) "(identifier) @x"))
+             (text-function #'tsc--buffer-substring-no-properties)
+             (texts (lambda (captures)
+                      (mapcar (lambda (capture) (tsc-node-text (cdr capture))) captures))))
+        (should (equal (funcall texts (tsc-query-captures query root-node text-function))
+                       '("foo" "bar")))
+        (should (equal (funcall texts (tsc-query-captures query root-node text-function nil 13))
+                       '("bar")))
+        (should (equal (funcall texts (tsc-query-captures query root-node text-function nil nil 12))
+                       '("foo")))
+        (should (equal (mapcar (lambda (m) (funcall texts (cdr m)))
+                               (tsc-query-matches query root-node text-function nil 13 24))
+                       '(("bar"))))))))
+
+(ert-deftest query::match-limit ()
+  (tsc-test-lang-with-file rust "data/query.rs"
+    (let ((cursor (tsc-make-query-cursor))
+          (query (tsc-make-query tree-sitter-language "(identifier) @x"))
+          (root-node (tsc-root-node tree-sitter-tree)))
+      (tsc-query-cursor-set-match-limit cursor 32)
+      (should (equal (tsc-query-cursor-match-limit cursor) 32))
+      (should (> (length (tsc-query-captures query root-node
+                                             #'tsc--buffer-substring-no-properties cursor))
+                 0))
+      (ert-info ("Matches that finish immediately should not count towards the limit")
+        (should-not (tsc-query-cursor-did-exceed-match-limit-p cursor))))))
+
+(ert-deftest query::time-budget ()
+  (tsc-test-lang-with-file rust "data/query.rs"
+    (let ((cursor (tsc-make-query-cursor))
+          (query (tsc-make-query tree-sitter-language "(identifier) @x"))
+          (root-node (tsc-root-node tree-sitter-tree)))
+      (should-not (tsc-query-cursor-time-budget cursor))
+      (tsc-query-cursor-set-time-budget cursor 0)
+      (should (equal (tsc-query-cursor-time-budget cursor) 0))
+      (should (equal (tsc-query-captures query root-node
+                                         #'tsc--buffer-substring-no-properties cursor)
+                     []))
+      (should (tsc-query-cursor-did-time-out-p cursor))
+      (tsc-query-cursor-set-time-budget cursor nil)
+      (should (> (length (tsc-query-matches query root-node
+                                            #'tsc--buffer-substring-no-properties cursor))
+                 0))
+      (should-not (tsc-query-cursor-did-time-out-p cursor)))))
+
+(ert-deftest query::disabling ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo() { bar(); }")
+      (let* ((root-node (tsc-root-node (tsc-parse-string parser (buffer-string))))
+             (query (tsc-make-query (tsc-parser-language parser)
+                                    [(function_item name: (identifier) @function)
+                                     (call_expression function: (identifier) @function.call)
+                                     (block) @block]))
+             (tags (lambda ()
+                     (mapcar #'car (tsc-query-captures
+                                    query root-node #'tsc--buffer-substring-no-properties)))))
+        (should (equal (funcall tags) '(function block function.call)))
+        (tsc-query-disable-capture query "block")
+        (should (equal (funcall tags) '(function function.call)))
+        (tsc-query-disable-pattern query 0)
+        (should (equal (funcall tags) '(function.call)))
+        (should-error (tsc-query-disable-pattern query 3) :type 'args-out-of-range)))))
+
+(ert-deftest query::streaming ()
+  (tsc-test-lang-with-file rust "data/query.rs"
+    (let* ((cursor (tsc-make-query-cursor))
+           (query (tsc-make-query tree-sitter-language
+                                  [(function_item name: (identifier) @name)]))
+           (root-node (tsc-root-node tree-sitter-tree))
+           (text-function #'tsc--buffer-substring-no-properties)
+           (expected (tsc-query-matches query root-node text-function))
+           (ranges (lambda (match)
+                     (cons (car match)
+                           (mapcar (pcase-lambda (`(,tag . ,node))
+                                     (cons tag (tsc-node-byte-range node)))
+                                   (cdr match)))))
+           matches match)
+      (should (> (length expected) 1))
+      (tsc-query-cursor-exec cursor query root-node text-function)
+      (while (setq match (tsc-query-cursor-next-match cursor))
+        (push match matches))
+      (should (equal (mapcar ranges (nreverse matches))
+                     (mapcar ranges expected)))
+      (should-not (tsc-query-cursor-next-match cursor))
+      (ert-info ("Editing the tree should not affect an ongoing execution")
+        (tsc-query-cursor-exec cursor query root-node text-function)
+        (should (equal (funcall ranges (tsc-query-cursor-next-match cursor))
+                       (funcall ranges (aref expected 0))))
+        (tsc-edit-tree tree-sitter-tree 1 1 1 '(1 . 0) '(1 . 0) '(1 . 0))
+        (should (equal (funcall ranges (tsc-query-cursor-next-match cursor))
+                       (funcall ranges (aref expected 1)))))
+      (ert-info ("The query should not be changed during an execution")
+        (tsc-query-cursor-exec cursor query root-node text-function)
+        (should (eq 'query-streamed
+                    (tsc-error-property
+                     (should-error (tsc-query-disable-pattern query 0) :type 'tsc-error)
+                     :kind)))
+        (should-not (tsc-release query)))
+      (ert-info ("Using the cursor for something else should end the execution")
+        (tsc-query-cursor-set-byte-range cursor 1 2)
+        (should-not (tsc-query-cursor-next-match cursor))
+        (should (tsc-release query))))))
+
+(ert-deftest query::capture-quantifiers ()
+  (let* ((language (tree-sitter-require 'rust))
+         (query (tsc-make-query language
+                                [(function_item name: (identifier) @name
+                                                parameters: (parameters (parameter)* @param))
+                                 (struct_item (visibility_modifier)? @vis
+                                              name: (type_identifier) @struct)
+                                 (block (expression_statement)+ @statement)]))
+         (captures (number-sequence 0 (1- (length (tsc-query-capture-tags query))))))
+    (should (equal (mapcar (lambda (pattern)
+                             (mapcar (lambda (capture)
+                                       (tsc-query-capture-quantifier query pattern capture))
+                                     captures))
+                           '(0 1 2))
+                   '((one zero-or-more zero zero zero)
+                     (zero zero zero-or-one one zero)
+                     (zero zero zero zero one-or-more))))
+    (should-error (tsc-query-capture-quantifier query 3 0) :type 'args-out-of-range)))
+
+(ert-deftest query::pattern-introspection ()
+  (let* ((language (tree-sitter-require 'rust))
+         (source "(identifier) @a\n((function_item name: (identifier) @b) @c)")
+         (query (tsc-make-query language source)))
+    (should (equal (tsc-query-start-byte-for-pattern query 0) 1))
+    (should (equal (tsc-query-start-byte-for-pattern query 1) 17))
+    (should-error (tsc-query-start-byte-for-pattern query 2) :type 'args-out-of-range)
+    (should (tsc-query-pattern-guaranteed-at-step-p query 2))))
+
+(ert-deftest query::highlight-region ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo(x: u32) { x }")
+      (let* ((language (tsc-parser-language parser))
+             (tree (tsc-parse-string parser (buffer-string)))
+             (highlights (tsc-make-query language
+                                         [(function_item name: (identifier) @function)
+                                          (parameter pattern: (identifier) @variable.parameter)
+                                          (identifier) @variable]))
+             (locals (tsc-make-query language
+                                     [(function_item) @local.scope
+                                      (parameter pattern: (identifier) @local.definition)
+                                      (identifier) @local.reference]))
+             (end (position-bytes (point-max))))
+        (should (equal (tsc-highlight-region `((,highlights ,tree nil)) 1 end)
+                       [(4 7 . function) (8 9 . variable.parameter) (18 19 . variable)]))
+        (ert-info ("References should be highlighted like their definitions")
+          (should (equal (tsc-highlight-region `((,highlights ,tree ,locals)) 1 end)
+                         [(4 7 . function) (8 9 . variable.parameter)
+                          (18 19 . variable.parameter)])))
+        (ert-info ("Later layers should be painted over earlier ones")
+          (should (equal (tsc-highlight-region
+                          `((,highlights ,tree nil)
+                            (,(tsc-make-query language [(parameter) @param]) ,tree nil))
+                          1 end)
+                         [(4 7 . function) (8 14 . param) (18 19 . variable)])))
+        (ert-info ("Spans should be clipped to the region")
+          (should (equal (tsc-highlight-region `((,highlights ,tree nil)) 5 9)
+                         [(5 7 . function) (8 9 . variable.parameter)])))
+        (ert-info ("Only the texts needed by predicates and locals should be fetched")
+          (let* ((fetched nil)
+                 (text-function (lambda (beg end)
+                                  (push (cons beg end) fetched)
+                                  (tsc--buffer-substring-no-properties beg end))))
+            (tsc-highlight-region `((,highlights ,tree nil)) 1 end text-function)
+            (should-not fetched)
+            (tsc-highlight-region `((,highlights ,tree ,locals)) 1 end text-function)
+            (should (member '(8 . 9) fetched))
+            (should-not (member '(1 . 21) fetched))))
+        (ert-info ("Errors of the text function should be propagated")
+          (should-error (tsc-highlight-region
+                         `((,highlights ,tree ,locals)) 1 end
+                         (lambda (_beg _end) (error "No text")))))))))
+
+(ert-deftest query::apply-spans ()
+  (with-temp-buffer
+    (insert "ăb cd")
+    (narrow-to-region 4 5)
+    (tsc-apply-spans (vector '(1 3 . bold) '(3 4 face italic help-echo "b") '(5 7)))
+    (widen)
+    (should (eq 'bold (get-text-property 1 'face)))
+    (should (eq 'italic (get-text-property 2 'face)))
+    (should (equal "b" (get-text-property 2 'help-echo)))
+    (should-not (text-properties-at 3))
+    (should-not (text-properties-at 4))
+    (tsc-apply-spans [(5 6 . "x")] 'tag)
+    (should (equal "x" (get-text-property 4 'tag)))))
+
+(ert-deftest query::injections ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn main() { foo!(1 + 2); bar!(x); }")
+      (let* ((language (tsc-parser-language parser))
+             (host-tree (tsc-parse-string parser (buffer-string)))
+             (query (tsc-make-query language
+                                    [((macro_invocation (token_tree) @injection.content)
+                                      (.set! injection.language "rust")
+                                      (.set! injection.include-children))
+                                     ((macro_invocation (token_tree) @injection.content)
+                                      (.set! injection.language "cobol"))]))
+             (fail nil)
+             (injector (tsc-make-injector query (lambda (name)
+                                                  (when fail
+                                                    (error "No language for %s" name))
+                                                  (when (equal name "rust")
+                                                    (tree-sitter-require 'rust))))))
+        (should (equal (tsc-injector-update injector host-tree) '("rust")))
+        (pcase-let ((`((,name . ,tree)) (tsc-injector-trees injector)))
+          (should (equal name "rust"))
+          (should (equal (length (tsc-tree-included-ranges tree)) 2)))
+        (ert-info ("Unchanged regions should not be re-parsed")
+          (should-not (tsc-injector-update injector host-tree)))
+        (ert-info ("Edited trees should be re-parsed")
+          (tsc-injector-edit injector 1 1 1 '(1 . 0) '(1 . 0) '(1 . 0))
+          (should (equal (tsc-injector-update injector host-tree) '("rust"))))
+        (ert-info ("A failed update should keep the trees")
+          (setq fail t)
+          (tsc-injector-edit injector 1 1 1 '(1 . 0) '(1 . 0) '(1 . 0))
+          (should-error (tsc-injector-update injector host-tree))
+          (should (equal (mapcar #'car (tsc-injector-trees injector)) '("rust"))))
+        (ert-info ("A released injector should have no trees")
+          (should (tsc-release injector))
+          (should (tsc-released-p injector))
+          (should-not (tsc-injector-trees injector))
+          (should-not (tsc-injector-update injector host-tree)))))))
+
+(ert-deftest node::fields ()
+  (tsc-test-with python parser
+    (let* ((language (tsc-parser-language parser))
+           (tree (tsc-parse-string parser "import a, b.c\n"))
+           (import (tsc-get-nth-named-child (tsc-root-node tree) 0))
+           (name-id (tsc-field-id-for-name language :name)))
+      (should (eql name-id (tsc-field-id-for-name language "name")))
+      (should (null (tsc-field-id-for-name language "no_such_field")))
+      (ert-info ("Field ids should give the same children as field names")
+        (should (tsc-node-eq (tsc-child-by-field-id import name-id)
+                             (tsc-get-child-by-field import :name))))
+      (ert-info ("All children of a repeated field should be returned")
+        (should (equal '(dotted_name dotted_name)
+                       (mapcar #'tsc-node-type (tsc-children-by-field import :name))))
+        (should (= 2 (length (tsc-children-by-field import name-id))))
+        (should (equal [] (tsc-children-by-field import :body))))
+      (ert-info ("Only children associated with a field should have one")
+        (should (null (tsc-field-name-for-child import 0)))
+        (should (eq :name (tsc-field-name-for-child import 1)))
+        (should (null (tsc-field-name-for-child import 2)))
+        (should (eq :name (tsc-field-name-for-child import 3)))))))
+
+(ert-deftest node::descendant-for-point-range ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo() {\n    ñ();\n}\n")
+      (let* ((root (tsc-root-node (tsc-parse-string parser (buffer-string))))
+             (map (tsc-buffer-position-map)))
+        (ert-info ("Points")
+          (should (eq 'identifier (tsc-node-type (tsc-descendant-for-point-range
+                                                  root '(1 . 3) '(1 . 6)))))
+          (should (equal "(" (tsc-node-type (tsc-descendant-for-point-range
+                                             root '(1 . 6) '(1 . 7)))))
+          (should (eq 'parameters (tsc-node-type (tsc-named-descendant-for-point-range
+                                                  root '(1 . 6) '(1 . 7))))))
+        (ert-info ("Positions, with and without a position map")
+          (dolist (map (list nil map))
+            (let ((node (tsc-named-descendant-for-point-range root 16 17 map)))
+              (should (eq 'identifier (tsc-node-type node)))
+              (should (equal '(2 . 4) (tsc-node-start-point node))))))
+        (should-error (tsc-descendant-for-point-range root 1 '(1 . 0))
+                      :type 'wrong-type-argument)))))
+
+(ert-deftest node::first-child-for-byte ()
+  (tsc-test-with rust parser
+    (let* ((root (tsc-root-node (tsc-parse-string parser "fn foo() {}\nfn bar() {}\n")))
+           (bar (tsc-get-nth-child root 1)))
+      (should (tsc-node-eq bar (tsc-first-child-for-byte root 13)))
+      (should (tsc-node-eq bar (tsc-first-child-for-byte root 12)))
+      (should (null (tsc-first-child-for-byte root 30)))
+      (ert-info ("Anonymous children should be skipped by the named variant")
+        (should (equal "fn" (tsc-node-type (tsc-first-child-for-byte bar 13))))
+        (should (eq 'identifier (tsc-node-type (tsc-first-named-child-for-byte bar 13))))
+        (should (null (tsc-first-named-child-for-byte bar 24)))))))
+
+(ert-deftest node::identity ()
+  (tsc-test-with rust parser
+    (let* ((tree (tsc-parse-string parser "fn foo() {}"))
+           (root (tsc-root-node tree))
+           (item (tsc-get-nth-child root 0))
+           (table (make-hash-table :test 'tsc-node-eq)))
+      (should (integerp (tsc-node-id item)))
+      (should (= (tsc-node-id item) (tsc-node-id (tsc-get-nth-child root 0))))
+      (should (/= (tsc-node-id item) (tsc-node-id root)))
+      (puthash item 'folded table)
+      (should (eq 'folded (gethash (tsc-get-parent (tsc-get-nth-child item 0)) table)))
+      (should (null (gethash root table))))))
+
+(ert-deftest node::buffer-text ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn ñandú() {}\n")
+      (let* ((buffer (current-buffer))
+             (tree (tsc-parse-string parser (buffer-string)))
+             (name (tsc-get-child-by-field (tsc-get-nth-child (tsc-root-node tree) 0) :name)))
+        (should (equal "ñandú" (tsc-node-buffer-text name)))
+        (narrow-to-region 1 3)
+        (should (equal "ñandú" (tsc-node-buffer-text name)))
+        (with-temp-buffer
+          (should (equal "ñandú" (tsc-node-buffer-text name buffer))))))))
+
+(ert-deftest query::first-n-captures ()
+  (tsc-test-lang-with-file c "data/range-restriction-and-early-termination.c"
+    (let ((query (tsc-make-query tree-sitter-language
+                                 [(call_expression
+                                   function: (identifier) @function
+                                   arguments: (argument_list (string_literal) @string.arg))
+                                  (string_literal) @string]))
+          (root-node (tsc-root-node tree-sitter-tree)))
+      (should (equal (mapcar #'car (tsc-first-n-captures
+                                    query root-node 1 (position-bytes (point-max)) 1))
+                     '(function)))
+      (should (equal (mapcar #'car (tsc-first-n-captures
+                                    query root-node 1 (position-bytes (point-max)) 10))
+                     '(function string.arg string))))))
+
+(ert-deftest query::captures-parallel ()
+  (tsc-test-lang-with-file rust "data/query.rs"
+    (let* ((query (tsc-make-query tree-sitter-language
+                                  "((function_item (identifier) @function)
+                                    (#match? @function \"make_query\"))"))
+           (items (tsc-make-query tree-sitter-language "(function_item) @item"))
+           (end-byte (position-bytes (point-max)))
+           (spans (tsc-query-captures-parallel `((,query . ,tree-sitter-tree)) 1 end-byte))
+           (expected (mapcar (pcase-lambda (`(,tag . ,node))
+                               (pcase-let ((`(,beg . ,end) (tsc-node-byte-range node)))
+                                 `(,beg ,end . ,tag)))
+                             (tsc-query-captures query (tsc-root-node tree-sitter-tree)
+                                                 #'tsc--buffer-substring-no-properties))))
+      (should (> (length spans) 0))
+      (should (equal (append spans nil) expected))
+      (ert-info ("Later jobs should be painted over earlier ones")
+        (let ((merged (tsc-query-captures-parallel
+                       `((,items . ,tree-sitter-tree) (,query . ,tree-sitter-tree))
+                       1 end-byte)))
+          (should (seq-every-p (lambda (span) (member span (append merged nil))) expected))
+          (should (memq 'item (mapcar #'cddr merged)))
+          (ert-info ("Spans should be sorted, and should not overlap")
+            (should (seq-every-p (pcase-lambda (`((,_ ,end . ,_) (,beg . ,_))) (<= end beg))
+                                 (seq-mapn #'list merged (seq-drop merged 1))))))))))
+
+(ert-deftest query::any-of ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo() { bar(baz); }")
+      (let* ((tree (tsc-parse-string parser (buffer-string)))
+             (root-node (tsc-root-node tree))
+             (captures (lambda (source)
+                         (mapcar (lambda (capture) (tsc-node-text (cdr capture)))
+                                 (tsc-query-captures
+                                  (tsc-make-query (tsc-parser-language parser) source)
+                                  root-node #'tsc--buffer-substring-no-properties)))))
+        (should (equal (funcall captures "((identifier) @x (#any-of? @x \"foo\" \"baz\"))")
+                       '("foo" "baz")))
+        (should (equal (funcall captures "((identifier) @x (#not-any-of? @x \"foo\" \"baz\"))")
+                       '("bar")))
+        (ert-info ("Malformed #any-of? predicates should be rejected")
+          (let ((err (should-error
+                      (tsc-make-query (tsc-parser-language parser)
+                                      "((identifier) @x (#any-of? \"foo\" @x))")
+                      :type 'tsc-query-invalid-predicate)))
+            (should (eq (tsc-error-property err :kind) 'predicate))))))))
+
+(ert-deftest query::error-data ()
+  (let ((language (tree-sitter-require 'rust)))
+    (pcase-dolist (`(,source ,symbol ,kind ,bytepos ,actual)
+                   '(("(identifier) @x (" tsc-query-invalid-syntax syntax nil nil)
+                     ("(identifier) (foo_bar)" tsc-query-invalid-node-type node-type 15 "foo_bar")))
+      (let ((err (should-error (tsc-make-query language source) :type symbol)))
+        (should (stringp (tsc-error-message err)))
+        (should (eq (tsc-error-property err :kind) kind))
+        (should (integerp (tsc-error-property err :bytepos)))
+        (when bytepos
+          (should (equal (tsc-error-property err :bytepos) bytepos)))
+        (should (equal (tsc-error-property err :actual) actual))
+        (should (consp (tsc-error-property err :point)))))))
+
+(ert-deftest query::custom-predicates-and-directives ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo() {}\nfn bar() {}")
+      (let* ((language (tsc-parser-language parser))
+             (tree (tsc-parse-chunks parser #'tsc--buffer-input nil))
+             (root-node (tsc-root-node tree))
+             (query (tsc-make-query
+                     language
+                     "((identifier) @name (#starts-with? @name \"b\") (#set! kind \"fn\")
+                       (#set! @name role definition) (#offset! @name 0 1 0 -1))"))
+             (names (lambda ()
+                      (mapcar (lambda (c) (tsc-node-text (cdr c)))
+                              (tsc-query-captures query root-node
+                                                  #'tsc--buffer-substring-no-properties)))))
+        (unwind-protect
+            (progn
+              (should (equal (funcall names) '("foo" "bar")))
+              (tsc-define-query-predicate
+               "starts-with?" (lambda (node prefix)
+                                (string-prefix-p prefix (tsc-node-text node))))
+              (should (equal (funcall names) '("bar")))
+              (ert-info ("Parallel queries should evaluate custom predicates too")
+                (should (equal (tsc-query-captures-parallel
+                                `((,query . ,tree)) 1 (position-bytes (point-max)))
+                               [(16 19 . name)])))
+              (let ((match (aref (tsc-query-matches query root-node
+                                                    #'tsc--buffer-substring-no-properties)
+                                 0)))
+                (should (equal (tsc-query-match-metadata query match)
+                               '(:properties (("kind" . "fn"))
+                                 :captures ((name :properties (("role" . "definition"))
+                                                  :range ((2 . 4) . (2 . 5)))))))))
+          (tsc-define-query-predicate "starts-with?" nil))
+        (should (equal (funcall names) '("foo" "bar")))))))
+
+(ert-deftest query::lisp-patterns ()
+  (let ((language (tree-sitter-require 'rust)))
+    (should (equal (tsc--stringify-patterns
+                    [(function_item :name (identifier) @name \. (parameters))
+                     ((identifier) @x (.eq? @x "a\"b\\"))
+                     [(integer_literal) "+"] @constant (_)* @any])
+                   (concat "(function_item name: (identifier) @name . (parameters))\n"
+                           "((identifier) @x (.eq? @x \"a\\\"b\\\\\"))\n"
+                           "[(integer_literal) \"+\"]\n@constant\n(_)\n*\n@any")))
+    (tsc-test-with rust parser
+      (with-temp-buffer
+        (insert "fn foo() {} fn bar() {}")
+        (let* ((name "bar")
+               (query (tsc-make-query language `[((identifier) @x (.eq? @x ,name))]))
+               (root-node (tsc-root-node (tsc-parse-chunks parser #'tsc--buffer-input nil)))
+               (captures (tsc-query-captures query root-node
+                                             #'tsc--buffer-substring-no-properties)))
+          (should (equal (mapcar (lambda (c) (tsc-node-text (cdr c))) captures) '("bar"))))))
+    (ert-info ("Errors should point to the offending element")
+      (let ((err (should-error (tsc-make-query language [(function_item (foo_bar))])
+                               :type 'tsc-query-invalid-node-type)))
+        (should (eq (tsc-error-property err :form) 'foo_bar)))
+      (let ((err (should-error (tsc-make-query language [(function_item :foo (identifier))])
+                               :type 'tsc-query-invalid-field)))
+        (should (eq (tsc-error-property err :form) :foo)))
+      (let ((err (should-error (tsc-make-query language [(identifier 1.5)])
+                               :type 'tsc-query-invalid-syntax)))
+        (should (equal (tsc-error-property err :form) 1.5))))))
+
+(ert-deftest query::validation-diagnostics ()
+  (let ((language (tree-sitter-require 'rust)))
+    (should-not (tsc-query-validate language "(identifier) @x"))
+    (let ((diagnostic (tsc-query-validate language "(identifier) (foo_bar)")))
+      (should (equal (plist-get diagnostic :message) "Invalid node type \"foo_bar\""))
+      (should (eq (plist-get diagnostic :kind) 'node-type))
+      (should (equal (plist-get diagnostic :bytepos) 15))
+      (should (equal (plist-get diagnostic :point) '(1 . 14)))
+      (should (equal (plist-get diagnostic :actual) "foo_bar")))
+    (should (eq (plist-get (tsc-query-validate language [(function_item foo: (identifier))]) :kind)
+                'field))))
+
+(ert-deftest query::editing-in-text-function ()
+  (tsc-test-with rust parser
+    (let* ((tree (tsc-parse-string parser "fn foo() {}"))
+           (query (tsc-make-query (tsc-parser-language parser)
+                                  [((identifier) @name (.eq? @name "foo"))])))
+      (ert-info ("Editing the tree while it's being queried should signal an error, not crash")
+        (should-error
+         (tsc-query-captures query (tsc-root-node tree)
+                             (lambda (_beg _end)
+                               (tsc-edit-tree tree 1 1 1 '(1 . 0) '(1 . 0) '(1 . 0))
+                               "foo")))))))
+
+(ert-deftest query::shared ()
+  (tsc-test-with rust parser
+    (let* ((language (tsc-parser-language parser))
+           (query (tsc-make-shared-query language [(identifier) @id])))
+      (should (eq query (tsc-make-shared-query language [(identifier) @id])))
+      (should-not (eq query (tsc-make-shared-query language [(identifier) @id] #'identity)))
+      (should-not (eq query (tsc-make-shared-query language [(identifier) @other])))
+      (ert-info ("Released queries should be compiled again")
+        (tsc-release query)
+        (should-not (eq query (tsc-make-shared-query language [(identifier) @id]))))
+      (should (> (plist-get (tsc-cache-stats) :query-hits) 0))
+      (tsc-clear-caches)
+      (should (equal (plist-get (tsc-cache-stats) :queries) 0))
+      (ert-info ("Loading a language again should return the loaded one")
+        (tsc--load-language (tsc--lang-load-file language) "tree_sitter_rust" 'rust)
+        (should (= 1 (plist-get (tsc-cache-stats) :language-hits)))))))
+
+(ert-deftest parser::pool ()
+  (tsc-test-with rust parser
+    (tsc-clear-caches)
+    (let* ((language (tsc-parser-language parser))
+           (pooled (tsc-checkout-parser language)))
+      (should (eq language (tsc-parser-language pooled)))
+      (tsc-set-included-ranges pooled [[1 3 (1 . 0) (1 . 2)]])
+      (tsc-parse-string pooled "fn foo() {}")
+      (tsc-checkin-parser pooled)
+      (should (= 1 (plist-get (tsc-cache-stats) :pooled-parsers)))
+      (ert-info ("Checked-in parsers cannot be used")
+        (should-error (tsc-parse-string pooled "fn bar() {}") :type 'tsc-error)
+        (should-error (tsc-checkin-parser pooled) :type 'tsc-error))
+      (ert-info ("Checked-out parsers should be reset")
+        (should (eq pooled (tsc-checkout-parser language)))
+        (should (equal [] (tsc-parser-included-ranges pooled)))
+        (should (equal (tsc-tree-to-sexp (tsc-parse-string parser "fn bar() {}"))
+                       (tsc-tree-to-sexp (tsc-parse-string pooled "fn bar() {}")))))
+      (should (= 0 (plist-get (tsc-cache-stats) :pooled-parsers))))))
+
+(ert-deftest query::cursor-pool ()
+  (tsc-test-with rust parser
+    (tsc-clear-caches)
+    (let* ((language (tsc-parser-language parser))
+           (tree (tsc-parse-string parser "fn foo() {}\nfn bar() {}"))
+           (root-node (tsc-root-node tree))
+           (query (tsc-make-query language "(identifier) @name"))
+           (captures (lambda (&optional beg-byte end-byte)
+                       (length (tsc-query-captures query root-node #'ignore nil
+                                                   beg-byte end-byte)))))
+      (should (= 1 (funcall captures 1 5)))
+      (should (= 1 (plist-get (tsc-cache-stats) :pooled-query-cursors)))
+      (ert-info ("Pooled cursors should be reset")
+        (should (= 2 (funcall captures))))
+      (should (= 1 (plist-get (tsc-cache-stats) :pooled-query-cursors)))
+      (ert-info ("Given cursors should not be pooled")
+        (tsc-query-matches query root-node #'ignore (tsc-make-query-cursor))
+        (should (= 1 (plist-get (tsc-cache-stats) :pooled-query-cursors))))
+      (tsc-clear-caches)
+      (should (= 0 (plist-get (tsc-cache-stats) :pooled-query-cursors))))))
+
+(ert-deftest query::requery ()
+  (tsc-test-with rust parser
+    (let* ((source "fn foo(a: T) {}")
+           (text-function (lambda (beg end) (substring source (1- beg) (1- end))))
+           (query (tsc-make-query (tsc-parser-language parser) [(identifier) @id]))
+           (old-tree (tsc-parse-string parser "fn foo() {}"))
+           (_ (tsc-edit-tree old-tree 8 8 12 '(1 . 7) '(1 . 7) '(1 . 11)))
+           (new-tree (tsc-parse-chunks parser (lambda (bytepos _l _c)
+                                                (substring source (1- (min bytepos 16))))
+                                       old-tree)))
+      (pcase-let ((`(,invalidated . ,captures)
+                   (tsc-requery query old-tree new-tree nil text-function)))
+        (should (< 0 (length invalidated)))
+        (ert-info ("Only the new parameter should be re-captured")
+          (should (equal '("a")
+                         (seq-map (lambda (capture)
+                                    (funcall text-function
+                                             (tsc-node-start-byte (cdr capture))
+                                             (tsc-node-end-byte (cdr capture))))
+                                  captures))))
+        (ert-info ("The captures should be within the invalidated ranges")
+          (seq-doseq (capture captures)
+            (should (seq-some (pcase-lambda (`(,beg . ,end))
+                                (and (<= beg (tsc-node-start-byte (cdr capture)))
+                                     (<= (tsc-node-end-byte (cdr capture)) end)))
+                              invalidated)))))
+      (pcase-let ((`(,invalidated . ,captures)
+                   (tsc-requery query new-tree new-tree '((4 . 7)) text-function)))
+        (ert-info ("Edited ranges should be invalidated")
+          (should (equal [(4 . 7)] invalidated))
+          (should (equal '(identifier) (seq-map (lambda (capture) (tsc-node-type (cdr capture)))
+                                                captures))))))))
+
+(ert-deftest query::capture-metadata ()
+  (tsc-test-with rust parser
+    (let ((query (tsc-make-query (tsc-parser-language parser)
+                                 [(identifier) @variable
+                                  (type_identifier) @type])))
+      (should (= 2 (tsc-query-capture-count query)))
+      (should (equal "type" (tsc-query-capture-name-for-id query 1)))
+      (should (= 1 (tsc-query-capture-index-for-name query "type")))
+      (should (eq 'type (aref (tsc-query-capture-tags query)
+                              (tsc-query-capture-index-for-name query "type"))))
+      (should-not (tsc-query-capture-index-for-name query "function"))
+      (should-error (tsc-query-capture-name-for-id query 2) :type 'args-out-of-range))))
+
 (ert-deftest query::validation ()
   ;; https://github.com/emacs-tree-sitter/elisp-tree-sitter/issues/125
   (should (tsc-make-query (tree-sitter-require 'rust)
@@ -408,6 +2280,32 @@ source_file (1 . 20)
   (should-error (tree-sitter-require 'abc-xyz))
   (tree-sitter-require 'rust))
 
+(ert-deftest load::wasm ()
+  (let ((err (should-error (tsc--load-language "/tmp/tree-sitter-rust.wasm" "tree_sitter_rust" 'rust)
+                           :type 'tsc-lang-load-failed)))
+    (should (eq 'wasm (tsc-error-property err :kind)))))
+
+(ert-deftest load::compile-grammar ()
+  (let ((err (should-error (tsc-compile-grammar (tsc-test-full-path "data")
+                                                (make-temp-file "tsc-grammar"))
+                           :type 'tsc-grammar-compile-failed)))
+    (should (eq 'no-parser (tsc-error-property err :kind)))))
+
+(ert-deftest load::doctor ()
+  (let ((report (tsc-doctor 'rust
+                            '((valid . "(identifier) @id")
+                              (invalid . "(not_a_node_type) @x"))
+                            "fn foo() {}")))
+    (should (equal (assq 'library report) '(library t)))
+    (should (equal (assq 'symbol report) '(symbol t)))
+    (should (nth 1 (assq 'abi report)))
+    (should (equal (seq-map (lambda (check) (seq-take check 3))
+                            (seq-filter (lambda (check) (eq (car check) 'query)) report))
+                   '((query t valid) (query nil invalid))))
+    (should (equal (assq 'parse report) '(parse t "source_file" nil))))
+  (should (equal (car (tsc-doctor 'abc-xyz))
+                 '(library nil "Cannot find shared library for abc-xyz"))))
+
 ;;; ----------------------------------------------------------------------------
 ;;; Highlighting tests.
 